@@ -9,22 +9,27 @@ use std::time::Duration;
 use clap::{clap_app, Arg};
 use futures::future::{self, Either};
 use log::info;
-use tokio::{self, runtime::Builder};
+use tokio::{self, runtime::Builder, sync::oneshot};
+use url::Url;
 
 #[cfg(feature = "local-redir")]
 use shadowsocks::config::RedirType;
+#[cfg(feature = "aead-cipher-2022")]
+use shadowsocks::relay::aead_2022;
 #[cfg(any(feature = "local-dns", feature = "local-tunnel"))]
 use shadowsocks::relay::socks5::Address;
 use shadowsocks::{
     acl::AccessControl,
-    crypto::v1::{available_ciphers, CipherKind},
+    config::{BalanceStrategy, LocalAuth, OnlineConfig},
+    crypto::v1::available_ciphers,
     plugin::PluginConfig,
-    run_local,
+    run_local_with_control,
     Config,
     ConfigType,
     Mode,
     ServerAddr,
     ServerConfig,
+    ServerControl,
 };
 
 mod allocator;
@@ -32,6 +37,7 @@ mod allocator;
 mod daemonize;
 mod logging;
 mod monitor;
+mod reload;
 mod validator;
 mod version;
 
@@ -54,7 +60,21 @@ const AVAILABLE_PROTOCOLS: &[&str] = &[
     "dns",
 ];
 
+const AVAILABLE_BALANCE_STRATEGIES: &[&str] = &[
+    "lowest_latency",
+    "round_robin",
+    "weighted_random",
+    "least_connections",
+    "consistent_hash",
+];
+
 fn main() {
+    // Methods of the original protocol, and AEAD-2022 methods
+    #[allow(unused_mut)]
+    let mut available_methods = available_ciphers().to_vec();
+    #[cfg(feature = "aead-cipher-2022")]
+    available_methods.extend(aead_2022::available_methods());
+
     let mut app = clap_app!(shadowsocks =>
         (version: self::version::VERSION)
         (about: "A fast tunnel proxy that helps you bypass firewalls.")
@@ -68,7 +88,7 @@ fn main() {
 
         (@arg SERVER_ADDR: -s --("server-addr") +takes_value {validator::validate_server_addr} requires[PASSWORD ENCRYPT_METHOD] "Server address")
         (@arg PASSWORD: -k --password +takes_value requires[SERVER_ADDR] "Server's password")
-        (@arg ENCRYPT_METHOD: -m --("encrypt-method") +takes_value requires[SERVER_ADDR] possible_values(available_ciphers()) +next_line_help "Server's encryption method")
+        (@arg ENCRYPT_METHOD: -m --("encrypt-method") +takes_value requires[SERVER_ADDR] possible_values(&available_methods) +next_line_help "Server's encryption method")
         (@arg TIMEOUT: --timeout +takes_value {validator::validate_u64} requires[SERVER_ADDR] "Server's timeout seconds for TCP relay")
 
         (@arg PLUGIN: --plugin +takes_value requires[SERVER_ADDR] "SIP003 (https://shadowsocks.org/en/spec/Plugin.html) plugin")
@@ -76,21 +96,30 @@ fn main() {
 
         (@arg URL: --("server-url") +takes_value {validator::validate_server_url} "Server address in SIP002 (https://shadowsocks.org/en/spec/SIP002-URI-Scheme.html) URL")
 
+        (@arg ONLINE_CONFIG_URL: --("online-config-url") +takes_value {validator::validate_online_config_url} "SIP008 (https://shadowsocks.org/en/wiki/SIP008-Online-Configuration-Delivery.html) online configuration URL, http, https and file schemes are supported")
+        (@arg ONLINE_CONFIG_UPDATE_INTERVAL: --("online-config-update-interval") +takes_value {validator::validate_positive_u64} requires[ONLINE_CONFIG_URL] "Seconds between fetching online configuration, default is 3600")
+        (@arg ONLINE_CONFIG_CACHE_PATH: --("online-config-cache-path") +takes_value requires[ONLINE_CONFIG_URL] "Path for caching online configuration, used if the URL is unreachable when starting")
+
         (@group SERVER_CONFIG =>
-            (@attributes +multiple arg[SERVER_ADDR URL]))
+            (@attributes +multiple arg[SERVER_ADDR URL ONLINE_CONFIG_URL]))
 
         (@arg PROTOCOL: --protocol +takes_value default_value("socks5") possible_values(AVAILABLE_PROTOCOLS) +next_line_help "Protocol that for communicating with clients")
 
         (@arg NO_DELAY: --("no-delay") !takes_value "Set TCP_NODELAY option for socket")
         (@arg NOFILE: -n --nofile +takes_value "Set RLIMIT_NOFILE with both soft and hard limit (only for *nix systems)")
         (@arg ACL: --acl +takes_value "Path to ACL (Access Control List)")
+        (@arg LOCAL_USERS_FILE: --("local-users-file") +takes_value "Path to users allowed to connect to HTTP and SOCKS5 local servers, one \"username:password\" per line")
 
         (@arg LOG_WITHOUT_TIME: --("log-without-time") "Log without datetime prefix")
         (@arg LOG_CONFIG: --("log-config") +takes_value "log4rs configuration file")
 
         (@arg UDP_TIMEOUT: --("udp-timeout") +takes_value {validator::validate_u64} "Timeout seconds for UDP relay")
+        (@arg SHUTDOWN_TIMEOUT: --("shutdown-timeout") +takes_value {validator::validate_u64} "Seconds for waiting in-flight connections to finish when shutting down")
         (@arg UDP_MAX_ASSOCIATIONS: --("udp-max-associations") +takes_value {validator::validate_u64} "Maximum associations to be kept simultaneously for UDP relay")
 
+        (@arg BALANCE_STRATEGY: --("balance-strategy") +takes_value possible_values(AVAILABLE_BALANCE_STRATEGIES) +next_line_help "Strategy of choosing a server for each connection, default is lowest_latency")
+        (@arg FAILOVER_RETRIES: --("failover-retries") +takes_value {validator::validate_u64} "Times of retrying with other servers if failed to connect to the chosen server, default is 1")
+
         (@arg UDP_BIND_ADDR: --("udp-bind-addr") +takes_value {validator::validate_server_addr} "UDP relay's bind address, default is the same as local-addr")
     );
 
@@ -171,6 +200,13 @@ fn main() {
         );
     }
 
+    #[cfg(feature = "metrics")]
+    {
+        app = clap_app!(@app (app)
+            (@arg METRICS_ADDRESS: --("metrics-address") +takes_value {validator::validate_socket_addr} "Address of Prometheus metrics exporter, should be ip:port")
+        );
+    }
+
     #[cfg(unix)]
     {
         app = clap_app!(@app (app)
@@ -222,13 +258,12 @@ fn main() {
         None => Config::new(config_type),
     };
 
+    // Servers specified by command line are kept after reloading configuration
+    let file_server_count = config.server.len();
+
     if let Some(svr_addr) = matches.value_of("SERVER_ADDR") {
         let password = matches.value_of("PASSWORD").expect("password");
-        let method = matches
-            .value_of("ENCRYPT_METHOD")
-            .expect("encrypt-method")
-            .parse::<CipherKind>()
-            .expect("encryption method");
+        let method = matches.value_of("ENCRYPT_METHOD").expect("encrypt-method");
         let svr_addr = svr_addr.parse::<ServerAddr>().expect("server-addr");
 
         let timeout = matches
@@ -236,7 +271,10 @@ fn main() {
             .map(|t| t.parse::<u64>().expect("timeout"))
             .map(Duration::from_secs);
 
-        let mut sc = ServerConfig::new(svr_addr, password.to_owned(), method, timeout, None);
+        let mut sc = match ServerConfig::with_method_name(svr_addr, password.to_owned(), method, timeout, None) {
+            Ok(sc) => sc,
+            Err(err) => panic!("encrypt-method \"{}\", {}", method, err),
+        };
 
         if let Some(p) = matches.value_of("PLUGIN") {
             let plugin = PluginConfig {
@@ -324,7 +362,7 @@ fn main() {
     }
 
     if let Some(acl_file) = matches.value_of("ACL") {
-        let acl = match AccessControl::load_from_file(acl_file) {
+        let acl = match AccessControl::load_from_file_with_geoip(acl_file, config.geoip_db.as_deref()) {
             Ok(acl) => acl,
             Err(err) => {
                 panic!("loading ACL \"{}\", {}", acl_file, err);
@@ -333,10 +371,25 @@ fn main() {
         config.acl = Some(acl);
     }
 
+    if let Some(users_file) = matches.value_of("LOCAL_USERS_FILE") {
+        let auth = match LocalAuth::load_from_file(users_file) {
+            Ok(auth) => auth,
+            Err(err) => {
+                panic!("loading local users \"{}\", {}", users_file, err);
+            }
+        };
+        config.local_auth = Some(auth);
+    }
+
     if matches.is_present("IPV6_FIRST") {
         config.ipv6_first = true;
     }
 
+    #[cfg(feature = "metrics")]
+    if let Some(ma) = matches.value_of("METRICS_ADDRESS") {
+        config.metrics_addr = Some(ma.parse::<std::net::SocketAddr>().expect("metrics-address"));
+    }
+
     #[cfg(feature = "local-tunnel")]
     if let Some(faddr) = matches.value_of("FORWARD_ADDR") {
         let addr = faddr.parse::<Address>().expect("forward-addr");
@@ -380,10 +433,38 @@ fn main() {
         config.udp_timeout = Some(Duration::from_secs(udp_timeout.parse::<u64>().expect("udp-timeout")));
     }
 
+    if let Some(shutdown_timeout) = matches.value_of("SHUTDOWN_TIMEOUT") {
+        let shutdown_timeout = shutdown_timeout.parse::<u64>().expect("shutdown-timeout");
+        config.shutdown_timeout = Some(Duration::from_secs(shutdown_timeout));
+    }
+
     if let Some(udp_max_assoc) = matches.value_of("UDP_MAX_ASSOCIATIONS") {
         config.udp_max_associations = Some(udp_max_assoc.parse::<usize>().expect("udp-max-associations"));
     }
 
+    if let Some(strategy) = matches.value_of("BALANCE_STRATEGY") {
+        config.balance_strategy = strategy.parse::<BalanceStrategy>().expect("balance-strategy");
+    }
+
+    if let Some(retries) = matches.value_of("FAILOVER_RETRIES") {
+        config.failover_retries = retries.parse::<usize>().expect("failover-retries");
+    }
+
+    if let Some(url) = matches.value_of("ONLINE_CONFIG_URL") {
+        let url = url.parse::<Url>().expect("online-config-url");
+        let mut online_config = OnlineConfig::new(url);
+
+        if let Some(interval) = matches.value_of("ONLINE_CONFIG_UPDATE_INTERVAL") {
+            let interval = interval.parse::<u64>().expect("online-config-update-interval");
+            online_config.update_interval = Duration::from_secs(interval);
+        }
+        if let Some(path) = matches.value_of("ONLINE_CONFIG_CACHE_PATH") {
+            online_config.cache_path = Some(path.into());
+        }
+
+        config.online_config = Some(online_config);
+    }
+
     if let Some(udp_bind_addr) = matches.value_of("UDP_BIND_ADDR") {
         config.udp_bind_addr = Some(udp_bind_addr.parse::<ServerAddr>().expect("udp-bind-addr"));
     }
@@ -399,11 +480,12 @@ fn main() {
         return;
     }
 
-    if config.server.is_empty() {
+    if config.server.is_empty() && config.online_config.is_none() {
         eprintln!(
             "missing proxy servers, consider specifying it by \
              --server-addr, --encrypt-method, --password command line option, \
                 or --server-url command line option, \
+                or --online-config-url command line option, \
                 or configuration file, check more details in https://shadowsocks.org/en/config/quick-guide.html"
         );
         println!("{}", matches.usage());
@@ -423,6 +505,20 @@ fn main() {
 
     info!("shadowsocks {}", self::version::VERSION);
 
+    let extra_servers = config.server[file_server_count..].to_vec();
+    let args_online_config = if matches.is_present("ONLINE_CONFIG_URL") {
+        config.online_config.clone()
+    } else {
+        None
+    };
+    let (reloader, reload_rx) = reload::ConfigReloader::new(
+        config.clone(),
+        matches.value_of("CONFIG"),
+        matches.value_of("ACL"),
+        extra_servers,
+        args_online_config,
+    );
+
     let mut builder = if cfg!(feature = "single-threaded") {
         Builder::new_current_thread()
     } else {
@@ -430,19 +526,39 @@ fn main() {
     };
     let runtime = builder.enable_all().build().expect("create tokio Runtime");
     runtime.block_on(async move {
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let control = ServerControl {
+            reload_rx: Some(reload_rx),
+            shutdown_rx: Some(shutdown_rx),
+        };
+
+        tokio::spawn(reloader.reload_on_signal());
+
         let abort_signal = monitor::create_signal_monitor();
-        let server = run_local(config);
+        let server = run_local_with_control(config, control);
 
         tokio::pin!(abort_signal);
         tokio::pin!(server);
 
-        match future::select(server, abort_signal).await {
+        match future::select(server.as_mut(), abort_signal).await {
             // Server future resolved without an error. This should never happen.
             Either::Left((Ok(..), ..)) => panic!("server exited unexpectly"),
             // Server future resolved with error, which are listener errors in most cases
             Either::Left((Err(err), ..)) => panic!("aborted with {}", err),
-            // The abort signal future resolved. Means we should just exit.
-            Either::Right(_) => (),
+            // The abort signal future resolved. Stop accepting and wait for in-flight connections.
+            Either::Right(_) => {
+                let _ = shutdown_tx.send(());
+            }
+        }
+
+        // Exit immediately if receiving another signal while draining
+        let abort_signal = monitor::create_signal_monitor();
+        tokio::pin!(abort_signal);
+
+        match future::select(server, abort_signal).await {
+            Either::Left((Ok(..), ..)) => (),
+            Either::Left((Err(err), ..)) => panic!("aborted with {}", err),
+            Either::Right(_) => info!("exiting without waiting in-flight connections"),
         }
     });
 }
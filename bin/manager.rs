@@ -9,6 +9,7 @@
 
 use std::{
     net::{IpAddr, SocketAddr},
+    path::PathBuf,
     time::Duration,
 };
 
@@ -61,6 +62,12 @@ fn main() {
         (@arg ENCRYPT_METHOD: -m --("encrypt-method") +takes_value possible_values(available_ciphers()) +next_line_help "Default encryption method")
         (@arg TIMEOUT: --timeout +takes_value {validator::validate_u64} "Default timeout seconds for TCP relay")
 
+        (@arg STAT_ADDRESS: --("stat-address") +takes_value {validator::validate_manager_addr} "Address of statistic collector, manager will send \"stat\" reports to it periodically")
+        (@arg STAT_INTERVAL: --("stat-interval") +takes_value {validator::validate_u64} "Interval seconds of sending \"stat\" reports, default is 10")
+        (@arg STAT_RESET: --("stat-reset") "Only count traffic since the last \"stat\" command or report")
+
+        (@arg STATE_FILE: --("state-file") +takes_value "Path to file for persisting servers created by \"add\" command, they will be restored when manager restarts")
+
         (@arg NOFILE: -n --nofile +takes_value "Set RLIMIT_NOFILE with both soft and hard limit (only for *nix systems)")
         (@arg ACL: --acl +takes_value "Path to ACL (Access Control List)")
 
@@ -76,6 +83,21 @@ fn main() {
         );
     }
 
+    #[cfg(feature = "manager-http")]
+    {
+        app = clap_app!(@app (app)
+            (@arg HTTP_ADDRESS: --("http-address") +takes_value {validator::validate_socket_addr} "Address of HTTP API, should be ip:port")
+            (@arg HTTP_TOKEN: --("http-token") +takes_value "Token required in \"Authorization: Bearer\" header of HTTP API requests, required if HTTP API doesn't listen on loopback address")
+        );
+    }
+
+    #[cfg(feature = "metrics")]
+    {
+        app = clap_app!(@app (app)
+            (@arg METRICS_ADDRESS: --("metrics-address") +takes_value {validator::validate_socket_addr} "Address of Prometheus metrics exporter, should be ip:port")
+        );
+    }
+
     #[cfg(any(target_os = "linux", target_os = "android"))]
     {
         app = clap_app!(@app (app)
@@ -162,6 +184,32 @@ fn main() {
         if let Some(sh) = matches.value_of("SERVER_HOST") {
             manager_config.server_host = sh.parse::<ManagerServerHost>().unwrap();
         }
+
+        if let Some(sa) = matches.value_of("STAT_ADDRESS") {
+            manager_config.stat_addr = Some(sa.parse::<ManagerAddr>().expect("stat-address"));
+        }
+
+        if let Some(t) = matches.value_of("STAT_INTERVAL") {
+            manager_config.stat_interval = Some(Duration::from_secs(t.parse::<u64>().expect("stat-interval")));
+        }
+
+        if matches.is_present("STAT_RESET") {
+            manager_config.stat_reset = true;
+        }
+
+        if let Some(p) = matches.value_of("STATE_FILE") {
+            manager_config.state_file = Some(PathBuf::from(p));
+        }
+
+        #[cfg(feature = "manager-http")]
+        if let Some(ha) = matches.value_of("HTTP_ADDRESS") {
+            manager_config.http_addr = Some(ha.parse::<SocketAddr>().expect("http-address"));
+        }
+
+        #[cfg(feature = "manager-http")]
+        if let Some(token) = matches.value_of("HTTP_TOKEN") {
+            manager_config.http_token = Some(token.to_owned());
+        }
     }
 
     if let Some(nofile) = matches.value_of("NOFILE") {
@@ -169,7 +217,7 @@ fn main() {
     }
 
     if let Some(acl_file) = matches.value_of("ACL") {
-        let acl = match AccessControl::load_from_file(acl_file) {
+        let acl = match AccessControl::load_from_file_with_geoip(acl_file, config.geoip_db.as_deref()) {
             Ok(acl) => acl,
             Err(err) => {
                 panic!("loading ACL \"{}\", {}", acl_file, err);
@@ -182,6 +230,11 @@ fn main() {
         config.ipv6_first = true;
     }
 
+    #[cfg(feature = "metrics")]
+    if let Some(ma) = matches.value_of("METRICS_ADDRESS") {
+        config.metrics_addr = Some(ma.parse::<SocketAddr>().expect("metrics-address"));
+    }
+
     // DONE reading options
 
     if config.manager.is_none() {
@@ -0,0 +1,123 @@
+//! Reloading configuration while running
+
+// Configuration is only reloaded by SIGHUP, which doesn't exist on other platforms
+#![cfg_attr(not(unix), allow(dead_code))]
+
+use std::io::{self, ErrorKind};
+
+use log::error;
+#[cfg(unix)]
+use log::info;
+use shadowsocks::{acl::AccessControl, config::OnlineConfig, Config, ServerConfig};
+use tokio::sync::mpsc;
+
+/// Reads configuration and ACL files again and sends them to the running server
+pub struct ConfigReloader {
+    config: Config,
+    config_path: Option<String>,
+    acl_path: Option<String>,
+    // Servers specified by command line options, kept after reloading
+    extra_servers: Vec<ServerConfig>,
+    // Online configuration specified by command line options, replaces the one in configuration file
+    args_online_config: Option<OnlineConfig>,
+    tx: mpsc::Sender<Config>,
+}
+
+impl ConfigReloader {
+    /// Create a reloader with the running `config`
+    ///
+    /// Only servers, server groups, online configuration and ACL are reloaded,
+    /// other options are kept the same as `config`
+    pub fn new(
+        config: Config,
+        config_path: Option<&str>,
+        acl_path: Option<&str>,
+        extra_servers: Vec<ServerConfig>,
+        args_online_config: Option<OnlineConfig>,
+    ) -> (ConfigReloader, mpsc::Receiver<Config>) {
+        let (tx, rx) = mpsc::channel(1);
+
+        let reloader = ConfigReloader {
+            config,
+            config_path: config_path.map(ToOwned::to_owned),
+            acl_path: acl_path.map(ToOwned::to_owned),
+            extra_servers,
+            args_online_config,
+            tx,
+        };
+
+        (reloader, rx)
+    }
+
+    /// Reload configuration every time SIGHUP is received
+    #[cfg(unix)]
+    pub async fn reload_on_signal(self) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sighup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(err) => {
+                error!("failed to listen SIGHUP for reloading configuration, {}", err);
+                return;
+            }
+        };
+
+        while sighup.recv().await.is_some() {
+            info!("received SIGHUP, reloading");
+            self.reload();
+        }
+    }
+
+    /// Reloading by signal is not supported on this platform
+    #[cfg(not(unix))]
+    pub async fn reload_on_signal(self) {
+        futures::future::pending().await
+    }
+
+    /// Reload configuration, errors are logged and the running configuration is kept
+    pub fn reload(&self) {
+        let config = match self.load() {
+            Ok(c) => c,
+            Err(err) => {
+                error!("failed to reload configuration, {}", err);
+                return;
+            }
+        };
+
+        if let Err(err) = self.tx.try_send(config) {
+            error!("failed to reload configuration, {}", err);
+        }
+    }
+
+    fn load(&self) -> io::Result<Config> {
+        let mut config = self.config.clone();
+
+        if let Some(ref path) = self.config_path {
+            let file_config = match Config::load_from_file(path, config.config_type) {
+                Ok(c) => c,
+                Err(err) => {
+                    let err = io::Error::new(ErrorKind::InvalidData, format!("loading config \"{}\", {}", path, err));
+                    return Err(err);
+                }
+            };
+
+            config.server = file_config.server;
+            config.server.extend(self.extra_servers.iter().cloned());
+            config.groups = file_config.groups;
+            config.online_config = self.args_online_config.clone().or(file_config.online_config);
+        }
+
+        if let Some(ref path) = self.acl_path {
+            let acl = match AccessControl::load_from_file_with_geoip(path, config.geoip_db.as_deref()) {
+                Ok(acl) => acl,
+                Err(err) => {
+                    let err = io::Error::new(err.kind(), format!("loading ACL \"{}\", {}", path, err));
+                    return Err(err);
+                }
+            };
+            config.acl = Some(acl);
+        }
+
+        Ok(config)
+    }
+}
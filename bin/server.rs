@@ -15,13 +15,15 @@ use std::{
 use clap::{clap_app, Arg};
 use futures::future::{self, Either};
 use log::info;
-use tokio::{self, runtime::Builder};
+use tokio::{self, runtime::Builder, sync::oneshot};
 
+#[cfg(feature = "aead-cipher-2022")]
+use shadowsocks::relay::aead_2022;
 use shadowsocks::{
     acl::AccessControl,
-    crypto::v1::{available_ciphers, CipherKind},
+    crypto::v1::available_ciphers,
     plugin::PluginConfig,
-    run_server,
+    run_server_with_control,
     Config,
     ConfigType,
     ManagerAddr,
@@ -29,6 +31,7 @@ use shadowsocks::{
     Mode,
     ServerAddr,
     ServerConfig,
+    ServerControl,
 };
 
 mod allocator;
@@ -36,10 +39,17 @@ mod allocator;
 mod daemonize;
 mod logging;
 mod monitor;
+mod reload;
 mod validator;
 mod version;
 
 fn main() {
+    // Methods of the original protocol, and AEAD-2022 methods
+    #[allow(unused_mut)]
+    let mut available_methods = available_ciphers().to_vec();
+    #[cfg(feature = "aead-cipher-2022")]
+    available_methods.extend(aead_2022::available_methods());
+
     #[allow(unused_mut)]
     let mut app = clap_app!(shadowsocks =>
         (version: self::version::VERSION)
@@ -54,7 +64,7 @@ fn main() {
 
         (@arg SERVER_ADDR: -s --("server-addr") +takes_value {validator::validate_server_addr} requires[PASSWORD ENCRYPT_METHOD] "Server address")
         (@arg PASSWORD: -k --password +takes_value requires[SERVER_ADDR] "Server's password")
-        (@arg ENCRYPT_METHOD: -m --("encrypt-method") +takes_value requires[SERVER_ADDR] possible_values(available_ciphers()) +next_line_help "Server's encryption method")
+        (@arg ENCRYPT_METHOD: -m --("encrypt-method") +takes_value requires[SERVER_ADDR] possible_values(&available_methods) +next_line_help "Server's encryption method")
         (@arg TIMEOUT: --timeout +takes_value {validator::validate_u64} requires[SERVER_ADDR] "Server's timeout seconds for TCP relay")
 
         (@arg PLUGIN: --plugin +takes_value requires[SERVER_ADDR] "SIP003 (https://shadowsocks.org/en/spec/Plugin.html) plugin")
@@ -70,9 +80,17 @@ fn main() {
         (@arg LOG_CONFIG: --("log-config") +takes_value "log4rs configuration file")
 
         (@arg UDP_TIMEOUT: --("udp-timeout") +takes_value {validator::validate_u64} "Timeout seconds for UDP relay")
+        (@arg SHUTDOWN_TIMEOUT: --("shutdown-timeout") +takes_value {validator::validate_u64} "Seconds for waiting in-flight connections to finish when shutting down")
         (@arg UDP_MAX_ASSOCIATIONS: --("udp-max-associations") +takes_value {validator::validate_u64} "Maximum associations to be kept simultaneously for UDP relay")
     );
 
+    #[cfg(feature = "metrics")]
+    {
+        app = clap_app!(@app (app)
+            (@arg METRICS_ADDRESS: --("metrics-address") +takes_value {validator::validate_socket_addr} "Address of Prometheus metrics exporter, should be ip:port")
+        );
+    }
+
     #[cfg(unix)]
     {
         app = clap_app!(@app (app)
@@ -117,20 +135,22 @@ fn main() {
         None => Config::new(ConfigType::Server),
     };
 
+    // Servers specified by command line are kept after reloading configuration
+    let file_server_count = config.server.len();
+
     if let Some(svr_addr) = matches.value_of("SERVER_ADDR") {
         let password = matches.value_of("PASSWORD").expect("password");
-        let method = matches
-            .value_of("ENCRYPT_METHOD")
-            .expect("encrypt-method")
-            .parse::<CipherKind>()
-            .expect("encryption method");
+        let method = matches.value_of("ENCRYPT_METHOD").expect("encrypt-method");
         let svr_addr = svr_addr.parse::<ServerAddr>().expect("server-addr");
         let timeout = matches
             .value_of("TIMEOUT")
             .map(|t| t.parse::<u64>().expect("timeout"))
             .map(Duration::from_secs);
 
-        let mut sc = ServerConfig::new(svr_addr, password.to_owned(), method, timeout, None);
+        let mut sc = match ServerConfig::with_method_name(svr_addr, password.to_owned(), method, timeout, None) {
+            Ok(sc) => sc,
+            Err(err) => panic!("encrypt-method \"{}\", {}", method, err),
+        };
 
         if let Some(p) = matches.value_of("PLUGIN") {
             let plugin = PluginConfig {
@@ -184,7 +204,7 @@ fn main() {
     }
 
     if let Some(acl_file) = matches.value_of("ACL") {
-        let acl = match AccessControl::load_from_file(acl_file) {
+        let acl = match AccessControl::load_from_file_with_geoip(acl_file, config.geoip_db.as_deref()) {
             Ok(acl) => acl,
             Err(err) => {
                 panic!("loading ACL \"{}\", {}", acl_file, err);
@@ -197,10 +217,20 @@ fn main() {
         config.ipv6_first = true;
     }
 
+    #[cfg(feature = "metrics")]
+    if let Some(ma) = matches.value_of("METRICS_ADDRESS") {
+        config.metrics_addr = Some(ma.parse::<std::net::SocketAddr>().expect("metrics-address"));
+    }
+
     if let Some(udp_timeout) = matches.value_of("UDP_TIMEOUT") {
         config.udp_timeout = Some(Duration::from_secs(udp_timeout.parse::<u64>().expect("udp-timeout")));
     }
 
+    if let Some(shutdown_timeout) = matches.value_of("SHUTDOWN_TIMEOUT") {
+        let shutdown_timeout = shutdown_timeout.parse::<u64>().expect("shutdown-timeout");
+        config.shutdown_timeout = Some(Duration::from_secs(shutdown_timeout));
+    }
+
     if let Some(udp_max_assoc) = matches.value_of("UDP_MAX_ASSOCIATIONS") {
         config.udp_max_associations = Some(udp_max_assoc.parse::<usize>().expect("udp-max-associations"));
     }
@@ -230,6 +260,16 @@ fn main() {
 
     info!("shadowsocks {}", self::version::VERSION);
 
+    // Only ACL could be reloaded, listening servers are kept
+    let extra_servers = config.server[file_server_count..].to_vec();
+    let (reloader, reload_rx) = reload::ConfigReloader::new(
+        config.clone(),
+        matches.value_of("CONFIG"),
+        matches.value_of("ACL"),
+        extra_servers,
+        None,
+    );
+
     let mut builder = if cfg!(feature = "single-threaded") {
         Builder::new_current_thread()
     } else {
@@ -237,19 +277,39 @@ fn main() {
     };
     let runtime = builder.enable_all().build().expect("create tokio Runtime");
     runtime.block_on(async move {
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let control = ServerControl {
+            reload_rx: Some(reload_rx),
+            shutdown_rx: Some(shutdown_rx),
+        };
+
+        tokio::spawn(reloader.reload_on_signal());
+
         let abort_signal = monitor::create_signal_monitor();
-        let server = run_server(config);
+        let server = run_server_with_control(config, control);
 
         tokio::pin!(abort_signal);
         tokio::pin!(server);
 
-        match future::select(server, abort_signal).await {
+        match future::select(server.as_mut(), abort_signal).await {
             // Server future resolved without an error. This should never happen.
             Either::Left((Ok(..), ..)) => panic!("server exited unexpectly"),
             // Server future resolved with error, which are listener errors in most cases
             Either::Left((Err(err), ..)) => panic!("aborted with {}", err),
-            // The abort signal future resolved. Means we should just exit.
-            Either::Right(_) => (),
+            // The abort signal future resolved. Stop accepting and wait for in-flight connections.
+            Either::Right(_) => {
+                let _ = shutdown_tx.send(());
+            }
+        }
+
+        // Exit immediately if receiving another signal while draining
+        let abort_signal = monitor::create_signal_monitor();
+        tokio::pin!(abort_signal);
+
+        match future::select(server, abort_signal).await {
+            Either::Left((Ok(..), ..)) => (),
+            Either::Left((Err(err), ..)) => panic!("aborted with {}", err),
+            Either::Right(_) => info!("exiting without waiting in-flight connections"),
         }
     });
 }
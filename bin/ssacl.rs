@@ -0,0 +1,255 @@
+//! ACL checking tool
+//!
+//! Prints whether addresses are proxied, bypassed, rejected or blocked by an ACL file, with the rules deciding them.
+//! Addresses are read from arguments, or from standard input line by line, like `example.com:443 udp`.
+
+use std::{
+    io::{self, BufRead},
+    net::IpAddr,
+    path::Path,
+};
+
+use clap::clap_app;
+use tokio::runtime::Builder;
+
+use shadowsocks::{
+    acl::{AccessControl, AclInspector, AclRule, Mode, Protocol, Route, Section},
+    config::{Config, ConfigType},
+    context::{Context, SharedContext},
+    relay::socks5::Address,
+};
+
+mod allocator;
+mod version;
+
+/// Parse an address like `example.com`, `example.com:443`, `1.2.3.4` or `[::1]:443`
+///
+/// Port is 80 if it is omitted, the same as `Address::from_str`
+fn parse_address(addr: &str) -> Option<Address> {
+    if let Ok(ip) = addr.parse::<IpAddr>() {
+        return Some(Address::SocketAddress((ip, 80).into()));
+    }
+    addr.parse::<Address>().ok()
+}
+
+/// Parse a target line, `ADDRESS [tcp|udp]`
+fn parse_target(line: &str, default_protocol: Protocol) -> Result<(Address, Protocol), String> {
+    let mut parts = line.split_whitespace();
+
+    let addr = parts.next().unwrap_or(line);
+    let addr = match parse_address(addr) {
+        Some(a) => a,
+        None => return Err(format!("invalid address \"{}\"", addr)),
+    };
+
+    let protocol = match parts.next() {
+        None => default_protocol,
+        Some("tcp") => Protocol::Tcp,
+        Some("udp") => Protocol::Udp,
+        Some(p) => return Err(format!("invalid protocol \"{}\"", p)),
+    };
+
+    Ok((addr, protocol))
+}
+
+fn protocol_name(protocol: Protocol) -> &'static str {
+    match protocol {
+        Protocol::Tcp => "tcp",
+        Protocol::Udp => "udp",
+    }
+}
+
+fn mode_description(mode: Mode) -> &'static str {
+    match mode {
+        Mode::BlackList => "proxies or accepts addresses that don't match any rules",
+        Mode::WhiteList => "bypasses or rejects addresses that don't match any rules",
+    }
+}
+
+fn format_rule(rule: &AclRule) -> String {
+    format!("{} line {}: {}", rule.section(), rule.line_number(), rule.rule())
+}
+
+fn print_statistics(inspector: &AclInspector) {
+    println!("mode: {:?}, {}", inspector.mode(), mode_description(inspector.mode()));
+
+    for (section, count) in inspector.rule_counts() {
+        println!("{}: {} rules", section, count);
+    }
+
+    for (rule, earlier) in inspector.duplicated_rules() {
+        println!(
+            "duplicated: {}, same as line {}",
+            format_rule(rule),
+            earlier.line_number()
+        );
+    }
+
+    for (rule, by) in inspector.shadowed_rules() {
+        println!("shadowed: {}, by {}", format_rule(rule), format_rule(by));
+    }
+}
+
+async fn check_target(
+    context: &SharedContext,
+    inspector: &AclInspector,
+    is_server: bool,
+    line: &str,
+    default_protocol: Protocol,
+) {
+    let (addr, protocol) = match parse_target(line, default_protocol) {
+        Ok(t) => t,
+        Err(err) => {
+            println!("{}: {}", line, err);
+            return;
+        }
+    };
+
+    // Domain names are also matched by IP rules, with their resolved addresses
+    let resolved = match addr {
+        Address::SocketAddress(..) => Vec::new(),
+        Address::DomainNameAddress(ref host, port) => match context.dns_resolve(host, port).await {
+            Ok(addrs) => addrs.into_iter().map(|a| a.ip()).collect(),
+            Err(err) => {
+                println!("{}: failed to resolve {}, {}", line, host, err);
+                Vec::new()
+            }
+        },
+    };
+
+    let default_rule = format!("no rules matched, default of {:?} mode", inspector.mode());
+
+    if is_server {
+        let outbound = if context.check_outbound_blocked(&addr, protocol).await {
+            "outbound blocked"
+        } else {
+            "outbound allowed"
+        };
+        let outbound_rule = match inspector.outbound_block_rule(&addr, &resolved, protocol) {
+            Some(rule) => format_rule(rule),
+            None => "no rules matched".to_owned(),
+        };
+
+        match addr {
+            Address::SocketAddress(ref saddr) => {
+                let client = if context.check_client_blocked(saddr).await {
+                    "client blocked"
+                } else {
+                    "client accepted"
+                };
+                let client_rule = match inspector.client_rule(&saddr.ip()) {
+                    Some(rule) => format_rule(rule),
+                    None => default_rule,
+                };
+
+                println!("{} {}: {}, {}", addr, protocol_name(protocol), outbound, client);
+                println!("    outbound: {}", outbound_rule);
+                println!("    client: {}", client_rule);
+            }
+            Address::DomainNameAddress(..) => {
+                println!("{} {}: {}", addr, protocol_name(protocol), outbound);
+                println!("    outbound: {}", outbound_rule);
+            }
+        }
+        return;
+    }
+
+    let route = context.route_target(&addr, protocol).await;
+    let decision = match route {
+        Route::Bypass => "bypassed".to_owned(),
+        Route::Proxy(None) => "proxied".to_owned(),
+        Route::Proxy(Some(ref group)) => format!("proxied via group {}", group),
+        Route::Reject => "rejected".to_owned(),
+    };
+    println!("{} {}: {}", addr, protocol_name(protocol), decision);
+
+    let rule = inspector.deciding_rule(&addr, &resolved, protocol);
+    match rule {
+        Some(rule) => println!("    {}", format_rule(rule)),
+        None => println!("    {}", default_rule),
+    }
+
+    // Proxied targets that don't match `[route:NAME]` sections are routed by `[route_all:NAME]`
+    let is_routed = rule.map_or(false, |r| matches!(r.section(), Section::Route(..)));
+    if let Route::Proxy(Some(..)) = route {
+        if let (false, Some((name, line_number))) = (is_routed, inspector.default_route()) {
+            println!("    [route_all:{}] line {}", name, line_number);
+        }
+    }
+}
+
+fn main() {
+    let app = clap_app!(ssacl =>
+        (version: self::version::VERSION)
+        (about: "Check how addresses are decided by an ACL file")
+        (@arg ACL: -a --acl +takes_value +required "Path to the ACL file")
+        (@arg GEOIP_DB: --("geoip-db") +takes_value "Path to the MaxMind GeoIP database for geoip: rules")
+        (@arg SERVER: --server !takes_value "Check outbound addresses and clients of servers, instead of targets of local servers")
+        (@arg UDP: -u --udp !takes_value "Check addresses without protocols as UDP instead of TCP")
+        (@arg STATS: -s --stats !takes_value "Print rule counts of sections, and duplicated or shadowed rules")
+        (@arg ADDRESS: +multiple "Addresses to check, like example.com:443 or 1.2.3.4, read from standard input if not provided")
+    );
+
+    let matches = app.get_matches();
+
+    let acl_file = matches.value_of("ACL").expect("acl");
+    let geoip_db = matches.value_of("GEOIP_DB").map(Path::new);
+
+    let acl = match AccessControl::load_from_file_with_geoip(acl_file, geoip_db) {
+        Ok(acl) => acl,
+        Err(err) => panic!("loading ACL \"{}\", {}", acl_file, err),
+    };
+    let inspector = match AclInspector::load_from_file_with_geoip(acl_file, geoip_db) {
+        Ok(i) => i,
+        Err(err) => panic!("loading ACL \"{}\", {}", acl_file, err),
+    };
+
+    let is_server = matches.is_present("SERVER");
+    let default_protocol = if matches.is_present("UDP") {
+        Protocol::Udp
+    } else {
+        Protocol::Tcp
+    };
+
+    let is_stats = matches.is_present("STATS");
+    if is_stats {
+        print_statistics(&inspector);
+    }
+
+    let targets = match matches.values_of("ADDRESS") {
+        Some(addrs) => addrs.map(ToOwned::to_owned).collect::<Vec<_>>(),
+        // Only print statistics
+        None if is_stats => Vec::new(),
+        None => {
+            let stdin = io::stdin();
+            let lines = stdin.lock().lines().collect::<io::Result<Vec<_>>>();
+            match lines {
+                Ok(lines) => lines
+                    .into_iter()
+                    .map(|l| l.trim().to_owned())
+                    .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                    .collect(),
+                Err(err) => panic!("reading standard input, {}", err),
+            }
+        }
+    };
+
+    let config_type = if is_server {
+        ConfigType::Server
+    } else {
+        ConfigType::Local
+    };
+    let mut config = Config::new(config_type);
+    config.acl = Some(acl);
+
+    let runtime = Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("create tokio Runtime");
+    runtime.block_on(async move {
+        let context = Context::new_shared(config).await;
+        for target in &targets {
+            check_target(&context, &inspector, is_server, target, default_protocol).await;
+        }
+    });
+}
@@ -2,9 +2,18 @@
 //!
 //! SS-URI = "ss://" userinfo "@" hostname ":" port [ "/" ] [ "?" plugin ] [ "#" tag ]
 //! userinfo = websafe-base64-encode-utf8(method  ":" password)
+//!
+//! Servers could also be converted between configuration files, SIP008 documents and lists of URLs.
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::Path,
+    process,
+};
 
 use clap::clap_app;
-use qrcode::{types::Color, QrCode};
+use qrcode::{render::svg, types::Color, QrCode};
 
 use shadowsocks::config::{Config, ConfigType, ServerConfig};
 
@@ -14,8 +23,36 @@ mod version;
 const BLACK: &str = "\x1b[40m  \x1b[0m";
 const WHITE: &str = "\x1b[47m  \x1b[0m";
 
-fn print_qrcode(encoded: &str) {
-    let qrcode = QrCode::new(encoded.as_bytes()).unwrap();
+const AVAILABLE_FORMATS: &[&str] = &["config", "sip008", "urls"];
+const AVAILABLE_QRCODE_FORMATS: &[&str] = &["svg", "png"];
+
+/// Format of files containing servers
+#[derive(Clone, Copy)]
+enum Format {
+    /// Configuration file, servers in `servers` or the standard single server format
+    Config,
+    /// SIP008 online configuration document
+    Sip008,
+    /// One SIP002 URL per line
+    Urls,
+}
+
+impl Format {
+    fn from_name(name: &str) -> Format {
+        match name {
+            "config" => Format::Config,
+            "sip008" => Format::Sip008,
+            "urls" => Format::Urls,
+            _ => unreachable!("unknown format {}", name),
+        }
+    }
+}
+
+fn print_qrcode(encoded: &str) -> Result<(), String> {
+    let qrcode = match QrCode::new(encoded.as_bytes()) {
+        Ok(q) => q,
+        Err(err) => return Err(format!("generating QRCode for \"{}\", {}", encoded, err)),
+    };
 
     for _ in 0..qrcode.width() + 2 {
         print!("{}", WHITE);
@@ -39,10 +76,24 @@ fn print_qrcode(encoded: &str) {
         print!("{}", WHITE);
     }
     println!();
+
+    Ok(())
+}
+
+/// URL in QRCode, the legacy format is the most compatible one, but it couldn't carry plugins
+fn qrcode_url(svr: &ServerConfig) -> String {
+    if svr.plugin().is_some() {
+        svr.to_url()
+    } else {
+        svr.to_qrcode_url()
+    }
 }
 
-fn encode(filename: &str, need_qrcode: bool) {
-    let config = Config::load_from_file(filename, ConfigType::Server).unwrap();
+fn encode(filename: &str, need_qrcode: bool) -> Result<(), String> {
+    let config = match Config::load_from_file(filename, ConfigType::Server) {
+        Ok(c) => c,
+        Err(err) => return Err(format!("loading config \"{}\", {}", filename, err)),
+    };
 
     for svr in config.server {
         let encoded = svr.to_url();
@@ -50,14 +101,19 @@ fn encode(filename: &str, need_qrcode: bool) {
         println!("{}", encoded);
 
         if need_qrcode {
-            let encoded = svr.to_qrcode_url();
-            print_qrcode(&encoded);
+            let encoded = qrcode_url(&svr);
+            print_qrcode(&encoded)?;
         }
     }
+
+    Ok(())
 }
 
-fn decode(encoded: &str, need_qrcode: bool) {
-    let svrconfig = ServerConfig::from_url(encoded).unwrap();
+fn decode(encoded: &str, need_qrcode: bool) -> Result<(), String> {
+    let svrconfig = match ServerConfig::from_url(encoded) {
+        Ok(s) => s,
+        Err(err) => return Err(format!("decoding \"{}\", {}", encoded, err)),
+    };
 
     let mut config = Config::new(ConfigType::Server);
     config.server.push(svrconfig);
@@ -65,28 +121,241 @@ fn decode(encoded: &str, need_qrcode: bool) {
     println!("{}", config);
 
     if need_qrcode {
-        print_qrcode(encoded);
+        print_qrcode(encoded)?;
+    }
+
+    Ok(())
+}
+
+fn read_input(path: &str) -> io::Result<String> {
+    let mut content = String::new();
+    if path == "-" {
+        io::stdin().read_to_string(&mut content)?;
+    } else {
+        fs::File::open(path)?.read_to_string(&mut content)?;
     }
+    Ok(content)
+}
+
+fn parse_servers(content: &str, format: Format) -> Result<Vec<ServerConfig>, String> {
+    match format {
+        Format::Config => match Config::load_from_str(content, ConfigType::Server) {
+            Ok(c) => Ok(c.server),
+            Err(err) => Err(err.to_string()),
+        },
+        Format::Sip008 => Config::load_servers_from_sip008(content).map_err(|err| err.to_string()),
+        Format::Urls => {
+            let mut servers = Vec::new();
+            for (n, line) in content.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+
+                match ServerConfig::from_url(line) {
+                    Ok(s) => servers.push(s),
+                    Err(err) => return Err(format!("line {}, {}", n + 1, err)),
+                }
+            }
+            Ok(servers)
+        }
+    }
+}
+
+fn format_servers(servers: Vec<ServerConfig>, format: Format) -> String {
+    match format {
+        Format::Config => {
+            let mut config = Config::new(ConfigType::Server);
+            config.server = servers;
+            format!("{}\n", config)
+        }
+        Format::Sip008 => format!("{}\n", Config::servers_to_sip008(&servers)),
+        Format::Urls => servers.iter().map(|s| s.to_url() + "\n").collect(),
+    }
+}
+
+/// Remarks are chosen by users, keep only characters that are safe in file names
+fn file_name_safe(name: &str) -> String {
+    let safe = |c: char| c.is_alphanumeric() || c == '-' || c == '.';
+    name.chars().map(|c| if safe(c) { c } else { '_' }).collect()
+}
+
+fn save_qrcodes(servers: &[ServerConfig], dir: &Path, format: &str) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+
+    for (idx, svr) in servers.iter().enumerate() {
+        let qrcode = match QrCode::new(qrcode_url(svr).as_bytes()) {
+            Ok(q) => q,
+            Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err.to_string())),
+        };
+
+        let name = match svr.remarks() {
+            Some(remarks) => file_name_safe(remarks),
+            None => file_name_safe(&svr.addr().to_string()),
+        };
+        let path = dir.join(format!("{}-{}.{}", idx + 1, name, format));
+
+        match format {
+            "svg" => {
+                let image = qrcode.render::<svg::Color>().min_dimensions(256, 256).build();
+                fs::write(&path, image)?;
+            }
+            "png" => {
+                let image = qrcode.render::<image::Luma<u8>>().min_dimensions(256, 256).build();
+                if let Err(err) = image.save(&path) {
+                    return Err(io::Error::new(io::ErrorKind::Other, err.to_string()));
+                }
+            }
+            _ => unreachable!("unknown QRCode format {}", format),
+        }
+    }
+
+    Ok(())
+}
+
+fn convert(
+    input: &str,
+    input_format: Format,
+    output: Option<&str>,
+    output_format: Format,
+) -> Result<Vec<ServerConfig>, String> {
+    let content = match read_input(input) {
+        Ok(c) => c,
+        Err(err) => return Err(format!("reading \"{}\", {}", input, err)),
+    };
+
+    let servers = match parse_servers(&content, input_format) {
+        Ok(s) => s,
+        Err(err) => return Err(format!("loading servers from \"{}\", {}", input, err)),
+    };
+
+    let converted = format_servers(servers.clone(), output_format);
+    let result = match output {
+        Some(path) => fs::write(path, converted),
+        None => io::stdout().write_all(converted.as_bytes()),
+    };
+    if let Err(err) = result {
+        return Err(format!("writing \"{}\", {}", output.unwrap_or("-"), err));
+    }
+
+    Ok(servers)
 }
 
 fn main() {
     let app = clap_app!(ssurl =>
         (version: self::version::VERSION)
         (about: "Encode and decode ShadowSocks URL")
-        (@arg ENCODE_CONFIG_PATH: -e --encode +takes_value conflicts_with[DECODE_CONFIG_PATH] required_unless[DECODE_CONFIG_PATH] "Encode the server configuration in the provided JSON file")
-        (@arg DECODE_CONFIG_PATH: -d --decode +takes_value required_unless[ENCODE_CONFIG_PATH] "Decode the server configuration from the provide ShadowSocks URL")
-        (@arg QRCODE: -c --qrcode !takes_value "Generate the QRCode with the provided configuration")
+        (@arg ENCODE_CONFIG_PATH: -e --encode +takes_value conflicts_with[DECODE_CONFIG_PATH INPUT] required_unless[DECODE_CONFIG_PATH INPUT] "Encode the server configuration in the provided JSON file")
+        (@arg DECODE_CONFIG_PATH: -d --decode +takes_value conflicts_with[INPUT] "Decode the server configuration from the provide ShadowSocks URL")
+        (@arg QRCODE: -c --qrcode !takes_value conflicts_with[INPUT] "Generate the QRCode with the provided configuration")
+
+        (@arg INPUT: -i --input +takes_value requires[INPUT_FORMAT OUTPUT_FORMAT] "Convert servers in this file, \"-\" for standard input")
+        (@arg INPUT_FORMAT: -I --("input-format") +takes_value possible_values(AVAILABLE_FORMATS) requires[INPUT] "Format of the input file, a configuration file, a SIP008 document, or SIP002 URLs one per line")
+        (@arg OUTPUT: -o --output +takes_value requires[INPUT] "Write converted servers to this file instead of standard output")
+        (@arg OUTPUT_FORMAT: -O --("output-format") +takes_value possible_values(AVAILABLE_FORMATS) requires[INPUT] "Format of the output")
+        (@arg QRCODE_DIR: --("qrcode-dir") +takes_value requires[INPUT] "Save the QRCode of each converted server into this directory")
+        (@arg QRCODE_FORMAT: --("qrcode-format") +takes_value possible_values(AVAILABLE_QRCODE_FORMATS) default_value("svg") requires[QRCODE_DIR] "Image format of QRCodes saved in --qrcode-dir")
     );
 
     let matches = app.get_matches();
 
     let need_qrcode = matches.is_present("QRCODE");
 
-    if let Some(file) = matches.value_of("ENCODE_CONFIG_PATH") {
-        encode(file, need_qrcode);
+    let result = if let Some(file) = matches.value_of("ENCODE_CONFIG_PATH") {
+        encode(file, need_qrcode)
     } else if let Some(encoded) = matches.value_of("DECODE_CONFIG_PATH") {
-        decode(encoded, need_qrcode);
+        decode(encoded, need_qrcode)
+    } else if let Some(input) = matches.value_of("INPUT") {
+        let input_format = Format::from_name(matches.value_of("INPUT_FORMAT").expect("input-format"));
+        let output_format = Format::from_name(matches.value_of("OUTPUT_FORMAT").expect("output-format"));
+
+        convert(input, input_format, matches.value_of("OUTPUT"), output_format).and_then(|servers| {
+            match matches.value_of("QRCODE_DIR") {
+                Some(dir) => {
+                    let format = matches.value_of("QRCODE_FORMAT").expect("qrcode-format");
+                    save_qrcodes(&servers, Path::new(dir), format)
+                        .map_err(|err| format!("saving QRCodes to \"{}\", {}", dir, err))
+                }
+                None => Ok(()),
+            }
+        })
     } else {
         println!("Use -h for more detail");
+        Ok(())
+    };
+
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use shadowsocks::{crypto::v1::CipherKind, plugin::PluginConfig};
+
+    use super::*;
+
+    fn servers() -> Vec<ServerConfig> {
+        let mut svr = ServerConfig::basic(
+            "127.0.0.1:8388".parse::<SocketAddr>().unwrap(),
+            "p@ss:word".to_owned(),
+            CipherKind::AES_256_GCM,
+        );
+        svr.set_remarks("My Server #1".to_owned());
+        svr.set_plugin(PluginConfig {
+            plugin: "obfs-local".to_owned(),
+            plugin_opts: Some("obfs=tls".to_owned()),
+            plugin_args: Vec::new(),
+        });
+
+        let another = ServerConfig::basic(
+            "[::1]:8389".parse::<SocketAddr>().unwrap(),
+            "password".to_owned(),
+            CipherKind::CHACHA20_POLY1305,
+        );
+
+        vec![svr, another]
+    }
+
+    fn assert_servers_eq(servers: &[ServerConfig], expected: &[ServerConfig]) {
+        assert_eq!(servers.len(), expected.len());
+        for (svr, exp) in servers.iter().zip(expected) {
+            assert_eq!(svr.addr().to_string(), exp.addr().to_string());
+            assert_eq!(svr.method(), exp.method());
+            assert_eq!(svr.password(), exp.password());
+            assert_eq!(svr.remarks(), exp.remarks());
+            assert_eq!(
+                svr.plugin().map(|p| (&p.plugin, &p.plugin_opts)),
+                exp.plugin().map(|p| (&p.plugin, &p.plugin_opts))
+            );
+        }
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        for svr in servers() {
+            let decoded = ServerConfig::from_url(&svr.to_url()).unwrap();
+            assert_servers_eq(&[decoded], &[svr]);
+        }
+    }
+
+    #[test]
+    fn convert_round_trip() {
+        for &format in &[Format::Config, Format::Sip008, Format::Urls] {
+            let converted = format_servers(servers(), format);
+            let parsed = parse_servers(&converted, format).unwrap();
+            assert_servers_eq(&parsed, &servers());
+        }
+    }
+
+    #[test]
+    fn invalid_input() {
+        assert!(decode("ss://invalid", false).is_err());
+        assert!(encode("/nonexistent/shadowsocks.json", false).is_err());
+        assert!(parse_servers("ss://invalid\n", Format::Urls).is_err());
+        assert!(parse_servers("[]", Format::Sip008).is_err());
     }
 }
@@ -7,6 +7,7 @@ use std::net::SocketAddr;
 #[cfg(feature = "local-dns")]
 use shadowsocks::config::LocalDnsAddr;
 use shadowsocks::{relay::socks5::Address, ManagerAddr, ServerAddr, ServerConfig};
+use url::Url;
 
 macro_rules! validate_type {
     ($name:ident, $ty:ty, $help:expr) => {
@@ -40,9 +41,23 @@ validate_type!(
 validate_type!(validate_u64, u64, "should be unsigned integer");
 validate_type!(validate_u32, u32, "should be unsigned integer");
 
+pub fn validate_positive_u64(v: String) -> Result<(), String> {
+    match v.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err("should be positive integer".to_owned()),
+    }
+}
+
 pub fn validate_server_url(v: String) -> Result<(), String> {
     match ServerConfig::from_url(&v) {
         Ok(..) => Ok(()),
         Err(..) => Err("should be SIP002 (https://shadowsocks.org/en/spec/SIP002-URI-Scheme.html) format".to_owned()),
     }
 }
+
+pub fn validate_online_config_url(v: String) -> Result<(), String> {
+    match v.parse::<Url>() {
+        Ok(u) if matches!(u.scheme(), "http" | "https" | "file") => Ok(()),
+        _ => Err("should be http, https or file URL".to_owned()),
+    }
+}
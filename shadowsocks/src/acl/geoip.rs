@@ -0,0 +1,159 @@
+//! GeoIP rules, countries of IP addresses are looked up in MaxMind databases (`.mmdb`)
+//!
+//! Databases are only supported with feature "acl-geoip", `geoip:private` rules are always available
+
+use std::{
+    collections::HashSet,
+    fmt,
+    io::{self, Error, ErrorKind},
+    net::IpAddr,
+    path::Path,
+    sync::Arc,
+};
+
+#[cfg(feature = "acl-geoip")]
+use maxminddb::{geoip2, Reader};
+
+/// A MaxMind GeoIP2 or GeoLite2 database, with countries of IP addresses
+#[cfg(feature = "acl-geoip")]
+pub(crate) type GeoIpDatabase = Reader<Vec<u8>>;
+
+/// GeoIP databases couldn't be opened without feature "acl-geoip"
+#[cfg(not(feature = "acl-geoip"))]
+pub(crate) enum GeoIpDatabase {}
+
+/// Networks of `geoip:private`, which are not in GeoIP databases
+pub(crate) const PRIVATE_NETWORKS: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.0.2.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "198.51.100.0/24",
+    "203.0.113.0/24",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// Open the GeoIP database at `path`
+#[cfg(feature = "acl-geoip")]
+pub(crate) fn open_database(path: &Path) -> io::Result<Arc<GeoIpDatabase>> {
+    match Reader::open_readfile(path) {
+        Ok(db) => Ok(Arc::new(db)),
+        Err(err) => {
+            let err = Error::new(
+                ErrorKind::Other,
+                format!("GeoIP database \"{}\" error: {}", path.display(), err),
+            );
+            Err(err)
+        }
+    }
+}
+
+/// Open the GeoIP database at `path`, always fails without feature "acl-geoip"
+#[cfg(not(feature = "acl-geoip"))]
+pub(crate) fn open_database(path: &Path) -> io::Result<Arc<GeoIpDatabase>> {
+    let err = Error::new(
+        ErrorKind::Other,
+        format!("GeoIP database \"{}\" requires feature \"acl-geoip\"", path.display()),
+    );
+    Err(err)
+}
+
+/// Look up ISO 3166-1 alpha-2 code of the country of `addr`, in upper case
+#[cfg(feature = "acl-geoip")]
+fn lookup_country(db: &GeoIpDatabase, addr: &IpAddr) -> Option<String> {
+    // Addresses that are not found in the database don't belong to any countries
+    let country = db.lookup::<geoip2::Country>(*addr).ok()?;
+    country
+        .country
+        .and_then(|c| c.iso_code)
+        .map(|code| code.to_ascii_uppercase())
+}
+
+#[cfg(not(feature = "acl-geoip"))]
+fn lookup_country(db: &GeoIpDatabase, _addr: &IpAddr) -> Option<String> {
+    match *db {}
+}
+
+/// Countries in `geoip:` rules
+#[derive(Clone, Default)]
+pub(crate) struct GeoIpRules {
+    db: Option<Arc<GeoIpDatabase>>,
+    // ISO 3166-1 alpha-2 codes, in upper case
+    countries: HashSet<String>,
+}
+
+impl fmt::Debug for GeoIpRules {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.countries.iter()).finish()
+    }
+}
+
+impl GeoIpRules {
+    /// Create rules of `countries`, which are looked up in `db`
+    pub fn new(db: Option<Arc<GeoIpDatabase>>, countries: HashSet<String>) -> GeoIpRules {
+        GeoIpRules { db, countries }
+    }
+
+    /// Check if `addr` is in one of the countries
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        if self.countries.is_empty() {
+            return false;
+        }
+
+        let db = match self.db {
+            Some(ref db) => db,
+            None => return false,
+        };
+
+        match lookup_country(db, addr) {
+            Some(code) => self.countries.contains(&code),
+            None => false,
+        }
+    }
+
+    /// Check if there are no countries
+    pub fn is_empty(&self) -> bool {
+        self.countries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ipnet::IpNet;
+
+    use super::*;
+
+    #[test]
+    fn private_networks() {
+        for net in PRIVATE_NETWORKS {
+            assert!(net.parse::<IpNet>().is_ok(), "{}", net);
+        }
+    }
+
+    #[test]
+    fn without_database() {
+        let countries = vec!["CN".to_owned()].into_iter().collect();
+        let rules = GeoIpRules::new(None, countries);
+        assert!(!rules.is_empty());
+        assert!(!rules.contains(&"1.2.4.8".parse().unwrap()));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("not-found.mmdb");
+        let err = open_database(&path).err().expect("open database");
+        #[cfg(not(feature = "acl-geoip"))]
+        assert!(err.to_string().contains("acl-geoip"), "{}", err);
+        assert!(err.to_string().contains(&path.display().to_string()), "{}", err);
+    }
+}
@@ -0,0 +1,513 @@
+//! Rules of ACL files line by line, for finding out which rules match addresses and checking the files
+
+use std::{
+    collections::HashMap,
+    io::{self, Error, ErrorKind},
+    net::IpAddr,
+    path::Path,
+    sync::Arc,
+};
+
+use ipnet::IpNet;
+use regex::{Regex, RegexBuilder};
+
+use crate::relay::socks5::Address;
+
+use super::{
+    geoip::{self, GeoIpDatabase},
+    read_lines,
+    regex_exact_domain,
+    regex_suffix_domain,
+    split_rule,
+    Mode,
+    Protocol,
+    Qualifier,
+    Rules,
+    RulesBuilder,
+    Section,
+    SectionHeader,
+    REGEX_SIZE_LIMIT,
+};
+
+/// Addresses that a rule matches, for finding rules covering the others
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum RuleAddress {
+    All,
+    Network(IpNet),
+    Exact(String),
+    Suffix(String),
+    Keyword(String),
+    // Regular expressions and countries, only identical rules cover each other
+    Other(String),
+}
+
+impl RuleAddress {
+    fn parse(addr: &str) -> RuleAddress {
+        if addr == "*" {
+            return RuleAddress::All;
+        }
+        if let Ok(net) = addr.parse::<IpNet>() {
+            return RuleAddress::Network(net);
+        }
+        if let Ok(ip) = addr.parse::<IpAddr>() {
+            return RuleAddress::Network(IpNet::from(ip));
+        }
+
+        let domain = |d: &str| d.trim_start_matches('.').trim_end_matches('.').to_ascii_lowercase();
+        if let Some(d) = addr.strip_prefix("full:") {
+            RuleAddress::Exact(domain(d))
+        } else if let Some(d) = addr.strip_prefix("domain:") {
+            RuleAddress::Suffix(domain(d))
+        } else if let Some(k) = addr.strip_prefix("keyword:") {
+            RuleAddress::Keyword(k.to_ascii_lowercase())
+        } else if let Some(d) = regex_suffix_domain(addr) {
+            RuleAddress::Suffix(d)
+        } else if let Some(d) = regex_exact_domain(addr) {
+            RuleAddress::Exact(d)
+        } else {
+            RuleAddress::Other(addr.strip_prefix("regexp:").unwrap_or(addr).to_owned())
+        }
+    }
+
+    /// Check if all addresses matched by `other` are matched by `self`
+    fn covers(&self, other: &RuleAddress) -> bool {
+        match (self, other) {
+            (RuleAddress::All, _) => true,
+            (RuleAddress::Network(a), RuleAddress::Network(b)) => a.contains(b),
+            (RuleAddress::Exact(a), RuleAddress::Exact(b)) => a == b,
+            (RuleAddress::Suffix(a), RuleAddress::Exact(b)) | (RuleAddress::Suffix(a), RuleAddress::Suffix(b)) => {
+                is_sub_domain(b, a)
+            }
+            (RuleAddress::Keyword(k), RuleAddress::Exact(b))
+            | (RuleAddress::Keyword(k), RuleAddress::Suffix(b))
+            | (RuleAddress::Keyword(k), RuleAddress::Keyword(b)) => b.contains(k.as_str()),
+            (RuleAddress::Other(a), RuleAddress::Other(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Check if `domain` is `parent` or one of its sub-domains
+fn is_sub_domain(domain: &str, parent: &str) -> bool {
+    match domain.strip_suffix(parent) {
+        Some(prefix) => prefix.is_empty() || prefix.ends_with('.'),
+        None => false,
+    }
+}
+
+impl Qualifier {
+    /// Check if all ports and protocols of `other` are matched by `self`
+    fn covers(&self, other: &Qualifier) -> bool {
+        if self.protocol.is_some() && self.protocol != other.protocol {
+            return false;
+        }
+        if self.ports.is_empty() {
+            return true;
+        }
+        !other.ports.is_empty()
+            && other
+                .ports
+                .iter()
+                .all(|p| self.ports.iter().any(|r| r.start() <= p.start() && p.end() <= r.end()))
+    }
+}
+
+/// A rule line in an ACL file
+#[derive(Debug)]
+pub struct AclRule {
+    section: Section,
+    line_number: usize,
+    rule: String,
+    qualifier: Qualifier,
+    address: RuleAddress,
+    // Address part of the rule, except regular expressions
+    rules: Rules,
+    regex: Option<Regex>,
+}
+
+impl AclRule {
+    /// Section of this rule
+    pub fn section(&self) -> &Section {
+        &self.section
+    }
+
+    /// Line number in the file, starting from 1
+    pub fn line_number(&self) -> usize {
+        self.line_number
+    }
+
+    /// The rule line
+    pub fn rule(&self) -> &str {
+        &self.rule
+    }
+
+    fn is_qualified(&self) -> bool {
+        self.qualifier != Qualifier::default()
+    }
+
+    fn matches_ip(&self, addr: &IpAddr) -> bool {
+        self.rules.check_ip_matched(addr)
+    }
+
+    fn matches_host(&self, host: &str) -> bool {
+        self.rules.check_host_matched(host) || self.regex.as_ref().map_or(false, |r| r.is_match(host))
+    }
+
+    /// Check if this rule matches `addr` with `protocol`, including its qualifier
+    ///
+    /// Domain names are also matched by IP rules with the addresses in `resolved`
+    fn matches(&self, addr: &Address, resolved: &[IpAddr], protocol: Protocol) -> bool {
+        if !self.qualifier.matches(addr.port(), protocol) {
+            return false;
+        }
+
+        match *addr {
+            Address::SocketAddress(ref saddr) => self.matches_ip(&saddr.ip()),
+            Address::DomainNameAddress(ref host, _) => {
+                self.matches_host(host) || resolved.iter().any(|ip| self.matches_ip(ip))
+            }
+        }
+    }
+
+    /// Check if all addresses matched by `other` are matched by this rule
+    fn covers(&self, other: &AclRule) -> bool {
+        self.qualifier.covers(&other.qualifier) && self.address.covers(&other.address)
+    }
+}
+
+/// Rules of an ACL file line by line
+///
+/// `AccessControl` merges rules of each section, so it couldn't tell which rule an address matches.
+/// This is only for inspecting ACL files, it is much slower than `AccessControl`.
+#[derive(Debug)]
+pub struct AclInspector {
+    mode: Mode,
+    rules: Vec<AclRule>,
+    // Names of `[route:NAME]` sections, in the order that they are checked
+    routes: Vec<String>,
+    // Group of the last `[route_all:NAME]` section, with its line number
+    default_route: Option<(String, usize)>,
+}
+
+/// Build the address part `addr` of a rule line, regular expressions are compiled alone instead of in a `RegexSet`
+fn build_rule(
+    line: &str,
+    addr: &str,
+    section: &Section,
+    geoip_db: Option<&Arc<GeoIpDatabase>>,
+) -> io::Result<(Rules, Option<Regex>)> {
+    let mut builder = RulesBuilder::default();
+    builder.add_address_rule(line, addr)?;
+
+    let regex = match builder.rule.pop() {
+        Some(r) => match RegexBuilder::new(&r).size_limit(REGEX_SIZE_LIMIT).build() {
+            Ok(r) => Some(r),
+            Err(err) => {
+                let err = Error::new(ErrorKind::Other, format!("{} regex error: {}", section, err));
+                return Err(err);
+            }
+        },
+        None => None,
+    };
+
+    Ok((builder.build(&section.to_string(), geoip_db)?, regex))
+}
+
+impl AclInspector {
+    /// Load rules from an ACL file, `geoip:` rules of countries are looked up in the MaxMind database `geoip_db`
+    pub fn load_from_file_with_geoip<P: AsRef<Path>>(p: P, geoip_db: Option<&Path>) -> io::Result<AclInspector> {
+        let geoip_db = match geoip_db {
+            Some(path) => Some(geoip::open_database(path)?),
+            None => None,
+        };
+
+        let mut mode = Mode::BlackList;
+        let mut rules = Vec::new();
+        let mut routes = Vec::new();
+        let mut default_route = None;
+
+        let mut curr = Section::Bypass;
+
+        for (line_number, line) in read_lines(p)? {
+            match SectionHeader::parse(&line) {
+                Some(SectionHeader::Mode(m)) => {
+                    mode = m;
+                }
+                Some(SectionHeader::DefaultRoute(name)) => {
+                    default_route = Some((name, line_number));
+                }
+                Some(SectionHeader::Rules(section)) => {
+                    if let Section::Route(ref name) = section {
+                        if !routes.contains(name) {
+                            routes.push(name.clone());
+                        }
+                    }
+                    curr = section;
+                }
+                None => {
+                    // Each rule is built separately for finding out which one decides
+                    let built = split_rule(&line).and_then(|(addr, qualifier)| {
+                        let (rules, regex) = build_rule(&line, addr, &curr, geoip_db.as_ref())?;
+                        Ok((RuleAddress::parse(addr), qualifier, rules, regex))
+                    });
+                    let (address, qualifier, built, regex) = match built {
+                        Ok(r) => r,
+                        Err(err) => return Err(Error::new(err.kind(), format!("line {}, {}", line_number, err))),
+                    };
+
+                    rules.push(AclRule {
+                        section: curr.clone(),
+                        line_number,
+                        rule: line,
+                        qualifier,
+                        address,
+                        rules: built,
+                        regex,
+                    });
+                }
+            }
+        }
+
+        Ok(AclInspector {
+            mode,
+            rules,
+            routes,
+            default_route,
+        })
+    }
+
+    /// Mode of the ACL file
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// All rules, in the order of the file
+    pub fn rules(&self) -> &[AclRule] {
+        &self.rules
+    }
+
+    /// Group of the last `[route_all:NAME]` section with its line number, which proxied targets are routed to
+    /// if they don't match `[route:NAME]` sections
+    pub fn default_route(&self) -> Option<(&str, usize)> {
+        self.default_route.as_ref().map(|(name, n)| (name.as_str(), *n))
+    }
+
+    /// The rule deciding the route of `addr` with `protocol` (for client), `None` if it is decided by the mode
+    ///
+    /// Rules are checked in the same order as `AccessControl`: `[reject_list]`, `[route:NAME]` sections, rules
+    /// with qualifiers in `[proxy_list]` and `[bypass_list]`, then the others in them.
+    /// Domain names are also matched by IP rules with the addresses in `resolved`
+    pub fn deciding_rule(&self, addr: &Address, resolved: &[IpAddr], protocol: Protocol) -> Option<&AclRule> {
+        if let Some(rule) = self.find_rule(&Section::Reject, |r| r.matches(addr, resolved, protocol)) {
+            return Some(rule);
+        }
+        for name in &self.routes {
+            let section = Section::Route(name.clone());
+            if let Some(rule) = self.find_rule(&section, |r| r.matches(addr, resolved, protocol)) {
+                return Some(rule);
+            }
+        }
+        for section in &[Section::Proxy, Section::Bypass] {
+            if let Some(rule) = self.find_rule(section, |r| r.is_qualified() && r.matches(addr, resolved, protocol)) {
+                return Some(rule);
+            }
+        }
+
+        // Only rules in the list of the mode are checked for IP addresses
+        let listed = self.listed_section();
+        match *addr {
+            Address::SocketAddress(ref saddr) => {
+                self.find_rule(&listed, |r| !r.is_qualified() && r.matches_ip(&saddr.ip()))
+            }
+            Address::DomainNameAddress(ref host, _) => {
+                for section in &[Section::Proxy, Section::Bypass] {
+                    if let Some(rule) = self.find_rule(section, |r| !r.is_qualified() && r.matches_host(host)) {
+                        return Some(rule);
+                    }
+                }
+
+                match self.mode {
+                    // Bypassed if any of the addresses are in `[bypass_list]`
+                    Mode::BlackList => self.find_rule(&listed, |r| {
+                        !r.is_qualified() && resolved.iter().any(|ip| r.matches_ip(ip))
+                    }),
+                    // Proxied if all of the addresses are in `[proxy_list]`
+                    Mode::WhiteList => {
+                        let mut deciding = None;
+                        for ip in resolved {
+                            let rule = self.find_rule(&listed, |r| !r.is_qualified() && r.matches_ip(ip))?;
+                            deciding.get_or_insert(rule);
+                        }
+                        deciding
+                    }
+                }
+            }
+        }
+    }
+
+    /// The rule in `[outbound_block_list]` blocking `addr` with `protocol` (for server)
+    ///
+    /// Domain names are also matched by IP rules with the addresses in `resolved`
+    pub fn outbound_block_rule(&self, addr: &Address, resolved: &[IpAddr], protocol: Protocol) -> Option<&AclRule> {
+        self.find_rule(&Section::OutboundBlock, |r| r.matches(addr, resolved, protocol))
+    }
+
+    /// The rule deciding if client `addr` is blocked (for server), `None` if it is decided by the mode
+    pub fn client_rule(&self, addr: &IpAddr) -> Option<&AclRule> {
+        self.find_rule(&self.listed_section(), |r| !r.is_qualified() && r.matches_ip(addr))
+    }
+
+    /// Section of IP addresses that are not decided by default of the mode
+    fn listed_section(&self) -> Section {
+        match self.mode {
+            Mode::BlackList => Section::Bypass,
+            Mode::WhiteList => Section::Proxy,
+        }
+    }
+
+    /// The first rule in `section` satisfying `f`
+    fn find_rule<F>(&self, section: &Section, f: F) -> Option<&AclRule>
+    where
+        F: Fn(&AclRule) -> bool,
+    {
+        self.rules.iter().find(|r| r.section == *section && f(r))
+    }
+
+    /// Number of rules in each section, in the order of their first appearances
+    pub fn rule_counts(&self) -> Vec<(&Section, usize)> {
+        let mut counts: Vec<(&Section, usize)> = Vec::new();
+        for rule in &self.rules {
+            match counts.iter_mut().find(|(s, _)| **s == rule.section) {
+                Some((_, n)) => *n += 1,
+                None => counts.push((&rule.section, 1)),
+            }
+        }
+        counts
+    }
+
+    /// Rules that are the same as earlier rules in the same section, with the earlier rules
+    pub fn duplicated_rules(&self) -> Vec<(&AclRule, &AclRule)> {
+        let mut duplicated = Vec::new();
+        for (idx, rule) in self.rules.iter().enumerate() {
+            let earlier = self.rules[..idx]
+                .iter()
+                .find(|r| r.section == rule.section && r.covers(rule) && rule.covers(r));
+            if let Some(earlier) = earlier {
+                duplicated.push((rule, earlier));
+            }
+        }
+        duplicated
+    }
+
+    /// Rules that never decide because all addresses they match are matched by other rules, with those rules
+    ///
+    /// Rules are shadowed by broader rules in the same section, or by rules in sections checked before their
+    /// sections: `[reject_list]`, then `[route:NAME]` sections, then the others. Duplicated rules are not included.
+    pub fn shadowed_rules(&self) -> Vec<(&AclRule, &AclRule)> {
+        let candidates = Candidates::new(&self.rules);
+
+        let mut shadowed = Vec::new();
+        for rule in &self.rules {
+            let by = candidates.of(rule).into_iter().map(|i| &self.rules[i]).find(|r| {
+                if !r.covers(rule) {
+                    return false;
+                }
+                if r.section == rule.section {
+                    // Identical rules in the same section are duplicated
+                    !rule.covers(r)
+                } else {
+                    self.is_checked_before(&r.section, &rule.section)
+                }
+            });
+            if let Some(by) = by {
+                shadowed.push((rule, by));
+            }
+        }
+        shadowed
+    }
+
+    /// Check if targets are checked by rules in section `a` before section `b` (for client)
+    fn is_checked_before(&self, a: &Section, b: &Section) -> bool {
+        // Bypassing and proxying are decided together by the mode
+        let order = |s: &Section| match *s {
+            Section::Reject => Some(0),
+            Section::Route(ref name) => self.routes.iter().position(|n| n == name).map(|i| i + 1),
+            Section::Bypass | Section::Proxy => Some(usize::max_value()),
+            Section::OutboundBlock => None,
+        };
+
+        match (order(a), order(b)) {
+            (Some(a), Some(b)) => a < b,
+            _ => false,
+        }
+    }
+}
+
+/// Indexes of rules that may cover a rule, for not comparing every pair of rules
+struct Candidates {
+    all: Vec<usize>,
+    networks: Vec<usize>,
+    exact: HashMap<String, Vec<usize>>,
+    suffix: HashMap<String, Vec<usize>>,
+    keyword: Vec<usize>,
+    other: HashMap<String, Vec<usize>>,
+}
+
+impl Candidates {
+    fn new(rules: &[AclRule]) -> Candidates {
+        let mut candidates = Candidates {
+            all: Vec::new(),
+            networks: Vec::new(),
+            exact: HashMap::new(),
+            suffix: HashMap::new(),
+            keyword: Vec::new(),
+            other: HashMap::new(),
+        };
+
+        for (idx, rule) in rules.iter().enumerate() {
+            match rule.address {
+                RuleAddress::All => candidates.all.push(idx),
+                RuleAddress::Network(..) => candidates.networks.push(idx),
+                RuleAddress::Exact(ref d) => candidates.exact.entry(d.clone()).or_default().push(idx),
+                RuleAddress::Suffix(ref d) => candidates.suffix.entry(d.clone()).or_default().push(idx),
+                RuleAddress::Keyword(..) => candidates.keyword.push(idx),
+                RuleAddress::Other(ref r) => candidates.other.entry(r.clone()).or_default().push(idx),
+            }
+        }
+
+        candidates
+    }
+
+    /// Indexes of rules that may cover `rule`, in the order of the file
+    fn of(&self, rule: &AclRule) -> Vec<usize> {
+        let mut idxs = self.all.clone();
+
+        match rule.address {
+            RuleAddress::All => {}
+            RuleAddress::Network(..) => idxs.extend_from_slice(&self.networks),
+            RuleAddress::Exact(ref d) | RuleAddress::Suffix(ref d) => {
+                if let RuleAddress::Exact(..) = rule.address {
+                    idxs.extend(self.exact.get(d).into_iter().flatten());
+                }
+
+                // The domain and all its parents
+                let mut parent = d.as_str();
+                loop {
+                    idxs.extend(self.suffix.get(parent).into_iter().flatten());
+                    match parent.find('.') {
+                        Some(pos) => parent = &parent[pos + 1..],
+                        None => break,
+                    }
+                }
+
+                idxs.extend_from_slice(&self.keyword);
+            }
+            RuleAddress::Keyword(..) => idxs.extend_from_slice(&self.keyword),
+            RuleAddress::Other(ref r) => idxs.extend(self.other.get(r).into_iter().flatten()),
+        }
+
+        idxs.sort_unstable();
+        idxs.dedup();
+        idxs
+    }
+}
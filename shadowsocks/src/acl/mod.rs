@@ -3,11 +3,14 @@
 //! This is for advance controlling server behaviors in both local and proxy servers.
 
 use std::{
+    collections::HashSet,
     fmt,
     fs::File,
     io::{self, BufRead, BufReader, Error, ErrorKind},
     net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
     path::Path,
+    sync::Arc,
 };
 
 use ipnet::{IpNet, Ipv4Net, Ipv6Net};
@@ -16,6 +19,17 @@ use regex::{RegexSet, RegexSetBuilder};
 
 use crate::{context::Context, relay::socks5::Address};
 
+use self::{
+    geoip::{GeoIpDatabase, GeoIpRules, PRIVATE_NETWORKS},
+    sub_domains_tree::SubDomainsTree,
+};
+
+pub use self::inspect::{AclInspector, AclRule};
+
+mod geoip;
+mod inspect;
+mod sub_domains_tree;
+
 /// Strategy mode that ACL is running
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Mode {
@@ -25,21 +39,124 @@ pub enum Mode {
     WhiteList,
 }
 
+/// Transport protocol of connections checked by ACL
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Protocol {
+    /// TCP connections
+    Tcp,
+    /// UDP associations
+    Udp,
+}
+
+/// Route of a target address (for client)
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Route {
+    /// Connects to the target directly
+    Bypass,
+    /// Connects through servers in the named group, or any servers if it is `None`
+    Proxy(Option<String>),
+    /// Rejects the connection
+    Reject,
+}
+
+/// Ports and protocol that a rule is restricted to
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+struct Qualifier {
+    // Matches all ports if it is empty
+    ports: Vec<RangeInclusive<u16>>,
+    // Matches both protocols if it is `None`
+    protocol: Option<Protocol>,
+}
+
+impl Qualifier {
+    /// Parse qualifiers following the address in a rule, like `port:25,465,587 tcp`, separated by whitespaces
+    fn parse<'a, I: Iterator<Item = &'a str>>(line: &str, qualifiers: I) -> io::Result<Qualifier> {
+        let invalid = |desc: &str| Error::new(ErrorKind::Other, format!("{} in rule \"{}\"", desc, line));
+
+        let mut qualifier = Qualifier::default();
+        for q in qualifiers {
+            let protocol = match q {
+                "tcp" => Protocol::Tcp,
+                "udp" => Protocol::Udp,
+                _ => {
+                    let ports = match q.strip_prefix("port:") {
+                        Some(p) => p,
+                        None => return Err(invalid(&format!("unknown qualifier \"{}\"", q))),
+                    };
+
+                    for range in ports.split(',') {
+                        let (start, end) = match range.find('-') {
+                            Some(idx) => (&range[..idx], &range[idx + 1..]),
+                            None => (range, range),
+                        };
+
+                        match (start.parse::<u16>(), end.parse::<u16>()) {
+                            (Ok(start), Ok(end)) if start <= end => qualifier.ports.push(start..=end),
+                            _ => return Err(invalid(&format!("invalid port range \"{}\"", range))),
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            if qualifier.protocol.map_or(false, |p| p != protocol) {
+                return Err(invalid("conflicting protocols"));
+            }
+            qualifier.protocol = Some(protocol);
+        }
+
+        Ok(qualifier)
+    }
+
+    /// Check if connections to `port` with `protocol` are restricted by this qualifier
+    fn matches(&self, port: u16, protocol: Protocol) -> bool {
+        if self.protocol.map_or(false, |p| p != protocol) {
+            return false;
+        }
+        self.ports.is_empty() || self.ports.iter().any(|r| r.contains(&port))
+    }
+}
+
+/// Size limit of compiled regular expressions
+const REGEX_SIZE_LIMIT: usize = usize::max_value();
+
 #[derive(Clone)]
 struct Rules {
+    // Matches all addresses, by `*`
+    all: bool,
     ipv4: IpRange<Ipv4Net>,
     ipv6: IpRange<Ipv6Net>,
-    rule: RegexSet,
+    geoip: GeoIpRules,
+    exact: HashSet<String>,
+    suffix: SubDomainsTree,
+    keyword: Vec<String>,
+    // Regular expressions, `None` if there aren't any, so rules without them don't compile a `RegexSet`
+    rule: Option<RegexSet>,
+    // Rules with ports or protocols, grouped by their qualifiers
+    qualified: Vec<(Qualifier, Rules)>,
 }
 
 impl fmt::Debug for Rules {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Rules {{ ipv4: {:?}, ipv6: {:?}, rule: [", self.ipv4, self.ipv6)?;
+        write!(
+            f,
+            "Rules {{ all: {}, ipv4: {:?}, ipv6: {:?}, geoip: {:?}, ",
+            self.all, self.ipv4, self.ipv6, self.geoip
+        )?;
+        write!(
+            f,
+            "exact: {} domains, suffix: {} domains, keyword: {:?}, rule: [",
+            self.exact.len(),
+            self.suffix.len(),
+            self.keyword
+        )?;
+
+        let patterns = self.rule.as_ref().map_or(&[][..], RegexSet::patterns);
 
         let max_len = 2;
-        let has_more = self.rule.len() > max_len;
+        let has_more = patterns.len() > max_len;
 
-        for (idx, r) in self.rule.patterns().iter().take(max_len).enumerate() {
+        for (idx, r) in patterns.iter().take(max_len).enumerate() {
             if idx > 0 {
                 f.write_str(", ")?;
             }
@@ -50,51 +167,442 @@ impl fmt::Debug for Rules {
             f.write_str(", ...")?;
         }
 
-        f.write_str("] }")
+        write!(f, "], qualified: {:?} }}", self.qualified)
     }
 }
 
 impl Rules {
-    /// Create a new rule
-    fn new(mut ipv4: IpRange<Ipv4Net>, mut ipv6: IpRange<Ipv6Net>, rule: RegexSet) -> Rules {
-        // Optimization, merging networks
-        ipv4.simplify();
-        ipv6.simplify();
-
-        Rules { ipv4, ipv6, rule }
-    }
-
     /// Check if the specified address matches these rules
-    #[allow(dead_code)]
-    fn check_address_matched(&self, addr: &Address) -> bool {
+    ///
+    /// Domain names are resolved for matching IP rules if they don't match any domain name rules
+    async fn check_address_matched(&self, context: &Context, addr: &Address) -> bool {
         match *addr {
             Address::SocketAddress(ref saddr) => self.check_ip_matched(&saddr.ip()),
-            Address::DomainNameAddress(ref domain, ..) => self.check_host_matched(domain),
+            Address::DomainNameAddress(ref host, port) => {
+                if self.check_host_matched(host) {
+                    return true;
+                }
+                if self.is_ip_empty() {
+                    return false;
+                }
+
+                if let Ok(vaddr) = context.dns_resolve(host, port).await {
+                    for addr in vaddr {
+                        if self.check_ip_matched(&addr.ip()) {
+                            return true;
+                        }
+                    }
+                }
+
+                false
+            }
+        }
+    }
+
+    /// Check if the specified address with `protocol` matches any rules, with or without qualifiers
+    async fn check_matched(&self, context: &Context, addr: &Address, protocol: Protocol) -> bool {
+        self.check_qualified_matched(context, addr, protocol).await || self.check_address_matched(context, addr).await
+    }
+
+    /// Check if the specified address with `protocol` matches rules with qualifiers
+    async fn check_qualified_matched(&self, context: &Context, addr: &Address, protocol: Protocol) -> bool {
+        for (qualifier, rules) in &self.qualified {
+            if qualifier.matches(addr.port(), protocol) && rules.check_address_matched(context, addr).await {
+                return true;
+            }
         }
+        false
     }
 
     /// Check if the specified address matches any rules
     fn check_ip_matched(&self, addr: &IpAddr) -> bool {
-        match addr {
+        if self.all {
+            return true;
+        }
+
+        let matched = match addr {
             IpAddr::V4(v4) => self.ipv4.contains(v4),
             IpAddr::V6(v6) => self.ipv6.contains(v6),
-        }
+        };
+
+        matched || self.geoip.contains(addr)
     }
 
     /// Check if the specified host matches any rules
     fn check_host_matched(&self, host: &str) -> bool {
-        self.rule.is_match(host)
+        if self.all {
+            return true;
+        }
+
+        // Domain names are case insensitive, and may be fully qualified with a trailing dot
+        let domain = host.trim_end_matches('.').to_ascii_lowercase();
+
+        if self.exact.contains(&domain) || self.suffix.contains(&domain) {
+            return true;
+        }
+        if self.keyword.iter().any(|k| domain.contains(k.as_str())) {
+            return true;
+        }
+
+        match self.rule {
+            Some(ref rule) => rule.is_match(host),
+            None => false,
+        }
     }
 
     /// Check if there are no rules for IP addresses
     fn is_ip_empty(&self) -> bool {
-        self.ipv4.is_empty() && self.ipv6.is_empty()
+        !self.all && self.ipv4.is_empty() && self.ipv6.is_empty() && self.geoip.is_empty()
     }
 
     /// Check if there are no rules for domain names
     fn is_host_empty(&self) -> bool {
-        self.rule.len() == 0
+        !self.all && self.exact.is_empty() && self.suffix.is_empty() && self.keyword.is_empty() && self.rule.is_none()
+    }
+}
+
+/// Collects rules of a section while loading ACL files
+#[derive(Default)]
+struct RulesBuilder {
+    all: bool,
+    ipv4: IpRange<Ipv4Net>,
+    ipv6: IpRange<Ipv6Net>,
+    geoip: HashSet<String>,
+    exact: HashSet<String>,
+    suffix: SubDomainsTree,
+    keyword: Vec<String>,
+    rule: Vec<String>,
+    qualified: Vec<(Qualifier, RulesBuilder)>,
+}
+
+impl RulesBuilder {
+    /// Add a rule line, see `AccessControl` for available rules
+    fn add_rule(&mut self, line: &str) -> io::Result<()> {
+        let (addr, qualifier) = split_rule(line)?;
+        if qualifier == Qualifier::default() {
+            return self.add_address_rule(line, addr);
+        }
+
+        let idx = match self.qualified.iter().position(|(q, _)| *q == qualifier) {
+            Some(idx) => idx,
+            None => {
+                self.qualified.push((qualifier, RulesBuilder::default()));
+                self.qualified.len() - 1
+            }
+        };
+        self.qualified[idx].1.add_address_rule(line, addr)
+    }
+
+    /// Add the address part of a rule line
+    fn add_address_rule(&mut self, line: &str, addr: &str) -> io::Result<()> {
+        if addr == "*" {
+            self.all = true;
+            return Ok(());
+        }
+
+        match addr.parse::<IpNet>() {
+            Ok(IpNet::V4(v4)) => {
+                self.ipv4.add(v4);
+                return Ok(());
+            }
+            Ok(IpNet::V6(v6)) => {
+                self.ipv6.add(v6);
+                return Ok(());
+            }
+            Err(..) => {}
+        }
+
+        // Maybe it is a pure IpAddr
+        match addr.parse::<IpAddr>() {
+            Ok(IpAddr::V4(v4)) => {
+                self.ipv4.add(Ipv4Net::from(v4));
+                return Ok(());
+            }
+            Ok(IpAddr::V6(v6)) => {
+                self.ipv6.add(Ipv6Net::from(v6));
+                return Ok(());
+            }
+            Err(..) => {}
+        }
+
+        if let Some(country) = addr.strip_prefix("geoip:") {
+            self.add_geoip_rule(line, country)?;
+        } else if let Some(domain) = addr.strip_prefix("full:") {
+            self.exact.insert(parse_domain_rule(line, domain)?);
+        } else if let Some(domain) = addr.strip_prefix("domain:") {
+            self.suffix.insert(&parse_domain_rule(line, domain)?);
+        } else if let Some(keyword) = addr.strip_prefix("keyword:") {
+            if keyword.is_empty() {
+                let err = Error::new(ErrorKind::Other, format!("empty keyword in rule \"{}\"", line));
+                return Err(err);
+            }
+            self.keyword.push(keyword.to_ascii_lowercase());
+        } else if let Some(regex) = addr.strip_prefix("regexp:") {
+            self.rule.push(regex.to_owned());
+        } else if let Some(domain) = regex_suffix_domain(addr) {
+            // The common `(^|\.)example\.com$` idiom doesn't have to be a regex
+            self.suffix.insert(&domain);
+        } else if let Some(domain) = regex_exact_domain(addr) {
+            self.exact.insert(domain);
+        } else {
+            // FIXME: If this line is not a valid regex, how can we know without actually compile it?
+            self.rule.push(addr.to_owned());
+        }
+
+        Ok(())
+    }
+
+    /// Add a `geoip:` rule, `country` is a country code, or `private` for private networks
+    fn add_geoip_rule(&mut self, line: &str, country: &str) -> io::Result<()> {
+        if country.eq_ignore_ascii_case("private") {
+            for net in PRIVATE_NETWORKS {
+                match net.parse::<IpNet>().expect("private network") {
+                    IpNet::V4(v4) => {
+                        self.ipv4.add(v4);
+                    }
+                    IpNet::V6(v6) => {
+                        self.ipv6.add(v6);
+                    }
+                }
+            }
+            return Ok(());
+        }
+
+        if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+            let err = Error::new(
+                ErrorKind::Other,
+                format!("invalid country code in rule \"{}\", expecting 2 letters", line),
+            );
+            return Err(err);
+        }
+
+        self.geoip.insert(country.to_ascii_uppercase());
+        Ok(())
+    }
+
+    /// Build rules, `section` names the section in errors
+    ///
+    /// `geoip:` rules of countries are looked up in `geoip_db`
+    fn build(self, section: &str, geoip_db: Option<&Arc<GeoIpDatabase>>) -> io::Result<Rules> {
+        if !self.geoip.is_empty() && geoip_db.is_none() {
+            let err = Error::new(
+                ErrorKind::Other,
+                format!("{} has geoip rules, but GeoIP database is not configured", section),
+            );
+            return Err(err);
+        }
+
+        let rule = if self.rule.is_empty() {
+            None
+        } else {
+            match RegexSetBuilder::new(self.rule).size_limit(REGEX_SIZE_LIMIT).build() {
+                Ok(r) => Some(r),
+                Err(err) => {
+                    let err = Error::new(ErrorKind::Other, format!("{} regex error: {}", section, err));
+                    return Err(err);
+                }
+            }
+        };
+
+        let RulesBuilder {
+            all,
+            mut ipv4,
+            mut ipv6,
+            geoip,
+            exact,
+            suffix,
+            keyword,
+            qualified,
+            ..
+        } = self;
+
+        // Optimization, merging networks
+        ipv4.simplify();
+        ipv6.simplify();
+
+        let mut qualified_rules = Vec::with_capacity(qualified.len());
+        for (qualifier, builder) in qualified {
+            qualified_rules.push((qualifier, builder.build(section, geoip_db)?));
+        }
+
+        Ok(Rules {
+            all,
+            ipv4,
+            ipv6,
+            geoip: GeoIpRules::new(geoip_db.cloned(), geoip),
+            exact,
+            suffix,
+            keyword,
+            rule,
+            qualified: qualified_rules,
+        })
+    }
+}
+
+/// Section of rules in ACL files
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Section {
+    /// `[outbound_block_list]`
+    OutboundBlock,
+    /// `[black_list]` or `[bypass_list]`, also rules before any sections
+    Bypass,
+    /// `[white_list]` or `[proxy_list]`
+    Proxy,
+    /// `[reject_list]`
+    Reject,
+    /// `[route:NAME]`, sections with the same name are merged
+    Route(String),
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Section::OutboundBlock => f.write_str("[outbound_block_list]"),
+            Section::Bypass => f.write_str("[black_list] or [bypass_list]"),
+            Section::Proxy => f.write_str("[white_list] or [proxy_list]"),
+            Section::Reject => f.write_str("[reject_list]"),
+            Section::Route(ref name) => write!(f, "[route:{}]", name),
+        }
+    }
+}
+
+/// Lines of ACL files that are not rules
+enum SectionHeader {
+    /// `[bypass_all]`, `[proxy_all]`, `[reject_all]` and `[accept_all]`
+    Mode(Mode),
+    /// `[route_all:NAME]`
+    DefaultRoute(String),
+    /// Following rules are added to this section
+    Rules(Section),
+}
+
+impl SectionHeader {
+    /// Parse `line` if it is a section header
+    fn parse(line: &str) -> Option<SectionHeader> {
+        let header = match line {
+            "[reject_all]" | "[bypass_all]" => SectionHeader::Mode(Mode::WhiteList),
+            "[accept_all]" | "[proxy_all]" => SectionHeader::Mode(Mode::BlackList),
+            "[outbound_block_list]" => SectionHeader::Rules(Section::OutboundBlock),
+            "[black_list]" | "[bypass_list]" => SectionHeader::Rules(Section::Bypass),
+            "[white_list]" | "[proxy_list]" => SectionHeader::Rules(Section::Proxy),
+            "[reject_list]" => SectionHeader::Rules(Section::Reject),
+            _ => {
+                if let Some(name) = section_argument(line, "route") {
+                    SectionHeader::Rules(Section::Route(name.to_owned()))
+                } else if let Some(name) = section_argument(line, "route_all") {
+                    SectionHeader::DefaultRoute(name.to_owned())
+                } else {
+                    return None;
+                }
+            }
+        };
+        Some(header)
+    }
+}
+
+/// Lines of an ACL file, without empty lines and comments, with line numbers starting from 1
+fn read_lines<P: AsRef<Path>>(p: P) -> io::Result<Vec<(usize, String)>> {
+    let fp = File::open(p)?;
+    let r = BufReader::new(fp);
+
+    let mut lines = Vec::new();
+    for (idx, line) in r.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+
+        // Comments
+        if line.starts_with('#') {
+            continue;
+        }
+
+        lines.push((idx + 1, line));
+    }
+    Ok(lines)
+}
+
+/// Argument of sections like `[route:NAME]`, which is `NAME`
+fn section_argument<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let arg = line
+        .strip_prefix('[')?
+        .strip_suffix(']')?
+        .strip_prefix(name)?
+        .strip_prefix(':')?;
+    if arg.is_empty() {
+        None
+    } else {
+        Some(arg)
+    }
+}
+
+/// Split a rule line into the address and its qualifiers, which follow the address after `;`
+///
+/// Regular expressions may contain spaces, so the address is the whole line if there is no `;`.
+/// `;` never appears in IP addresses or domain names.
+fn split_rule(line: &str) -> io::Result<(&str, Qualifier)> {
+    let (addr, qualifiers) = match line.rfind(';') {
+        Some(idx) => (line[..idx].trim_end(), &line[idx + 1..]),
+        None => return Ok((line, Qualifier::default())),
+    };
+
+    if addr.is_empty() {
+        let err = Error::new(ErrorKind::Other, format!("missing address in rule \"{}\"", line));
+        return Err(err);
+    }
+
+    let qualifier = Qualifier::parse(line, qualifiers.split_whitespace())?;
+    if qualifier == Qualifier::default() {
+        let err = Error::new(
+            ErrorKind::Other,
+            format!("missing qualifiers after \";\" in rule \"{}\"", line),
+        );
+        return Err(err);
+    }
+
+    Ok((addr, qualifier))
+}
+
+/// Domain name of `full:` and `domain:` rules, in lower case
+fn parse_domain_rule(line: &str, domain: &str) -> io::Result<String> {
+    let domain = domain.trim_start_matches('.').trim_end_matches('.');
+    if domain.is_empty() {
+        let err = Error::new(ErrorKind::Other, format!("empty domain name in rule \"{}\"", line));
+        return Err(err);
+    }
+    Ok(domain.to_ascii_lowercase())
+}
+
+/// Domain name matched by regex `(^|\.)example\.com$`, and all its sub-domains
+fn regex_suffix_domain(regex: &str) -> Option<String> {
+    let regex = regex.strip_suffix('$')?;
+    let domain = regex
+        .strip_prefix(r"(^|\.)")
+        .or_else(|| regex.strip_prefix(r"(\.|^)"))?;
+    unescape_regex_domain(domain)
+}
+
+/// Domain name matched by regex `^example\.com$`
+fn regex_exact_domain(regex: &str) -> Option<String> {
+    let domain = regex.strip_prefix('^')?.strip_suffix('$')?;
+    unescape_regex_domain(domain)
+}
+
+/// Domain name in a regex, which only have letters, digits, `-`, `_` and dots (escaped or not)
+///
+/// An unescaped `.` matches any character, but it is obviously a dot in the domain name.
+fn unescape_regex_domain(regex: &str) -> Option<String> {
+    let domain = regex.replace(r"\.", ".");
+    if domain.is_empty() || domain.starts_with('.') || domain.ends_with('.') || domain.contains("..") {
+        return None;
     }
+    if !domain
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    {
+        return None;
+    }
+    Some(domain.to_ascii_lowercase())
 }
 
 /// ACL rules
@@ -123,6 +631,9 @@ impl Rules {
 ///     * `[proxy_all]` - ACL runs in `WhiteList` mode.
 ///     * `[bypass_list]` - Rules for connecting directly
 ///     * `[proxy_list]` - Rules for connecting through proxies
+///     * `[route:NAME]` - Rules for connecting through servers in group `NAME`
+///     * `[route_all:NAME]` - Connects through servers in group `NAME` if targets are proxied by the other sections
+///     * `[reject_list]` - Rules for rejecting connections
 /// - For remote servers (`ssserver`)
 ///     * `[reject_all]` - ACL runs in `BlackList` mode.
 ///     * `[accept_all]` - ACL runs in `WhiteList` mode.
@@ -137,143 +648,121 @@ impl Rules {
 /// - `BlackList` - Bypasses / Rejects all addresses except those in `[proxy_list]` or `[white_list]`
 /// - `WhiltList` - Proxies / Accepts all addresses except those in `[bypass_list]` or `[black_list]`
 ///
+/// ## Routes
+///
+/// Targets matching `[reject_list]` are rejected, then those matching `[route:NAME]` sections (in the order
+/// of first appearance) are proxied through group `NAME`. They are checked before `[bypass_list]` and `[proxy_list]`.
+/// Groups are configured in `Config::groups`.
+///
 /// ## Rules
 ///
 /// Rules can be either
 ///
 /// - CIDR form network addresses, like `10.9.0.32/16`
 /// - IP addresses, like `127.0.0.1` or `::1`
-/// - Regular Expression for matching hosts, like `(^|\.)gmail\.com$`
+/// - Domain names with all their sub-domains, like `domain:gmail.com`
+/// - Exact domain names, like `full:mail.google.com`
+/// - Keywords in domain names, like `keyword:google`
+/// - Countries of IP addresses, like `geoip:CN`, looked up in the database of `load_from_file_with_geoip`
+/// - Private networks, like `geoip:private`, which doesn't require GeoIP databases
+/// - Regular Expression for matching hosts, like `regexp:^mail[0-9]+\.google\.com$`, the `regexp:` prefix is optional
+///
+/// - `*` for all addresses, which is only useful with qualifiers
+///
+/// Regular expressions in the form of `(^|\.)gmail\.com$` and `^mail\.google\.com$` are loaded as
+/// `domain:` and `full:` rules, they are much faster than regular expressions.
+///
+/// ## Qualifiers
+///
+/// Rules could be followed by `;` and qualifiers separated by spaces, for matching targets only on some ports or
+/// protocols
+///
+/// - Ports and port ranges, like `port:25,465,587` or `port:6881-6889`
+/// - Protocols, `tcp` or `udp`
+///
+/// For example, `* ; udp port:123` matches all UDP targets on port 123. Qualified rules are checked before the others.
+/// Lines without `;` don't have qualifiers, regular expressions could contain spaces.
+/// They are only applied to targets and outbound addresses, not to clients of servers.
 #[derive(Debug, Clone)]
 pub struct AccessControl {
     outbound_block: Rules,
     black_list: Rules,
     white_list: Rules,
+    reject_list: Rules,
+    routes: Vec<(String, Rules)>,
+    default_route: Option<String>,
     mode: Mode,
 }
 
 impl AccessControl {
     /// Load ACL rules from a file
     pub fn load_from_file<P: AsRef<Path>>(p: P) -> io::Result<AccessControl> {
-        let fp = File::open(p)?;
-        let r = BufReader::new(fp);
+        AccessControl::load_from_file_with_geoip(p, None)
+    }
+
+    /// Load ACL rules from a file, `geoip:` rules of countries are looked up in the MaxMind database `geoip_db`
+    pub fn load_from_file_with_geoip<P: AsRef<Path>>(p: P, geoip_db: Option<&Path>) -> io::Result<AccessControl> {
+        let geoip_db = match geoip_db {
+            Some(path) => Some(geoip::open_database(path)?),
+            None => None,
+        };
 
         let mut mode = Mode::BlackList;
 
-        let mut outbound_block_ipv4 = IpRange::new();
-        let mut outbound_block_ipv6 = IpRange::new();
-        let mut outbound_block_rules = Vec::new();
-        let mut bypass_ipv4 = IpRange::new();
-        let mut bypass_ipv6 = IpRange::new();
-        let mut bypass_rules = Vec::new();
-        let mut proxy_ipv4 = IpRange::new();
-        let mut proxy_ipv6 = IpRange::new();
-        let mut proxy_rules = Vec::new();
-
-        let mut curr_ipv4 = &mut bypass_ipv4;
-        let mut curr_ipv6 = &mut bypass_ipv6;
-        let mut curr_rules = &mut bypass_rules;
-
-        for line in r.lines() {
-            let line = line?;
-            if line.is_empty() {
-                continue;
-            }
+        let mut outbound_block = RulesBuilder::default();
+        let mut bypass = RulesBuilder::default();
+        let mut proxy = RulesBuilder::default();
+        let mut reject = RulesBuilder::default();
+        let mut routes: Vec<(String, RulesBuilder)> = Vec::new();
+        let mut default_route = None;
 
-            // Comments
-            if line.starts_with('#') {
-                continue;
-            }
+        let mut curr = Section::Bypass;
 
-            match line.as_str() {
-                "[reject_all]" | "[bypass_all]" => {
-                    mode = Mode::WhiteList;
+        for (_, line) in read_lines(p)? {
+            match SectionHeader::parse(&line) {
+                Some(SectionHeader::Mode(m)) => {
+                    mode = m;
                 }
-                "[accept_all]" | "[proxy_all]" => {
-                    mode = Mode::BlackList;
+                Some(SectionHeader::DefaultRoute(name)) => {
+                    default_route = Some(name);
                 }
-                "[outbound_block_list]" => {
-                    curr_ipv4 = &mut outbound_block_ipv4;
-                    curr_ipv6 = &mut outbound_block_ipv6;
-                    curr_rules = &mut outbound_block_rules;
-                }
-                "[black_list]" | "[bypass_list]" => {
-                    curr_ipv4 = &mut bypass_ipv4;
-                    curr_ipv6 = &mut bypass_ipv6;
-                    curr_rules = &mut bypass_rules;
-                }
-                "[white_list]" | "[proxy_list]" => {
-                    curr_ipv4 = &mut proxy_ipv4;
-                    curr_ipv6 = &mut proxy_ipv6;
-                    curr_rules = &mut proxy_rules;
-                }
-                _ => {
-                    match line.parse::<IpNet>() {
-                        Ok(IpNet::V4(v4)) => {
-                            curr_ipv4.add(v4);
-                        }
-                        Ok(IpNet::V6(v6)) => {
-                            curr_ipv6.add(v6);
-                        }
-                        Err(..) => {
-                            // Maybe it is a pure IpAddr
-                            match line.parse::<IpAddr>() {
-                                Ok(IpAddr::V4(v4)) => {
-                                    curr_ipv4.add(Ipv4Net::from(v4));
-                                }
-                                Ok(IpAddr::V6(v6)) => {
-                                    curr_ipv6.add(Ipv6Net::from(v6));
-                                }
-                                Err(..) => {
-                                    // FIXME: If this line is not a valid regex, how can we know without actually compile it?
-                                    curr_rules.push(line);
-                                }
-                            }
+                Some(SectionHeader::Rules(section)) => {
+                    if let Section::Route(ref name) = section {
+                        if !routes.iter().any(|(n, _)| n == name) {
+                            routes.push((name.clone(), RulesBuilder::default()));
                         }
                     }
+                    curr = section;
+                }
+                None => {
+                    let rules = match curr {
+                        Section::OutboundBlock => &mut outbound_block,
+                        Section::Bypass => &mut bypass,
+                        Section::Proxy => &mut proxy,
+                        Section::Reject => &mut reject,
+                        Section::Route(ref name) => {
+                            let (_, rules) = routes.iter_mut().find(|(n, _)| n == name).expect("route section");
+                            rules
+                        }
+                    };
+                    rules.add_rule(&line)?;
                 }
             }
         }
 
-        const REGEX_SIZE_LIMIT: usize = usize::max_value();
-
-        let outbound_block_regex = match RegexSetBuilder::new(outbound_block_rules)
-            .size_limit(REGEX_SIZE_LIMIT)
-            .build()
-        {
-            Ok(r) => r,
-            Err(err) => {
-                let err = Error::new(ErrorKind::Other, format!("[outbound_block_list] regex error: {}", err));
-                return Err(err);
-            }
-        };
-
-        let bypass_regex = match RegexSetBuilder::new(bypass_rules).size_limit(REGEX_SIZE_LIMIT).build() {
-            Ok(r) => r,
-            Err(err) => {
-                let err = Error::new(
-                    ErrorKind::Other,
-                    format!("[black_list] or [bypass_list] regex error: {}", err),
-                );
-                return Err(err);
-            }
-        };
-
-        let proxy_regex = match RegexSetBuilder::new(proxy_rules).size_limit(REGEX_SIZE_LIMIT).build() {
-            Ok(r) => r,
-            Err(err) => {
-                let err = Error::new(
-                    ErrorKind::Other,
-                    format!("[white_list] or [proxy_list] regex error: {}", err),
-                );
-                return Err(err);
-            }
-        };
+        let mut route_rules = Vec::with_capacity(routes.len());
+        for (name, builder) in routes {
+            let rules = builder.build(&Section::Route(name.clone()).to_string(), geoip_db.as_ref())?;
+            route_rules.push((name, rules));
+        }
 
         Ok(AccessControl {
-            outbound_block: Rules::new(outbound_block_ipv4, outbound_block_ipv6, outbound_block_regex),
-            black_list: Rules::new(bypass_ipv4, bypass_ipv6, bypass_regex),
-            white_list: Rules::new(proxy_ipv4, proxy_ipv6, proxy_regex),
+            outbound_block: outbound_block.build(&Section::OutboundBlock.to_string(), geoip_db.as_ref())?,
+            black_list: bypass.build(&Section::Bypass.to_string(), geoip_db.as_ref())?,
+            white_list: proxy.build(&Section::Proxy.to_string(), geoip_db.as_ref())?,
+            reject_list: reject.build(&Section::Reject.to_string(), geoip_db.as_ref())?,
+            routes: route_rules,
+            default_route,
             mode,
         })
     }
@@ -330,10 +819,39 @@ impl AccessControl {
         }
     }
 
-    /// Check if target address should be bypassed (for client)
+    /// Check if target address with `protocol` should be bypassed (for client)
     ///
     /// This function may perform a DNS resolution
-    pub async fn check_target_bypassed(&self, context: &Context, addr: &Address) -> bool {
+    pub async fn check_target_bypassed(&self, context: &Context, addr: &Address, protocol: Protocol) -> bool {
+        if let Some(bypassed) = self.check_qualified_target_bypassed(context, addr, protocol).await {
+            return bypassed;
+        }
+        self.check_unqualified_target_bypassed(context, addr).await
+    }
+
+    /// Check target address with `protocol` by rules with ports or protocols (for client)
+    ///
+    /// Return
+    /// - `Some(false)` if it matches rules in `proxy_list` (should be proxied)
+    /// - `Some(true)` if it matches rules in `bypass_list` (should be bypassed)
+    /// - `None` if it doesn't match any of these rules
+    pub(crate) async fn check_qualified_target_bypassed(
+        &self,
+        context: &Context,
+        addr: &Address,
+        protocol: Protocol,
+    ) -> Option<bool> {
+        if self.white_list.check_qualified_matched(context, addr, protocol).await {
+            return Some(false);
+        }
+        if self.black_list.check_qualified_matched(context, addr, protocol).await {
+            return Some(true);
+        }
+        None
+    }
+
+    /// Check target address by rules without ports or protocols (for client)
+    pub(crate) async fn check_unqualified_target_bypassed(&self, context: &Context, addr: &Address) -> bool {
         match *addr {
             Address::SocketAddress(ref addr) => !self.check_ip_in_proxy_list(&addr.ip()),
             // Resolve hostname and check the list
@@ -356,6 +874,36 @@ impl AccessControl {
         }
     }
 
+    /// Check route of target address with `protocol` by `[reject_list]` and `[route:NAME]` sections (for client)
+    ///
+    /// Return `None` if it doesn't match any rules of these sections, it is bypassed or proxied by the others
+    ///
+    /// This function may perform a DNS resolution
+    pub async fn check_target_route(&self, context: &Context, addr: &Address, protocol: Protocol) -> Option<Route> {
+        if self.reject_list.check_matched(context, addr, protocol).await {
+            return Some(Route::Reject);
+        }
+        for (name, rules) in &self.routes {
+            if rules.check_matched(context, addr, protocol).await {
+                return Some(Route::Proxy(Some(name.clone())));
+            }
+        }
+        None
+    }
+
+    /// Group of servers for proxied targets that don't match any `[route:NAME]` sections, by `[route_all:NAME]`
+    pub fn default_route(&self) -> Option<&str> {
+        self.default_route.as_deref()
+    }
+
+    /// Names of groups in `[route:NAME]` and `[route_all:NAME]` sections
+    pub fn route_groups(&self) -> impl Iterator<Item = &str> {
+        self.routes
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .chain(self.default_route.as_deref())
+    }
+
     /// Check if client address should be blocked (for server)
     pub fn check_client_blocked(&self, addr: &SocketAddr) -> bool {
         match self.mode {
@@ -370,11 +918,19 @@ impl AccessControl {
         }
     }
 
-    /// Check if outbound address is blocked (for server)
+    /// Check if outbound address with `protocol` is blocked (for server)
     ///
     /// NOTE: `Address::DomainName` is only validated by regex rules,
     ///       resolved addresses are checked in the `lookup_outbound_then!` macro
-    pub async fn check_outbound_blocked(&self, context: &Context, outbound: &Address) -> bool {
+    pub async fn check_outbound_blocked(&self, context: &Context, outbound: &Address, protocol: Protocol) -> bool {
+        if self
+            .outbound_block
+            .check_qualified_matched(context, outbound, protocol)
+            .await
+        {
+            return true;
+        }
+
         match outbound {
             Address::SocketAddress(saddr) => self.outbound_block.check_ip_matched(&saddr.ip()),
             Address::DomainNameAddress(host, port) => {
@@ -395,3 +951,223 @@ impl AccessControl {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::config::{Config, ConfigType};
+
+    use super::*;
+
+    fn load_acl(content: &str) -> AccessControl {
+        let dir = tempfile::tempdir().unwrap();
+        let acl_path = dir.path().join("rules.acl");
+        fs::write(&acl_path, content).unwrap();
+        AccessControl::load_from_file(&acl_path).unwrap()
+    }
+
+    fn build_rules(lines: &[&str]) -> Rules {
+        let mut builder = RulesBuilder::default();
+        for line in lines {
+            builder.add_rule(line).unwrap();
+        }
+        builder.build("[test]", None).unwrap()
+    }
+
+    #[test]
+    fn regex_with_spaces() {
+        let rules = build_rules(&[r"^(mail| www)\.example\.com$", "^foo bar$"]);
+        assert!(rules.qualified.is_empty());
+        assert_eq!(rules.rule.as_ref().map(RegexSet::len), Some(2));
+
+        assert!(rules.check_host_matched("mail.example.com"));
+        assert!(rules.check_host_matched(" www.example.com"));
+        assert!(!rules.check_host_matched("www.example.com"));
+        assert!(rules.check_host_matched("foo bar"));
+    }
+
+    #[test]
+    fn regex_domain_idioms() {
+        assert_eq!(
+            regex_suffix_domain(r"(^|\.)example\.com$"),
+            Some("example.com".to_owned())
+        );
+        assert_eq!(
+            regex_suffix_domain(r"(\.|^)Example\.COM$"),
+            Some("example.com".to_owned())
+        );
+        assert_eq!(regex_suffix_domain(r"(^|\.)example\.com"), None);
+        assert_eq!(regex_suffix_domain(r"(^|\.)ex.*\.com$"), None);
+
+        assert_eq!(
+            regex_exact_domain(r"^mail\.example\.com$"),
+            Some("mail.example.com".to_owned())
+        );
+        // Unescaped dots are dots in domain names
+        assert_eq!(
+            regex_exact_domain(r"^mail.example.com$"),
+            Some("mail.example.com".to_owned())
+        );
+        assert_eq!(regex_exact_domain(r"^mail[0-9]+\.example\.com$"), None);
+        assert_eq!(regex_exact_domain(r"^\.example\.com$"), None);
+        assert_eq!(regex_exact_domain(r"^example\.\.com$"), None);
+
+        // Idioms are loaded as domain rules instead of regular expressions
+        let rules = build_rules(&[
+            r"(^|\.)example\.com$",
+            r"^mail\.example\.org$",
+            r"^cdn[0-9]+\.example\.net$",
+        ]);
+        assert_eq!(rules.suffix.len(), 1);
+        assert_eq!(rules.exact.len(), 1);
+        assert_eq!(rules.rule.as_ref().map(RegexSet::len), Some(1));
+    }
+
+    #[test]
+    fn domain_rules() {
+        let rules = build_rules(&["full:www.example.com", "domain:.Example.ORG.", "keyword:google"]);
+        assert!(rules.rule.is_none());
+
+        // Exact rules
+        assert!(rules.check_host_matched("www.example.com"));
+        assert!(rules.check_host_matched("WWW.Example.com."));
+        assert!(!rules.check_host_matched("example.com"));
+        assert!(!rules.check_host_matched("a.www.example.com"));
+
+        // Suffix rules
+        assert!(rules.check_host_matched("example.org"));
+        assert!(rules.check_host_matched("www.example.org"));
+        assert!(!rules.check_host_matched("notexample.org"));
+
+        // Keyword rules
+        assert!(rules.check_host_matched("googleapis.com"));
+        assert!(rules.check_host_matched("www.GOOGLE.com"));
+        assert!(!rules.check_host_matched("goo.gle"));
+
+        for line in &["full:", "domain:.", "keyword:"] {
+            assert!(RulesBuilder::default().add_rule(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn geoip_rules() {
+        // Private networks don't require GeoIP databases
+        let rules = build_rules(&["geoip:private"]);
+        assert!(rules.geoip.is_empty());
+        for ip in &["10.1.2.3", "192.168.1.1", "127.0.0.1", "::1", "fd00::1"] {
+            assert!(rules.check_ip_matched(&ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in &["1.2.4.8", "8.8.8.8", "2001:db8::1"] {
+            assert!(!rules.check_ip_matched(&ip.parse().unwrap()), "{}", ip);
+        }
+
+        // Countries require GeoIP databases
+        let mut builder = RulesBuilder::default();
+        builder.add_rule("geoip:CN").unwrap();
+        let err = builder
+            .build("[test]", None)
+            .err()
+            .expect("build without GeoIP database");
+        assert!(err.to_string().contains("GeoIP database is not configured"), "{}", err);
+
+        for line in &["geoip:", "geoip:china", "geoip:C1"] {
+            assert!(RulesBuilder::default().add_rule(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn rule_qualifiers() {
+        let (addr, qualifier) = split_rule("10.0.0.0/8 ; tcp port:80,8000-8999").unwrap();
+        assert_eq!(addr, "10.0.0.0/8");
+        assert_eq!(qualifier.protocol, Some(Protocol::Tcp));
+        assert_eq!(qualifier.ports, vec![80..=80, 8000..=8999]);
+        assert!(qualifier.matches(8080, Protocol::Tcp));
+        assert!(!qualifier.matches(8080, Protocol::Udp));
+        assert!(!qualifier.matches(443, Protocol::Tcp));
+
+        let (addr, qualifier) = split_rule(r"^(mail| www)\.example\.com$;udp").unwrap();
+        assert_eq!(addr, r"^(mail| www)\.example\.com$");
+        assert_eq!(qualifier.protocol, Some(Protocol::Udp));
+        assert!(qualifier.ports.is_empty());
+
+        // Qualifiers are only parsed after `;`
+        let (addr, qualifier) = split_rule("* udp port:123").unwrap();
+        assert_eq!(addr, "* udp port:123");
+        assert_eq!(qualifier, Qualifier::default());
+
+        for line in &["* ;", "; udp", "* ; port:", "* ; udp tcp", "* ; udp port:123 sctp"] {
+            assert!(split_rule(line).is_err(), "{}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn route_sections() {
+        let acl = load_acl(
+            "[route:us]
+10.0.0.0/8
+
+[reject_list]
+10.1.0.0/16
+
+[route:hk]
+10.0.0.0/16
+192.168.0.0/16
+
+[route_all:jp]
+
+[route:us]
+192.168.1.0/24
+",
+        );
+
+        // Sections with the same name are merged, in the order of first appearance
+        assert_eq!(acl.route_groups().collect::<Vec<_>>(), ["us", "hk", "jp"]);
+        assert_eq!(acl.default_route(), Some("jp"));
+
+        let context = Context::new_shared(Config::new(ConfigType::Local)).await;
+        let addr = |s: &str| Address::SocketAddress(s.parse().unwrap());
+        let group = |s: &str| Some(Route::Proxy(Some(s.to_owned())));
+
+        let targets = [
+            // Rejected before routes
+            ("10.1.0.1:80", Some(Route::Reject)),
+            // Matches both groups, routed to the first section
+            ("10.0.0.1:80", group("us")),
+            ("192.168.1.1:80", group("us")),
+            ("192.168.2.1:80", group("hk")),
+            ("172.16.0.1:80", None),
+        ];
+        for (target, route) in targets.iter() {
+            let result = acl.check_target_route(&context, &addr(target), Protocol::Tcp).await;
+            assert_eq!(result, *route, "{}", target);
+        }
+    }
+
+    #[test]
+    fn route_to_unknown_group() {
+        let mut config = Config::load_from_str(
+            r#"{
+                "local_address": "127.0.0.1",
+                "local_port": 1080,
+                "servers": [
+                    {"address": "1.1.1.1", "port": 8388, "password": "p", "method": "aes-256-gcm", "id": "us-1"}
+                ],
+                "groups": {
+                    "us": ["us-1"]
+                }
+            }"#,
+            ConfigType::Local,
+        )
+        .unwrap();
+
+        config.acl = Some(load_acl("[route:us]\n10.0.0.0/8\n"));
+        assert!(config.check_integrity().is_ok());
+
+        config.acl = Some(load_acl("[route:us]\n10.0.0.0/8\n\n[route:hk]\n"));
+        assert!(config.check_integrity().is_err());
+
+        config.acl = Some(load_acl("[route_all:jp]\n"));
+        assert!(config.check_integrity().is_err());
+    }
+}
@@ -0,0 +1,101 @@
+//! Domain suffix rules, stored in a trie of reversed labels
+
+use std::collections::HashMap;
+
+/// Matches domain names and all their sub-domains
+///
+/// `example.com` is stored as `com` -> `example`, so both `example.com` and `www.example.com` are found by
+/// walking through labels from the end of the name.
+#[derive(Debug, Clone, Default)]
+pub struct SubDomainsTree {
+    children: HashMap<String, SubDomainsTree>,
+    included: bool,
+}
+
+impl SubDomainsTree {
+    /// Add `domain` and all its sub-domains, `domain` must be in lower case
+    pub fn insert(&mut self, domain: &str) {
+        let mut node = self;
+        for label in domain.rsplit('.') {
+            // Sub-domains of an included domain are already matched
+            if node.included {
+                return;
+            }
+            node = node.children.entry(label.to_owned()).or_default();
+        }
+
+        node.included = true;
+        node.children.clear();
+    }
+
+    /// Check if `host` is one of the domains or their sub-domains, `host` must be in lower case
+    pub fn contains(&self, host: &str) -> bool {
+        let mut node = self;
+        for label in host.rsplit('.') {
+            if node.included {
+                return true;
+            }
+            node = match node.children.get(label) {
+                Some(n) => n,
+                None => return false,
+            };
+        }
+
+        node.included
+    }
+
+    /// Number of domains in the tree
+    pub fn len(&self) -> usize {
+        let n = usize::from(self.included);
+        self.children.values().fold(n, |n, c| n + c.len())
+    }
+
+    /// Check if there are no domains in the tree
+    pub fn is_empty(&self) -> bool {
+        !self.included && self.children.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sub_domains() {
+        let mut tree = SubDomainsTree::default();
+        assert!(tree.is_empty());
+
+        tree.insert("example.com");
+        tree.insert("mail.google.com");
+        assert_eq!(tree.len(), 2);
+
+        assert!(tree.contains("example.com"));
+        assert!(tree.contains("www.example.com"));
+        assert!(tree.contains("a.b.example.com"));
+        assert!(!tree.contains("com"));
+        assert!(!tree.contains("notexample.com"));
+        assert!(!tree.contains("example.com.cn"));
+
+        assert!(tree.contains("mail.google.com"));
+        assert!(!tree.contains("google.com"));
+        assert!(!tree.contains("www.google.com"));
+    }
+
+    #[test]
+    fn parent_domains() {
+        let mut tree = SubDomainsTree::default();
+
+        // Sub-domains are removed when their parents are added
+        tree.insert("mail.google.com");
+        tree.insert("www.google.com");
+        tree.insert("google.com");
+        assert_eq!(tree.len(), 1);
+
+        // Sub-domains of added domains are ignored
+        tree.insert("api.google.com");
+        assert_eq!(tree.len(), 1);
+
+        assert!(tree.contains("google.com"));
+        assert!(tree.contains("api.google.com"));
+    }
+}
@@ -40,8 +40,12 @@
 //! ```
 //!
 //! These defined server will be used with a load balancing algorithm.
+//!
+//! For servers, entries in `servers` sharing the same `server` and `server_port` are different users of one port.
+//! They must use the same AEAD method with different passwords, users are identified by their keys.
 
 use std::{
+    collections::{BTreeMap, HashMap},
     convert::{From, Infallible},
     default::Default,
     error,
@@ -56,21 +60,24 @@ use std::{
     time::Duration,
 };
 
-use base64::{decode_config, encode_config, URL_SAFE_NO_PAD};
+use base64::{decode_config, encode_config, STANDARD_NO_PAD, URL_SAFE_NO_PAD};
 use bytes::Bytes;
 use cfg_if::cfg_if;
 use log::error;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
 use serde::{Deserialize, Serialize};
 #[cfg(feature = "trust-dns")]
 use trust_dns_resolver::config::{NameServerConfig, Protocol, ResolverConfig};
-use url::{self, Url};
+use url::{self, Position, Url};
 
+#[cfg(feature = "aead-cipher-2022")]
+use crate::relay::aead_2022;
 use crate::{
     acl::AccessControl,
     context::Context,
-    crypto::v1::{openssl_bytes_to_key, CipherKind},
+    crypto::v1::{openssl_bytes_to_key, CipherCategory, CipherKind},
     plugin::PluginConfig,
-    relay::{dns_resolver::resolve_bind_addr, socks5::Address},
+    relay::{dns_resolver::resolve_bind_addr, socks5::Address, utils::constant_time_eq},
 };
 
 #[cfg(feature = "trust-dns")]
@@ -96,6 +103,20 @@ struct SSConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     manager_port: Option<u16>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    manager_stat_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manager_stat_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manager_stat_reset: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manager_state_file: Option<String>,
+    #[cfg(feature = "manager-http")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manager_http_address: Option<String>,
+    #[cfg(feature = "manager-http")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manager_http_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     password: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     method: Option<String>,
@@ -124,6 +145,29 @@ struct SSConfig {
     nofile: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     ipv6_first: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shutdown_timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    balance_strategy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    health_check: Option<SSHealthCheckConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failover_retries: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    online_config: Option<SSOnlineConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replay_filter: Option<SSReplayFilterConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    geoip_db: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    groups: Option<BTreeMap<String, Vec<String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_users: Option<Vec<SSLocalUserConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_users_file: Option<String>,
+    #[cfg(feature = "metrics")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metrics_address: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -149,10 +193,90 @@ struct SSServerExtConfig {
     remarks: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    weight: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    priority: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backup: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SSHealthCheckConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tcp: Option<SSHealthCheckProbe>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    udp: Option<SSHealthCheckProbe>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeout: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failure_threshold: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SSHealthCheckProbe {
+    #[serde(rename = "type")]
+    probe_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expected_status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SSOnlineConfig {
+    url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    update_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SSReplayFilterConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entries: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_rate: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snapshot_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snapshot_interval: Option<u64>,
+}
+
+// SIP008 online configuration document
+//
+// Other fields, such as `bytes_used` and `bytes_remaining`, are ignored
+#[derive(Serialize, Deserialize, Debug)]
+struct SIP008Config {
+    version: u32,
+    servers: Vec<SSServerExtConfig>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SSLocalUserConfig {
+    username: String,
+    password: String,
+}
+
+/// Printed instead of passwords of local users
+const REDACTED_PASSWORD: &str = "******";
+
+impl fmt::Debug for SSLocalUserConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SSLocalUserConfig")
+            .field("username", &self.username)
+            .field("password", &REDACTED_PASSWORD)
+            .finish()
+    }
 }
 
 /// Server address
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum ServerAddr {
     /// IP Address
     SocketAddr(SocketAddr),
@@ -275,6 +399,99 @@ impl From<&ServerAddr> for Address {
     }
 }
 
+/// Traffic limits of a server
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TrafficLimit {
+    /// Maximum speed of receiving from clients, in bytes per second
+    pub upload: Option<u64>,
+    /// Maximum speed of sending to clients, in bytes per second
+    pub download: Option<u64>,
+    /// Total bytes allowed to be transferred, both directions are counted
+    pub quota: Option<u64>,
+}
+
+impl TrafficLimit {
+    /// Check if there is no limit at all
+    pub fn is_unlimited(&self) -> bool {
+        self.upload.is_none() && self.download.is_none() && self.quota.is_none()
+    }
+}
+
+/// Users allowed to use local servers
+#[derive(Clone, Default)]
+pub struct LocalAuth {
+    users: HashMap<String, String>,
+}
+
+impl fmt::Debug for LocalAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Passwords are never printed
+        let mut usernames = self.users.keys().collect::<Vec<_>>();
+        usernames.sort();
+        f.debug_struct("LocalAuth").field("users", &usernames).finish()
+    }
+}
+
+impl LocalAuth {
+    /// Create an empty user list
+    pub fn new() -> LocalAuth {
+        LocalAuth::default()
+    }
+
+    /// Load users from file
+    ///
+    /// Each line is a `username:password` pair, empty lines and lines starting with `#` are ignored
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> io::Result<LocalAuth> {
+        let mut reader = OpenOptions::new().read(true).open(path)?;
+        let mut content = String::new();
+        reader.read_to_string(&mut content)?;
+
+        let mut auth = LocalAuth::new();
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match line.find(':') {
+                Some(pos) => auth.add_user(&line[..pos], &line[pos + 1..]),
+                None => {
+                    let err = io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid user \"{}\", should be \"username:password\"", line),
+                    );
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(auth)
+    }
+
+    /// Add a user, password of an existing user will be replaced
+    pub fn add_user(&mut self, username: &str, password: &str) {
+        self.users.insert(username.to_owned(), password.to_owned());
+    }
+
+    /// Check if `username` and `password` matches one of the users
+    pub fn check(&self, username: &str, password: &str) -> bool {
+        match self.users.get(username) {
+            Some(p) => constant_time_eq(p.as_bytes(), password.as_bytes()),
+            None => false,
+        }
+    }
+
+    /// Check if there is no user
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    /// Iterate all `(username, password)` pairs
+    pub fn users(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.users.iter().map(|(u, p)| (u.as_str(), p.as_str()))
+    }
+}
+
 /// Configuration for a server
 #[derive(Clone, Debug)]
 pub struct ServerConfig {
@@ -297,6 +514,16 @@ pub struct ServerConfig {
     remarks: Option<String>,
     /// ID (SIP008) is a random generated UUID
     id: Option<String>,
+    /// Traffic limits, only used in server
+    traffic_limit: TrafficLimit,
+    /// Weight in load balancing, only used in local
+    weight: u32,
+    /// Priority in load balancing, servers with lower values are preferred, only used in local
+    priority: u32,
+    /// Backup servers are used only if all the other servers are down, only used in local
+    backup: bool,
+    /// Method is the AEAD-2022 edition of `method`, `enc_key` is the pre-shared key
+    aead_2022: bool,
 }
 
 impl ServerConfig {
@@ -322,17 +549,55 @@ impl ServerConfig {
             plugin_addr: None,
             remarks: None,
             id: None,
+            traffic_limit: TrafficLimit::default(),
+            weight: 1,
+            priority: 0,
+            backup: false,
+            aead_2022: false,
         }
     }
 
+    /// Creates a new ServerConfig with name of the method
+    ///
+    /// [AEAD-2022](https://github.com/shadowsocks/shadowsocks-org/issues/196) methods are supported besides
+    /// `CipherKind`s if feature "aead-cipher-2022" is enabled, `password` of them is a base64 encoded pre-shared key.
+    pub fn with_method_name(
+        addr: ServerAddr,
+        password: String,
+        method: &str,
+        timeout: Option<Duration>,
+        plugin: Option<PluginConfig>,
+    ) -> Result<ServerConfig, MethodError> {
+        if let Ok(m) = method.parse::<CipherKind>() {
+            return Ok(ServerConfig::new(addr, password, m, timeout, plugin));
+        }
+
+        #[cfg(feature = "aead-cipher-2022")]
+        if let Some(method) = aead_2022::method_from_name(method) {
+            let key = match aead_2022::decode_psk(method, &password) {
+                Some(k) => k,
+                None => return Err(MethodError::InvalidKey),
+            };
+
+            let mut svr_cfg = ServerConfig::new(addr, password, method, timeout, plugin);
+            svr_cfg.enc_key = Bytes::from(key);
+            svr_cfg.aead_2022 = true;
+
+            return Ok(svr_cfg);
+        }
+
+        Err(MethodError::Unsupported)
+    }
+
     /// Create a basic config
     pub fn basic(addr: SocketAddr, password: String, method: CipherKind) -> ServerConfig {
         ServerConfig::new(ServerAddr::SocketAddr(addr), password, method, None, None)
     }
 
-    /// Set encryption method
+    /// Set encryption method of the original protocol
     pub fn set_method(&mut self, method: CipherKind, password: String) {
         self.method = method;
+        self.aead_2022 = false;
 
         let mut key = vec![0u8; method.key_len()];
         openssl_bytes_to_key(password.as_bytes(), &mut key);
@@ -372,10 +637,29 @@ impl ServerConfig {
     }
 
     /// Get method
+    ///
+    /// For AEAD-2022 methods, it is the AEAD cipher that the method is based on
     pub fn method(&self) -> CipherKind {
         self.method
     }
 
+    /// Check if method is an AEAD-2022 method
+    pub fn is_aead_2022(&self) -> bool {
+        self.aead_2022
+    }
+
+    /// Get name of the method, as it is written in configuration
+    pub fn method_name(&self) -> String {
+        #[cfg(feature = "aead-cipher-2022")]
+        if self.aead_2022 {
+            if let Some(name) = aead_2022::method_name(self.method) {
+                return name.to_owned();
+            }
+        }
+
+        self.method.to_string()
+    }
+
     /// Get timeout
     pub fn timeout(&self) -> Option<Duration> {
         self.timeout
@@ -396,6 +680,16 @@ impl ServerConfig {
         self.plugin_addr.as_ref()
     }
 
+    /// Set traffic limits
+    pub fn set_traffic_limit(&mut self, limit: TrafficLimit) {
+        self.traffic_limit = limit;
+    }
+
+    /// Get traffic limits
+    pub fn traffic_limit(&self) -> &TrafficLimit {
+        &self.traffic_limit
+    }
+
     /// Get server's external address
     pub fn external_addr(&self) -> &ServerAddr {
         self.plugin_addr.as_ref().unwrap_or(&self.addr)
@@ -421,18 +715,56 @@ impl ServerConfig {
         self.id = Some(id)
     }
 
+    /// Get weight in load balancing, default is 1
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+
+    /// Set weight in load balancing, must be greater than 0
+    pub fn set_weight(&mut self, weight: u32) {
+        assert!(weight > 0, "weight must be greater than 0");
+        self.weight = weight;
+    }
+
+    /// Get priority in load balancing, servers with lower values are preferred, default is 0
+    pub fn priority(&self) -> u32 {
+        self.priority
+    }
+
+    /// Set priority in load balancing
+    pub fn set_priority(&mut self, priority: u32) {
+        self.priority = priority;
+    }
+
+    /// Check if it is a backup server, which is used only if all the other servers are down
+    pub fn is_backup(&self) -> bool {
+        self.backup
+    }
+
+    /// Set as a backup server
+    pub fn set_backup(&mut self, backup: bool) {
+        self.backup = backup;
+    }
+
+    /// Check if it is load balanced with default weight, priority and role
+    fn is_default_balanced(&self) -> bool {
+        self.weight == 1 && self.priority == 0 && !self.backup
+    }
+
     /// Get URL for QRCode
     /// ```plain
-    /// ss:// + base64(method:password@host:port)
+    /// ss:// + base64(method:password@host:port) + #remarks
     /// ```
     pub fn to_qrcode_url(&self) -> String {
-        let param = format!("{}:{}@{}", self.method(), self.password(), self.addr());
-        format!("ss://{}", encode_config(&param, URL_SAFE_NO_PAD))
+        let param = format!("{}:{}@{}", self.method_name(), self.password(), self.addr());
+        let mut url = format!("ss://{}", encode_config(&param, URL_SAFE_NO_PAD));
+        self.append_url_remarks(&mut url);
+        url
     }
 
     /// Get [SIP002](https://github.com/shadowsocks/shadowsocks-org/issues/27) URL
     pub fn to_url(&self) -> String {
-        let user_info = format!("{}:{}", self.method(), self.password());
+        let user_info = format!("{}:{}", self.method_name(), self.password());
         let encoded_user_info = encode_config(&user_info, URL_SAFE_NO_PAD);
 
         let mut url = format!("ss://{}@{}", encoded_user_info, self.addr());
@@ -447,11 +779,23 @@ impl ServerConfig {
             url += "/?";
             url += &serde_urlencoded::to_string(&plugin_param).unwrap();
         }
+        self.append_url_remarks(&mut url);
 
         url
     }
 
+    fn append_url_remarks(&self, url: &mut String) {
+        if let Some(ref remarks) = self.remarks {
+            url.push('#');
+            url.extend(utf8_percent_encode(remarks, URL_FRAGMENT_ENCODE_SET));
+        }
+    }
+
     /// Parse from [SIP002](https://github.com/shadowsocks/shadowsocks-org/issues/27) URL
+    ///
+    /// Userinfo could be either base64 encoded or percent-encoded `method:password`.
+    /// The legacy `ss://BASE64(method:password@host:port)` URL is also accepted.
+    /// Fragment of the URL is the remarks of the server.
     pub fn from_url(encoded: &str) -> Result<ServerConfig, UrlParseError> {
         let parsed = Url::parse(encoded).map_err(UrlParseError::from)?;
 
@@ -459,50 +803,62 @@ impl ServerConfig {
             return Err(UrlParseError::InvalidScheme);
         }
 
-        let user_info = parsed.username();
-        let account = match decode_config(user_info, URL_SAFE_NO_PAD) {
-            Ok(account) => match String::from_utf8(account) {
-                Ok(ac) => ac,
-                Err(..) => {
-                    return Err(UrlParseError::InvalidAuthInfo);
+        let remarks = parsed
+            .fragment()
+            .filter(|f| !f.is_empty())
+            .map(|f| percent_decode_str(f).decode_utf8_lossy().into_owned());
+
+        // Legacy URL doesn't have userinfo, everything is encoded as the host
+        if !parsed[..Position::BeforeHost].contains('@') {
+            let mut svrconfig = ServerConfig::from_legacy_url(&parsed)?;
+            svrconfig.remarks = remarks;
+            return Ok(svrconfig);
+        }
+
+        let (method, pwd) = match parsed.password() {
+            // Plain userinfo, `method:password`
+            Some(pwd) => {
+                let method = percent_decode_str(parsed.username()).decode_utf8();
+                let pwd = percent_decode_str(pwd).decode_utf8();
+                match (method, pwd) {
+                    (Ok(m), Ok(p)) => (m.into_owned(), p.into_owned()),
+                    _ => return Err(UrlParseError::InvalidAuthInfo),
+                }
+            }
+            // Base64 encoded userinfo
+            None => {
+                let user_info = percent_decode_str(parsed.username()).decode_utf8_lossy();
+                let account = match decode_url_base64(&user_info) {
+                    Some(account) => match String::from_utf8(account) {
+                        Ok(ac) => ac,
+                        Err(..) => return Err(UrlParseError::InvalidAuthInfo),
+                    },
+                    None => return Err(UrlParseError::InvalidUserInfo),
+                };
+
+                match account.find(':') {
+                    Some(pos) => (account[..pos].to_owned(), account[pos + 1..].to_owned()),
+                    None => return Err(UrlParseError::InvalidAuthInfo),
                 }
-            },
-            Err(err) => {
-                error!("Failed to parse UserInfo with Base64, err: {}", err);
-                return Err(UrlParseError::InvalidUserInfo);
             }
         };
 
         let host = match parsed.host_str() {
-            Some(host) => host,
-            None => return Err(UrlParseError::MissingHost),
+            Some(host) if !host.is_empty() => host,
+            _ => return Err(UrlParseError::MissingHost),
         };
 
         let port = parsed.port().unwrap_or(8388);
-        let addr = format!("{}:{}", host, port);
-
-        let mut sp2 = account.splitn(2, ':');
-        let (method, pwd) = match (sp2.next(), sp2.next()) {
-            (Some(m), Some(p)) => (m, p),
-            _ => panic!("Malformed input"),
-        };
-
-        let addr = match addr.parse::<ServerAddr>() {
+        let addr = match format!("{}:{}", host, port).parse::<ServerAddr>() {
             Ok(a) => a,
-            Err(err) => {
-                error!("Failed to parse \"{}\" to ServerAddr, err: {:?}", addr, err);
-                return Err(UrlParseError::InvalidServerAddr);
-            }
+            Err(..) => return Err(UrlParseError::InvalidServerAddr),
         };
 
         let mut plugin = None;
         if let Some(q) = parsed.query() {
             let query = match serde_urlencoded::from_bytes::<Vec<(String, String)>>(q.as_bytes()) {
                 Ok(q) => q,
-                Err(err) => {
-                    error!("Failed to parse QueryString, err: {}", err);
-                    return Err(UrlParseError::InvalidQueryString);
-                }
+                Err(..) => return Err(UrlParseError::InvalidQueryString),
             };
 
             for (key, value) in query {
@@ -512,7 +868,7 @@ impl ServerConfig {
 
                 let mut vsp = value.splitn(2, ';');
                 match vsp.next() {
-                    None => {}
+                    None | Some("") => {}
                     Some(p) => {
                         plugin = Some(PluginConfig {
                             plugin: p.to_owned(),
@@ -524,10 +880,136 @@ impl ServerConfig {
             }
         }
 
-        let svrconfig = ServerConfig::new(addr, pwd.to_owned(), method.parse().unwrap(), None, plugin);
+        let mut svrconfig = ServerConfig::with_method_name(addr, pwd, &method, None, plugin)?;
+        svrconfig.remarks = remarks;
+
+        Ok(svrconfig)
+    }
+
+    /// Parse from legacy `ss://BASE64(method:password@host:port)` URL
+    fn from_legacy_url(parsed: &Url) -> Result<ServerConfig, UrlParseError> {
+        // Standard base64 may contain '/', which is parsed as the path
+        let encoded = &parsed[Position::BeforeHost..Position::AfterQuery];
+        let decoded = match decode_url_base64(encoded) {
+            Some(d) => match String::from_utf8(d) {
+                Ok(d) => d,
+                Err(..) => return Err(UrlParseError::InvalidAuthInfo),
+            },
+            None => return Err(UrlParseError::InvalidUserInfo),
+        };
+
+        // Password may contain '@', but host couldn't
+        let (account, addr) = match decoded.rfind('@') {
+            Some(pos) => (&decoded[..pos], &decoded[pos + 1..]),
+            None => return Err(UrlParseError::MissingHost),
+        };
+
+        let (method, pwd) = match account.find(':') {
+            Some(pos) => (&account[..pos], &account[pos + 1..]),
+            None => return Err(UrlParseError::InvalidAuthInfo),
+        };
+
+        if addr.is_empty() {
+            return Err(UrlParseError::MissingHost);
+        }
+        let addr = match addr.parse::<ServerAddr>() {
+            Ok(a) => a,
+            Err(..) => return Err(UrlParseError::InvalidServerAddr),
+        };
 
+        let svrconfig = ServerConfig::with_method_name(addr, pwd.to_owned(), method, None, None)?;
         Ok(svrconfig)
     }
+
+    fn from_ssconfig(svr: SSServerExtConfig, default_timeout: Option<u64>) -> Result<ServerConfig, Error> {
+        let address = svr.server;
+        let port = svr.server_port;
+
+        let addr = match address.parse::<Ipv4Addr>() {
+            Ok(v4) => ServerAddr::SocketAddr(SocketAddr::V4(SocketAddrV4::new(v4, port))),
+            Err(..) => match address.parse::<Ipv6Addr>() {
+                Ok(v6) => ServerAddr::SocketAddr(SocketAddr::V6(SocketAddrV6::new(v6, port, 0, 0))),
+                Err(..) => ServerAddr::DomainName(address, port),
+            },
+        };
+
+        let plugin = match svr.plugin {
+            None => None,
+            Some(p) => {
+                if p.is_empty() {
+                    // SIP008 allows "plugin" to be an empty string
+                    // Empty string implies "no plugin"
+                    None
+                } else {
+                    Some(PluginConfig {
+                        plugin: p,
+                        plugin_opts: svr.plugin_opts,
+                        plugin_args: svr.plugin_args.unwrap_or_default(),
+                    })
+                }
+            }
+        };
+
+        let timeout = svr.timeout.or(default_timeout).map(Duration::from_secs);
+        let mut nsvr = match ServerConfig::with_method_name(addr, svr.password, &svr.method, timeout, plugin) {
+            Ok(s) => s,
+            Err(err) => return Err(method_error(&svr.method, err)),
+        };
+
+        nsvr.remarks = svr.remarks;
+        nsvr.id = svr.id;
+
+        match svr.weight {
+            Some(0) => {
+                let err = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid `weight`",
+                    Some("`weight` must be greater than 0".to_owned()),
+                );
+                return Err(err);
+            }
+            Some(w) => nsvr.weight = w,
+            None => {}
+        }
+        if let Some(p) = svr.priority {
+            nsvr.priority = p;
+        }
+        if let Some(b) = svr.backup {
+            nsvr.backup = b;
+        }
+
+        Ok(nsvr)
+    }
+
+    fn to_ssconfig(&self) -> SSServerExtConfig {
+        SSServerExtConfig {
+            server: match self.addr {
+                ServerAddr::SocketAddr(ref sa) => sa.ip().to_string(),
+                ServerAddr::DomainName(ref dm, ..) => dm.to_string(),
+            },
+            server_port: match self.addr {
+                ServerAddr::SocketAddr(ref sa) => sa.port(),
+                ServerAddr::DomainName(.., port) => port,
+            },
+            password: self.password.clone(),
+            method: self.method_name(),
+            plugin: self.plugin.as_ref().map(|p| p.plugin.to_string()),
+            plugin_opts: self.plugin.as_ref().and_then(|p| p.plugin_opts.clone()),
+            plugin_args: self.plugin.as_ref().and_then(|p| {
+                if p.plugin_args.is_empty() {
+                    None
+                } else {
+                    Some(p.plugin_args.clone())
+                }
+            }),
+            timeout: self.timeout.map(|t| t.as_secs()),
+            remarks: self.remarks.clone(),
+            id: self.id.clone(),
+            weight: if self.weight != 1 { Some(self.weight) } else { None },
+            priority: if self.priority != 0 { Some(self.priority) } else { None },
+            backup: if self.backup { Some(true) } else { None },
+        }
+    }
 }
 
 impl FromStr for ServerConfig {
@@ -684,6 +1166,66 @@ cfg_if! {
     }
 }
 
+/// Characters percent-encoded in remarks, which is the fragment of URLs
+const URL_FRAGMENT_ENCODE_SET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'`');
+
+/// Decode base64 in URLs
+///
+/// It should be URL-safe without padding, but standard alphabet and padding are also used by some clients
+fn decode_url_base64(encoded: &str) -> Option<Vec<u8>> {
+    let encoded = encoded.trim_end_matches('=');
+    match decode_config(encoded, URL_SAFE_NO_PAD) {
+        Ok(d) => Some(d),
+        Err(..) => decode_config(encoded, STANDARD_NO_PAD).ok(),
+    }
+}
+
+/// Error of creating `ServerConfig` with name of the method
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MethodError {
+    /// Method is not supported
+    Unsupported,
+    /// Pre-shared key of AEAD-2022 method is not base64 encoded, or its length doesn't match the method
+    InvalidKey,
+}
+
+impl fmt::Display for MethodError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MethodError::Unsupported => write!(f, "unsupported method"),
+            MethodError::InvalidKey => write!(f, "invalid pre-shared key"),
+        }
+    }
+}
+
+impl error::Error for MethodError {}
+
+/// Configuration error of `method`, or `password` as the pre-shared key
+fn method_error(method: &str, err: MethodError) -> Error {
+    match err {
+        MethodError::Unsupported => Error::new(
+            ErrorKind::Invalid,
+            "unsupported method",
+            Some(format!("`{}` is not a supported method", method)),
+        ),
+        MethodError::InvalidKey => Error::new(
+            ErrorKind::Invalid,
+            "invalid `password`",
+            Some(format!(
+                "`password` of `{}` must be a base64 encoded pre-shared key of the method's key length",
+                method
+            )),
+        ),
+    }
+}
+
 /// Shadowsocks URL parsing Error
 #[derive(Debug, Clone)]
 pub enum UrlParseError {
@@ -692,10 +1234,20 @@ pub enum UrlParseError {
     InvalidUserInfo,
     MissingHost,
     InvalidAuthInfo,
+    UnsupportedMethod,
     InvalidServerAddr,
     InvalidQueryString,
 }
 
+impl From<MethodError> for UrlParseError {
+    fn from(err: MethodError) -> UrlParseError {
+        match err {
+            MethodError::Unsupported => UrlParseError::UnsupportedMethod,
+            MethodError::InvalidKey => UrlParseError::InvalidAuthInfo,
+        }
+    }
+}
+
 impl From<url::ParseError> for UrlParseError {
     fn from(err: url::ParseError) -> UrlParseError {
         UrlParseError::ParseError(err)
@@ -710,6 +1262,7 @@ impl fmt::Display for UrlParseError {
             UrlParseError::InvalidUserInfo => write!(f, "invalid user info"),
             UrlParseError::MissingHost => write!(f, "missing host"),
             UrlParseError::InvalidAuthInfo => write!(f, "invalid authentication info"),
+            UrlParseError::UnsupportedMethod => write!(f, "unsupported method"),
             UrlParseError::InvalidServerAddr => write!(f, "invalid server address"),
             UrlParseError::InvalidQueryString => write!(f, "invalid query string"),
         }
@@ -724,6 +1277,7 @@ impl error::Error for UrlParseError {
             UrlParseError::InvalidUserInfo => None,
             UrlParseError::MissingHost => None,
             UrlParseError::InvalidAuthInfo => None,
+            UrlParseError::UnsupportedMethod => None,
             UrlParseError::InvalidServerAddr => None,
             UrlParseError::InvalidQueryString => None,
         }
@@ -835,49 +1389,457 @@ impl ConfigType {
         }
     }
 
-    /// Check if it is manager server type
-    pub fn is_manager(self) -> bool {
-        matches!(self, ConfigType::Manager)
+    /// Check if it is manager server type
+    pub fn is_manager(self) -> bool {
+        matches!(self, ConfigType::Manager)
+    }
+}
+
+/// Server mode
+#[derive(Clone, Copy, Debug)]
+pub enum Mode {
+    TcpOnly,
+    TcpAndUdp,
+    UdpOnly,
+}
+
+impl Mode {
+    pub fn enable_udp(self) -> bool {
+        matches!(self, Mode::UdpOnly | Mode::TcpAndUdp)
+    }
+
+    pub fn enable_tcp(self) -> bool {
+        matches!(self, Mode::TcpOnly | Mode::TcpAndUdp)
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Mode::TcpOnly => f.write_str("tcp_only"),
+            Mode::TcpAndUdp => f.write_str("tcp_and_udp"),
+            Mode::UdpOnly => f.write_str("udp_only"),
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp_only" => Ok(Mode::TcpOnly),
+            "tcp_and_udp" => Ok(Mode::TcpAndUdp),
+            "udp_only" => Ok(Mode::UdpOnly),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Strategy of load balancers for choosing a server for each connection
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BalanceStrategy {
+    /// Server with the lowest latency score, checked periodically
+    LowestLatency,
+    /// Servers in turn
+    RoundRobin,
+    /// Random server, servers with lower latency scores are more likely to be chosen
+    WeightedRandom,
+    /// Server with the least active connections
+    LeastConnections,
+    /// Consistent hashing on the target host, connections to the same host use the same server
+    ConsistentHash,
+}
+
+impl Default for BalanceStrategy {
+    fn default() -> BalanceStrategy {
+        BalanceStrategy::LowestLatency
+    }
+}
+
+impl fmt::Display for BalanceStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BalanceStrategy::LowestLatency => f.write_str("lowest_latency"),
+            BalanceStrategy::RoundRobin => f.write_str("round_robin"),
+            BalanceStrategy::WeightedRandom => f.write_str("weighted_random"),
+            BalanceStrategy::LeastConnections => f.write_str("least_connections"),
+            BalanceStrategy::ConsistentHash => f.write_str("consistent_hash"),
+        }
+    }
+}
+
+impl FromStr for BalanceStrategy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lowest_latency" => Ok(BalanceStrategy::LowestLatency),
+            "round_robin" => Ok(BalanceStrategy::RoundRobin),
+            "weighted_random" => Ok(BalanceStrategy::WeightedRandom),
+            "least_connections" => Ok(BalanceStrategy::LeastConnections),
+            "consistent_hash" => Ok(BalanceStrategy::ConsistentHash),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Probe for checking health and latency of servers in load balancers
+#[derive(Clone, Debug, PartialEq)]
+pub enum HealthCheckProbe {
+    /// Send an HTTP GET request to `url` through the server, succeeded if the response has `expected_status`
+    ///
+    /// Only for TCP, `url` must be `http`
+    Http { url: Url, expected_status: u16 },
+    /// Connect to the address through the server
+    ///
+    /// Only for TCP. Connections are established lazily in shadowsocks, so this only checks the server itself
+    Tcp(Address),
+    /// Send a DNS query to the resolver through the server, in TCP or UDP as the server type
+    Dns(Address),
+    /// Do not check, servers are always considered healthy
+    Disabled,
+}
+
+impl HealthCheckProbe {
+    /// Check if probing is disabled
+    pub fn is_disabled(&self) -> bool {
+        matches!(self, HealthCheckProbe::Disabled)
+    }
+
+    fn from_ssconfig(probe: SSHealthCheckProbe, key: &str, is_tcp: bool) -> Result<HealthCheckProbe, Error> {
+        let parse_address = |address: Option<String>| match address.as_ref().map(|a| a.parse::<Address>()) {
+            Some(Ok(addr)) => Ok(addr),
+            _ => {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid `address` of health check probe",
+                    Some(format!("{}, {:?}", key, address)),
+                );
+                Err(e)
+            }
+        };
+
+        let probe = match (probe.probe_type.as_str(), is_tcp) {
+            ("http", true) => {
+                let url = match probe.url.as_ref().map(|u| Url::parse(u)) {
+                    Some(Ok(url)) if url.scheme() == "http" && url.host().is_some() => url,
+                    _ => {
+                        let e = Error::new(
+                            ErrorKind::Invalid,
+                            "invalid `url` of health check probe, must be an http URL",
+                            Some(format!("{}, {:?}", key, probe.url)),
+                        );
+                        return Err(e);
+                    }
+                };
+
+                HealthCheckProbe::Http {
+                    url,
+                    expected_status: probe.expected_status.unwrap_or(200),
+                }
+            }
+            ("tcp", true) => HealthCheckProbe::Tcp(parse_address(probe.address)?),
+            ("dns", _) => HealthCheckProbe::Dns(parse_address(probe.address)?),
+            ("disabled", _) => HealthCheckProbe::Disabled,
+            (t, _) => {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "invalid `type` of health check probe",
+                    Some(format!("{}, {}", key, t)),
+                );
+                return Err(e);
+            }
+        };
+
+        Ok(probe)
+    }
+
+    fn to_ssconfig(&self) -> SSHealthCheckProbe {
+        let mut probe = SSHealthCheckProbe {
+            probe_type: String::new(),
+            url: None,
+            expected_status: None,
+            address: None,
+        };
+
+        match *self {
+            HealthCheckProbe::Http {
+                ref url,
+                expected_status,
+            } => {
+                probe.probe_type = "http".to_owned();
+                probe.url = Some(url.to_string());
+                probe.expected_status = Some(expected_status);
+            }
+            HealthCheckProbe::Tcp(ref addr) => {
+                probe.probe_type = "tcp".to_owned();
+                probe.address = Some(addr.to_string());
+            }
+            HealthCheckProbe::Dns(ref addr) => {
+                probe.probe_type = "dns".to_owned();
+                probe.address = Some(addr.to_string());
+            }
+            HealthCheckProbe::Disabled => probe.probe_type = "disabled".to_owned(),
+        }
+
+        probe
+    }
+}
+
+/// Health checking of servers in load balancers
+#[derive(Clone, Debug, PartialEq)]
+pub struct HealthCheckConfig {
+    /// Probe for TCP servers, default is `http://detectportal.firefox.com/success.txt`
+    pub tcp_probe: HealthCheckProbe,
+    /// Probe for UDP servers, default is a DNS query to `8.8.8.8:53`
+    pub udp_probe: HealthCheckProbe,
+    /// Interval between probes, default is 6 seconds
+    pub interval: Duration,
+    /// Probes longer than this are counted as timeout, default is 2 seconds
+    pub timeout: Duration,
+    /// Server is considered down after this many consecutive failed probes, default is 1
+    pub failure_threshold: usize,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> HealthCheckConfig {
+        HealthCheckConfig {
+            tcp_probe: HealthCheckProbe::Http {
+                url: Url::parse("http://detectportal.firefox.com/success.txt").unwrap(),
+                expected_status: 200,
+            },
+            udp_probe: HealthCheckProbe::Dns(Address::SocketAddress(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)),
+                53,
+            ))),
+            interval: Duration::from_secs(6),
+            timeout: Duration::from_secs(2),
+            failure_threshold: 1,
+        }
+    }
+}
+
+impl HealthCheckConfig {
+    fn from_ssconfig(config: SSHealthCheckConfig) -> Result<HealthCheckConfig, Error> {
+        let mut health_check = HealthCheckConfig::default();
+
+        if let Some(probe) = config.tcp {
+            health_check.tcp_probe = HealthCheckProbe::from_ssconfig(probe, "health_check.tcp", true)?;
+        }
+        if let Some(probe) = config.udp {
+            health_check.udp_probe = HealthCheckProbe::from_ssconfig(probe, "health_check.udp", false)?;
+        }
+
+        if let Some(interval) = config.interval {
+            if interval == 0 {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "`health_check.interval` must be greater than 0",
+                    None,
+                );
+                return Err(e);
+            }
+            health_check.interval = Duration::from_secs(interval);
+        }
+        if let Some(timeout) = config.timeout {
+            if timeout == 0 {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "`health_check.timeout` must be greater than 0",
+                    None,
+                );
+                return Err(e);
+            }
+            health_check.timeout = Duration::from_secs(timeout);
+        }
+        if let Some(threshold) = config.failure_threshold {
+            if threshold == 0 {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "`health_check.failure_threshold` must be greater than 0",
+                    None,
+                );
+                return Err(e);
+            }
+            health_check.failure_threshold = threshold;
+        }
+
+        Ok(health_check)
+    }
+
+    fn to_ssconfig(&self) -> SSHealthCheckConfig {
+        SSHealthCheckConfig {
+            tcp: Some(self.tcp_probe.to_ssconfig()),
+            udp: Some(self.udp_probe.to_ssconfig()),
+            interval: Some(self.interval.as_secs()),
+            timeout: Some(self.timeout.as_secs()),
+            failure_threshold: Some(self.failure_threshold),
+        }
     }
 }
 
-/// Server mode
-#[derive(Clone, Copy, Debug)]
-pub enum Mode {
-    TcpOnly,
-    TcpAndUdp,
-    UdpOnly,
+/// Default interval of fetching SIP008 online configuration, see `OnlineConfig::update_interval`
+const DEFAULT_ONLINE_CONFIG_UPDATE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// [SIP008](https://github.com/shadowsocks/shadowsocks-org/issues/89) online configuration
+///
+/// Servers in the fetched document are used by load balancers together with servers in `Config::server`
+#[derive(Clone, Debug, PartialEq)]
+pub struct OnlineConfig {
+    /// URL of the document, `http`, `https` and `file` schemes are supported
+    pub url: Url,
+    /// Interval of fetching the document again, default is 1 hour
+    pub update_interval: Duration,
+    /// Path for caching the last fetched document, which is used if `url` is unreachable when starting
+    pub cache_path: Option<PathBuf>,
 }
 
-impl Mode {
-    pub fn enable_udp(self) -> bool {
-        matches!(self, Mode::UdpOnly | Mode::TcpAndUdp)
+impl OnlineConfig {
+    /// Create an `OnlineConfig` with default options
+    pub fn new(url: Url) -> OnlineConfig {
+        OnlineConfig {
+            url,
+            update_interval: DEFAULT_ONLINE_CONFIG_UPDATE_INTERVAL,
+            cache_path: None,
+        }
     }
 
-    pub fn enable_tcp(self) -> bool {
-        matches!(self, Mode::TcpOnly | Mode::TcpAndUdp)
+    fn from_ssconfig(config: SSOnlineConfig) -> Result<OnlineConfig, Error> {
+        let url = match Url::parse(&config.url) {
+            Ok(u) => u,
+            Err(err) => {
+                let e = Error::new(ErrorKind::Invalid, "invalid `online_config.url`", Some(err.to_string()));
+                return Err(e);
+            }
+        };
+
+        if !matches!(url.scheme(), "http" | "https" | "file") {
+            let e = Error::new(
+                ErrorKind::Invalid,
+                "invalid `online_config.url`",
+                Some(format!("unsupported scheme `{}`", url.scheme())),
+            );
+            return Err(e);
+        }
+
+        let mut online_config = OnlineConfig::new(url);
+
+        if let Some(interval) = config.update_interval {
+            if interval == 0 {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "`online_config.update_interval` must be greater than 0",
+                    None,
+                );
+                return Err(e);
+            }
+            online_config.update_interval = Duration::from_secs(interval);
+        }
+        online_config.cache_path = config.cache_path.map(PathBuf::from);
+
+        Ok(online_config)
+    }
+
+    fn to_ssconfig(&self) -> SSOnlineConfig {
+        let update_interval = if self.update_interval != DEFAULT_ONLINE_CONFIG_UPDATE_INTERVAL {
+            Some(self.update_interval.as_secs())
+        } else {
+            None
+        };
+
+        SSOnlineConfig {
+            url: self.url.to_string(),
+            update_interval,
+            cache_path: self.cache_path.as_ref().map(|p| p.to_string_lossy().into_owned()),
+        }
     }
 }
 
-impl fmt::Display for Mode {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            Mode::TcpOnly => f.write_str("tcp_only"),
-            Mode::TcpAndUdp => f.write_str("tcp_and_udp"),
-            Mode::UdpOnly => f.write_str("udp_only"),
+/// Default interval of saving snapshots of the replay filter, see `ReplayFilterConfig::snapshot_interval`
+const DEFAULT_REPLAY_FILTER_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Filter of salts (IVs) that have been seen, for detecting replay attacks
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplayFilterConfig {
+    /// Number of salts remembered, default is 1,000,000 for servers and 10,000 for local servers
+    pub entries: Option<usize>,
+    /// False positive rate of the filter, default is 1e-6 for servers and 1e-15 for local servers
+    pub error_rate: Option<f64>,
+    /// File for saving snapshots of the filter, which is loaded when starting
+    ///
+    /// Only for servers. Snapshots are saved periodically and when shutting down gracefully,
+    /// so salts captured before restarting couldn't be replayed. Servers of a manager append their ports to it.
+    pub snapshot_path: Option<PathBuf>,
+    /// Interval of saving snapshots to `snapshot_path`, default is 10 minutes
+    pub snapshot_interval: Duration,
+}
+
+impl Default for ReplayFilterConfig {
+    fn default() -> ReplayFilterConfig {
+        ReplayFilterConfig {
+            entries: None,
+            error_rate: None,
+            snapshot_path: None,
+            snapshot_interval: DEFAULT_REPLAY_FILTER_SNAPSHOT_INTERVAL,
         }
     }
 }
 
-impl FromStr for Mode {
-    type Err = ();
+impl ReplayFilterConfig {
+    fn from_ssconfig(config: SSReplayFilterConfig) -> Result<ReplayFilterConfig, Error> {
+        let mut replay_filter = ReplayFilterConfig::default();
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "tcp_only" => Ok(Mode::TcpOnly),
-            "tcp_and_udp" => Ok(Mode::TcpAndUdp),
-            "udp_only" => Ok(Mode::UdpOnly),
-            _ => Err(()),
+        if let Some(entries) = config.entries {
+            if entries == 0 {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "`replay_filter.entries` must be greater than 0",
+                    None,
+                );
+                return Err(e);
+            }
+            replay_filter.entries = Some(entries);
+        }
+        if let Some(error_rate) = config.error_rate {
+            if error_rate <= 0.0 || error_rate >= 1.0 {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "`replay_filter.error_rate` must be between 0 and 1",
+                    Some(error_rate.to_string()),
+                );
+                return Err(e);
+            }
+            replay_filter.error_rate = Some(error_rate);
+        }
+        replay_filter.snapshot_path = config.snapshot_path.map(PathBuf::from);
+        if let Some(interval) = config.snapshot_interval {
+            if interval == 0 {
+                let e = Error::new(
+                    ErrorKind::Invalid,
+                    "`replay_filter.snapshot_interval` must be greater than 0",
+                    None,
+                );
+                return Err(e);
+            }
+            replay_filter.snapshot_interval = Duration::from_secs(interval);
+        }
+
+        Ok(replay_filter)
+    }
+
+    fn to_ssconfig(&self) -> SSReplayFilterConfig {
+        let snapshot_interval = if self.snapshot_interval != DEFAULT_REPLAY_FILTER_SNAPSHOT_INTERVAL {
+            Some(self.snapshot_interval.as_secs())
+        } else {
+            None
+        };
+
+        SSReplayFilterConfig {
+            entries: self.entries,
+            error_rate: self.error_rate,
+            snapshot_path: self.snapshot_path.as_ref().map(|p| p.to_string_lossy().into_owned()),
+            snapshot_interval,
         }
     }
 }
@@ -1122,6 +2084,24 @@ pub struct ManagerConfig {
     ///
     /// Note: Outbound address is defined in Config.local_addr
     pub server_host: ManagerServerHost,
+    /// Address of statistic collector. Manager sends servers' statistic data to it periodically
+    pub stat_addr: Option<ManagerAddr>,
+    /// Interval of sending statistic data to `stat_addr`
+    pub stat_interval: Option<Duration>,
+    /// `stat` command and reports sent to `stat_addr` only count traffic since their own last reads
+    pub stat_reset: bool,
+    /// Path to the file for persisting servers created by `add` command
+    ///
+    /// Servers in this file will be created again when manager is restarted
+    pub state_file: Option<PathBuf>,
+    /// Address of HTTP API
+    #[cfg(feature = "manager-http")]
+    pub http_addr: Option<SocketAddr>,
+    /// Token required in `Authorization: Bearer <token>` header of HTTP API requests
+    ///
+    /// HTTP API can only listen on loopback addresses without a token
+    #[cfg(feature = "manager-http")]
+    pub http_token: Option<String>,
 }
 
 impl ManagerConfig {
@@ -1132,6 +2112,14 @@ impl ManagerConfig {
             method: None,
             timeout: None,
             server_host: ManagerServerHost::default(),
+            stat_addr: None,
+            stat_interval: None,
+            stat_reset: false,
+            state_file: None,
+            #[cfg(feature = "manager-http")]
+            http_addr: None,
+            #[cfg(feature = "manager-http")]
+            http_token: None,
         }
     }
 
@@ -1141,6 +2129,9 @@ impl ManagerConfig {
     }
 }
 
+/// Default times of retrying with other servers, see `Config::failover_retries`
+const DEFAULT_FAILOVER_RETRIES: usize = 1;
+
 /// Configuration
 #[derive(Clone, Debug)]
 pub struct Config {
@@ -1226,6 +2217,33 @@ pub struct Config {
     /// TLS cryptographic identity, private keys (PEM), RSA or PKCS #8
     #[cfg(feature = "local-http-rustls")]
     pub tls_identity_private_key_path: Option<PathBuf>,
+    /// Time for waiting in-flight connections to finish when shutting down, default is 30 seconds
+    pub shutdown_timeout: Option<Duration>,
+    /// Strategy of choosing a server from `server` for each connection
+    pub balance_strategy: BalanceStrategy,
+    /// Health checking of servers in load balancers
+    pub health_check: HealthCheckConfig,
+    /// Times of retrying with other servers if failed to connect to the chosen server, default is 1
+    ///
+    /// TCP connections are retried before replying to clients, UDP associations are bound to another server
+    pub failover_retries: usize,
+    /// SIP008 online configuration, servers are fetched periodically and reloaded while running
+    pub online_config: Option<OnlineConfig>,
+    /// Filter of salts for detecting replay attacks
+    pub replay_filter: ReplayFilterConfig,
+    /// Path to the MaxMind GeoIP database (`.mmdb`) for `geoip:` rules in ACL files
+    pub geoip_db: Option<PathBuf>,
+    /// Named groups of servers for `[route:NAME]` sections in ACL files
+    ///
+    /// Members are `id`s or `remarks` of servers
+    pub groups: BTreeMap<String, Vec<String>>,
+    /// Users allowed to use local servers, authentication is disabled if it is `None`
+    ///
+    /// Supported by HTTP and SOCKS5 local servers
+    pub local_auth: Option<LocalAuth>,
+    /// Address of HTTP server exporting Prometheus metrics
+    #[cfg(feature = "metrics")]
+    pub metrics_addr: Option<SocketAddr>,
 }
 
 /// Configuration parsing error kind
@@ -1331,6 +2349,17 @@ impl Config {
             tls_identity_certificate_path: None,
             #[cfg(feature = "local-http-rustls")]
             tls_identity_private_key_path: None,
+            shutdown_timeout: None,
+            balance_strategy: BalanceStrategy::default(),
+            health_check: HealthCheckConfig::default(),
+            failover_retries: DEFAULT_FAILOVER_RETRIES,
+            online_config: None,
+            replay_filter: ReplayFilterConfig::default(),
+            geoip_db: None,
+            groups: BTreeMap::new(),
+            local_auth: None,
+            #[cfg(feature = "metrics")]
+            metrics_addr: None,
         }
     }
 
@@ -1402,18 +2431,6 @@ impl Config {
                     },
                 };
 
-                let method = match m.parse::<CipherKind>() {
-                    Ok(m) => m,
-                    Err(..) => {
-                        let err = Error::new(
-                            ErrorKind::Invalid,
-                            "unsupported method",
-                            Some(format!("`{}` is not a supported method", m)),
-                        );
-                        return Err(err);
-                    }
-                };
-
                 let plugin = match config.plugin {
                     None => None,
                     Some(p) => {
@@ -1432,7 +2449,10 @@ impl Config {
                 };
 
                 let timeout = config.timeout.map(Duration::from_secs);
-                let nsvr = ServerConfig::new(addr, pwd, method, timeout, plugin);
+                let nsvr = match ServerConfig::with_method_name(addr, pwd, &m, timeout, plugin) {
+                    Ok(s) => s,
+                    Err(err) => return Err(method_error(&m, err)),
+                };
 
                 nconfig.server.push(nsvr);
             }
@@ -1450,52 +2470,7 @@ impl Config {
         // Ext servers
         if let Some(servers) = config.servers {
             for svr in servers {
-                let address = svr.server;
-                let port = svr.server_port;
-
-                let addr = match address.parse::<Ipv4Addr>() {
-                    Ok(v4) => ServerAddr::SocketAddr(SocketAddr::V4(SocketAddrV4::new(v4, port))),
-                    Err(..) => match address.parse::<Ipv6Addr>() {
-                        Ok(v6) => ServerAddr::SocketAddr(SocketAddr::V6(SocketAddrV6::new(v6, port, 0, 0))),
-                        Err(..) => ServerAddr::DomainName(address, port),
-                    },
-                };
-
-                let method = match svr.method.parse::<CipherKind>() {
-                    Ok(m) => m,
-                    Err(..) => {
-                        let err = Error::new(
-                            ErrorKind::Invalid,
-                            "unsupported method",
-                            Some(format!("`{}` is not a supported method", svr.method)),
-                        );
-                        return Err(err);
-                    }
-                };
-
-                let plugin = match svr.plugin {
-                    None => None,
-                    Some(p) => {
-                        if p.is_empty() {
-                            // SIP008 allows "plugin" to be an empty string
-                            // Empty string implies "no plugin"
-                            None
-                        } else {
-                            Some(PluginConfig {
-                                plugin: p,
-                                plugin_opts: svr.plugin_opts,
-                                plugin_args: svr.plugin_args.unwrap_or_default(),
-                            })
-                        }
-                    }
-                };
-
-                let timeout = svr.timeout.or(config.timeout).map(Duration::from_secs);
-                let mut nsvr = ServerConfig::new(addr, svr.password, method, timeout, plugin);
-
-                nsvr.remarks = svr.remarks;
-                nsvr.id = svr.id;
-
+                let nsvr = ServerConfig::from_ssconfig(svr, config.timeout)?;
                 nconfig.server.push(nsvr);
             }
         }
@@ -1532,7 +2507,36 @@ impl Config {
                 }
             };
 
-            let manager_config = ManagerConfig::new(manager);
+            let mut manager_config = ManagerConfig::new(manager);
+
+            if let Some(sa) = config.manager_stat_address {
+                manager_config.stat_addr = match sa.parse::<ManagerAddr>() {
+                    Ok(addr) => Some(addr),
+                    Err(..) => {
+                        let e = Error::new(ErrorKind::Invalid, "invalid `manager_stat_address`", Some(sa));
+                        return Err(e);
+                    }
+                };
+            }
+            manager_config.stat_interval = config.manager_stat_interval.map(Duration::from_secs);
+            manager_config.stat_reset = config.manager_stat_reset.unwrap_or(false);
+            manager_config.state_file = config.manager_state_file.map(PathBuf::from);
+
+            #[cfg(feature = "manager-http")]
+            if let Some(ha) = config.manager_http_address {
+                manager_config.http_addr = match ha.parse::<SocketAddr>() {
+                    Ok(addr) => Some(addr),
+                    Err(..) => {
+                        let e = Error::new(ErrorKind::Invalid, "invalid `manager_http_address`", Some(ha));
+                        return Err(e);
+                    }
+                };
+            }
+            #[cfg(feature = "manager-http")]
+            {
+                manager_config.http_token = config.manager_http_token;
+            }
+
             nconfig.manager = Some(manager_config);
         }
 
@@ -1644,6 +2648,76 @@ impl Config {
             nconfig.ipv6_first = f;
         }
 
+        // Graceful shutdown
+        nconfig.shutdown_timeout = config.shutdown_timeout.map(Duration::from_secs);
+
+        // Load balancing
+        if let Some(bs) = config.balance_strategy {
+            match bs.parse::<BalanceStrategy>() {
+                Ok(b) => nconfig.balance_strategy = b,
+                Err(..) => {
+                    let e = Error::new(ErrorKind::Invalid, "invalid `balance_strategy`", Some(bs));
+                    return Err(e);
+                }
+            }
+        }
+        if let Some(hc) = config.health_check {
+            nconfig.health_check = HealthCheckConfig::from_ssconfig(hc)?;
+        }
+        if let Some(r) = config.failover_retries {
+            nconfig.failover_retries = r;
+        }
+        if let Some(oc) = config.online_config {
+            nconfig.online_config = Some(OnlineConfig::from_ssconfig(oc)?);
+        }
+        if let Some(rf) = config.replay_filter {
+            nconfig.replay_filter = ReplayFilterConfig::from_ssconfig(rf)?;
+        }
+
+        // GeoIP database for ACL, ACL files are loaded by the caller
+        nconfig.geoip_db = config.geoip_db.map(PathBuf::from);
+        if let Some(groups) = config.groups {
+            nconfig.groups = groups;
+        }
+
+        // Local users, both from configuration and file are allowed
+        if config.local_users.is_some() || config.local_users_file.is_some() {
+            let mut auth = match config.local_users_file {
+                Some(path) => match LocalAuth::load_from_file(&path) {
+                    Ok(a) => a,
+                    Err(err) => {
+                        let e = Error::new(
+                            ErrorKind::Invalid,
+                            "invalid `local_users_file`",
+                            Some(format!("{}, {}", path, err)),
+                        );
+                        return Err(e);
+                    }
+                },
+                None => LocalAuth::new(),
+            };
+
+            if let Some(users) = config.local_users {
+                for user in users {
+                    auth.add_user(&user.username, &user.password);
+                }
+            }
+
+            nconfig.local_auth = Some(auth);
+        }
+
+        // Prometheus metrics
+        #[cfg(feature = "metrics")]
+        if let Some(ma) = config.metrics_address {
+            nconfig.metrics_addr = match ma.parse::<SocketAddr>() {
+                Ok(addr) => Some(addr),
+                Err(..) => {
+                    let e = Error::new(ErrorKind::Invalid, "invalid `metrics_address`", Some(ma));
+                    return Err(e);
+                }
+            };
+        }
+
         Ok(nconfig)
     }
 
@@ -1661,6 +2735,35 @@ impl Config {
         Config::load_from_str(&content[..], config_type)
     }
 
+    /// Load servers from a [SIP008](https://github.com/shadowsocks/shadowsocks-org/issues/89) document
+    pub fn load_servers_from_sip008(s: &str) -> Result<Vec<ServerConfig>, Error> {
+        let c = json5::from_str::<SIP008Config>(s)?;
+
+        if c.version != 1 {
+            let e = Error::new(
+                ErrorKind::Invalid,
+                "unsupported SIP008 `version`",
+                Some(format!("version {} is not supported", c.version)),
+            );
+            return Err(e);
+        }
+
+        let mut servers = Vec::with_capacity(c.servers.len());
+        for svr in c.servers {
+            servers.push(ServerConfig::from_ssconfig(svr, None)?);
+        }
+        Ok(servers)
+    }
+
+    /// Dump servers into a [SIP008](https://github.com/shadowsocks/shadowsocks-org/issues/89) document
+    pub fn servers_to_sip008(servers: &[ServerConfig]) -> String {
+        let c = SIP008Config {
+            version: 1,
+            servers: servers.iter().map(ServerConfig::to_ssconfig).collect(),
+        };
+        serde_json::to_string_pretty(&c).unwrap()
+    }
+
     #[cfg(feature = "trust-dns")]
     /// Get `trust-dns`'s `ResolverConfig` by DNS configuration string
     pub(crate) fn get_dns_config(&self) -> Option<ResolverConfig> {
@@ -1677,6 +2780,38 @@ impl Config {
         false
     }
 
+    /// Group servers' indexes by their listening addresses
+    ///
+    /// Servers sharing the same address are different users of one server,
+    /// users are identified by their keys
+    pub fn server_groups(&self) -> Vec<Vec<usize>> {
+        let mut groups: Vec<(String, Vec<usize>)> = Vec::new();
+        for (idx, svr_cfg) in self.server.iter().enumerate() {
+            let addr = svr_cfg.addr().to_string();
+            match groups.iter_mut().find(|(a, _)| *a == addr) {
+                Some((_, g)) => g.push(idx),
+                None => groups.push((addr, vec![idx])),
+            }
+        }
+        groups.into_iter().map(|(_, g)| g).collect()
+    }
+
+    /// Indexes of servers in the group `name`, `None` if the group is not configured
+    pub fn servers_in_group(&self, name: &str) -> Option<Vec<usize>> {
+        let members = self.groups.get(name)?;
+
+        let mut idxs = Vec::new();
+        for (idx, svr_cfg) in self.server.iter().enumerate() {
+            let is_member = members
+                .iter()
+                .any(|m| svr_cfg.id() == Some(m.as_str()) || svr_cfg.remarks() == Some(m.as_str()));
+            if is_member {
+                idxs.push(idx);
+            }
+        }
+        Some(idxs)
+    }
+
     /// Check if all required fields are already set
     pub fn check_integrity(&self) -> Result<(), Error> {
         if self.config_type.is_local() {
@@ -1701,7 +2836,8 @@ impl Config {
                 }
             }
 
-            if self.server.is_empty() {
+            // Servers could be fetched from online configuration when starting
+            if self.server.is_empty() && self.online_config.is_none() {
                 let err = Error::new(
                     ErrorKind::MissingField,
                     "missing `servers` for client configuration",
@@ -1709,6 +2845,32 @@ impl Config {
                 );
                 return Err(err);
             }
+
+            // Nobody could pass the authentication
+            if let Some(ref auth) = self.local_auth {
+                if auth.is_empty() {
+                    let err = Error::new(
+                        ErrorKind::Invalid,
+                        "`local_users` and `local_users_file` don't have any user",
+                        None,
+                    );
+                    return Err(err);
+                }
+            }
+
+            // Targets routed to unknown groups couldn't be proxied
+            if let Some(ref acl) = self.acl {
+                for name in acl.route_groups() {
+                    if !self.groups.contains_key(name) {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "ACL routes to a group that is not in `groups`",
+                            Some(format!("group {}", name)),
+                        );
+                        return Err(err);
+                    }
+                }
+            }
         }
 
         if self.config_type.is_server() {
@@ -1731,6 +2893,45 @@ impl Config {
                     return Err(err);
                 }
             }
+
+            // Multiple users sharing the same port
+            for group in self.server_groups() {
+                if group.len() <= 1 {
+                    continue;
+                }
+
+                let method = self.server[group[0]].method();
+                for idx in group {
+                    let svr_cfg = &self.server[idx];
+                    if svr_cfg.method() != method || method.category() != CipherCategory::Aead {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "servers sharing the same address must use the same AEAD method",
+                            Some(format!("server {}", svr_cfg.addr())),
+                        );
+                        return Err(err);
+                    }
+
+                    // Identifying users is only implemented for the original AEAD protocol
+                    if svr_cfg.is_aead_2022() {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "servers sharing the same address cannot use AEAD-2022 methods",
+                            Some(format!("server {}", svr_cfg.addr())),
+                        );
+                        return Err(err);
+                    }
+
+                    if svr_cfg.plugin().is_some() {
+                        let err = Error::new(
+                            ErrorKind::Invalid,
+                            "servers sharing the same address cannot have plugins",
+                            Some(format!("server {}", svr_cfg.addr())),
+                        );
+                        return Err(err);
+                    }
+                }
+            }
         }
 
         if self.config_type.is_manager() {
@@ -1753,6 +2954,34 @@ impl Config {
                     return Err(err);
                 }
             }
+
+            #[cfg(feature = "manager-http")]
+            if let Some(ManagerConfig {
+                http_addr: Some(ref addr),
+                ref http_token,
+                ..
+            }) = self.manager
+            {
+                match *http_token {
+                    Some(ref token) if token.is_empty() => {
+                        let err = Error::new(
+                            ErrorKind::Malformed,
+                            "`manager_http_token` shouldn't be an empty string",
+                            None,
+                        );
+                        return Err(err);
+                    }
+                    None if !addr.ip().is_loopback() => {
+                        let err = Error::new(
+                            ErrorKind::MissingField,
+                            "`manager_http_token` is required for HTTP API listening on non-loopback address",
+                            Some(addr.to_string()),
+                        );
+                        return Err(err);
+                    }
+                    _ => {}
+                }
+            }
         }
 
         for server in &self.server {
@@ -1893,7 +3122,10 @@ impl fmt::Display for Config {
         // For 1 servers, uses standard configure format
         match self.server.len() {
             0 => {}
-            1 if self.server[0].id().is_none() && self.server[0].remarks.is_none() => {
+            1 if self.server[0].id().is_none()
+                && self.server[0].remarks.is_none()
+                && self.server[0].is_default_balanced() =>
+            {
                 let svr = &self.server[0];
 
                 jconf.server = Some(match *svr.addr() {
@@ -1904,7 +3136,7 @@ impl fmt::Display for Config {
                     ServerAddr::SocketAddr(ref sa) => sa.port(),
                     ServerAddr::DomainName(.., port) => port,
                 });
-                jconf.method = Some(svr.method().to_string());
+                jconf.method = Some(svr.method_name());
                 jconf.password = Some(svr.password().to_string());
                 jconf.plugin = svr.plugin().map(|p| p.plugin.to_string());
                 jconf.plugin_opts = svr.plugin().and_then(|p| p.plugin_opts.clone());
@@ -1921,30 +3153,7 @@ impl fmt::Display for Config {
                 let mut vsvr = Vec::new();
 
                 for svr in &self.server {
-                    vsvr.push(SSServerExtConfig {
-                        server: match *svr.addr() {
-                            ServerAddr::SocketAddr(ref sa) => sa.ip().to_string(),
-                            ServerAddr::DomainName(ref dm, ..) => dm.to_string(),
-                        },
-                        server_port: match *svr.addr() {
-                            ServerAddr::SocketAddr(ref sa) => sa.port(),
-                            ServerAddr::DomainName(.., port) => port,
-                        },
-                        password: svr.password().to_string(),
-                        method: svr.method().to_string(),
-                        plugin: svr.plugin().map(|p| p.plugin.to_string()),
-                        plugin_opts: svr.plugin().and_then(|p| p.plugin_opts.clone()),
-                        plugin_args: svr.plugin().and_then(|p| {
-                            if p.plugin_args.is_empty() {
-                                None
-                            } else {
-                                Some(p.plugin_args.clone())
-                            }
-                        }),
-                        timeout: svr.timeout().map(|t| t.as_secs()),
-                        remarks: svr.remarks.clone(),
-                        id: svr.id.clone(),
-                    });
+                    vsvr.push(svr.to_ssconfig());
                 }
 
                 jconf.servers = Some(vsvr);
@@ -1965,6 +3174,18 @@ impl fmt::Display for Config {
                 #[cfg(unix)]
                 ManagerAddr::UnixSocketAddr(..) => None,
             };
+
+            jconf.manager_stat_address = m.stat_addr.as_ref().map(ToString::to_string);
+            jconf.manager_stat_interval = m.stat_interval.map(|t| t.as_secs());
+            if m.stat_reset {
+                jconf.manager_stat_reset = Some(m.stat_reset);
+            }
+            jconf.manager_state_file = m.state_file.as_ref().map(|p| p.display().to_string());
+            #[cfg(feature = "manager-http")]
+            {
+                jconf.manager_http_address = m.http_addr.as_ref().map(ToString::to_string);
+                jconf.manager_http_token = m.http_token.clone();
+            }
         }
 
         jconf.mode = Some(self.mode.to_string());
@@ -1988,6 +3209,44 @@ impl fmt::Display for Config {
             jconf.ipv6_first = Some(self.ipv6_first);
         }
 
+        jconf.shutdown_timeout = self.shutdown_timeout.map(|t| t.as_secs());
+
+        if self.balance_strategy != BalanceStrategy::default() {
+            jconf.balance_strategy = Some(self.balance_strategy.to_string());
+        }
+        if self.health_check != HealthCheckConfig::default() {
+            jconf.health_check = Some(self.health_check.to_ssconfig());
+        }
+        if self.failover_retries != DEFAULT_FAILOVER_RETRIES {
+            jconf.failover_retries = Some(self.failover_retries);
+        }
+        jconf.online_config = self.online_config.as_ref().map(OnlineConfig::to_ssconfig);
+        if self.replay_filter != ReplayFilterConfig::default() {
+            jconf.replay_filter = Some(self.replay_filter.to_ssconfig());
+        }
+        jconf.geoip_db = self.geoip_db.as_ref().map(|p| p.to_string_lossy().into_owned());
+        if !self.groups.is_empty() {
+            jconf.groups = Some(self.groups.clone());
+        }
+
+        // Passwords of local users are redacted, they shouldn't be found in logs
+        if let Some(ref auth) = self.local_auth {
+            let mut users = auth
+                .users()
+                .map(|(u, _)| SSLocalUserConfig {
+                    username: u.to_owned(),
+                    password: REDACTED_PASSWORD.to_owned(),
+                })
+                .collect::<Vec<_>>();
+            users.sort_by(|a, b| a.username.cmp(&b.username));
+            jconf.local_users = Some(users);
+        }
+
+        #[cfg(feature = "metrics")]
+        {
+            jconf.metrics_address = self.metrics_addr.as_ref().map(ToString::to_string);
+        }
+
         write!(f, "{}", json5::to_string(&jconf).unwrap())
     }
 }
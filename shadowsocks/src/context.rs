@@ -1,12 +1,14 @@
 //! Shadowsocks Server Context
 
-#[cfg(feature = "local-dns")]
-use std::net::IpAddr;
 #[cfg(feature = "local-dns")]
 use std::time::Duration;
 use std::{
-    io,
-    net::SocketAddr,
+    collections::HashMap,
+    ffi::OsString,
+    fs,
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    path::Path,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -14,10 +16,12 @@ use std::{
 };
 
 use bloomfilter::Bloom;
-use log::{log_enabled, warn};
-#[cfg(feature = "local-dns")]
+use bytes::{Buf, BufMut};
+use log::{debug, info, log_enabled, warn};
+#[cfg(any(feature = "local-dns", feature = "aead-cipher-2022"))]
 use lru_time_cache::LruCache;
 use spin::Mutex as SpinMutex;
+use tokio::sync::watch;
 #[cfg(feature = "local-dns")]
 use tokio::sync::Mutex as AsyncMutex;
 #[cfg(feature = "trust-dns")]
@@ -29,11 +33,15 @@ use crate::relay::dns_resolver::create_resolver;
 use crate::relay::dnsrelay::upstream::LocalUpstream;
 #[cfg(feature = "local-flow-stat")]
 use crate::relay::flow::ServerFlowStatistic;
+#[cfg(feature = "metrics")]
+use crate::relay::metrics::{Metrics, SharedMetrics};
+#[cfg(feature = "aead-cipher-2022")]
+use crate::relay::{aead_2022::SALT_EXPIRY_DURATION, udprelay::aead_2022::PeerSessions};
 use crate::{
-    acl::AccessControl,
-    config::{Config, ConfigType, ServerConfig},
+    acl::{AccessControl, Protocol, Route},
+    config::{Config, ServerConfig},
     crypto::v1::CipherKind,
-    relay::{dns_resolver::resolve, socks5::Address},
+    relay::{control::ConnectionTracker, dns_resolver::resolve, socks5::Address},
 };
 
 // Entries for server's bloom filter
@@ -56,6 +64,13 @@ const BF_ERROR_RATE_FOR_SERVER: f64 = 1e-6;
 // Borrowed from shadowsocks-libev's default value
 const BF_ERROR_RATE_FOR_CLIENT: f64 = 1e-15;
 
+// Sessions of AEAD-2022 UDP clients remembered by servers
+#[cfg(feature = "aead-cipher-2022")]
+const AEAD_2022_CLIENT_SESSIONS_CAPACITY: usize = 65_536;
+
+// Leading bytes of replay filter snapshots, with the format version
+const BF_SNAPSHOT_MAGIC: &[u8] = b"SSPPBF01";
+
 // A bloom filter borrowed from shadowsocks-libev's `ppbloom`
 //
 // It contains 2 bloom filters and each one holds 1/2 entries.
@@ -68,14 +83,8 @@ struct PingPongBloom {
 }
 
 impl PingPongBloom {
-    fn new(ty: ConfigType) -> PingPongBloom {
-        let (mut item_count, fp_p) = if ty.is_local() {
-            (BF_NUM_ENTRIES_FOR_CLIENT, BF_ERROR_RATE_FOR_CLIENT)
-        } else {
-            (BF_NUM_ENTRIES_FOR_SERVER, BF_ERROR_RATE_FOR_SERVER)
-        };
-
-        item_count /= 2;
+    fn new(config: &Config) -> PingPongBloom {
+        let (item_count, fp_p) = PingPongBloom::parameters(config);
 
         PingPongBloom {
             blooms: [
@@ -88,6 +97,155 @@ impl PingPongBloom {
         }
     }
 
+    // Entries of each bloom filter and the error rate, configured by `config.replay_filter`
+    fn parameters(config: &Config) -> (usize, f64) {
+        let (item_count, fp_p) = if config.config_type.is_local() {
+            (BF_NUM_ENTRIES_FOR_CLIENT, BF_ERROR_RATE_FOR_CLIENT)
+        } else {
+            (BF_NUM_ENTRIES_FOR_SERVER, BF_ERROR_RATE_FOR_SERVER)
+        };
+
+        let item_count = config.replay_filter.entries.unwrap_or(item_count);
+        let fp_p = config.replay_filter.error_rate.unwrap_or(fp_p);
+
+        ((item_count / 2).max(1), fp_p)
+    }
+
+    // Load filters from a snapshot saved by `to_snapshot`
+    //
+    // Snapshots of filters with different parameters are rejected, they couldn't be resized.
+    fn from_snapshot(config: &Config, mut snapshot: &[u8]) -> io::Result<PingPongBloom> {
+        let invalid = |desc: &str| io::Error::new(ErrorKind::InvalidData, desc.to_owned());
+
+        if !snapshot.starts_with(BF_SNAPSHOT_MAGIC) {
+            return Err(invalid("not a replay filter snapshot"));
+        }
+        snapshot.advance(BF_SNAPSHOT_MAGIC.len());
+
+        let (item_count, fp_p) = PingPongBloom::parameters(config);
+        let expected = Bloom::<[u8]>::new_for_fp_rate(item_count, fp_p);
+
+        if snapshot.remaining() < 8 + 1 {
+            return Err(invalid("snapshot is truncated"));
+        }
+        if snapshot.get_u64() != item_count as u64 {
+            return Err(invalid("snapshot has a different number of entries"));
+        }
+        let current = snapshot.get_u8() as usize;
+        if current >= 2 {
+            return Err(invalid("snapshot is corrupted"));
+        }
+
+        let mut blooms = Vec::with_capacity(2);
+        let mut bloom_count = [0; 2];
+        for count in bloom_count.iter_mut() {
+            if snapshot.remaining() < 8 + 8 + 4 + 4 * 8 + 8 {
+                return Err(invalid("snapshot is truncated"));
+            }
+
+            *count = snapshot.get_u64() as usize;
+            let bits = snapshot.get_u64();
+            let k_num = snapshot.get_u32();
+            if bits != expected.number_of_bits() || k_num != expected.number_of_hash_functions() {
+                return Err(invalid("snapshot has a different error rate"));
+            }
+            let sip_keys = [
+                (snapshot.get_u64(), snapshot.get_u64()),
+                (snapshot.get_u64(), snapshot.get_u64()),
+            ];
+
+            let bitmap_len = snapshot.get_u64() as usize;
+            if snapshot.remaining() < bitmap_len {
+                return Err(invalid("snapshot is truncated"));
+            }
+            blooms.push(Bloom::from_existing(&snapshot[..bitmap_len], bits, k_num, sip_keys));
+            snapshot.advance(bitmap_len);
+        }
+
+        let second = blooms.pop().expect("bloom");
+        let first = blooms.pop().expect("bloom");
+
+        Ok(PingPongBloom {
+            blooms: [first, second],
+            bloom_count,
+            item_count,
+            current,
+        })
+    }
+
+    // Save filters in a snapshot, which could be loaded by `from_snapshot`
+    fn to_snapshot(&self) -> Vec<u8> {
+        let mut snapshot = Vec::new();
+        snapshot.put_slice(BF_SNAPSHOT_MAGIC);
+        snapshot.put_u64(self.item_count as u64);
+        snapshot.put_u8(self.current as u8);
+
+        for (bloom, &count) in self.blooms.iter().zip(self.bloom_count.iter()) {
+            snapshot.put_u64(count as u64);
+            snapshot.put_u64(bloom.number_of_bits());
+            snapshot.put_u32(bloom.number_of_hash_functions());
+            for &(k0, k1) in bloom.sip_keys().iter() {
+                snapshot.put_u64(k0);
+                snapshot.put_u64(k1);
+            }
+
+            let bitmap = bloom.bitmap();
+            snapshot.put_u64(bitmap.len() as u64);
+            snapshot.put_slice(&bitmap);
+        }
+
+        snapshot
+    }
+
+    // Load filters from snapshot file `path`, or create new filters if the snapshot couldn't be used
+    fn load_or_new(config: &Config, path: &Path) -> PingPongBloom {
+        let snapshot = match fs::read(path) {
+            Ok(s) => s,
+            Err(ref err) if err.kind() == ErrorKind::NotFound => {
+                debug!("replay filter snapshot {} not found", path.display());
+                return PingPongBloom::new(config);
+            }
+            Err(err) => {
+                warn!(
+                    "failed to read replay filter snapshot {}, error: {}",
+                    path.display(),
+                    err
+                );
+                return PingPongBloom::new(config);
+            }
+        };
+
+        match PingPongBloom::from_snapshot(config, &snapshot) {
+            Ok(ppbloom) => {
+                info!("loaded replay filter snapshot {}", path.display());
+                ppbloom
+            }
+            Err(err) => {
+                warn!("discarded replay filter snapshot {}, error: {}", path.display(), err);
+                PingPongBloom::new(config)
+            }
+        }
+    }
+
+    // Copy filters, for saving snapshots without holding the lock of `Context::nonce_ppbloom`
+    fn copy(&self) -> PingPongBloom {
+        let copy_bloom = |bloom: &Bloom<[u8]>| {
+            Bloom::from_existing(
+                &bloom.bitmap(),
+                bloom.number_of_bits(),
+                bloom.number_of_hash_functions(),
+                bloom.sip_keys(),
+            )
+        };
+
+        PingPongBloom {
+            blooms: [copy_bloom(&self.blooms[0]), copy_bloom(&self.blooms[1])],
+            bloom_count: self.bloom_count,
+            item_count: self.item_count,
+            current: self.current,
+        }
+    }
+
     // Check if data in `buf` exist.
     //
     // Set into the current bloom filter if not exist.
@@ -125,6 +283,10 @@ impl PingPongBloom {
 pub struct ServerState {
     #[cfg(feature = "trust-dns")]
     dns_resolver: Option<TokioAsyncResolver>,
+    // In-flight connections, waited for when shutting down
+    connections: ConnectionTracker,
+    #[cfg(feature = "metrics")]
+    metrics: SharedMetrics,
 }
 
 #[cfg(feature = "trust-dns")]
@@ -136,6 +298,9 @@ impl ServerState {
                 Ok(resolver) => Some(resolver),
                 Err(..) => None,
             },
+            connections: ConnectionTracker::default(),
+            #[cfg(feature = "metrics")]
+            metrics: Metrics::new_shared(config),
         };
 
         Arc::new(state)
@@ -150,8 +315,26 @@ impl ServerState {
 #[cfg(not(feature = "trust-dns"))]
 impl ServerState {
     /// Create a global shared server state
-    pub async fn new_shared(_config: &Config) -> SharedServerState {
-        Arc::new(ServerState {})
+    #[allow(unused_variables)]
+    pub async fn new_shared(config: &Config) -> SharedServerState {
+        Arc::new(ServerState {
+            connections: ConnectionTracker::default(),
+            #[cfg(feature = "metrics")]
+            metrics: Metrics::new_shared(config),
+        })
+    }
+}
+
+impl ServerState {
+    /// Get in-flight connections of all servers
+    pub fn connections(&self) -> &ConnectionTracker {
+        &self.connections
+    }
+
+    /// Get the global shared metrics
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> &SharedMetrics {
+        &self.metrics
     }
 }
 
@@ -171,7 +354,26 @@ pub struct Context {
 
     // Check for duplicated IV/Nonce, for prevent replay attack
     // https://github.com/shadowsocks/shadowsocks-org/issues/44
-    nonce_ppbloom: SpinMutex<PingPongBloom>,
+    // Shared with contexts created by `reload_servers`, for keeping the history
+    nonce_ppbloom: Arc<SpinMutex<PingPongBloom>>,
+
+    // Salts of AEAD-2022 requests, older requests are rejected by their timestamps
+    #[cfg(feature = "aead-cipher-2022")]
+    aead_2022_salts: Arc<SpinMutex<LruCache<Vec<u8>, ()>>>,
+
+    // Sessions of AEAD-2022 UDP clients, shared by all UDP associations
+    #[cfg(feature = "aead-cipher-2022")]
+    aead_2022_client_sessions: Arc<SpinMutex<PeerSessions>>,
+
+    // ACL, initialized from `config.acl` and could be replaced while running
+    acl: SpinMutex<Option<Arc<AccessControl>>>,
+
+    // Clients holding authenticated SOCKS5 UDP ASSOCIATE connections, counted by their IP addresses
+    udp_associate_clients: SpinMutex<HashMap<IpAddr, usize>>,
+
+    // Servers replaced by `reload_servers`, subscribed by load balancers
+    reload_servers_tx: watch::Sender<Option<SharedContext>>,
+    reload_servers_rx: watch::Receiver<Option<SharedContext>>,
 
     // For Android's flow stat report
     #[cfg(feature = "local-flow-stat")]
@@ -200,6 +402,11 @@ impl Context {
     ///
     /// This is useful when you are running multiple servers in one process
     fn new_with_state(config: Config, server_state: SharedServerState) -> Context {
+        let nonce_ppbloom = match config.replay_filter.snapshot_path {
+            Some(ref path) if config.config_type.is_server() => PingPongBloom::load_or_new(&config, path),
+            _ => PingPongBloom::new(&config),
+        };
+
         for server in &config.server {
             let t = server.method();
 
@@ -218,7 +425,8 @@ impl Context {
             }
         }
 
-        let nonce_ppbloom = SpinMutex::new(PingPongBloom::new(config.config_type));
+        let acl = SpinMutex::new(config.acl.clone().map(Arc::new));
+        let (reload_servers_tx, reload_servers_rx) = watch::channel(None);
         #[cfg(feature = "local-dns")]
         let local_dns = if config.local_dns_addr.is_some() {
             Some(LocalUpstream::new(&config))
@@ -230,7 +438,16 @@ impl Context {
             config,
             server_state,
             server_running: AtomicBool::new(true),
-            nonce_ppbloom,
+            nonce_ppbloom: Arc::new(SpinMutex::new(nonce_ppbloom)),
+            #[cfg(feature = "aead-cipher-2022")]
+            // Not bounded by capacity, salts are never evicted before expired, or requests could be replayed
+            aead_2022_salts: Arc::new(SpinMutex::new(LruCache::with_expiry_duration(SALT_EXPIRY_DURATION))),
+            #[cfg(feature = "aead-cipher-2022")]
+            aead_2022_client_sessions: Arc::new(SpinMutex::new(PeerSessions::new(AEAD_2022_CLIENT_SESSIONS_CAPACITY))),
+            acl,
+            udp_associate_clients: SpinMutex::new(HashMap::new()),
+            reload_servers_tx,
+            reload_servers_rx,
             #[cfg(feature = "local-flow-stat")]
             local_flow_statistic: ServerFlowStatistic::new(),
             #[cfg(feature = "local-dns")]
@@ -290,7 +507,6 @@ impl Context {
     /// Perform a DNS resolution
     pub async fn dns_resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
         if log_enabled!(log::Level::Debug) {
-            use log::debug;
             use std::time::Instant;
 
             let start = Instant::now();
@@ -332,7 +548,62 @@ impl Context {
 
     /// Stops the server, kills all detached running tasks
     pub fn set_server_stopped(&self) {
-        self.server_running.store(false, Ordering::Release)
+        self.server_running.store(false, Ordering::Release);
+
+        if let Some(ref context) = *self.reload_servers_rx.borrow() {
+            context.set_server_stopped();
+        }
+    }
+
+    /// Replace remote servers with `config.server`
+    ///
+    /// Load balancers will pick servers from `config` for new connections, established connections are not affected.
+    pub fn reload_servers(&self, config: Config) -> io::Result<()> {
+        if config.server.is_empty() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "missing servers"));
+        }
+
+        // Plugins are started with the server, their local addresses couldn't be changed
+        if config.has_server_plugins() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "servers with plugins couldn't be reloaded",
+            ));
+        }
+
+        #[cfg(feature = "metrics")]
+        self.metrics().register_local_servers(&config.server);
+
+        // Replay filter keeps its history
+        let mut context = Context::new_with_state(config, self.server_state.clone());
+        context.share_replay_filter(self);
+        let context = SharedContext::new(context);
+
+        let prev = self.reload_servers_rx.borrow().clone();
+        let _ = self.reload_servers_tx.send(Some(context));
+
+        // Load balancers of previous servers have been stopped
+        if let Some(prev) = prev {
+            prev.set_server_stopped();
+        }
+
+        Ok(())
+    }
+
+    // Share replay filters of `other`, for keeping the history
+    fn share_replay_filter(&mut self, other: &Context) {
+        self.nonce_ppbloom = other.nonce_ppbloom.clone();
+
+        #[cfg(feature = "aead-cipher-2022")]
+        {
+            self.aead_2022_salts = other.aead_2022_salts.clone();
+            self.aead_2022_client_sessions = other.aead_2022_client_sessions.clone();
+        }
+    }
+
+    /// Subscribe servers replaced by `reload_servers`
+    pub(crate) fn subscribe_servers(&self) -> watch::Receiver<Option<SharedContext>> {
+        self.reload_servers_rx.clone()
     }
 
     /// Check if nonce exist or not
@@ -346,7 +617,84 @@ impl Context {
         }
 
         let mut ppbloom = self.nonce_ppbloom.lock();
-        ppbloom.check_and_set(nonce)
+        let exist = ppbloom.check_and_set(nonce);
+
+        #[cfg(feature = "metrics")]
+        if exist {
+            self.metrics().incr_replay_hit();
+        }
+
+        exist
+    }
+
+    /// Save a snapshot of the replay filter to `config.replay_filter.snapshot_path`
+    ///
+    /// Does nothing if it isn't configured, or this isn't a server
+    pub async fn save_replay_filter(&self) -> io::Result<()> {
+        let path = match self.config.replay_filter.snapshot_path {
+            Some(ref p) if self.config.config_type.is_server() => p,
+            _ => return Ok(()),
+        };
+
+        // Requests are checked with the lock held, so it is only held while copying filters
+        let ppbloom = self.nonce_ppbloom.lock().copy();
+        let snapshot = ppbloom.to_snapshot();
+
+        // Replace the previous snapshot at once, it won't be corrupted if the server is killed while writing
+        let mut tmp_path = OsString::from(path.as_os_str());
+        tmp_path.push(".tmp");
+        tokio::fs::write(&tmp_path, snapshot).await?;
+        tokio::fs::rename(&tmp_path, path).await?;
+
+        debug!("saved replay filter snapshot {}", path.display());
+
+        Ok(())
+    }
+
+    /// Check if salt of an AEAD-2022 request exist or not
+    ///
+    /// If not, remember it for `aead_2022::SALT_EXPIRY_DURATION`. Only salts of requests with authenticated headers
+    /// and valid timestamps should be checked, so the others couldn't fill up the memory
+    #[cfg(feature = "aead-cipher-2022")]
+    pub fn check_aead_2022_salt_and_set(&self, salt: &[u8]) -> bool {
+        let mut salts = self.aead_2022_salts.lock();
+        let exist = salts.insert(salt.to_vec(), ()).is_some();
+
+        #[cfg(feature = "metrics")]
+        if exist {
+            self.metrics().incr_replay_hit();
+        }
+
+        exist
+    }
+
+    /// Sessions of AEAD-2022 UDP clients, for checking replayed packets in all UDP associations
+    #[cfg(feature = "aead-cipher-2022")]
+    pub(crate) fn aead_2022_client_sessions(&self) -> Arc<SpinMutex<PeerSessions>> {
+        self.aead_2022_client_sessions.clone()
+    }
+
+    /// Allow SOCKS5 UDP packets from `ip`, until the UDP ASSOCIATE connection is closed
+    pub(crate) fn add_udp_associate_client(&self, ip: IpAddr) {
+        *self.udp_associate_clients.lock().entry(ip).or_insert(0) += 1;
+    }
+
+    /// Remove a client added by `add_udp_associate_client`
+    pub(crate) fn remove_udp_associate_client(&self, ip: IpAddr) {
+        let mut clients = self.udp_associate_clients.lock();
+        if let Some(count) = clients.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                clients.remove(&ip);
+            }
+        }
+    }
+
+    /// Check if SOCKS5 UDP packets from `ip` are allowed
+    ///
+    /// Packets from any clients are allowed if authentication is not enabled
+    pub(crate) fn check_udp_associate_client(&self, ip: &IpAddr) -> bool {
+        self.config.local_auth.is_none() || self.udp_associate_clients.lock().contains_key(ip)
     }
 
     /// Check client ACL (for server)
@@ -357,11 +705,11 @@ impl Context {
         }
     }
 
-    /// Check outbound address ACL (for server)
-    pub async fn check_outbound_blocked(&self, addr: &Address) -> bool {
+    /// Check outbound address ACL of `protocol` (for server)
+    pub async fn check_outbound_blocked(&self, addr: &Address, protocol: Protocol) -> bool {
         match self.acl() {
             None => false,
-            Some(a) => a.check_outbound_blocked(self, addr).await,
+            Some(a) => a.check_outbound_blocked(self, addr, protocol).await,
         }
     }
 
@@ -393,8 +741,25 @@ impl Context {
     }
 
     /// Get ACL control instance
-    pub fn acl(&self) -> Option<&AccessControl> {
-        self.config.acl.as_ref()
+    pub fn acl(&self) -> Option<Arc<AccessControl>> {
+        self.acl.lock().clone()
+    }
+
+    /// Replace ACL, established connections are not affected
+    pub async fn set_acl(&self, acl: Option<AccessControl>) {
+        let acl = acl.map(Arc::new);
+
+        // Connections to reloaded servers are checked by the same ACL
+        let reloaded = self.reload_servers_rx.borrow().clone();
+        if let Some(reloaded) = reloaded {
+            *reloaded.acl.lock() = acl.clone();
+        }
+
+        *self.acl.lock() = acl;
+
+        // Cached results were decided by the previous ACL
+        #[cfg(feature = "local-dns")]
+        self.reverse_lookup_cache.lock().await.clear();
     }
 
     /// Get local DNS connector
@@ -403,12 +768,17 @@ impl Context {
         &self.local_dns.as_ref().expect("local DNS uninitialized")
     }
 
-    /// Check target address ACL (for client)
-    pub async fn check_target_bypassed(&self, target: &Address) -> bool {
+    /// Check target address ACL of `protocol` (for client)
+    pub async fn check_target_bypassed(&self, target: &Address, protocol: Protocol) -> bool {
         match self.acl() {
             // Proxy everything by default
             None => false,
             Some(a) => {
+                // Rules with ports or protocols are more specific than the reverse lookup cache
+                if let Some(bypassed) = a.check_qualified_target_bypassed(self, target, protocol).await {
+                    return bypassed;
+                }
+
                 #[cfg(feature = "local-dns")]
                 {
                     if let Address::SocketAddress(ref saddr) = target {
@@ -421,14 +791,47 @@ impl Context {
                     }
                 }
 
-                self.check_target_bypassed_with_acl(a, target).await
+                self.check_target_bypassed_with_acl(&a, target).await
             }
         }
     }
 
+    /// Check route of target address with `protocol` (for client)
+    ///
+    /// Rejected and routed targets are checked before bypassed targets
+    pub async fn route_target(&self, target: &Address, protocol: Protocol) -> Route {
+        let default_route = match self.acl() {
+            // Proxy everything by default
+            None => return Route::Proxy(None),
+            Some(a) => {
+                if let Some(route) = a.check_target_route(self, target, protocol).await {
+                    return route;
+                }
+                a.default_route().map(ToOwned::to_owned)
+            }
+        };
+
+        if self.check_target_bypassed(target, protocol).await {
+            Route::Bypass
+        } else {
+            Route::Proxy(default_route)
+        }
+    }
+
     #[inline(always)]
     async fn check_target_bypassed_with_acl(&self, a: &AccessControl, target: &Address) -> bool {
-        a.check_target_bypassed(self, target).await
+        a.check_unqualified_target_bypassed(self, target).await
+    }
+
+    /// Get in-flight connections of all servers
+    pub fn connections(&self) -> &ConnectionTracker {
+        self.server_state.connections()
+    }
+
+    /// Get the global shared metrics
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> &SharedMetrics {
+        self.server_state.metrics()
     }
 
     /// Get client flow statistics
@@ -437,3 +840,28 @@ impl Context {
         &self.local_flow_statistic
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::config::ConfigType;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn replay_filter_kept_after_reloading() {
+        let mut config = Config::new(ConfigType::Socks5Local);
+        config.server = vec![ServerConfig::basic(
+            "127.0.0.1:8001".parse().unwrap(),
+            "test-password".to_owned(),
+            CipherKind::AES_256_GCM,
+        )];
+
+        let context = Context::new_shared(config.clone()).await;
+        assert!(!context.check_nonce_and_set(b"nonce"));
+
+        context.reload_servers(config).unwrap();
+        let reloaded = context.subscribe_servers().borrow().clone().unwrap();
+        assert!(reloaded.check_nonce_and_set(b"nonce"));
+        assert!(!reloaded.check_nonce_and_set(b"another nonce"));
+    }
+}
@@ -79,9 +79,10 @@ use std::io;
 pub use self::{
     config::{ClientConfig, Config, ConfigType, ManagerAddr, ManagerConfig, Mode, ServerAddr, ServerConfig},
     relay::{
-        local::run as run_local,
+        control::ServerControl,
+        local::{run as run_local, run_with_control as run_local_with_control},
         manager::run as run_manager,
-        server::run as run_server,
+        server::{run as run_server, run_with_control as run_server_with_control},
         tcprelay::client::Socks5Client,
     },
 };
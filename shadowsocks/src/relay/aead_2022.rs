@@ -0,0 +1,292 @@
+//! Common facilities of [AEAD-2022](https://github.com/shadowsocks/shadowsocks-org/issues/196) (SIP022) methods
+//!
+//! Every AEAD-2022 method is based on an AEAD cipher of the original protocol, differences are
+//!
+//! 1. Server's "password" is a base64 encoded pre-shared key (PSK) with exactly the cipher's key length.
+//! 2. Session subkeys are derived by BLAKE3 from the PSK and the salt (TCP) or the session ID (UDP).
+//! 3. Requests are timestamped, so servers only have to remember salts of requests within `MAX_TIMESTAMP_DIFF`.
+//!
+//! TCP framing is implemented in `tcprelay::aead_2022`, UDP framing is implemented in `udprelay::aead_2022`.
+//! AEAD-2022 methods are only available with feature "aead-cipher-2022".
+
+#[cfg(test)]
+use std::cell::Cell;
+use std::{
+    io::{self, ErrorKind},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crypto2::aeadcipher::{Aes128Gcm, Aes256Gcm, Chacha20Poly1305};
+use rand::Rng;
+
+use crate::crypto::v1::CipherKind;
+
+/// AEAD-2022 methods, and the AEAD ciphers they are based on
+const METHODS: &[(&str, CipherKind)] = &[
+    ("2022-blake3-aes-128-gcm", CipherKind::AES_128_GCM),
+    ("2022-blake3-aes-256-gcm", CipherKind::AES_256_GCM),
+    ("2022-blake3-chacha20-poly1305", CipherKind::CHACHA20_POLY1305),
+];
+
+/// Context string of BLAKE3 for deriving session subkeys
+const SESSION_SUBKEY_CONTEXT: &str = "shadowsocks 2022 session subkey";
+
+/// Maximum difference between timestamps in requests and local time, in seconds
+pub(crate) const MAX_TIMESTAMP_DIFF: u64 = 30;
+
+/// Period for servers to remember salts of TCP requests
+///
+/// Requests sent earlier than that are rejected because of their timestamps
+pub(crate) const SALT_EXPIRY_DURATION: Duration = Duration::from_secs(2 * MAX_TIMESTAMP_DIFF);
+
+/// Maximum length of padding in request headers
+pub(crate) const MAX_PADDING_LENGTH: usize = 900;
+
+/// Length of AEAD tags, all AEAD-2022 ciphers have 16 bytes tags
+pub(crate) const TAG_LEN: usize = 16;
+
+/// Length of AEAD nonces, all AEAD-2022 ciphers (except XChaCha20-Poly1305 for UDP) have 12 bytes nonces
+pub(crate) const NONCE_LEN: usize = 12;
+
+/// Names of all AEAD-2022 methods
+pub fn available_methods() -> Vec<&'static str> {
+    METHODS.iter().map(|&(name, _)| name).collect()
+}
+
+/// Get the AEAD cipher that AEAD-2022 method `name` is based on
+pub fn method_from_name(name: &str) -> Option<CipherKind> {
+    METHODS.iter().find(|&&(n, _)| n == name).map(|&(_, method)| method)
+}
+
+/// Get name of the AEAD-2022 method based on AEAD cipher `method`
+pub fn method_name(method: CipherKind) -> Option<&'static str> {
+    METHODS.iter().find(|&&(_, m)| m == method).map(|&(name, _)| name)
+}
+
+/// Decode the base64 encoded pre-shared key, which must have exactly `method.key_len()` bytes
+pub fn decode_psk(method: CipherKind, psk: &str) -> Option<Vec<u8>> {
+    match base64::decode(psk) {
+        Ok(key) if key.len() == method.key_len() => Some(key),
+        _ => None,
+    }
+}
+
+/// Derive a session subkey from the pre-shared key and the salt (TCP) or the session ID (UDP)
+pub(crate) fn session_subkey(psk: &[u8], salt: &[u8]) -> Vec<u8> {
+    let mut hasher = blake3::Hasher::new_derive_key(SESSION_SUBKEY_CONTEXT);
+    hasher.update(psk);
+    hasher.update(salt);
+
+    let mut key = vec![0u8; psk.len()];
+    hasher.finalize_xof().fill(&mut key);
+    key
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Local time of tests, for checking requests captured at that time
+    pub(crate) static TEST_TIMESTAMP: Cell<Option<u64>> = Cell::new(None);
+}
+
+/// Current UNIX timestamp, in seconds
+pub(crate) fn unix_timestamp() -> u64 {
+    #[cfg(test)]
+    {
+        if let Some(timestamp) = TEST_TIMESTAMP.with(Cell::get) {
+            return timestamp;
+        }
+    }
+
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(..) => 0,
+    }
+}
+
+/// Check if `timestamp` in a header is close enough to local time
+pub(crate) fn check_timestamp(timestamp: u64) -> io::Result<()> {
+    let now = unix_timestamp();
+    let diff = if now > timestamp {
+        now - timestamp
+    } else {
+        timestamp - now
+    };
+
+    if diff > MAX_TIMESTAMP_DIFF {
+        let err = io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "timestamp {} differs from local time {} by more than {} seconds",
+                timestamp, now, MAX_TIMESTAMP_DIFF
+            ),
+        );
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+/// Length of padding in a request header, headers without any payload must be padded
+pub(crate) fn padding_len(payload_len: usize) -> usize {
+    if payload_len == 0 {
+        rand::thread_rng().gen_range(1, MAX_PADDING_LENGTH + 1)
+    } else {
+        0
+    }
+}
+
+/// AEAD cipher with a session subkey
+pub(crate) enum AeadCipher {
+    Aes128Gcm(Aes128Gcm),
+    Aes256Gcm(Aes256Gcm),
+    ChaCha20Poly1305(Chacha20Poly1305),
+}
+
+impl AeadCipher {
+    pub fn new(method: CipherKind, key: &[u8]) -> AeadCipher {
+        match method {
+            CipherKind::AES_128_GCM => AeadCipher::Aes128Gcm(Aes128Gcm::new(key)),
+            CipherKind::AES_256_GCM => AeadCipher::Aes256Gcm(Aes256Gcm::new(key)),
+            CipherKind::CHACHA20_POLY1305 => AeadCipher::ChaCha20Poly1305(Chacha20Poly1305::new(key)),
+            _ => unreachable!("{} is not a cipher of AEAD-2022 methods", method),
+        }
+    }
+
+    /// Encrypt `m` in place, the last `TAG_LEN` bytes of `m` are filled with the tag
+    pub fn encrypt(&self, nonce: &[u8], m: &mut [u8]) {
+        match *self {
+            AeadCipher::Aes128Gcm(ref c) => c.encrypt_slice(nonce, &[], m),
+            AeadCipher::Aes256Gcm(ref c) => c.encrypt_slice(nonce, &[], m),
+            AeadCipher::ChaCha20Poly1305(ref c) => c.encrypt_slice(nonce, &[], m),
+        }
+    }
+
+    /// Decrypt `m` in place, the last `TAG_LEN` bytes of `m` are the tag
+    ///
+    /// Returns `false` if the tag doesn't match
+    pub fn decrypt(&self, nonce: &[u8], m: &mut [u8]) -> bool {
+        if m.len() < TAG_LEN {
+            return false;
+        }
+
+        match *self {
+            AeadCipher::Aes128Gcm(ref c) => c.decrypt_slice(nonce, &[], m),
+            AeadCipher::Aes256Gcm(ref c) => c.decrypt_slice(nonce, &[], m),
+            AeadCipher::ChaCha20Poly1305(ref c) => c.decrypt_slice(nonce, &[], m),
+        }
+    }
+}
+
+/// AEAD cipher of one direction of a TCP stream
+///
+/// Nonce is a little-endian counter, increased after every chunk
+pub(crate) struct StreamCipher {
+    cipher: AeadCipher,
+    nonce: [u8; NONCE_LEN],
+}
+
+impl StreamCipher {
+    pub fn new(method: CipherKind, psk: &[u8], salt: &[u8]) -> StreamCipher {
+        let key = session_subkey(psk, salt);
+
+        StreamCipher {
+            cipher: AeadCipher::new(method, &key),
+            nonce: [0u8; NONCE_LEN],
+        }
+    }
+
+    /// Encrypt a chunk in place, the last `TAG_LEN` bytes are filled with the tag
+    pub fn encrypt_packet(&mut self, m: &mut [u8]) {
+        self.cipher.encrypt(&self.nonce, m);
+        self.increase_nonce();
+    }
+
+    /// Decrypt a chunk in place, returns `false` if the tag doesn't match
+    pub fn decrypt_packet(&mut self, m: &mut [u8]) -> bool {
+        let ok = self.cipher.decrypt(&self.nonce, m);
+        self.increase_nonce();
+        ok
+    }
+
+    fn increase_nonce(&mut self) {
+        for b in self.nonce.iter_mut() {
+            *b = b.wrapping_add(1);
+            if *b != 0 {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn psk(len: u8) -> Vec<u8> {
+        (0..len).collect()
+    }
+
+    fn salt(len: u8) -> Vec<u8> {
+        (len..2 * len).collect()
+    }
+
+    #[test]
+    fn session_subkey_vectors() {
+        assert_eq!(
+            session_subkey(&psk(16), &salt(16)),
+            hex("bc32fb8d5205f7b84f9691dfb9f04ff3")
+        );
+        assert_eq!(
+            session_subkey(&psk(32), &salt(32)),
+            hex("374fca03e4dae7f998fd7e59c1edfcc8e3197f4db1c19ca1671be3b66a92ddda")
+        );
+        assert_eq!(
+            session_subkey(&psk(16), &0x0102030405060708u64.to_be_bytes()),
+            hex("b8473b44792f673ee36a405dfa755cc4")
+        );
+    }
+
+    #[test]
+    fn stream_cipher_vectors() {
+        let chunks: &[(&[u8], &str)] = &[
+            (b"hello", "c22b97be766c879569a5b45858c0778c1beeb49e16"),
+            (b"world", "9d62a2c06a55ed7b8d02c4f5fa14a115c2ac59de25"),
+        ];
+
+        let mut encryptor = StreamCipher::new(CipherKind::AES_256_GCM, &psk(32), &salt(32));
+        let mut decryptor = StreamCipher::new(CipherKind::AES_256_GCM, &psk(32), &salt(32));
+        for &(data, expected) in chunks {
+            let mut chunk = data.to_vec();
+            chunk.resize(data.len() + TAG_LEN, 0);
+            encryptor.encrypt_packet(&mut chunk);
+            assert_eq!(chunk, hex(expected));
+
+            assert!(decryptor.decrypt_packet(&mut chunk));
+            assert_eq!(&chunk[..data.len()], data);
+        }
+
+        // Nonce has been increased
+        let mut chunk = hex(chunks[0].1);
+        assert!(!decryptor.decrypt_packet(&mut chunk));
+    }
+
+    #[test]
+    fn methods() {
+        for name in available_methods() {
+            let method = method_from_name(name).unwrap();
+            assert_eq!(method_name(method), Some(name));
+
+            let key = base64::encode(&psk(method.key_len() as u8));
+            assert_eq!(decode_psk(method, &key), Some(psk(method.key_len() as u8)));
+            assert_eq!(decode_psk(method, &base64::encode(b"short")), None);
+        }
+        assert_eq!(method_from_name("aes-256-gcm"), None);
+    }
+}
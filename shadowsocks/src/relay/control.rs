@@ -0,0 +1,210 @@
+//! Controlling running servers from outside, reloading configurations and shutting down gracefully
+
+use std::{
+    io::{self, ErrorKind},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use futures::future::{self, select_all, BoxFuture, Either};
+use log::{error, info, warn};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time,
+};
+
+use crate::{config::Config, context::SharedContext};
+
+/// Default time for waiting in-flight connections to finish when shutting down
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Interval for checking whether all connections are finished
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Controls running servers from outside, for example, by signals
+#[derive(Default)]
+pub struct ServerControl {
+    /// New configurations for reloading
+    pub reload_rx: Option<mpsc::Receiver<Config>>,
+    /// Servers stop accepting new connections and wait in-flight connections to finish after it resolves
+    pub shutdown_rx: Option<oneshot::Receiver<()>>,
+}
+
+/// Counts in-flight connections and UDP associations
+#[derive(Default)]
+pub struct ConnectionTracker {
+    active: Arc<AtomicUsize>,
+    draining: AtomicBool,
+}
+
+impl ConnectionTracker {
+    /// Count a connection until the returned guard is dropped
+    pub fn track(&self) -> ConnectionGuard {
+        self.active.fetch_add(1, Ordering::AcqRel);
+
+        ConnectionGuard {
+            active: self.active.clone(),
+        }
+    }
+
+    /// Number of in-flight connections
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// Check if `drain` has been called, new UDP associations are refused after that
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    /// Wait for all connections to finish in `timeout`
+    ///
+    /// Returns the number of connections that are still in-flight
+    pub async fn drain(&self, timeout: Duration) -> usize {
+        self.draining.store(true, Ordering::Release);
+
+        let deadline = Instant::now() + timeout;
+
+        while self.active() > 0 && Instant::now() < deadline {
+            time::sleep(DRAIN_CHECK_INTERVAL).await;
+        }
+
+        self.active()
+    }
+}
+
+/// Keeps a connection counted in `ConnectionTracker` until it is dropped
+pub struct ConnectionGuard {
+    active: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Runs `servers` and `relays` until one of them exits, or `shutdown_rx` resolves
+///
+/// `servers` are dropped for stop accepting when shutting down. `relays`, like plugins and UDP relays, are kept running
+/// until connections are drained, because connections and UDP associations are relayed through them.
+pub(crate) async fn serve(
+    context: SharedContext,
+    servers: Vec<BoxFuture<'static, io::Result<()>>>,
+    relays: Vec<BoxFuture<'static, io::Result<()>>>,
+    shutdown_rx: Option<oneshot::Receiver<()>>,
+) -> io::Result<()> {
+    let shutdown = async move {
+        match shutdown_rx {
+            // Sender is dropped also means shutdown
+            Some(rx) => {
+                let _ = rx.await;
+            }
+            None => future::pending().await,
+        }
+    };
+
+    let server_count = servers.len();
+
+    let mut vf = servers;
+    vf.extend(relays);
+    // `select_all` requires at least one relay
+    vf.push(Box::pin(future::pending()));
+
+    tokio::pin!(shutdown);
+
+    let servers = match future::select(select_all(vf), shutdown).await {
+        Either::Left(((res, ..), ..)) => {
+            error!("one of servers exited unexpectly, result: {:?}", res);
+
+            // Tells all detached tasks to exit
+            context.set_server_stopped();
+
+            save_replay_filter(&context).await;
+
+            return Err(io::Error::new(ErrorKind::Other, "server exited unexpectly"));
+        }
+        Either::Right((_, servers)) => servers,
+    };
+
+    // Drop servers for stop accepting
+    let mut vf = servers.into_inner();
+    let relays = vf.split_off(server_count);
+    drop(vf);
+
+    context.set_server_stopped();
+
+    let connections = context.connections();
+    let timeout = context.config().shutdown_timeout.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT);
+
+    info!(
+        "shutting down, waiting {} connections to finish in {}s",
+        connections.active(),
+        timeout.as_secs()
+    );
+
+    let drain = connections.drain(timeout);
+    tokio::pin!(drain);
+
+    let force_closed = match future::select(drain, select_all(relays)).await {
+        Either::Left((n, ..)) => n,
+        Either::Right(((res, ..), ..)) => {
+            error!("relays exited while shutting down, result: {:?}", res);
+            connections.active()
+        }
+    };
+
+    if force_closed > 0 {
+        warn!("shutdown timed out, {} connections are force closed", force_closed);
+    } else {
+        info!("all connections finished, shutdown completed");
+    }
+
+    save_replay_filter(&context).await;
+
+    Ok(())
+}
+
+/// Saves snapshots of the replay filter every `replay_filter.snapshot_interval`
+///
+/// Snapshots are also saved by `serve` when the server stops, this one keeps recent snapshots if the process is killed.
+pub(crate) async fn replay_filter_snapshot_task(context: SharedContext) -> io::Result<()> {
+    let interval = context.config().replay_filter.snapshot_interval;
+
+    loop {
+        time::sleep(interval).await;
+        save_replay_filter(&context).await;
+    }
+}
+
+async fn save_replay_filter(context: &SharedContext) {
+    if let Err(err) = context.save_replay_filter().await {
+        error!("failed to save replay filter snapshot, error: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn drain_waits_for_connections() {
+        let tracker = ConnectionTracker::default();
+        assert!(!tracker.is_draining());
+
+        let guard = tracker.track();
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(200)).await;
+            drop(guard);
+        });
+
+        assert_eq!(tracker.drain(Duration::from_secs(5)).await, 0);
+        assert!(tracker.is_draining());
+
+        let _guard = tracker.track();
+        assert_eq!(tracker.drain(Duration::from_millis(200)).await, 1);
+    }
+}
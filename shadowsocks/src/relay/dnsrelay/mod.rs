@@ -212,7 +212,7 @@ where
         let remote_response_fut = try_timeout(remote.lookup(&self.context, query), Some(Duration::from_secs(5)));
         let local_response_fut = try_timeout(local.lookup(&self.context, query), Some(Duration::from_secs(5)));
 
-        match should_forward_by_query(acl, query) {
+        match should_forward_by_query(acl.as_deref(), query) {
             Some(true) => {
                 let remote_response = remote_response_fut.await;
                 trace!("pick remote response (query): {:?}", remote_response);
@@ -228,7 +228,7 @@ where
 
         let decider = async {
             let local_response = local_response_fut.await;
-            if should_forward_by_response(acl, &local_response, query) {
+            if should_forward_by_response(acl.as_deref(), &local_response, query) {
                 None
             } else {
                 Some(local_response)
@@ -1,14 +1,14 @@
 //! Server network flow statistic
 
-use std::{
-    collections::BTreeMap,
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
-    },
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
 };
 
-use crate::config::Config;
+use crate::{
+    config::{Config, TrafficLimit},
+    relay::limit::ServerTrafficLimiter,
+};
 
 /// Flow statistic for one server
 pub struct FlowStatistic {
@@ -56,6 +56,7 @@ impl Default for FlowStatistic {
 pub struct ServerFlowStatistic {
     tcp: FlowStatistic,
     udp: FlowStatistic,
+    limiter: Option<ServerTrafficLimiter>,
 }
 
 /// Shared reference for ServerFlowStatistic
@@ -67,6 +68,7 @@ impl ServerFlowStatistic {
         ServerFlowStatistic {
             tcp: FlowStatistic::new(),
             udp: FlowStatistic::new(),
+            limiter: None,
         }
     }
 
@@ -75,6 +77,34 @@ impl ServerFlowStatistic {
         Arc::new(ServerFlowStatistic::new())
     }
 
+    /// Create a new shared reference of ServerFlowStatistic, with traffic limits enforced
+    pub fn new_shared_with_limit(limit: &TrafficLimit) -> SharedServerFlowStatistic {
+        let limiter = if limit.is_unlimited() {
+            None
+        } else {
+            Some(ServerTrafficLimiter::new(*limit))
+        };
+
+        Arc::new(ServerFlowStatistic {
+            tcp: FlowStatistic::new(),
+            udp: FlowStatistic::new(),
+            limiter,
+        })
+    }
+
+    /// Traffic limiter of this server
+    pub fn limiter(&self) -> Option<&ServerTrafficLimiter> {
+        self.limiter.as_ref()
+    }
+
+    /// Check if traffic quota of this server is used up
+    pub fn is_quota_exhausted(&self) -> bool {
+        match self.limiter {
+            Some(ref limiter) => limiter.is_exhausted(),
+            None => false,
+        }
+    }
+
     /// TCP relay server flow statistic
     pub fn tcp(&self) -> &FlowStatistic {
         &self.tcp
@@ -98,8 +128,11 @@ impl Default for ServerFlowStatistic {
 }
 
 /// FlowStatic for multiple servers
+///
+/// Statistics are kept for every servers in `Config::server`, ordered by their indexes.
+/// Servers sharing the same port (multi-user) have their own statistics.
 pub struct MultiServerFlowStatistic {
-    servers: BTreeMap<u16, SharedServerFlowStatistic>,
+    servers: Vec<(u16, SharedServerFlowStatistic)>,
 }
 
 /// Shared reference for `MultiServerFlowStatistic`
@@ -108,9 +141,10 @@ pub type SharedMultiServerFlowStatistic = Arc<MultiServerFlowStatistic>;
 impl MultiServerFlowStatistic {
     /// Create statistics for every servers in config
     pub fn new(config: &Config) -> MultiServerFlowStatistic {
-        let mut servers = BTreeMap::new();
+        let mut servers = Vec::with_capacity(config.server.len());
         for svr_cfg in &config.server {
-            servers.insert(svr_cfg.addr().port(), ServerFlowStatistic::new_shared());
+            let flow_stat = ServerFlowStatistic::new_shared_with_limit(svr_cfg.traffic_limit());
+            servers.push((svr_cfg.addr().port(), flow_stat));
         }
 
         MultiServerFlowStatistic { servers }
@@ -122,7 +156,23 @@ impl MultiServerFlowStatistic {
     }
 
     /// Get ServerFlowStatistic by port
+    ///
+    /// If there are multiple users sharing the same port, this will return the first one
     pub fn get(&self, port: u16) -> Option<&SharedServerFlowStatistic> {
-        self.servers.get(&port)
+        self.servers.iter().find(|(p, _)| *p == port).map(|(_, s)| s)
+    }
+
+    /// Get ServerFlowStatistic by server's index in `Config::server`
+    pub fn get_by_index(&self, idx: usize) -> Option<&SharedServerFlowStatistic> {
+        self.servers.get(idx).map(|(_, s)| s)
+    }
+
+    /// Transmission statistic of all servers (users) listening on `port`
+    pub fn port_trans_stat(&self, port: u16) -> usize {
+        self.servers
+            .iter()
+            .filter(|(p, _)| *p == port)
+            .map(|(_, s)| s.trans_stat())
+            .sum()
     }
 }
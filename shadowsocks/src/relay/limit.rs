@@ -0,0 +1,208 @@
+//! Server traffic limits
+
+use std::time::{Duration, Instant};
+
+use spin::Mutex;
+
+use crate::config::TrafficLimit;
+
+/// Transfers are allowed to burst up to this duration's worth of bytes
+const RATE_LIMIT_BURST: Duration = Duration::from_secs(1);
+
+struct LimiterState {
+    upload_next: Instant,
+    download_next: Instant,
+    quota_used: u64,
+}
+
+/// Enforces `TrafficLimit` of a server, shared by all its TCP connections and UDP associations
+pub struct ServerTrafficLimiter {
+    limit: TrafficLimit,
+    state: Mutex<LimiterState>,
+}
+
+impl ServerTrafficLimiter {
+    /// Create a limiter
+    pub fn new(limit: TrafficLimit) -> ServerTrafficLimiter {
+        let now = Instant::now();
+
+        ServerTrafficLimiter {
+            limit,
+            state: Mutex::new(LimiterState {
+                upload_next: now,
+                download_next: now,
+                quota_used: 0,
+            }),
+        }
+    }
+
+    /// Limits of this server
+    pub fn limit(&self) -> &TrafficLimit {
+        &self.limit
+    }
+
+    /// Record `n` bytes received from client
+    ///
+    /// Returns how long it should wait before receiving more
+    pub fn consume_upload(&self, n: usize) -> Duration {
+        let mut state = self.state.lock();
+        state.quota_used = state.quota_used.saturating_add(n as u64);
+        consume_rate(&mut state.upload_next, self.limit.upload, n)
+    }
+
+    /// Record `n` bytes sent to client
+    ///
+    /// Returns how long it should wait before sending more
+    pub fn consume_download(&self, n: usize) -> Duration {
+        let mut state = self.state.lock();
+        state.quota_used = state.quota_used.saturating_add(n as u64);
+        consume_rate(&mut state.download_next, self.limit.download, n)
+    }
+
+    /// Check if client could send `n` bytes right now, used by UDP relay for dropping packets
+    pub fn check_upload(&self, n: usize) -> bool {
+        self.check_rate(true, n)
+    }
+
+    /// Check if server could send `n` bytes to client right now, used by UDP relay for dropping packets
+    pub fn check_download(&self, n: usize) -> bool {
+        self.check_rate(false, n)
+    }
+
+    fn check_rate(&self, upload: bool, n: usize) -> bool {
+        if self.is_exhausted() {
+            return false;
+        }
+
+        let mut state = self.state.lock();
+        let (next, rate) = if upload {
+            (&mut state.upload_next, self.limit.upload)
+        } else {
+            (&mut state.download_next, self.limit.download)
+        };
+
+        let mut new_next = *next;
+        if consume_rate(&mut new_next, rate, n) > Duration::from_secs(0) {
+            return false;
+        }
+        *next = new_next;
+
+        state.quota_used = state.quota_used.saturating_add(n as u64);
+        true
+    }
+
+    /// Count `n` bytes transferred before this limiter was created, e.g. by the server it replaces
+    pub fn add_quota_used(&self, n: u64) {
+        let mut state = self.state.lock();
+        state.quota_used = state.quota_used.saturating_add(n);
+    }
+
+    /// Total bytes transferred, counted for `quota`
+    pub fn quota_used(&self) -> u64 {
+        self.state.lock().quota_used
+    }
+
+    /// Check if `quota` is used up
+    pub fn is_exhausted(&self) -> bool {
+        match self.limit.quota {
+            Some(quota) => self.quota_used() >= quota,
+            None => false,
+        }
+    }
+}
+
+/// Token bucket with `RATE_LIMIT_BURST`, `next` is the time when the bucket will be full again
+fn consume_rate(next: &mut Instant, rate: Option<u64>, n: usize) -> Duration {
+    let rate = match rate {
+        Some(0) | None => return Duration::from_secs(0),
+        Some(r) => r,
+    };
+
+    let now = Instant::now();
+    let start = if *next > now { *next } else { now };
+    *next = start + Duration::from_secs_f64(n as f64 / rate as f64);
+
+    (*next - now).checked_sub(RATE_LIMIT_BURST).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn limiter(upload: Option<u64>, download: Option<u64>, quota: Option<u64>) -> ServerTrafficLimiter {
+        ServerTrafficLimiter::new(TrafficLimit {
+            upload,
+            download,
+            quota,
+        })
+    }
+
+    #[test]
+    fn quota_counts_both_directions() {
+        let l = limiter(None, None, Some(100));
+
+        assert_eq!(l.consume_upload(40), Duration::from_secs(0));
+        assert!(!l.is_exhausted());
+        assert_eq!(l.consume_download(60), Duration::from_secs(0));
+        assert_eq!(l.quota_used(), 100);
+        assert!(l.is_exhausted());
+
+        // UDP packets are dropped once quota is used up
+        assert!(!l.check_upload(1));
+        assert!(!l.check_download(1));
+        assert_eq!(l.quota_used(), 100);
+    }
+
+    #[test]
+    fn quota_used_carried_over() {
+        let l = limiter(None, None, Some(100));
+
+        l.add_quota_used(90);
+        assert_eq!(l.quota_used(), 90);
+        assert!(l.check_upload(10));
+        assert!(l.is_exhausted());
+
+        l.add_quota_used(u64::MAX);
+        assert_eq!(l.quota_used(), u64::MAX);
+    }
+
+    #[test]
+    fn quota_used_saturated() {
+        // Restored from a server which has no quota
+        let l = limiter(None, None, None);
+        l.add_quota_used(u64::MAX);
+
+        assert_eq!(l.consume_upload(1), Duration::from_secs(0));
+        assert_eq!(l.consume_download(1), Duration::from_secs(0));
+        assert!(l.check_upload(1));
+        assert!(l.check_download(1));
+        assert_eq!(l.quota_used(), u64::MAX);
+    }
+
+    #[test]
+    fn unlimited_without_quota() {
+        let l = limiter(None, None, None);
+
+        assert_eq!(l.consume_upload(usize::MAX / 2), Duration::from_secs(0));
+        assert!(!l.is_exhausted());
+        assert!(l.check_download(1024));
+    }
+
+    #[test]
+    fn rate_allows_burst() {
+        let l = limiter(Some(1000), Some(1000), None);
+
+        // Up to `RATE_LIMIT_BURST` worth of bytes could be sent immediately
+        assert_eq!(l.consume_upload(1000), Duration::from_secs(0));
+        assert!(l.check_download(1000));
+
+        // Then transfers have to wait
+        let wait = l.consume_upload(1000);
+        assert!(wait > Duration::from_millis(900), "{:?}", wait);
+        assert!(wait <= Duration::from_secs(1), "{:?}", wait);
+        assert!(!l.check_download(1000));
+
+        // Dropped packets are not counted
+        assert_eq!(l.quota_used(), 3000);
+    }
+}
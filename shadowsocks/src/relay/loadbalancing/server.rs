@@ -1,17 +1,20 @@
 use std::{
-    collections::VecDeque,
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
     fmt,
+    hash::{Hash, Hasher},
     io,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    mem,
+    ops::Deref,
+    str,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
-    time::{Duration, Instant},
+    time::Instant,
 };
 
 use crate::{
-    config::{Config, ServerConfig},
+    config::{BalanceStrategy, Config, HealthCheckProbe, ServerConfig},
     context::{Context, SharedContext},
     relay::{
         socks5::Address,
@@ -21,18 +24,47 @@ use crate::{
 };
 
 use byte_string::ByteStr;
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
+use rand::Rng;
+use spin::Mutex as SpinMutex;
 use tokio::{
     self,
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
     sync::{Barrier, Mutex},
     time,
 };
+use url::{Host, Position, Url};
 
 const MAX_LATENCY_QUEUE_SIZE: usize = 99;
-const DEFAULT_CHECK_INTERVAL_SEC: u64 = 6;
-const DEFAULT_CHECK_TIMEOUT_SEC: u64 = 2; // Latency shouldn't greater than 2 secs, that's too long
-const MAX_SERVER_RTT: u64 = DEFAULT_CHECK_TIMEOUT_SEC * 1000;
+const MAX_SERVER_SCORE: u64 = 1000;
+// Virtual nodes of each server on the consistent hashing ring
+const CONSISTENT_HASH_REPLICAS: usize = 64;
+
+// TransactionID: 0x1234
+// Flags: 0x0100 RD
+// Questions: 0x0001
+// Answer RRs: 0x0000
+// Authority RRs: 0x0000
+// Additional RRs: 0x0000
+// Queries
+//    - QNAME: \x07 firefox \x03 com \x00
+//    - QTYPE: 0x0001 A
+//    - QCLASS: 0x0001 IN
+static DNS_QUERY: &[u8] = b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x07firefox\x03com\x00\x00\x01\x00\x01";
+
+fn check_dns_answer(addr: &Address, dns_answer: &[u8]) -> io::Result<()> {
+    // DNS packet must have at least 6 * 2 bytes
+    if dns_answer.len() < 12 || &dns_answer[0..2] != b"\x12\x34" {
+        use std::io::{Error, ErrorKind};
+
+        debug!("unexpected response from {}, {:?}", addr, ByteStr::new(dns_answer));
+
+        let err = Error::new(ErrorKind::InvalidData, format!("unexpected response from {}", addr));
+        return Err(err);
+    }
+
+    Ok(())
+}
 
 /// Identifier of a valid server
 pub trait ServerData: Send + Sync {
@@ -54,10 +86,12 @@ struct ServerStatisticData {
     latency_stdev: f64,
     /// Score's average
     latency_mean: f64,
+    /// Health check timeout (in millisec), latency shouldn't be greater than it
+    max_rtt: u64,
 }
 
-fn max_latency_stdev() -> f64 {
-    let mrtt = MAX_SERVER_RTT as f64;
+fn max_latency_stdev(max_rtt: u64) -> f64 {
+    let mrtt = max_rtt as f64;
     let avg = (0.0 + mrtt) / 2.0;
     let diff1 = (0.0 - avg) * (0.0 - avg);
     let diff2 = (mrtt - avg) * (mrtt - avg);
@@ -66,22 +100,23 @@ fn max_latency_stdev() -> f64 {
 }
 
 impl ServerStatisticData {
-    fn new() -> ServerStatisticData {
+    fn new(max_rtt: u64) -> ServerStatisticData {
         ServerStatisticData {
-            rtt: MAX_SERVER_RTT,
+            rtt: max_rtt,
             fail_rate: 1.0,
             latency_queue: VecDeque::new(),
             latency_stdev: 0.0,
             latency_mean: 0.0,
+            max_rtt,
         }
     }
 
     fn score(&self) -> u64 {
         // Normalize rtt
-        let nrtt = self.rtt as f64 / MAX_SERVER_RTT as f64;
+        let nrtt = self.rtt as f64 / self.max_rtt as f64;
 
         // Normalize stdev
-        let nstdev = self.latency_stdev / max_latency_stdev();
+        let nstdev = self.latency_stdev / max_latency_stdev(self.max_rtt);
 
         const SCORE_RTT_WEIGHT: f64 = 1.0;
         const SCORE_FAIL_WEIGHT: f64 = 3.0;
@@ -171,8 +206,8 @@ impl ServerStatisticData {
 pub struct SharedServerStatisticData(Arc<Mutex<ServerStatisticData>>);
 
 impl SharedServerStatisticData {
-    fn new() -> SharedServerStatisticData {
-        SharedServerStatisticData(Arc::new(Mutex::new(ServerStatisticData::new())))
+    fn new(max_rtt: u64) -> SharedServerStatisticData {
+        SharedServerStatisticData(Arc::new(Mutex::new(ServerStatisticData::new(max_rtt))))
     }
 
     pub async fn report_failure(&self) -> u64 {
@@ -201,23 +236,32 @@ pub struct ServerStatistic<S: ServerData> {
     context: SharedContext,
     server_idx: usize,
     data: SharedServerStatisticData,
+    // Last score, for choosing servers without locking `data`
+    last_score: AtomicU64,
+    // Consecutive failed probes, server is down if it reaches the failure threshold
+    failures: AtomicUsize,
+    // Number of `SharedServerStatistic` handed out by balancers
+    active: AtomicUsize,
 }
 
-pub type SharedServerStatistic<S> = Arc<ServerStatistic<S>>;
-
 impl<S: ServerData> ServerStatistic<S> {
     fn new(context: SharedContext, server_idx: usize) -> ServerStatistic<S> {
-        let data = SharedServerStatisticData::new();
+        let timeout = context.config().health_check.timeout;
+        let max_rtt = timeout.as_secs() * 1000 + u64::from(timeout.subsec_millis());
+        let data = SharedServerStatisticData::new(max_rtt);
 
         ServerStatistic {
             server: S::create_server(&context, server_idx, &data),
             context,
             server_idx,
             data,
+            last_score: AtomicU64::new(MAX_SERVER_SCORE),
+            failures: AtomicUsize::new(0),
+            active: AtomicUsize::new(0),
         }
     }
 
-    fn new_shared(context: SharedContext, server_idx: usize) -> SharedServerStatistic<S> {
+    fn new_shared(context: SharedContext, server_idx: usize) -> Arc<ServerStatistic<S>> {
         Arc::new(ServerStatistic::new(context, server_idx))
     }
 
@@ -243,7 +287,14 @@ impl<S: ServerData> ServerStatistic<S> {
     }
 
     async fn push_score(&self, score: Score) -> u64 {
-        self.data.push_score(score).await
+        match score {
+            Score::Errored => self.failures.fetch_add(1, Ordering::Relaxed),
+            Score::Latency(..) => self.failures.swap(0, Ordering::Relaxed),
+        };
+
+        let score = self.data.push_score(score).await;
+        self.last_score.store(score, Ordering::Relaxed);
+        score
     }
 
     pub async fn score(&self) -> u64 {
@@ -251,7 +302,32 @@ impl<S: ServerData> ServerStatistic<S> {
     }
 
     pub async fn report_failure(&self) -> u64 {
-        self.data.report_failure().await
+        let score = self.data.report_failure().await;
+        self.last_score.store(score, Ordering::Relaxed);
+        score
+    }
+
+    /// Number of connections using this server
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    fn last_score(&self) -> u64 {
+        self.last_score.load(Ordering::Relaxed)
+    }
+
+    fn is_available(&self) -> bool {
+        self.failures.load(Ordering::Relaxed) < self.config().health_check.failure_threshold
+    }
+
+    /// Servers in lower tiers are preferred, backup servers are always in higher tiers than primary servers
+    fn tier(&self) -> (bool, u32) {
+        let svr_cfg = self.server_config();
+        (svr_cfg.is_backup(), svr_cfg.priority())
+    }
+
+    fn weight(&self) -> u64 {
+        u64::from(self.server_config().weight())
     }
 
     async fn data_debug_string(&self) -> String {
@@ -259,6 +335,47 @@ impl<S: ServerData> ServerStatistic<S> {
     }
 }
 
+/// Server chosen by `PingBalancer`
+///
+/// Counted as an active connection of the server until it is dropped, each clone is counted separately
+pub struct SharedServerStatistic<S: ServerData> {
+    stat: Arc<ServerStatistic<S>>,
+    // Group that the server is chosen from, failover servers are chosen from the same group
+    group: Option<Arc<str>>,
+}
+
+impl<S: ServerData> SharedServerStatistic<S> {
+    fn new(stat: Arc<ServerStatistic<S>>, group: Option<Arc<str>>) -> SharedServerStatistic<S> {
+        stat.active.fetch_add(1, Ordering::Relaxed);
+        SharedServerStatistic { stat, group }
+    }
+
+    /// Name of the group in `Config::groups` that the server is chosen from
+    pub fn group(&self) -> Option<&str> {
+        self.group.as_deref()
+    }
+}
+
+impl<S: ServerData> Clone for SharedServerStatistic<S> {
+    fn clone(&self) -> SharedServerStatistic<S> {
+        SharedServerStatistic::new(self.stat.clone(), self.group.clone())
+    }
+}
+
+impl<S: ServerData> Drop for SharedServerStatistic<S> {
+    fn drop(&mut self) {
+        self.stat.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl<S: ServerData> Deref for SharedServerStatistic<S> {
+    type Target = ServerStatistic<S>;
+
+    fn deref(&self) -> &ServerStatistic<S> {
+        &self.stat
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 enum Score {
     Latency(u64),
@@ -271,6 +388,16 @@ pub enum ServerType {
     Udp,
 }
 
+impl ServerType {
+    /// Health check probe of this type of servers
+    fn probe(self, config: &Config) -> &HealthCheckProbe {
+        match self {
+            ServerType::Tcp => &config.health_check.tcp_probe,
+            ServerType::Udp => &config.health_check.udp_probe,
+        }
+    }
+}
+
 impl fmt::Display for ServerType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
@@ -281,27 +408,188 @@ impl fmt::Display for ServerType {
 }
 
 struct BestServer<S: ServerData> {
-    servers: Vec<SharedServerStatistic<S>>,
+    servers: Vec<Arc<ServerStatistic<S>>>,
     best_idx: AtomicUsize,
+    strategy: BalanceStrategy,
+    // For round robin
+    next_idx: AtomicUsize,
+    // For consistent hashing, (hash, server index) sorted by hash
+    ring: Vec<(u64, usize)>,
+    // Shared with probing tasks, cleared when servers are replaced
+    running: Arc<AtomicBool>,
+    // Name of the group, if these servers are a group in `Config::groups`
+    group: Option<Arc<str>>,
+    // Groups of these servers, by name
+    groups: HashMap<String, SharedBestServer<S>>,
 }
 
 type SharedBestServer<S> = Arc<BestServer<S>>;
 
+fn hash_of<T: Hash + ?Sized>(t: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    t.hash(&mut hasher);
+    hasher.finish()
+}
+
 impl<S: ServerData> BestServer<S> {
-    fn new(servers: Vec<SharedServerStatistic<S>>) -> BestServer<S> {
+    fn new(
+        servers: Vec<Arc<ServerStatistic<S>>>,
+        strategy: BalanceStrategy,
+        running: Arc<AtomicBool>,
+    ) -> BestServer<S> {
+        let mut ring = Vec::new();
+        if let BalanceStrategy::ConsistentHash = strategy {
+            for (idx, svr) in servers.iter().enumerate() {
+                let addr = svr.server_config().addr().to_string();
+                for replica in 0..CONSISTENT_HASH_REPLICAS * svr.weight() as usize {
+                    ring.push((hash_of(&(&addr, replica)), idx));
+                }
+            }
+            ring.sort();
+        }
+
         BestServer {
             servers,
             best_idx: AtomicUsize::new(0),
+            strategy,
+            next_idx: AtomicUsize::new(0),
+            ring,
+            running,
+            group: None,
+            groups: HashMap::new(),
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.running.load(Ordering::Acquire)
+    }
+
+    /// Stops probing tasks of these servers
+    fn stop(&self) {
+        self.running.store(false, Ordering::Release)
+    }
+
+    fn pick_server(&self, target: Option<&Address>) -> SharedServerStatistic<S> {
+        let idx = if self.servers.len() == 1 {
+            0
+        } else {
+            match self.strategy {
+                BalanceStrategy::LowestLatency => self.best_server_idx(),
+                BalanceStrategy::RoundRobin => self.pick_round_robin(),
+                BalanceStrategy::WeightedRandom => self.pick_weighted_random(),
+                BalanceStrategy::LeastConnections => self.pick_least_connections(),
+                BalanceStrategy::ConsistentHash => match target {
+                    Some(target) => self.pick_consistent_hash(target),
+                    None => self.best_server_idx(),
+                },
+            }
+        };
+        SharedServerStatistic::new(self.servers[idx].clone(), self.group.clone())
+    }
+
+    /// Servers that could be chosen
+    ///
+    /// Available servers in the lowest tier, backup servers are chosen only if all primary servers are down.
+    /// Servers in the lowest tier are candidates if none of them is available.
+    fn candidates(&self) -> impl Iterator<Item = (usize, &Arc<ServerStatistic<S>>)> {
+        let any_available = self.servers.iter().any(|s| s.is_available());
+        let tier = self
+            .servers
+            .iter()
+            .filter(|s| !any_available || s.is_available())
+            .map(|s| s.tier())
+            .min();
+
+        self.servers
+            .iter()
+            .enumerate()
+            .filter(move |(_, s)| (!any_available || s.is_available()) && Some(s.tier()) == tier)
+    }
+
+    fn pick_round_robin(&self) -> usize {
+        // Each server is chosen `weight` times in a round
+        let total = self.candidates().map(|(_, s)| s.weight()).sum::<u64>();
+        if total == 0 {
+            return self.best_server_idx();
         }
+
+        let mut n = self.next_idx.fetch_add(1, Ordering::Relaxed) as u64 % total;
+        for (idx, svr) in self.candidates() {
+            let w = svr.weight();
+            if n < w {
+                return idx;
+            }
+            n -= w;
+        }
+        self.best_server_idx()
     }
 
-    fn new_shared(servers: Vec<SharedServerStatistic<S>>) -> SharedBestServer<S> {
-        Arc::new(BestServer::new(servers))
+    fn pick_weighted_random(&self) -> usize {
+        // Lower score, higher weight
+        let weight = |svr: &ServerStatistic<S>| (MAX_SERVER_SCORE.saturating_sub(svr.last_score()) + 1) * svr.weight();
+
+        let total = self.candidates().map(|(_, s)| weight(s)).sum::<u64>();
+        let mut n = rand::thread_rng().gen_range(0, total);
+        for (idx, svr) in self.candidates() {
+            let w = weight(svr);
+            if n < w {
+                return idx;
+            }
+            n -= w;
+        }
+        self.best_server_idx()
     }
 
-    fn pick_server(&self) -> SharedServerStatistic<S> {
-        let idx = self.best_idx.load(Ordering::Relaxed);
-        self.servers[idx].clone()
+    fn pick_least_connections(&self) -> usize {
+        // Connections per weight, in 0.001 precision
+        self.candidates()
+            .min_by_key(|(_, s)| (s.active_connections() as u64 * 1000 / s.weight(), s.last_score()))
+            .map(|(idx, _)| idx)
+            .unwrap_or_else(|| self.best_server_idx())
+    }
+
+    fn pick_consistent_hash(&self, target: &Address) -> usize {
+        // Hash on host only, so that different ports of the same site go through the same server
+        let hash = match *target {
+            Address::SocketAddress(ref sa) => hash_of(&sa.ip()),
+            Address::DomainNameAddress(ref dn, _) => hash_of(dn.as_str()),
+        };
+
+        // The first virtual node clockwise, skipping servers that are not candidates
+        let start = match self.ring.binary_search(&(hash, 0)) {
+            Ok(pos) | Err(pos) => pos,
+        };
+        let candidates = self.candidates().map(|(idx, _)| idx).collect::<Vec<_>>();
+        for i in 0..self.ring.len() {
+            let (_, idx) = self.ring[(start + i) % self.ring.len()];
+            if candidates.contains(&idx) {
+                return idx;
+            }
+        }
+        self.best_server_idx()
+    }
+
+    /// Check if `server` is still the one that would be chosen
+    ///
+    /// The best server is preferred for `LowestLatency`, any of candidates for the other strategies
+    fn is_preferred(&self, server: &Arc<ServerStatistic<S>>) -> bool {
+        match self.strategy {
+            BalanceStrategy::LowestLatency if self.servers.len() > 1 => {
+                Arc::ptr_eq(&self.servers[self.best_server_idx()], server)
+            }
+            _ => self.candidates().any(|(_, s)| Arc::ptr_eq(s, server)),
+        }
+    }
+
+    fn pick_failover_server(&self, tried: &[SharedServerStatistic<S>]) -> Option<SharedServerStatistic<S>> {
+        let is_tried = |svr: &Arc<ServerStatistic<S>>| tried.iter().any(|t| Arc::ptr_eq(&t.stat, svr));
+
+        // Untried servers, available servers first, then servers in lower tiers and with lower scores
+        self.servers
+            .iter()
+            .filter(|s| !is_tried(s))
+            .min_by_key(|s| (!s.is_available(), s.tier(), s.last_score()))
+            .map(|s| SharedServerStatistic::new(s.clone(), self.group.clone()))
     }
 
     async fn recalculate_best_server(&self) -> Option<(usize, usize)> {
@@ -310,7 +598,7 @@ impl<S: ServerData> BestServer<S> {
         let mut best_idx = 0;
         let mut best_score = u64::max_value();
 
-        for (idx, svr) in self.servers.iter().enumerate() {
+        for (idx, svr) in self.candidates() {
             let score = svr.score().await;
             if score < best_score {
                 best_idx = idx;
@@ -333,29 +621,81 @@ impl<S: ServerData> BestServer<S> {
 }
 
 /// Load balancer based on pinging latencies of all servers
-#[derive(Clone)]
+///
+/// Servers are chosen by `Config::balance_strategy`
 pub struct PingBalancer<S: ServerData> {
-    best: SharedBestServer<S>,
+    // Replaced when servers are reloaded
+    best: Arc<SpinMutex<SharedBestServer<S>>>,
+}
+
+// Derived `Clone` requires `S: Clone`
+impl<S: ServerData> Clone for PingBalancer<S> {
+    fn clone(&self) -> PingBalancer<S> {
+        PingBalancer {
+            best: self.best.clone(),
+        }
+    }
 }
 
 impl<S: ServerData + 'static> PingBalancer<S> {
     /// Create a PingBalancer
+    ///
+    /// Servers will be replaced by `Context::reload_servers`
     pub async fn new(context: SharedContext, server_type: ServerType) -> PingBalancer<S> {
+        let mut servers_rx = context.subscribe_servers();
+
+        // Servers may be reloaded before this balancer is created
+        let servers_context = match *servers_rx.borrow() {
+            Some(ref c) => c.clone(),
+            None => context,
+        };
+        let best = PingBalancer::<S>::create_best_server(servers_context, server_type).await;
+        let best = Arc::new(SpinMutex::new(best));
+
+        {
+            let best = best.clone();
+
+            // Exits when context is dropped
+            tokio::spawn(async move {
+                while servers_rx.changed().await.is_ok() {
+                    let servers_context = match *servers_rx.borrow() {
+                        Some(ref c) => c.clone(),
+                        None => continue,
+                    };
+
+                    let new_best = PingBalancer::<S>::create_best_server(servers_context, server_type).await;
+                    let old_best = mem::replace(&mut *best.lock(), new_best);
+                    old_best.stop();
+
+                    info!("reloaded {} servers", server_type);
+                }
+            });
+        }
+
+        PingBalancer { best }
+    }
+
+    async fn create_best_server(context: SharedContext, server_type: ServerType) -> SharedBestServer<S> {
         let server_count = context.config().server.len();
         let mut servers = Vec::with_capacity(server_count);
 
+        let interval = context.config().health_check.interval;
+
         // Check only required if servers count > 1, otherwise, always use the first one
         let check_required = server_count > 1;
+        let probe_required = check_required && !server_type.probe(context.config()).is_disabled();
         // Barrier count = current + probing tasks
-        let check_barrier = Arc::new(Barrier::new(1 + server_count));
+        let check_barrier = Arc::new(Barrier::new(1 + if probe_required { server_count } else { 0 }));
+        let running = Arc::new(AtomicBool::new(true));
 
         for idx in 0..server_count {
             let stat = ServerStatistic::<S>::new_shared(context.clone(), idx);
 
-            if check_required {
+            if probe_required {
                 let stat = stat.clone();
                 let context = context.clone();
                 let check_barrier = check_barrier.clone();
+                let running = running.clone();
 
                 // Start a background task for probing
                 tokio::spawn(async move {
@@ -370,9 +710,9 @@ impl<S: ServerData + 'static> PingBalancer<S> {
 
                     check_barrier.wait().await;
 
-                    while context.server_running() {
+                    while context.server_running() && running.load(Ordering::Acquire) {
                         PingBalancer::<S>::check_update_score(&stat, server_type).await;
-                        time::sleep(Duration::from_secs(DEFAULT_CHECK_INTERVAL_SEC)).await;
+                        time::sleep(interval).await;
                     }
 
                     debug!(
@@ -386,52 +726,89 @@ impl<S: ServerData + 'static> PingBalancer<S> {
             servers.push(stat);
         }
 
-        let best = BestServer::new_shared(servers);
+        // Servers in groups share statistics and probing tasks with all servers
+        let strategy = context.config().balance_strategy;
+        let mut groups = HashMap::new();
+        for name in context.config().groups.keys() {
+            let idxs = context.config().servers_in_group(name).unwrap_or_default();
+            if idxs.is_empty() {
+                warn!("server group {} doesn't have any servers", name);
+                continue;
+            }
+
+            let group_servers = idxs.into_iter().map(|idx| servers[idx].clone()).collect();
+            let group = BestServer {
+                group: Some(Arc::from(name.as_str())),
+                ..BestServer::new(group_servers, strategy, running.clone())
+            };
+            groups.insert(name.clone(), Arc::new(group));
+        }
+
+        let best = Arc::new(BestServer {
+            groups,
+            ..BestServer::new(servers, strategy, running)
+        });
 
         if check_required {
             // Wait all tasks start (run at least one round)
             check_barrier.wait().await;
             trace!("all latency probing tasks are started, creating best server choosing task");
 
-            // Reinitialize a Barrier for waiting choosing task
-            let check_barrier = Arc::new(Barrier::new(2));
-            let best = best.clone();
+            PingBalancer::<S>::start_choosing_task(context.clone(), best.clone(), server_type).await;
+            for group in best.groups.values() {
+                if group.servers.len() > 1 {
+                    PingBalancer::<S>::start_choosing_task(context.clone(), group.clone(), server_type).await;
+                }
+            }
+        }
 
-            {
-                let context = context.clone();
-                let check_barrier = check_barrier.clone();
+        best
+    }
 
-                tokio::spawn(async move {
-                    // Check once for initializing data
-                    best.recalculate_best_server().await;
+    /// Start a task choosing the best server of `best`, returns after it has chosen at least once
+    async fn start_choosing_task(context: SharedContext, best: SharedBestServer<S>, server_type: ServerType) {
+        let interval = context.config().health_check.interval;
+        let check_barrier = Arc::new(Barrier::new(2));
 
-                    trace!(
-                        "started best server choosing task, chosen server index {}",
-                        best.best_server_idx()
-                    );
+        {
+            let check_barrier = check_barrier.clone();
 
-                    check_barrier.wait().await;
+            tokio::spawn(async move {
+                // Check once for initializing data
+                best.recalculate_best_server().await;
 
-                    while context.server_running() {
-                        if let Some((old_idx, new_idx)) = best.recalculate_best_server().await {
-                            info!(
-                                "switched {} server from {} to {}",
-                                server_type,
-                                context.server_config(old_idx).addr(),
-                                context.server_config(new_idx).addr()
-                            );
-                        }
-
-                        time::sleep(Duration::from_secs(DEFAULT_CHECK_INTERVAL_SEC)).await;
+                trace!(
+                    "started best server choosing task, chosen server index {}",
+                    best.best_server_idx()
+                );
+
+                check_barrier.wait().await;
+
+                let servers_name = match best.group {
+                    Some(ref group) => format!("{} server in group {}", server_type, group),
+                    None => format!("{} server", server_type),
+                };
+
+                while context.server_running() && best.is_running() {
+                    if let Some((old_idx, new_idx)) = best.recalculate_best_server().await {
+                        info!(
+                            "switched {} from {} to {}",
+                            servers_name,
+                            best.servers[old_idx].server_config().addr(),
+                            best.servers[new_idx].server_config().addr()
+                        );
+
+                        #[cfg(feature = "metrics")]
+                        context.metrics().incr_balancer_switch(&server_type.to_string());
                     }
-                });
-            }
 
-            // Wait for choosing task to check at least once
-            check_barrier.wait().await;
+                    time::sleep(interval).await;
+                }
+            });
         }
 
-        PingBalancer { best }
+        // Wait for choosing task to check at least once
+        check_barrier.wait().await;
     }
 
     async fn check_update_score(stat: &ServerStatistic<S>, server_type: ServerType) {
@@ -440,6 +817,13 @@ impl<S: ServerData + 'static> PingBalancer<S> {
             Err(..) => stat.push_score(Score::Errored).await, // Penalty
         };
 
+        #[cfg(feature = "metrics")]
+        stat.context().metrics().set_balancer_score(
+            &server_type.to_string(),
+            &stat.server_config().addr().to_string(),
+            score,
+        );
+
         debug!(
             "updated remote {} server {} (score: {})",
             server_type,
@@ -455,121 +839,103 @@ impl<S: ServerData + 'static> PingBalancer<S> {
         );
     }
 
-    /// Detect TCP connectivity with Chromium [Network Portal Detection](https://www.chromium.org/chromium-os/chromiumos-design-docs/network-portal-detection)
-    #[allow(dead_code)]
-    async fn check_request_tcp_chromium(stat: &ServerStatistic<S>) -> io::Result<()> {
-        static GET_BODY: &[u8] =
-            b"GET /generate_204 HTTP/1.1\r\nHost: clients3.google.com\r\nConnection: close\r\nAccept: */*\r\n\r\n";
+    /// Detect TCP connectivity by sending an HTTP GET request to `url`
+    async fn check_request_http(stat: &ServerStatistic<S>, url: &Url, expected_status: u16) -> io::Result<()> {
+        use std::io::{Error, ErrorKind};
 
-        let addr = Address::DomainNameAddress("clients3.google.com".to_owned(), 80);
+        let port = url.port_or_known_default().unwrap_or(80);
+        let addr = match url.host() {
+            Some(Host::Domain(dn)) => Address::DomainNameAddress(dn.to_owned(), port),
+            Some(Host::Ipv4(ip)) => Address::SocketAddress((ip, port).into()),
+            Some(Host::Ipv6(ip)) => Address::SocketAddress((ip, port).into()),
+            None => return Err(Error::new(ErrorKind::InvalidInput, "health check url without host")),
+        };
+
+        let host = &url[Position::BeforeHost..Position::AfterPort];
+        let path = &url[Position::BeforePath..Position::AfterQuery];
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nAccept: */*\r\n\r\n",
+            path, host
+        );
 
         let mut stream = TcpServerClient::connect(stat.clone_context(), &addr, stat.server_config()).await?;
-        stream.write_all(GET_BODY).await?;
+        stream.write_all(request.as_bytes()).await?;
 
         let mut reader = BufReader::new(stream);
 
         let mut buf = Vec::new();
         reader.read_until(b'\n', &mut buf).await?;
 
-        static EXPECTED_HTTP_STATUS_LINE: &[u8] = b"HTTP/1.1 204 No Content\r\n";
-        if buf != EXPECTED_HTTP_STATUS_LINE {
-            use std::io::{Error, ErrorKind};
+        // Status line: HTTP/1.1 200 OK
+        let status = str::from_utf8(&buf)
+            .ok()
+            .and_then(|line| line.split_whitespace().nth(1))
+            .and_then(|code| code.parse::<u16>().ok());
 
-            debug!(
-                "unexpected response from http://clients3.google.com/generate_204, {:?}",
-                ByteStr::new(&buf)
-            );
+        if status != Some(expected_status) {
+            debug!("unexpected response from {}, {:?}", url, ByteStr::new(&buf));
 
-            let err = Error::new(
-                ErrorKind::InvalidData,
-                "unexpected response from http://clients3.google.com/generate_204",
-            );
+            let err = Error::new(ErrorKind::InvalidData, format!("unexpected response from {}", url));
             return Err(err);
         }
 
         Ok(())
     }
 
-    /// Detect TCP connectivity with Firefox's http://detectportal.firefox.com/success.txt
-    async fn check_request_tcp_firefox(stat: &ServerStatistic<S>) -> io::Result<()> {
-        static GET_BODY: &[u8] =
-            b"GET /success.txt HTTP/1.1\r\nHost: detectportal.firefox.com\r\nConnection: close\r\nAccept: */*\r\n\r\n";
-
-        let addr = Address::DomainNameAddress("detectportal.firefox.com".to_owned(), 80);
-
-        let mut stream = TcpServerClient::connect(stat.clone_context(), &addr, stat.server_config()).await?;
-        stream.write_all(GET_BODY).await?;
-
-        let mut reader = BufReader::new(stream);
+    /// Detect TCP connectivity by connecting to `addr`
+    async fn check_request_tcp(stat: &ServerStatistic<S>, addr: &Address) -> io::Result<()> {
+        let _ = TcpServerClient::connect(stat.clone_context(), addr, stat.server_config()).await?;
+        Ok(())
+    }
 
-        let mut buf = Vec::new();
-        reader.read_until(b'\n', &mut buf).await?;
+    /// Detect TCP connectivity by sending a DNS query to `addr` in TCP
+    async fn check_request_dns_tcp(stat: &ServerStatistic<S>, addr: &Address) -> io::Result<()> {
+        let mut stream = TcpServerClient::connect(stat.clone_context(), addr, stat.server_config()).await?;
 
-        static EXPECTED_HTTP_STATUS_LINE: &[u8] = b"HTTP/1.1 200 OK\r\n";
-        if buf != EXPECTED_HTTP_STATUS_LINE {
-            use std::io::{Error, ErrorKind};
+        // DNS over TCP is prefixed with 2 bytes length
+        let mut query = Vec::with_capacity(2 + DNS_QUERY.len());
+        query.extend_from_slice(&(DNS_QUERY.len() as u16).to_be_bytes());
+        query.extend_from_slice(DNS_QUERY);
+        stream.write_all(&query).await?;
 
-            debug!(
-                "unexpected response from http://detectportal.firefox.com/success.txt, {:?}",
-                ByteStr::new(&buf)
-            );
+        let mut len_buf = [0u8; 2];
+        stream.read_exact(&mut len_buf).await?;
 
-            let err = Error::new(
-                ErrorKind::InvalidData,
-                "unexpected response from http://detectportal.firefox.com/success.txt",
-            );
-            return Err(err);
-        }
+        let mut dns_answer = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+        stream.read_exact(&mut dns_answer).await?;
 
-        Ok(())
+        check_dns_answer(addr, &dns_answer)
     }
 
-    async fn check_request_udp(stat: &ServerStatistic<S>) -> io::Result<()> {
-        // TransactionID: 0x1234
-        // Flags: 0x0100 RD
-        // Questions: 0x0001
-        // Answer RRs: 0x0000
-        // Authority RRs: 0x0000
-        // Additional RRs: 0x0000
-        // Queries
-        //    - QNAME: \x07 firefox \x03 com \x00
-        //    - QTYPE: 0x0001 A
-        //    - QCLASS: 0x0001 IN
-        static DNS_QUERY: &[u8] =
-            b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x07firefox\x03com\x00\x00\x01\x00\x01";
-
-        let addr = Address::SocketAddress(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)), 53));
-
+    /// Detect UDP connectivity by sending a DNS query to `addr`
+    async fn check_request_dns_udp(stat: &ServerStatistic<S>, addr: &Address) -> io::Result<()> {
         let client = UdpServerClient::new(stat.context(), stat.server_config()).await?;
-        client.send_to(stat.context(), &addr, DNS_QUERY).await?;
+        client.send_to(stat.context(), addr, DNS_QUERY).await?;
 
         let (_, dns_answer) = client.recv_from(stat.context()).await?;
 
-        // DNS packet must have at least 6 * 2 bytes
-        if dns_answer.len() < 12 || &dns_answer[0..2] != b"\x12\x34" {
-            use std::io::{Error, ErrorKind};
-
-            debug!("unexpected response from 8.8.8.8:53, {:?}", ByteStr::new(&dns_answer));
-
-            let err = Error::new(ErrorKind::InvalidData, "unexpected response from 8.8.8.8:53");
-            return Err(err);
-        }
-
-        Ok(())
+        check_dns_answer(addr, &dns_answer)
     }
 
     async fn check_request(stat: &ServerStatistic<S>, server_type: ServerType) -> io::Result<()> {
-        match server_type {
-            ServerType::Tcp => PingBalancer::<S>::check_request_tcp_firefox(stat).await,
-            ServerType::Udp => PingBalancer::<S>::check_request_udp(stat).await,
+        match *server_type.probe(stat.config()) {
+            HealthCheckProbe::Http {
+                ref url,
+                expected_status,
+            } => PingBalancer::<S>::check_request_http(stat, url, expected_status).await,
+            HealthCheckProbe::Tcp(ref addr) => PingBalancer::<S>::check_request_tcp(stat, addr).await,
+            HealthCheckProbe::Dns(ref addr) => match server_type {
+                ServerType::Tcp => PingBalancer::<S>::check_request_dns_tcp(stat, addr).await,
+                ServerType::Udp => PingBalancer::<S>::check_request_dns_udp(stat, addr).await,
+            },
+            HealthCheckProbe::Disabled => Ok(()),
         }
     }
 
     async fn check_delay(stat: &ServerStatistic<S>, server_type: ServerType) -> io::Result<u64> {
         let start = Instant::now();
 
-        // Send HTTP GET and read the first byte
-        let timeout = Duration::from_secs(DEFAULT_CHECK_TIMEOUT_SEC);
+        let timeout = stat.config().health_check.timeout;
         let res = time::timeout(timeout, PingBalancer::<S>::check_request(stat, server_type)).await;
 
         let elapsed = Instant::now() - start;
@@ -598,26 +964,80 @@ impl<S: ServerData + 'static> PingBalancer<S> {
             }
             Err(..) => {
                 // Timeout
-                trace!(
+                debug!(
                     "checked remote {} server {} latency timeout, elapsed {} ms",
                     server_type,
                     stat.server_config().addr(),
                     elapsed
                 );
 
-                // NOTE: timeout is still available, but server is too slow
-                Ok(elapsed)
+                // NOTE: server that doesn't answer in time is counted as down, backup servers could take over
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("health check timed out after {} ms", elapsed),
+                ))
             }
         }
     }
 }
 
 impl<S: ServerData> PingBalancer<S> {
-    /// Pick the best server with current known statistic data
+    /// Pick a server with current known statistic data
     ///
-    /// Return a `Arc` shared server statistic reference
+    /// Consistent hashing falls back to the server with the lowest latency, because target is unknown
     pub fn pick_server(&self) -> SharedServerStatistic<S> {
-        self.best.pick_server()
+        self.best.lock().pick_server(None)
+    }
+
+    /// Pick a server for connecting to `target`
+    pub fn pick_server_for(&self, target: &Address) -> SharedServerStatistic<S> {
+        self.best.lock().pick_server(Some(target))
+    }
+
+    /// Pick a server in group `name` of `Config::groups` for connecting to `target`
+    ///
+    /// Returns `None` if the group is not configured or doesn't have any servers
+    pub fn pick_server_in_group(&self, name: &str, target: &Address) -> Option<SharedServerStatistic<S>> {
+        let best = self.best.lock();
+        best.groups.get(name).map(|g| g.pick_server(Some(target)))
+    }
+
+    /// Pick a server replacing `server` for connecting to `target`, if `server` is not preferred anymore
+    ///
+    /// Servers are replaced if they are down, or the best server changes, or servers in lower tiers recover.
+    /// Returns `None` if `server` should be kept
+    pub fn pick_replacement(
+        &self,
+        server: &SharedServerStatistic<S>,
+        target: &Address,
+    ) -> Option<SharedServerStatistic<S>> {
+        let best = self.best.lock();
+        let best = match server.group.as_deref() {
+            Some(name) => best.groups.get(name)?,
+            None => &*best,
+        };
+
+        if best.is_preferred(&server.stat) {
+            return None;
+        }
+
+        let next = best.pick_server(Some(target));
+        if Arc::ptr_eq(&next.stat, &server.stat) {
+            None
+        } else {
+            Some(next)
+        }
+    }
+
+    /// Pick another server for retrying after failed with servers in `tried`
+    ///
+    /// Servers are picked from the same group as `tried`. Returns `None` if all servers are tried
+    pub fn pick_failover_server(&self, tried: &[SharedServerStatistic<S>]) -> Option<SharedServerStatistic<S>> {
+        let best = self.best.lock();
+        match tried.first().and_then(|t| t.group.as_deref()) {
+            Some(name) => best.groups.get(name)?.pick_failover_server(tried),
+            None => best.pick_failover_server(tried),
+        }
     }
 }
 
@@ -635,3 +1055,288 @@ pub type PlainPingBalancer = PingBalancer<EmptyServerData>;
 
 /// Shared PlainServerStatistic
 pub type SharedPlainServerStatistic = SharedServerStatistic<EmptyServerData>;
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        time::Duration,
+    };
+
+    use tokio::net::TcpListener;
+
+    use crate::{
+        config::{ConfigType, ServerAddr},
+        crypto::v1::CipherKind,
+    };
+
+    use super::*;
+
+    fn server_config(port: u16) -> ServerConfig {
+        ServerConfig::basic(
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port),
+            "test-password".to_owned(),
+            CipherKind::AES_256_GCM,
+        )
+    }
+
+    async fn best_server(servers: Vec<ServerConfig>, strategy: BalanceStrategy) -> BestServer<EmptyServerData> {
+        let mut config = Config::new(ConfigType::Socks5Local);
+        config.server = servers;
+        config.balance_strategy = strategy;
+        let context = Context::new_shared(config).await;
+
+        let servers = (0..context.config().server.len())
+            .map(|idx| ServerStatistic::new_shared(context.clone(), idx))
+            .collect();
+        BestServer::new(servers, strategy, Arc::new(AtomicBool::new(true)))
+    }
+
+    fn set_down(best: &BestServer<EmptyServerData>, idx: usize, down: bool) {
+        let failures = if down {
+            best.servers[idx].config().health_check.failure_threshold
+        } else {
+            0
+        };
+        best.servers[idx].failures.store(failures, Ordering::Relaxed);
+    }
+
+    fn picked_port(server: &ServerStatistic<EmptyServerData>) -> u16 {
+        match *server.server_config().addr() {
+            ServerAddr::SocketAddr(ref sa) => sa.port(),
+            ServerAddr::DomainName(_, port) => port,
+        }
+    }
+
+    fn target(host: &str) -> Address {
+        Address::DomainNameAddress(host.to_owned(), 443)
+    }
+
+    #[tokio::test]
+    async fn round_robin_by_weight() {
+        let mut weighted = server_config(8002);
+        weighted.set_weight(2);
+        let best = best_server(vec![server_config(8001), weighted], BalanceStrategy::RoundRobin).await;
+
+        let mut ports = (0..6).map(|_| picked_port(&best.pick_server(None))).collect::<Vec<_>>();
+        ports.sort();
+        assert_eq!(ports, [8001, 8001, 8002, 8002, 8002, 8002]);
+    }
+
+    #[tokio::test]
+    async fn least_connections() {
+        let best = best_server(
+            vec![server_config(8001), server_config(8002)],
+            BalanceStrategy::LeastConnections,
+        )
+        .await;
+
+        let first = best.pick_server(None);
+        let second = best.pick_server(None);
+        assert_ne!(picked_port(&first), picked_port(&second));
+
+        // Connection is closed, the server is chosen again
+        let port = picked_port(&first);
+        drop(first);
+        assert_eq!(picked_port(&best.pick_server(None)), port);
+    }
+
+    #[tokio::test]
+    async fn consistent_hash_skips_unavailable_servers() {
+        let servers = vec![server_config(8001), server_config(8002), server_config(8003)];
+        let best = best_server(servers, BalanceStrategy::ConsistentHash).await;
+
+        let port = picked_port(&best.pick_server(Some(&target("www.example.com"))));
+        for _ in 0..10 {
+            assert_eq!(picked_port(&best.pick_server(Some(&target("www.example.com")))), port);
+        }
+
+        let idx = best.servers.iter().position(|s| picked_port(s) == port).unwrap();
+        set_down(&best, idx, true);
+        assert_ne!(picked_port(&best.pick_server(Some(&target("www.example.com")))), port);
+    }
+
+    #[tokio::test]
+    async fn priority_and_backup() {
+        let mut secondary = server_config(8002);
+        secondary.set_priority(1);
+        let mut backup = server_config(8003);
+        backup.set_backup(true);
+
+        for &strategy in &[
+            BalanceStrategy::RoundRobin,
+            BalanceStrategy::WeightedRandom,
+            BalanceStrategy::LeastConnections,
+            BalanceStrategy::ConsistentHash,
+        ] {
+            let servers = vec![server_config(8001), secondary.clone(), backup.clone()];
+            let best = best_server(servers, strategy).await;
+            let pick = || picked_port(&best.pick_server(Some(&target("www.example.com"))));
+
+            assert_eq!(pick(), 8001);
+
+            set_down(&best, 0, true);
+            assert_eq!(pick(), 8002);
+
+            // Backup servers are chosen only if all primary servers are down
+            set_down(&best, 1, true);
+            assert_eq!(pick(), 8003);
+
+            // Primary server is back
+            set_down(&best, 1, false);
+            assert_eq!(pick(), 8002);
+
+            // None is available, servers in the lowest tier are chosen
+            set_down(&best, 1, true);
+            set_down(&best, 2, true);
+            assert_eq!(pick(), 8001);
+        }
+    }
+
+    #[tokio::test]
+    async fn failover_prefers_available_servers() {
+        let mut backup = server_config(8003);
+        backup.set_backup(true);
+        let best = best_server(
+            vec![server_config(8001), server_config(8002), backup],
+            BalanceStrategy::RoundRobin,
+        )
+        .await;
+
+        set_down(&best, 1, true);
+
+        let first = best.pick_server(None);
+        assert_eq!(picked_port(&first), 8001);
+
+        let mut tried = vec![first];
+        let next = best.pick_failover_server(&tried).unwrap();
+        assert_eq!(picked_port(&next), 8003);
+
+        tried.push(next);
+        let next = best.pick_failover_server(&tried).unwrap();
+        assert_eq!(picked_port(&next), 8002);
+
+        tried.push(next);
+        assert!(best.pick_failover_server(&tried).is_none());
+    }
+
+    #[tokio::test]
+    async fn replaced_if_not_preferred() {
+        let mut backup = server_config(8003);
+        backup.set_backup(true);
+        let best = best_server(
+            vec![server_config(8001), server_config(8002), backup],
+            BalanceStrategy::RoundRobin,
+        )
+        .await;
+        let balancer = PingBalancer {
+            best: Arc::new(SpinMutex::new(Arc::new(best))),
+        };
+        let target = target("www.example.com");
+        let best = balancer.best.lock().clone();
+
+        let server = balancer.pick_server_for(&target);
+        assert!(balancer.pick_replacement(&server, &target).is_none());
+
+        // Server is down
+        set_down(&best, 0, true);
+        let server = balancer.pick_replacement(&server, &target).unwrap();
+        assert_eq!(picked_port(&server), 8002);
+
+        // All primary servers are down
+        set_down(&best, 1, true);
+        let server = balancer.pick_replacement(&server, &target).unwrap();
+        assert_eq!(picked_port(&server), 8003);
+        assert!(balancer.pick_replacement(&server, &target).is_none());
+
+        // Primary server recovers
+        set_down(&best, 0, false);
+        let server = balancer.pick_replacement(&server, &target).unwrap();
+        assert_eq!(picked_port(&server), 8001);
+    }
+
+    #[tokio::test]
+    async fn groups() {
+        let mut config = Config::new(ConfigType::Socks5Local);
+        for port in 8001..=8003 {
+            let mut svr_cfg = server_config(port);
+            svr_cfg.set_id(format!("server-{}", port));
+            config.server.push(svr_cfg);
+        }
+        config
+            .groups
+            .insert("g".to_owned(), vec!["server-8002".to_owned(), "server-8003".to_owned()]);
+        config.balance_strategy = BalanceStrategy::RoundRobin;
+        config.health_check.tcp_probe = HealthCheckProbe::Disabled;
+        let context = Context::new_shared(config).await;
+
+        let balancer = PlainPingBalancer::new(context, ServerType::Tcp).await;
+        assert!(balancer
+            .pick_server_in_group("unknown", &target("www.example.com"))
+            .is_none());
+
+        let mut ports = (0..4)
+            .map(|_| picked_port(&balancer.pick_server_in_group("g", &target("www.example.com")).unwrap()))
+            .collect::<Vec<_>>();
+        ports.sort();
+        assert_eq!(ports, [8002, 8002, 8003, 8003]);
+
+        // Failover servers are in the same group
+        let first = balancer.pick_server_in_group("g", &target("www.example.com")).unwrap();
+        let first_port = picked_port(&first);
+        assert_eq!(first.group(), Some("g"));
+        let mut tried = vec![first];
+        let next = balancer.pick_failover_server(&tried).unwrap();
+        assert_eq!(picked_port(&next), if first_port == 8002 { 8003 } else { 8002 });
+        assert_eq!(next.group(), Some("g"));
+        tried.push(next);
+        assert!(balancer.pick_failover_server(&tried).is_none());
+    }
+
+    #[tokio::test]
+    async fn unanswered_primary_fails_over_to_backup() {
+        // Primary accepts connections, but never answers
+        let primary = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let primary_addr = primary.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = primary.accept().await {
+                held.push(stream);
+            }
+        });
+
+        // Backup answers every probe
+        let backup = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backup_addr = backup.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = backup.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    if stream.read(&mut buf).await.is_ok() {
+                        let _ = stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await;
+                    }
+                });
+            }
+        });
+
+        let mut config = Config::new(ConfigType::Socks5Local);
+        let mut backup_cfg = ServerConfig::basic(backup_addr, String::new(), CipherKind::NONE);
+        backup_cfg.set_backup(true);
+        config.server = vec![
+            ServerConfig::basic(primary_addr, String::new(), CipherKind::NONE),
+            backup_cfg,
+        ];
+        config.health_check.tcp_probe = HealthCheckProbe::Http {
+            url: Url::parse("http://127.0.0.1/generate_204").unwrap(),
+            expected_status: 204,
+        };
+        config.health_check.timeout = Duration::from_millis(500);
+        config.health_check.interval = Duration::from_secs(60);
+        let context = Context::new_shared(config).await;
+
+        // Returns after all servers are probed once
+        let balancer = PlainPingBalancer::new(context, ServerType::Tcp).await;
+
+        assert_eq!(picked_port(&balancer.pick_server()), backup_addr.port());
+    }
+}
@@ -2,20 +2,37 @@
 
 use std::io::{self, ErrorKind};
 
-use futures::{future::select_all, FutureExt};
-use log::{debug, error, trace, warn};
+use futures::{future, FutureExt};
+use log::{debug, error, info, trace, warn};
+use tokio::{
+    sync::mpsc,
+    time::{self, Instant, Interval},
+};
 
-#[cfg(feature = "local-flow-stat")]
-use crate::context::SharedContext;
 use crate::{
-    config::{Config, ConfigType},
-    context::{Context, ServerState},
+    config::{Config, ConfigType, OnlineConfig},
+    context::{Context, ServerState, SharedContext},
     plugin::{PluginMode, Plugins},
-    relay::{tcprelay::local::run as run_tcp, udprelay::local::run as run_udp, utils::set_nofile},
+    relay::{
+        control::{self, ServerControl},
+        online_config::{self, OnlineServers},
+        tcprelay::local::run as run_tcp,
+        udprelay::local::run as run_udp,
+        utils::set_nofile,
+    },
 };
 
 /// Relay server running under local environment.
-pub async fn run(mut config: Config) -> io::Result<()> {
+pub async fn run(config: Config) -> io::Result<()> {
+    run_with_control(config, ServerControl::default()).await
+}
+
+/// Relay server running under local environment, controlled by `control`
+///
+/// ACL and servers are replaced by configurations received from `control.reload_rx`,
+/// servers in `config.online_config` are fetched again and replaced periodically.
+/// Returns `Ok` after shutting down by `control.shutdown_rx`.
+pub async fn run_with_control(mut config: Config, control: ServerControl) -> io::Result<()> {
     trace!("initializing local server with {:?}", config);
 
     assert!(config.config_type.is_local());
@@ -43,6 +60,42 @@ pub async fn run(mut config: Config) -> io::Result<()> {
         }
     }
 
+    // Servers in online configuration are appended to servers in configuration
+    let online_servers = match config.online_config {
+        Some(ref online_config) => {
+            // Only for resolving and connecting to host of the URL, metrics of servers are registered later
+            let context = Context::new_shared(config.clone()).await;
+            match online_config::load_servers(&context, online_config).await {
+                Ok(servers) => {
+                    info!(
+                        "loaded {} servers from online configuration {}",
+                        servers.servers.len(),
+                        online_config.url
+                    );
+                    config.server.extend(servers.servers.iter().cloned());
+                    Some(servers)
+                }
+                Err(err) => {
+                    error!(
+                        "failed to load online configuration from {}, {}",
+                        online_config.url, err
+                    );
+
+                    // Start with servers in configuration, online configuration will be fetched again later
+                    if config.server.is_empty() {
+                        return Err(err);
+                    }
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+
+    if config.server.is_empty() {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "missing servers"));
+    }
+
     let config_type = config.config_type;
     let mode = config.mode;
 
@@ -50,6 +103,8 @@ pub async fn run(mut config: Config) -> io::Result<()> {
     let state = ServerState::new_shared(&config).await;
 
     let mut vf = Vec::new();
+    // Kept running while draining connections
+    let mut relay_vf = Vec::new();
 
     let enable_tcp = match config_type {
         // Socks5 always true, because UDP associate command also requires a TCP connection
@@ -93,7 +148,7 @@ pub async fn run(mut config: Config) -> io::Result<()> {
 
         if config.has_server_plugins() {
             let plugins = Plugins::launch_plugins(&mut config, PluginMode::Client).await?;
-            vf.push(plugins.join_all().boxed());
+            relay_vf.push(plugins.join_all().boxed());
         }
 
         let context = Context::new_with_state_shared(config, state);
@@ -119,7 +174,7 @@ pub async fn run(mut config: Config) -> io::Result<()> {
         // Run UDP relay before starting plugins
         // Because plugins doesn't support UDP relay
         let udp_fut = run_udp(context.clone());
-        vf.push(udp_fut.boxed());
+        relay_vf.push(udp_fut.boxed());
     }
 
     #[cfg(feature = "local-dns")]
@@ -131,6 +186,19 @@ pub async fn run(mut config: Config) -> io::Result<()> {
         vf.push(dns_relay.boxed());
     }
 
+    if control.reload_rx.is_some() || context.config().online_config.is_some() {
+        let reload_fut = reload_task(context.clone(), control.reload_rx, online_servers);
+        vf.push(reload_fut.boxed());
+    }
+
+    #[cfg(feature = "metrics")]
+    if let Some(metrics_addr) = context.config().metrics_addr {
+        use crate::relay::metrics::run as run_metrics;
+
+        let metrics_fut = run_metrics(metrics_addr, context.metrics().clone());
+        vf.push(metrics_fut.boxed());
+    }
+
     #[cfg(feature = "local-flow-stat")]
     if context.config().stat_path.is_some() {
         // For Android's flow statistic
@@ -139,13 +207,119 @@ pub async fn run(mut config: Config) -> io::Result<()> {
         vf.push(report_fut.boxed());
     }
 
-    let (res, ..) = select_all(vf.into_iter()).await;
-    error!("one of servers exited unexpectly, result: {:?}", res);
+    control::serve(context, vf, relay_vf, control.shutdown_rx).await
+}
+
+async fn reload_task(
+    context: SharedContext,
+    mut reload_rx: Option<mpsc::Receiver<Config>>,
+    mut online_servers: Option<OnlineServers>,
+) -> io::Result<()> {
+    // Servers in online configuration are appended to servers in `config` when reloading
+    let mut config = context.config().clone();
+    if let Some(ref online) = online_servers {
+        let n = config.server.len() - online.servers.len();
+        config.server.truncate(n);
+    }
+
+    let mut online_config = context.config().online_config.clone();
+    let mut update_interval = online_config.as_ref().map(online_config_interval);
+
+    loop {
+        // Nothing is applied unless servers are reloaded successfully
+        let (new_config, new_online_servers, reload_acl) = tokio::select! {
+            c = async {
+                match reload_rx {
+                    Some(ref mut rx) => rx.recv().await,
+                    None => future::pending().await,
+                }
+            } => match c {
+                Some(c) if c.online_config == online_config => (c, online_servers.clone(), true),
+                Some(c) => {
+                    // Online configuration is changed, servers are loaded from the new one
+                    let new_online_servers = match c.online_config {
+                        Some(ref new_online_config) => {
+                            match online_config::load_servers(&context, new_online_config).await {
+                                Ok(servers) => Some(servers),
+                                Err(err) => {
+                                    error!(
+                                        "failed to load online configuration from {}, {}",
+                                        new_online_config.url, err
+                                    );
+                                    None
+                                }
+                            }
+                        }
+                        None => None,
+                    };
+                    (c, new_online_servers, true)
+                }
+                None => {
+                    // Keep serving without reloading
+                    reload_rx = None;
+                    continue;
+                }
+            },
+            _ = async {
+                match update_interval {
+                    Some(ref mut i) => i.tick().await,
+                    None => future::pending().await,
+                }
+            } => {
+                let online_config = online_config.as_ref().expect("online_config");
+                match online_config::fetch_servers(&context, online_config).await {
+                    Ok(servers) => {
+                        if online_servers.as_ref().map(|s| &s.document) == Some(&servers.document) {
+                            debug!("online configuration {} is not changed", online_config.url);
+                            continue;
+                        }
+                        (config.clone(), Some(servers), false)
+                    }
+                    Err(err) => {
+                        error!("failed to fetch online configuration from {}, {}", online_config.url, err);
+                        continue;
+                    }
+                }
+            }
+        };
+
+        let mut servers_config = new_config.clone();
+        if let Some(ref online) = new_online_servers {
+            servers_config.server.extend(online.servers.iter().cloned());
+        }
+
+        if let Err(err) = context.reload_servers(servers_config) {
+            if reload_acl {
+                error!("failed to reload configuration, ACL and servers are kept, {}", err);
+            } else {
+                error!("failed to reload servers from online configuration, {}", err);
+            }
+            continue;
+        }
+
+        if reload_acl {
+            context.set_acl(new_config.acl.clone()).await;
+            info!("reloaded ACL and servers");
+        } else {
+            info!("reloaded servers from online configuration");
+        }
+
+        if new_config.online_config != online_config {
+            online_config = new_config.online_config.clone();
+            update_interval = online_config.as_ref().map(online_config_interval);
+        }
 
-    // Tells all detached tasks to exit
-    context.set_server_stopped();
+        config = new_config;
+        online_servers = new_online_servers;
+    }
+}
 
-    Err(io::Error::new(io::ErrorKind::Other, "server exited unexpectly"))
+/// Interval of fetching `online_config` again, starts after one period
+fn online_config_interval(online_config: &OnlineConfig) -> Interval {
+    time::interval_at(
+        Instant::now() + online_config.update_interval,
+        online_config.update_interval,
+    )
 }
 
 #[cfg(feature = "local-flow-stat")]
@@ -0,0 +1,422 @@
+//! HTTP API for manager
+//!
+//! * `GET /servers` - Lists all current running servers
+//! * `GET /servers/{port}` - Gets server listening on `port`
+//! * `POST /servers/{port}` - Starts a server instance on `port`, body is the same as `add` command's parameter
+//! * `DELETE /servers/{port}` - Deletes server listening on `port`
+//! * `GET /stats` - Lists all servers' total TCP and UDP traffic, which is never reset, even if `stat_reset` is enabled
+//!
+//! Errors are reported in JSON `{"error":"..."}` with status code
+//!
+//! If a token is configured, every request must carry it in `Authorization: Bearer <token>` header,
+//! otherwise it is rejected with `401 Unauthorized`
+
+use std::{
+    convert::Infallible,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use hyper::{
+    body::HttpBody,
+    header::{self, HeaderValue},
+    service::{make_service_fn, service_fn},
+    Body,
+    Method,
+    Request,
+    Response,
+    Server,
+    StatusCode,
+};
+use log::{debug, error, info, trace};
+use serde_json::{json, Value};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::relay::utils::constant_time_eq;
+
+use super::{ManagerRequest, STAT_TOTAL_ACTION};
+
+/// Maximum size of request bodies, larger ones are rejected with `413 Payload Too Large`
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Starts HTTP API server on `bind_addr`, requests are forwarded to `ManagerService` by `tx`
+///
+/// Requests without a matching `token` are rejected
+pub async fn run(bind_addr: SocketAddr, token: Option<String>, tx: mpsc::Sender<ManagerRequest>) -> io::Result<()> {
+    let token = Arc::new(token);
+
+    let make_service = make_service_fn(move |_| {
+        let tx = tx.clone();
+        let token = token.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let tx = tx.clone();
+                let token = token.clone();
+                async move {
+                    trace!("HTTP API {} {}", req.method(), req.uri());
+
+                    let result = match check_authorization(&req, token.as_deref()) {
+                        Ok(()) => dispatch_request(req, &tx).await,
+                        Err(err) => Err(err),
+                    };
+
+                    let resp = match result {
+                        Ok(resp) => resp,
+                        Err(err) => {
+                            debug!("HTTP API request failed with {}, error: {}", err.status, err.message);
+                            err.into_response()
+                        }
+                    };
+                    Ok::<_, Infallible>(resp)
+                }
+            }))
+        }
+    });
+
+    let server = match Server::try_bind(&bind_addr) {
+        Ok(builder) => builder.tcp_sleep_on_accept_errors(true).serve(make_service),
+        Err(err) => {
+            let err = io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("failed to bind {}, {}", bind_addr, err),
+            );
+            return Err(err);
+        }
+    };
+
+    info!("shadowsocks manager HTTP API listening on {}", server.local_addr());
+
+    if let Err(err) = server.await {
+        error!("manager HTTP API server exited with error: {}", err);
+        return Err(io::Error::new(ErrorKind::Other, err));
+    }
+
+    Ok(())
+}
+
+/// Error response of HTTP API
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new<M: Into<String>>(status: StatusCode, message: M) -> ApiError {
+        ApiError {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn into_response(self) -> Response<Body> {
+        let mut resp = json_response(self.status, &json!({ "error": self.message }));
+        if self.status == StatusCode::UNAUTHORIZED {
+            resp.headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        resp
+    }
+}
+
+impl From<io::Error> for ApiError {
+    fn from(err: io::Error) -> ApiError {
+        let status = match err.kind() {
+            ErrorKind::InvalidData | ErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        ApiError::new(status, err.to_string())
+    }
+}
+
+fn json_response(status: StatusCode, value: &Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(value.to_string()))
+        .unwrap()
+}
+
+/// Checks `Authorization: Bearer <token>` header of `req`
+fn check_authorization(req: &Request<Body>, token: Option<&str>) -> Result<(), ApiError> {
+    let token = match token {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+
+    let provided = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    match provided {
+        Some(p) if constant_time_eq(p.trim().as_bytes(), token.as_bytes()) => Ok(()),
+        _ => Err(ApiError::new(StatusCode::UNAUTHORIZED, "unauthorized")),
+    }
+}
+
+async fn dispatch_request(req: Request<Body>, tx: &mpsc::Sender<ManagerRequest>) -> Result<Response<Body>, ApiError> {
+    let path = req.uri().path().trim_end_matches('/').to_owned();
+    let segments = path.split('/').skip(1).collect::<Vec<_>>();
+
+    match segments.as_slice() {
+        ["servers"] => match *req.method() {
+            Method::GET => {
+                let servers = send_json_command(tx, "list", String::new()).await?;
+                Ok(json_response(StatusCode::OK, &servers))
+            }
+            _ => Err(ApiError::new(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")),
+        },
+        ["servers", port] => {
+            let port = match port.parse::<u16>() {
+                Ok(p) => p,
+                Err(..) => {
+                    return Err(ApiError::new(
+                        StatusCode::BAD_REQUEST,
+                        format!("invalid port \"{}\"", port),
+                    ))
+                }
+            };
+
+            match *req.method() {
+                Method::GET => {
+                    let server = get_server(tx, port).await?;
+                    Ok(json_response(StatusCode::OK, &server))
+                }
+                Method::POST => {
+                    add_server(req, tx, port).await?;
+
+                    let server = get_server(tx, port).await?;
+                    Ok(json_response(StatusCode::CREATED, &server))
+                }
+                Method::DELETE => {
+                    // Respond 404 if server doesn't exist
+                    let _ = get_server(tx, port).await?;
+
+                    let param = json!({ "server_port": port }).to_string();
+                    send_command(tx, "remove", param).await?;
+
+                    let mut resp = Response::new(Body::empty());
+                    *resp.status_mut() = StatusCode::NO_CONTENT;
+                    Ok(resp)
+                }
+                _ => Err(ApiError::new(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")),
+            }
+        }
+        ["stats"] => match *req.method() {
+            Method::GET => {
+                // Reading it shouldn't consume traffic counted for `stat` command
+                let stats = send_json_command(tx, STAT_TOTAL_ACTION, String::new()).await?;
+                Ok(json_response(StatusCode::OK, &stats))
+            }
+            _ => Err(ApiError::new(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")),
+        },
+        _ => Err(ApiError::new(StatusCode::NOT_FOUND, "not found")),
+    }
+}
+
+async fn get_server(tx: &mpsc::Sender<ManagerRequest>, port: u16) -> Result<Value, ApiError> {
+    let servers = send_json_command(tx, "list", String::new()).await?;
+
+    if let Value::Array(servers) = servers {
+        for server in servers {
+            if server.get("server_port").and_then(Value::as_u64) == Some(port as u64) {
+                return Ok(server);
+            }
+        }
+    }
+
+    Err(ApiError::new(
+        StatusCode::NOT_FOUND,
+        format!("server on port {} not found", port),
+    ))
+}
+
+async fn add_server(req: Request<Body>, tx: &mpsc::Sender<ManagerRequest>, port: u16) -> Result<(), ApiError> {
+    let body = read_body(req).await?;
+
+    let mut server = match serde_json::from_slice::<Value>(&body) {
+        Ok(Value::Object(o)) => o,
+        Ok(..) => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "server config must be a JSON object",
+            ))
+        }
+        Err(err) => return Err(ApiError::new(StatusCode::BAD_REQUEST, err.to_string())),
+    };
+
+    // Port in path always overrides the one in body
+    server.insert("server_port".to_owned(), Value::from(port));
+
+    send_command(tx, "add", Value::Object(server).to_string()).await?;
+    Ok(())
+}
+
+/// Reads body of `req`, stops as soon as it is larger than `MAX_BODY_SIZE`
+async fn read_body(req: Request<Body>) -> Result<Vec<u8>, ApiError> {
+    let too_large = || {
+        let msg = format!("request body is larger than {} bytes", MAX_BODY_SIZE);
+        ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, msg)
+    };
+
+    let content_length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if content_length.map_or(false, |n| n > MAX_BODY_SIZE) {
+        return Err(too_large());
+    }
+
+    let mut body = req.into_body();
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+            Ok(c) => c,
+            Err(err) => return Err(ApiError::new(StatusCode::BAD_REQUEST, err.to_string())),
+        };
+        if buf.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(too_large());
+        }
+        buf.extend_from_slice(&chunk);
+    }
+
+    Ok(buf)
+}
+
+/// Sends command to `ManagerService` and parses its response as JSON
+async fn send_json_command(tx: &mpsc::Sender<ManagerRequest>, action: &str, param: String) -> Result<Value, ApiError> {
+    let resp = send_command(tx, action, param).await?;
+
+    match serde_json::from_slice(&resp) {
+        Ok(v) => Ok(v),
+        Err(err) => Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, err.to_string())),
+    }
+}
+
+/// Sends command to `ManagerService`, the same as commands received from the manager socket
+async fn send_command(tx: &mpsc::Sender<ManagerRequest>, action: &str, param: String) -> Result<Vec<u8>, ApiError> {
+    let (reply_tx, reply_rx) = oneshot::channel();
+
+    let req = ManagerRequest {
+        action: action.to_owned(),
+        param,
+        reply: reply_tx,
+    };
+
+    if tx.send(req).await.is_err() {
+        return Err(ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "manager is not running"));
+    }
+
+    match reply_rx.await {
+        Ok(Ok(resp)) => Ok(resp.unwrap_or_default()),
+        Ok(Err(err)) => Err(ApiError::from(err)),
+        Err(..) => Err(ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "manager is not running")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::{super::ManagerService, *};
+    use crate::{
+        config::{Config, ConfigType, ManagerAddr, ManagerConfig},
+        context::{Context, ServerState},
+    };
+
+    /// Starts a `ManagerService` handling requests of the HTTP API
+    async fn start_service() -> mpsc::Sender<ManagerRequest> {
+        let bind_addr = ManagerAddr::SocketAddr(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0));
+
+        let mut config = Config::new(ConfigType::Manager);
+        config.manager = Some(ManagerConfig::new(bind_addr.clone()));
+
+        let state = ServerState::new_shared(&config).await;
+        let context = Context::new_with_state_shared(config, state);
+        let mut service = ManagerService::bind(&bind_addr, context).await.unwrap();
+
+        let (tx, rx) = mpsc::channel(32);
+        service.set_request_receiver(rx);
+        tokio::spawn(async move {
+            let _ = service.serve().await;
+        });
+
+        tx
+    }
+
+    async fn request(tx: &mpsc::Sender<ManagerRequest>, method: Method, path: &str, body: Body) -> (StatusCode, Value) {
+        let req = Request::builder().method(method).uri(path).body(body).unwrap();
+        send_request(tx, req).await
+    }
+
+    async fn send_request(tx: &mpsc::Sender<ManagerRequest>, req: Request<Body>) -> (StatusCode, Value) {
+        let resp = match dispatch_request(req, tx).await {
+            Ok(resp) => resp,
+            Err(err) => err.into_response(),
+        };
+
+        let status = resp.status();
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        if body.is_empty() {
+            (status, Value::Null)
+        } else {
+            (status, serde_json::from_slice(&body).unwrap())
+        }
+    }
+
+    #[tokio::test]
+    async fn add_server_invalid_input() {
+        let tx = start_service().await;
+
+        let param = json!({ "password": "test-password", "method": "unknown-method" });
+        let (status, value) = request(&tx, Method::POST, "/servers/0", Body::from(param.to_string())).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(value["error"].as_str().unwrap().contains("unknown-method"));
+
+        let param = json!({ "password": "test-password", "method": "aes-256-gcm", "mode": "unknown-mode" });
+        let (status, value) = request(&tx, Method::POST, "/servers/0", Body::from(param.to_string())).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(value["error"].as_str().unwrap().contains("unknown-mode"));
+
+        let (status, value) = request(&tx, Method::GET, "/servers", Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(value, json!([]));
+    }
+
+    #[tokio::test]
+    async fn add_server_body_too_large() {
+        let tx = start_service().await;
+
+        let param = json!({ "password": "x".repeat(MAX_BODY_SIZE), "method": "aes-256-gcm" });
+        let (status, ..) = request(&tx, Method::POST, "/servers/0", Body::from(param.to_string())).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Rejected by `Content-Length` before reading
+        let req = Request::post("/servers/0")
+            .header(header::CONTENT_LENGTH, MAX_BODY_SIZE + 1)
+            .body(Body::empty())
+            .unwrap();
+        let (status, ..) = send_request(&tx, req).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Streamed in chunks
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for _ in 0..MAX_BODY_SIZE / 1024 + 1 {
+                if sender.send_data(vec![b' '; 1024].into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let (status, ..) = request(&tx, Method::POST, "/servers/0", body).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        let (status, value) = request(&tx, Method::GET, "/servers", Body::empty()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(value, json!([]));
+    }
+}
@@ -0,0 +1,1310 @@
+//! Server manager
+//!
+//! Service for managing multiple relay servers
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Error, ErrorKind},
+    net::{Ipv4Addr, SocketAddr},
+    path::Path,
+    str,
+    time::Duration,
+};
+
+use byte_string::ByteStr;
+use futures::future::{self, AbortHandle, FutureExt};
+use log::{debug, error, info, trace, warn};
+#[cfg(unix)]
+use tokio::net::{unix::SocketAddr as UnixSocketAddr, UnixDatagram};
+use tokio::{
+    self,
+    fs,
+    io::AsyncWriteExt,
+    net::UdpSocket,
+    sync::{mpsc, oneshot},
+    time::{self, Interval},
+};
+
+use crate::{
+    config::{
+        Config,
+        ConfigType,
+        ManagerAddr,
+        ManagerConfig,
+        Mode,
+        ReplayFilterConfig,
+        ServerAddr,
+        ServerConfig,
+        TrafficLimit,
+    },
+    context::{Context, ServerState, SharedContext, SharedServerState},
+    crypto::v1::CipherKind,
+    plugin::PluginConfig,
+    relay::{
+        control::ServerControl,
+        flow::{MultiServerFlowStatistic, SharedServerFlowStatistic},
+        sys::create_udp_socket,
+        udprelay::MAXIMUM_UDP_PAYLOAD_SIZE,
+        utils::set_nofile,
+    },
+};
+
+#[cfg(feature = "metrics")]
+use super::metrics::SharedMetrics;
+use super::server;
+
+#[cfg(feature = "manager-http")]
+mod http;
+
+/// Default interval for sending statistic data to `ManagerConfig::stat_addr`
+const DEFAULT_STAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Interval for saving used quotas into `ManagerConfig::state_file`
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+mod protocol {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct ServerConfig {
+        pub server_port: u16,
+        pub password: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub method: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub no_delay: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub plugin: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub plugin_opts: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub mode: Option<String>,
+        /// Maximum speed of receiving from clients, bytes per second
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub upload_limit: Option<u64>,
+        /// Maximum speed of sending to clients, bytes per second
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub download_limit: Option<u64>,
+        /// Total bytes allowed to be transferred
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub quota: Option<u64>,
+        /// Bytes counted for `quota`, reported by `list` and saved in state file
+        ///
+        /// Servers created with it continue counting from it, unless they replace a running server
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub quota_used: Option<u64>,
+        /// Whether `quota` is used up, only reported by `list`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub quota_exhausted: Option<bool>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub struct RemoveRequest {
+        pub server_port: u16,
+    }
+
+    #[derive(Serialize, Debug, Clone, Copy, Default)]
+    pub struct ServerStat {
+        pub tcp_tx: u64,
+        pub tcp_rx: u64,
+        pub udp_tx: u64,
+        pub udp_rx: u64,
+    }
+
+    impl ServerStat {
+        pub fn total(&self) -> u64 {
+            self.tcp_tx + self.tcp_rx + self.udp_tx + self.udp_rx
+        }
+
+        /// Traffic since `earlier` was taken
+        pub fn since(&self, earlier: &ServerStat) -> ServerStat {
+            ServerStat {
+                tcp_tx: self.tcp_tx.saturating_sub(earlier.tcp_tx),
+                tcp_rx: self.tcp_rx.saturating_sub(earlier.tcp_rx),
+                udp_tx: self.udp_tx.saturating_sub(earlier.udp_tx),
+                udp_rx: self.udp_rx.saturating_sub(earlier.udp_rx),
+            }
+        }
+    }
+}
+
+/// Consumers of traffic statistics, which only see traffic since their last reads if `stat_reset` is enabled
+#[derive(Debug, Clone, Copy)]
+enum StatConsumer {
+    /// `stat` command without parameter
+    Query,
+    /// Reports sent to `ManagerConfig::stat_addr`
+    Report,
+}
+
+struct ServerInstance {
+    config: Config,
+    flow_stat: SharedServerFlowStatistic,
+    /// Statistics seen by the last `StatConsumer::Query` and `StatConsumer::Report`
+    queried_stat: protocol::ServerStat,
+    reported_stat: protocol::ServerStat,
+    watcher: AbortHandle,
+    /// Parameters of `add` command that created this server, `None` for servers in configuration
+    request: Option<protocol::ServerConfig>,
+    #[cfg(feature = "metrics")]
+    metrics: SharedMetrics,
+}
+
+impl Drop for ServerInstance {
+    fn drop(&mut self) {
+        self.watcher.abort();
+
+        #[cfg(feature = "metrics")]
+        self.metrics.unregister_server(&self.flow_stat);
+    }
+}
+
+impl ServerInstance {
+    async fn start_server(
+        config: Config,
+        server_state: SharedServerState,
+        request: Option<protocol::ServerConfig>,
+    ) -> io::Result<ServerInstance> {
+        let server_port = config.server[0].addr().port();
+
+        let flow_stat = MultiServerFlowStatistic::new_shared(&config);
+
+        #[cfg(feature = "metrics")]
+        let metrics = server_state.metrics().clone();
+
+        let watcher = {
+            // Run server in current process, sharing the same tokio runtime
+            //
+            // NOTE: This may make different users interfere with each other,
+            // which means that this is not a good decision
+
+            let config = config.clone();
+            let flow_stat = flow_stat.clone();
+
+            let (server, watcher) = future::abortable(server::run_with(
+                config,
+                flow_stat,
+                server_state,
+                ServerControl::default(),
+            ));
+
+            tokio::spawn(async move {
+                match server.await {
+                    Ok(unexpected_exit) => {
+                        error!(
+                            "server listening on port {} exited with result {:?}",
+                            server_port, unexpected_exit
+                        );
+                    }
+                    Err(..) => {
+                        debug!("server listening on port {} killed", server_port);
+                    }
+                }
+            });
+
+            watcher
+        };
+
+        let flow_stat = flow_stat
+            .get(server_port)
+            .expect("port not existed in multi-server flow statistic")
+            .clone();
+
+        trace!("created server listening on port {}", server_port);
+
+        Ok(ServerInstance {
+            config,
+            flow_stat,
+            queried_stat: protocol::ServerStat::default(),
+            reported_stat: protocol::ServerStat::default(),
+            watcher,
+            request,
+            #[cfg(feature = "metrics")]
+            metrics,
+        })
+    }
+
+    /// Bytes counted for quota, `None` if this server has no quota
+    fn quota_used(&self) -> Option<u64> {
+        let limiter = self.flow_stat.limiter()?;
+        limiter.limit().quota.map(|_| limiter.quota_used())
+    }
+
+    fn flow_trans_stat(&self) -> usize {
+        self.flow_stat.trans_stat()
+    }
+
+    /// Traffic statistic of this server, only traffic since the last read of `consumer` is counted if `reset` is `true`
+    ///
+    /// Counters are never reset, because they are shared with metrics and traffic limits
+    fn flow_stat(&mut self, consumer: StatConsumer, reset: bool) -> protocol::ServerStat {
+        let tcp = self.flow_stat.tcp();
+        let udp = self.flow_stat.udp();
+
+        let stat = protocol::ServerStat {
+            tcp_tx: tcp.tx() as u64,
+            tcp_rx: tcp.rx() as u64,
+            udp_tx: udp.tx() as u64,
+            udp_rx: udp.rx() as u64,
+        };
+
+        if !reset {
+            return stat;
+        }
+
+        let seen = match consumer {
+            StatConsumer::Query => &mut self.queried_stat,
+            StatConsumer::Report => &mut self.reported_stat,
+        };
+        let since = stat.since(seen);
+        *seen = stat;
+        since
+    }
+}
+
+/// Datagram socket for manager
+///
+/// For *nix system, this is a wrapper for both UDP socket and Unix socket
+pub enum ManagerDatagram {
+    UdpDatagram(UdpSocket),
+    #[cfg(unix)]
+    UnixDatagram(UnixDatagram),
+}
+
+impl ManagerDatagram {
+    /// Create a `ManagerDatagram` binding to requested `bind_addr`
+    pub async fn bind(bind_addr: &ManagerAddr, context: &Context) -> io::Result<ManagerDatagram> {
+        match *bind_addr {
+            ManagerAddr::SocketAddr(ref saddr) => Ok(ManagerDatagram::UdpDatagram(create_udp_socket(saddr).await?)),
+            ManagerAddr::DomainName(ref dname, port) => {
+                let (_, socket) = lookup_then!(context, dname, port, |saddr| { create_udp_socket(&saddr).await })?;
+
+                Ok(ManagerDatagram::UdpDatagram(socket))
+            }
+            #[cfg(unix)]
+            ManagerAddr::UnixSocketAddr(ref path) => {
+                use std::fs;
+
+                // Remove it first incase it is already exists
+                let _ = fs::remove_file(path);
+
+                Ok(ManagerDatagram::UnixDatagram(UnixDatagram::bind(path)?))
+            }
+        }
+    }
+
+    /// Create a `ManagerDatagram` for sending data to manager
+    pub async fn bind_for(bind_addr: &ManagerAddr) -> io::Result<ManagerDatagram> {
+        match *bind_addr {
+            ManagerAddr::SocketAddr(..) | ManagerAddr::DomainName(..) => {
+                // Bind to 0.0.0.0 and let system allocate a port
+                let local_addr = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0);
+                Ok(ManagerDatagram::UdpDatagram(create_udp_socket(&local_addr).await?))
+            }
+            #[cfg(unix)]
+            // For unix socket, it doesn't need to bind to any valid address
+            // Because manager won't response to you
+            ManagerAddr::UnixSocketAddr(..) => Ok(ManagerDatagram::UnixDatagram(UnixDatagram::unbound()?)),
+        }
+    }
+
+    /// Receives data from the socket.
+    pub async fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, ManagerSocketAddr)> {
+        match *self {
+            ManagerDatagram::UdpDatagram(ref mut udp) => {
+                let (s, addr) = udp.recv_from(buf).await?;
+                Ok((s, ManagerSocketAddr::SocketAddr(addr)))
+            }
+            #[cfg(unix)]
+            ManagerDatagram::UnixDatagram(ref mut unix) => {
+                let (s, addr) = unix.recv_from(buf).await?;
+                Ok((s, ManagerSocketAddr::UnixSocketAddr(addr)))
+            }
+        }
+    }
+
+    /// Sends data on the socket to the specified address.
+    pub async fn send_to(&mut self, buf: &[u8], target: &ManagerSocketAddr) -> io::Result<usize> {
+        match *self {
+            ManagerDatagram::UdpDatagram(ref mut udp) => match *target {
+                ManagerSocketAddr::SocketAddr(ref saddr) => udp.send_to(buf, saddr).await,
+                #[cfg(unix)]
+                ManagerSocketAddr::UnixSocketAddr(..) => {
+                    let err = Error::new(ErrorKind::InvalidInput, "udp datagram requires IP address target");
+                    Err(err)
+                }
+            },
+            #[cfg(unix)]
+            ManagerDatagram::UnixDatagram(ref mut unix) => match *target {
+                ManagerSocketAddr::UnixSocketAddr(ref saddr) => match saddr.as_pathname() {
+                    Some(paddr) => unix.send_to(buf, paddr).await,
+                    None => {
+                        let err = Error::new(ErrorKind::InvalidInput, "target address must not be unnamed");
+                        Err(err)
+                    }
+                },
+                ManagerSocketAddr::SocketAddr(..) => {
+                    let err = Error::new(ErrorKind::InvalidInput, "unix datagram requires path address target");
+                    Err(err)
+                }
+            },
+        }
+    }
+
+    /// Sends data on the socket to the specified manager address
+    pub async fn send_to_manager(&mut self, buf: &[u8], context: &Context, target: &ManagerAddr) -> io::Result<usize> {
+        match *self {
+            ManagerDatagram::UdpDatagram(ref mut udp) => match *target {
+                ManagerAddr::SocketAddr(ref saddr) => udp.send_to(buf, saddr).await,
+                ManagerAddr::DomainName(ref dname, port) => {
+                    let (_, n) = lookup_then!(context, dname, port, |saddr| { udp.send_to(buf, saddr).await })?;
+                    Ok(n)
+                }
+                #[cfg(unix)]
+                ManagerAddr::UnixSocketAddr(..) => {
+                    let err = Error::new(ErrorKind::InvalidInput, "udp datagram requires IP address target");
+                    Err(err)
+                }
+            },
+            #[cfg(unix)]
+            ManagerDatagram::UnixDatagram(ref mut unix) => match *target {
+                ManagerAddr::UnixSocketAddr(ref paddr) => unix.send_to(buf, paddr).await,
+                ManagerAddr::SocketAddr(..) | ManagerAddr::DomainName(..) => {
+                    let err = Error::new(ErrorKind::InvalidInput, "unix datagram requires path address target");
+                    Err(err)
+                }
+            },
+        }
+    }
+
+    /// Returns the local address that this socket is bound to.
+    pub fn local_addr(&self) -> io::Result<ManagerSocketAddr> {
+        match *self {
+            ManagerDatagram::UdpDatagram(ref socket) => socket.local_addr().map(ManagerSocketAddr::SocketAddr),
+            #[cfg(unix)]
+            ManagerDatagram::UnixDatagram(ref dgram) => dgram.local_addr().map(ManagerSocketAddr::UnixSocketAddr),
+        }
+    }
+}
+
+/// Target address for manager for representing client connections
+#[derive(Debug)]
+pub enum ManagerSocketAddr {
+    SocketAddr(SocketAddr),
+    #[cfg(unix)]
+    UnixSocketAddr(UnixSocketAddr),
+}
+
+impl ManagerSocketAddr {
+    /// Check if it is unnamed (not binded to any valid address), only valid for `UnixSocketAddr`
+    pub fn is_unnamed(&self) -> bool {
+        match *self {
+            ManagerSocketAddr::SocketAddr(..) => false,
+            #[cfg(unix)]
+            ManagerSocketAddr::UnixSocketAddr(ref s) => s.is_unnamed(),
+        }
+    }
+}
+
+impl fmt::Display for ManagerSocketAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ManagerSocketAddr::SocketAddr(ref saddr) => fmt::Display::fmt(saddr, f),
+            #[cfg(unix)]
+            ManagerSocketAddr::UnixSocketAddr(ref saddr) => fmt::Debug::fmt(saddr, f),
+        }
+    }
+}
+
+/// Periodically sends `stat` reports to `ManagerConfig::stat_addr`
+///
+/// Reports are compatible with shadowsocks-libev's `ss-server`: `stat: {"port":bytes}`
+struct StatReporter {
+    socket: ManagerDatagram,
+    addr: ManagerAddr,
+    interval: Interval,
+}
+
+impl StatReporter {
+    async fn new(manager_config: &ManagerConfig) -> io::Result<Option<StatReporter>> {
+        let addr = match manager_config.stat_addr {
+            Some(ref a) => a.clone(),
+            None => return Ok(None),
+        };
+
+        let socket = ManagerDatagram::bind_for(&addr).await?;
+        let interval = time::interval(manager_config.stat_interval.unwrap_or(DEFAULT_STAT_INTERVAL));
+
+        Ok(Some(StatReporter { socket, addr, interval }))
+    }
+
+    async fn tick(reporter: &mut Option<StatReporter>) {
+        match *reporter {
+            Some(ref mut r) => {
+                r.interval.tick().await;
+            }
+            None => future::pending().await,
+        }
+    }
+}
+
+/// Action of `ManagerRequest` for total traffic of all servers, which is never reset unlike `stat` command
+///
+/// It is only accepted from `ManagerRequest`, not from the manager socket
+#[cfg_attr(not(feature = "manager-http"), allow(dead_code))]
+const STAT_TOTAL_ACTION: &str = "stat-total";
+
+/// Command sent to `ManagerService` from control interfaces other than the manager socket
+///
+/// It will be handled by `ManagerService::dispatch_request`, just like commands received from the manager socket
+#[cfg_attr(not(feature = "manager-http"), allow(dead_code))]
+struct ManagerRequest {
+    action: String,
+    param: String,
+    reply: oneshot::Sender<io::Result<Option<Vec<u8>>>>,
+}
+
+struct ManagerService {
+    socket: ManagerDatagram,
+    servers: HashMap<u16, ServerInstance>,
+    /// Servers in `ManagerConfig::state_file` that failed to be restored
+    ///
+    /// They are kept in the state file, until they are created again by `add` command or removed by `remove` command
+    unrestored: Vec<protocol::ServerConfig>,
+    context: SharedContext,
+    request_rx: Option<mpsc::Receiver<ManagerRequest>>,
+}
+
+impl ManagerService {
+    async fn bind(bind_addr: &ManagerAddr, context: SharedContext) -> io::Result<ManagerService> {
+        let socket = ManagerDatagram::bind(bind_addr, &context).await?;
+
+        Ok(ManagerService {
+            socket,
+            servers: HashMap::new(),
+            unrestored: Vec::new(),
+            context,
+            request_rx: None,
+        })
+    }
+
+    /// Handles `ManagerRequest`s received from `rx` in `serve`
+    #[cfg(feature = "manager-http")]
+    fn set_request_receiver(&mut self, rx: mpsc::Receiver<ManagerRequest>) {
+        self.request_rx = Some(rx);
+    }
+
+    async fn recv_request(rx: &mut Option<mpsc::Receiver<ManagerRequest>>) -> ManagerRequest {
+        if let Some(ref mut rx) = *rx {
+            if let Some(req) = rx.recv().await {
+                return req;
+            }
+        }
+        future::pending().await
+    }
+
+    fn local_addr(&self) -> io::Result<ManagerSocketAddr> {
+        self.socket.local_addr()
+    }
+
+    async fn serve(&mut self) -> io::Result<()> {
+        let mut buf = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
+
+        let manager_config = self.context.config().manager.as_ref().expect("manager config");
+        let mut stat_reporter = StatReporter::new(manager_config).await?;
+        let mut state_save_interval = time::interval(STATE_SAVE_INTERVAL);
+
+        loop {
+            let (recv_len, src_addr) = tokio::select! {
+                r = self.socket.recv_from(&mut buf) => r?,
+                _ = state_save_interval.tick() => {
+                    if self.has_quota() {
+                        self.save_state().await;
+                    }
+                    continue;
+                }
+                _ = StatReporter::tick(&mut stat_reporter) => {
+                    if let Some(ref mut reporter) = stat_reporter {
+                        self.report_stat(reporter).await;
+                    }
+                    continue;
+                }
+                req = ManagerService::recv_request(&mut self.request_rx) => {
+                    let result = self.dispatch_request(&req.action, &req.param).await;
+                    let _ = req.reply.send(result);
+                    continue;
+                }
+            };
+            let pkt = &buf[..recv_len];
+
+            let resp_pkt = match self.handle_packet(pkt).await {
+                Some(p) => p,
+                None => continue,
+            };
+
+            if src_addr.is_unnamed() {
+                trace!(
+                    "received a packet ({} bytes) from an unnamed unix-socket client, \
+                     unsound because we are unable to send response back to it",
+                    recv_len
+                );
+                continue;
+            }
+
+            let n = match self.socket.send_to(&resp_pkt, &src_addr).await {
+                Ok(n) => n,
+                Err(err) => {
+                    debug!("response send_to failed, destination: {:?}, error: {}", src_addr, err);
+                    continue;
+                }
+            };
+
+            if n != resp_pkt.len() {
+                warn!(
+                    "response packet truncated, packet: {}, sent: {}, destination: {:?}",
+                    resp_pkt.len(),
+                    n,
+                    src_addr
+                );
+            }
+        }
+    }
+
+    async fn handle_packet(&mut self, pkt: &[u8]) -> Option<Vec<u8>> {
+        trace!("REQUEST: {:?}", ByteStr::new(pkt));
+
+        // Payload must be UTF-8 encoded, or JSON decode will fail
+        let pkt = match str::from_utf8(pkt) {
+            Ok(p) => p,
+            Err(..) => {
+                error!("received non-UTF8 encoded packet: {:?}", ByteStr::new(pkt));
+
+                return Some(b"invalid encoding".to_vec());
+            }
+        };
+
+        let (action, param) = match pkt.find(':') {
+            None => (pkt.trim(), ""),
+            Some(idx) => {
+                let (action, param) = pkt.split_at(idx);
+                (action.trim(), param[1..].trim())
+            }
+        };
+
+        match self.dispatch_command(action, param).await {
+            Ok(v) => v,
+            Err(err) => {
+                error!("failed to handle action \"{}\", error: {}", action, err);
+
+                Some(Vec::from(err.to_string()))
+            }
+        }
+    }
+
+    /// Handles `ManagerRequest`, which accepts `STAT_TOTAL_ACTION` besides all commands of the manager socket
+    async fn dispatch_request(&mut self, action: &str, param: &str) -> io::Result<Option<Vec<u8>>> {
+        match action {
+            STAT_TOTAL_ACTION => self.handle_stat_total().await,
+            _ => self.dispatch_command(action, param).await,
+        }
+    }
+
+    async fn dispatch_command(&mut self, action: &str, param: &str) -> io::Result<Option<Vec<u8>>> {
+        match action {
+            "add" => {
+                let p: protocol::ServerConfig = match serde_json::from_str(param) {
+                    Ok(p) => p,
+                    Err(err) => {
+                        let err = Error::new(ErrorKind::InvalidData, err);
+                        return Err(err);
+                    }
+                };
+
+                self.handle_add(p).await
+            }
+            "remove" => {
+                let p: protocol::RemoveRequest = match serde_json::from_str(param) {
+                    Ok(p) => p,
+                    Err(err) => {
+                        let err = Error::new(ErrorKind::InvalidData, err);
+                        return Err(err);
+                    }
+                };
+
+                self.handle_remove(&p).await
+            }
+            "list" => self.handle_list().await,
+            "ping" => self.handle_ping().await,
+            "stat" => {
+                // Query without parameter
+                if param.is_empty() {
+                    return self.handle_stat_query().await;
+                }
+
+                let pmap: HashMap<String, u64> = match serde_json::from_str(param) {
+                    Ok(p) => p,
+                    Err(err) => {
+                        let err = Error::new(ErrorKind::InvalidData, err);
+                        return Err(err);
+                    }
+                };
+
+                self.handle_stat(&pmap).await
+            }
+            _ => {
+                let err = Error::new(ErrorKind::InvalidData, format!("unrecognized command \"{}\"", action));
+                Err(err)
+            }
+        }
+    }
+
+    async fn handle_add(&mut self, p: protocol::ServerConfig) -> io::Result<Option<Vec<u8>>> {
+        trace!("ACTION \"add\" {:?}", p);
+
+        self.add_server(p).await?;
+        self.save_state().await;
+
+        Ok(Some(b"ok\n".to_vec()))
+    }
+
+    async fn add_server(&mut self, p: protocol::ServerConfig) -> io::Result<()> {
+        // Usage is saved from the running server by `save_state`
+        let mut request = p.clone();
+        request.quota_used = None;
+        request.quota_exhausted = None;
+
+        let manager_config = self.context.config().manager.as_ref().expect("manager config");
+
+        let server_port = p.server_port;
+
+        let method = match p.method {
+            Some(method) => method,
+            None => manager_config
+                .method
+                // Default method as shadowsocks-libev's ss-server
+                // Just for compatiblity, some shadowsocks manager relies on this default method
+                .unwrap_or(CipherKind::NONE)
+                .to_string(),
+        };
+
+        let bind_addr = manager_config.bind_addr(&self.context, p.server_port).await?;
+        let plugin = match p.plugin {
+            Some(pp) => Some(PluginConfig {
+                plugin: pp,
+                plugin_opts: p.plugin_opts,
+                plugin_args: Vec::new(),
+            }),
+            None => None,
+        };
+
+        let addr = ServerAddr::from(bind_addr);
+        let mut svr_cfg =
+            match ServerConfig::with_method_name(addr, p.password, &method, manager_config.timeout, plugin) {
+                Ok(s) => s,
+                Err(err) => {
+                    let err = Error::new(ErrorKind::InvalidInput, format!("method \"{}\", {}", method, err));
+                    return Err(err);
+                }
+            };
+
+        svr_cfg.set_traffic_limit(TrafficLimit {
+            upload: p.upload_limit,
+            download: p.download_limit,
+            quota: p.quota,
+        });
+
+        let mut config = Config::new(ConfigType::Server);
+        config.server.push(svr_cfg);
+
+        config.local_addr = self.context.config().local_addr.clone();
+
+        // Mode
+        if let Some(mode) = p.mode {
+            config.mode = match mode.parse::<Mode>() {
+                Ok(m) => m,
+                Err(..) => {
+                    let err = Error::new(ErrorKind::InvalidInput, format!("unrecognized mode \"{}\"", mode));
+                    return Err(err);
+                }
+            };
+        } else {
+            config.mode = self.context.config().mode;
+        }
+
+        // TCP_NODELAY
+        if let Some(b) = p.no_delay {
+            config.no_delay = b;
+        } else {
+            config.no_delay = self.context.config().no_delay;
+        }
+
+        // SO_MARK
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        {
+            config.outbound_fwmark = self.context.config().outbound_fwmark;
+        }
+
+        // UDP configurations
+        config.udp_timeout = self.context.config().udp_timeout;
+        config.udp_max_associations = self.context.config().udp_max_associations;
+
+        // Replay filter
+        config.replay_filter = server_replay_filter(&self.context.config().replay_filter, server_port);
+
+        // ACL
+        // FIXME: AccessControl structure may be quite expensive to copy
+        config.acl = self.context.config().acl.clone();
+
+        // Used quota shouldn't be reset by adding the same server again
+        let quota_used = match self.servers.get(&server_port).and_then(|inst| inst.flow_stat.limiter()) {
+            Some(limiter) => Some(limiter.quota_used()),
+            None => p.quota_used,
+        };
+
+        // Close it first
+        let _ = self.servers.remove(&server_port);
+        self.start_server_with_config(server_port, config, Some(request))
+            .await?;
+
+        if let Some(quota_used) = quota_used {
+            if let Some(limiter) = self.servers[&server_port].flow_stat.limiter() {
+                limiter.add_quota_used(quota_used);
+            }
+        }
+
+        self.unrestored.retain(|p| p.server_port != server_port);
+        Ok(())
+    }
+
+    async fn start_server_with_config(
+        &mut self,
+        server_port: u16,
+        config: Config,
+        request: Option<protocol::ServerConfig>,
+    ) -> io::Result<()> {
+        let server = ServerInstance::start_server(config, self.context.server_state().clone(), request).await?;
+        self.servers.insert(server_port, server);
+
+        Ok(())
+    }
+
+    async fn handle_remove(&mut self, p: &protocol::RemoveRequest) -> io::Result<Option<Vec<u8>>> {
+        trace!("ACTION \"remove\" {:?}", p);
+
+        let unrestored_count = self.unrestored.len();
+        self.unrestored.retain(|u| u.server_port != p.server_port);
+
+        if self.servers.remove(&p.server_port).is_some() || self.unrestored.len() != unrestored_count {
+            self.save_state().await;
+        }
+        Ok(Some(b"ok\n".to_vec()))
+    }
+
+    /// Creates servers that were saved in `ManagerConfig::state_file`
+    async fn load_state(&mut self, path: &Path) -> io::Result<()> {
+        let content = match fs::read(path).await {
+            Ok(c) => c,
+            Err(ref err) if err.kind() == ErrorKind::NotFound => {
+                debug!("manager state file {} not found", path.display());
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+
+        let servers: Vec<protocol::ServerConfig> = match serde_json::from_slice(&content) {
+            Ok(s) => s,
+            Err(err) => {
+                let err = Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid manager state file {}, {}", path.display(), err),
+                );
+                return Err(err);
+            }
+        };
+
+        info!("restoring {} servers from {}", servers.len(), path.display());
+
+        for p in servers {
+            if let Err(err) = self.add_server(p.clone()).await {
+                error!(
+                    "failed to restore server on port {}, it is kept in {}, error: {}",
+                    p.server_port,
+                    path.display(),
+                    err
+                );
+                self.unrestored.push(p);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check if any server created by `add` command has a quota, whose usage should be saved periodically
+    fn has_quota(&self) -> bool {
+        self.servers
+            .values()
+            .any(|inst| inst.request.is_some() && inst.quota_used().is_some())
+    }
+
+    /// Saves servers created by `add` command into `ManagerConfig::state_file`
+    ///
+    /// File is written and synced to a temporary path first and then renamed, so it won't be corrupted if manager
+    /// or the system crashes
+    async fn save_state(&self) {
+        let path = match self.context.config().manager {
+            Some(ManagerConfig {
+                state_file: Some(ref p),
+                ..
+            }) => p,
+            _ => return,
+        };
+
+        let mut servers = self
+            .servers
+            .values()
+            .filter_map(|inst| {
+                let mut p = inst.request.clone()?;
+                p.quota_used = inst.quota_used();
+                Some(p)
+            })
+            .chain(self.unrestored.iter().cloned())
+            .collect::<Vec<_>>();
+        servers.sort_by_key(|p| p.server_port);
+
+        let content = serde_json::to_vec_pretty(&servers).expect("convert server configs into JSON");
+
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+
+        let result = match write_synced(Path::new(&tmp_path), &content).await {
+            Ok(..) => fs::rename(&tmp_path, path).await,
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = result {
+            error!("failed to save manager state file {}, error: {}", path.display(), err);
+        }
+    }
+
+    async fn handle_list(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut buf = String::new();
+        buf += "[";
+        let mut is_first = true;
+        for (_, inst) in self.servers.iter() {
+            let config = &inst.config;
+            let svr_cfg = &config.server[0];
+            let limit = svr_cfg.traffic_limit();
+            let limiter = inst.flow_stat.limiter();
+
+            let p = protocol::ServerConfig {
+                server_port: svr_cfg.addr().port(),
+                method: Some(svr_cfg.method_name()),
+                password: svr_cfg.password().to_string(),
+                no_delay: None,
+                plugin: None,
+                plugin_opts: None,
+                mode: None,
+                upload_limit: limit.upload,
+                download_limit: limit.download,
+                quota: limit.quota,
+                quota_used: inst.quota_used(),
+                quota_exhausted: limiter.and_then(|l| l.limit().quota.map(|_| l.is_exhausted())),
+            };
+
+            if is_first {
+                is_first = false;
+            } else {
+                buf += ",";
+            }
+
+            buf += &serde_json::to_string(&p).expect("convert server config into JSON");
+        }
+        buf += "]\n";
+
+        trace!("ACTION \"list\" returns {:?}", ByteStr::new(buf.as_bytes()));
+
+        Ok(Some(buf.into_bytes()))
+    }
+
+    async fn handle_ping(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut buf = String::new();
+        buf += "stat: {";
+        let mut is_first = true;
+        for (port, inst) in self.servers.iter() {
+            if is_first {
+                is_first = false;
+            } else {
+                buf += ",";
+            }
+
+            buf += &format!("\"{}\":{}", port, inst.flow_trans_stat());
+        }
+        buf += "}\n";
+
+        trace!("ACTION \"ping\" returns {:?}", ByteStr::new(buf.as_bytes()));
+
+        Ok(Some(buf.into_bytes()))
+    }
+
+    async fn handle_stat(&mut self, pmap: &HashMap<String, u64>) -> io::Result<Option<Vec<u8>>> {
+        trace!("ACTION \"stat\" {:?}", pmap);
+
+        // NOTE: Reports from servers are ignored in this implementation
+        //       because servers are spawned in the same process with the manager
+
+        Ok(None)
+    }
+
+    async fn handle_stat_query(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let reset = self.stat_reset();
+        let buf = self.query_stat(reset);
+
+        trace!("ACTION \"stat\" returns {:?}", ByteStr::new(&buf));
+
+        Ok(Some(buf))
+    }
+
+    async fn handle_stat_total(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let buf = self.query_stat(false);
+
+        trace!("ACTION \"{}\" returns {:?}", STAT_TOTAL_ACTION, ByteStr::new(&buf));
+
+        Ok(Some(buf))
+    }
+
+    /// Traffic statistic of all servers in JSON, only traffic since the last query is counted if `reset` is `true`
+    fn query_stat(&mut self, reset: bool) -> Vec<u8> {
+        let mut smap = HashMap::with_capacity(self.servers.len());
+        for (port, inst) in self.servers.iter_mut() {
+            smap.insert(port.to_string(), inst.flow_stat(StatConsumer::Query, reset));
+        }
+
+        let mut buf = serde_json::to_string(&smap).expect("convert server stat into JSON");
+        buf += "\n";
+        buf.into_bytes()
+    }
+
+    async fn report_stat(&mut self, reporter: &mut StatReporter) {
+        let reset = self.stat_reset();
+
+        let mut smap = HashMap::with_capacity(self.servers.len());
+        for (port, inst) in self.servers.iter_mut() {
+            smap.insert(port.to_string(), inst.flow_stat(StatConsumer::Report, reset).total());
+        }
+
+        let mut buf = String::new();
+        buf += "stat: ";
+        buf += &serde_json::to_string(&smap).expect("convert server stat into JSON");
+
+        trace!("REPORT \"stat\" {:?}", ByteStr::new(buf.as_bytes()));
+
+        if let Err(err) = reporter
+            .socket
+            .send_to_manager(buf.as_bytes(), &self.context, &reporter.addr)
+            .await
+        {
+            warn!("failed to send stat report to {}, error: {}", reporter.addr, err);
+        }
+    }
+
+    fn stat_reset(&self) -> bool {
+        match self.context.config().manager {
+            Some(ref mc) => mc.stat_reset,
+            None => false,
+        }
+    }
+}
+
+/// Replay filter of the server listening on `server_port`
+///
+/// Servers couldn't share the same snapshot file, each one saves snapshots to `snapshot_path` with `.PORT` appended
+fn server_replay_filter(config: &ReplayFilterConfig, server_port: u16) -> ReplayFilterConfig {
+    let mut config = config.clone();
+    if let Some(path) = config.snapshot_path.take() {
+        let mut path = path.into_os_string();
+        path.push(format!(".{}", server_port));
+        config.snapshot_path = Some(path.into());
+    }
+    config
+}
+
+/// Write `content` into file `path`, and wait until it is flushed to disk
+async fn write_synced(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut file = fs::File::create(path).await?;
+    file.write_all(content).await?;
+    file.sync_all().await
+}
+
+/// Server manager for supporting [Manage Multiple Users](https://github.com/shadowsocks/shadowsocks/wiki/Manage-Multiple-Users) APIs
+pub async fn run(config: Config) -> io::Result<()> {
+    assert!(config.config_type.is_manager());
+
+    if let Err(err) = config.check_integrity() {
+        let e = io::Error::new(ErrorKind::Other, err.desc);
+        return Err(e);
+    }
+
+    if let Some(nofile) = config.nofile {
+        debug!("setting RLIMIT_NOFILE to {}", nofile);
+        if let Err(err) = set_nofile(nofile) {
+            match err.kind() {
+                ErrorKind::PermissionDenied => {
+                    warn!("insufficient permission to change RLIMIT_NOFILE, try to restart as root user");
+                }
+                ErrorKind::InvalidInput => {
+                    warn!("invalid `nofile` value {}, decrease it and try again", nofile);
+                }
+                _ => {
+                    error!("failed to set RLIMIT_NOFILE with value {}, error: {}", nofile, err);
+                }
+            }
+            return Err(err);
+        }
+    }
+
+    // Create a context containing a DNS resolver and server running state flag.
+    let state = ServerState::new_shared(&config).await;
+    let context = Context::new_with_state_shared(config, state.clone());
+    let manager_config = match context.config().manager {
+        Some(ref mc) => mc,
+        None => {
+            let err = Error::new(ErrorKind::Other, "missing `manager` in configuration");
+            return Err(err);
+        }
+    };
+
+    let bind_addr = &manager_config.addr;
+
+    let mut service = ManagerService::bind(bind_addr, context.clone()).await?;
+
+    let actual_local_addr = service.local_addr()?;
+    info!("shadowsocks manager listening on {}", actual_local_addr);
+
+    // Creates known servers in configuration
+    let config = context.config();
+
+    if !config.server.is_empty() {
+        for svr_cfg in &config.server {
+            let mut clean_config = Config::new(ConfigType::Server);
+            clean_config.local_addr = config.local_addr.clone();
+            clean_config.mode = config.mode;
+            clean_config.no_delay = config.no_delay;
+            clean_config.udp_timeout = config.udp_timeout;
+            clean_config.replay_filter = server_replay_filter(&config.replay_filter, svr_cfg.addr().port());
+
+            clean_config.server.push(svr_cfg.clone());
+
+            service
+                .start_server_with_config(svr_cfg.addr().port(), clean_config, None)
+                .await?;
+        }
+    }
+
+    // Servers created by `add` command before restart
+    if let Some(ref state_file) = manager_config.state_file {
+        service.load_state(state_file).await?;
+    }
+
+    let mut vf = Vec::new();
+
+    #[cfg(feature = "manager-http")]
+    if let Some(http_addr) = manager_config.http_addr {
+        let (tx, rx) = mpsc::channel(32);
+        service.set_request_receiver(rx);
+
+        vf.push(http::run(http_addr, manager_config.http_token.clone(), tx).boxed());
+    }
+
+    #[cfg(feature = "metrics")]
+    if let Some(metrics_addr) = config.metrics_addr {
+        use crate::relay::metrics::run as run_metrics;
+
+        // Shared by all servers created by manager
+        vf.push(run_metrics(metrics_addr, state.metrics().clone()).boxed());
+    }
+
+    vf.push(service.serve().boxed());
+
+    let (res, ..) = future::select_all(vf).await;
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn manager_config() -> ManagerConfig {
+        ManagerConfig::new(ManagerAddr::SocketAddr(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)))
+    }
+
+    async fn bind_service(state_file: &Path) -> ManagerService {
+        let mut manager_config = manager_config();
+        manager_config.state_file = Some(state_file.to_owned());
+
+        bind_service_with(manager_config).await
+    }
+
+    async fn bind_service_with(manager_config: ManagerConfig) -> ManagerService {
+        let bind_addr = manager_config.addr.clone();
+
+        let mut config = Config::new(ConfigType::Manager);
+        config.manager = Some(manager_config);
+
+        let state = ServerState::new_shared(&config).await;
+        let context = Context::new_with_state_shared(config, state);
+        ManagerService::bind(&bind_addr, context).await.unwrap()
+    }
+
+    // Servers are keyed by their ports, so free ports are picked before adding them, sorted as in the state file
+    fn free_ports(n: usize) -> Vec<u16> {
+        let listeners = (0..n)
+            .map(|_| std::net::TcpListener::bind("0.0.0.0:0").unwrap())
+            .collect::<Vec<_>>();
+        let mut ports = listeners
+            .iter()
+            .map(|l| l.local_addr().unwrap().port())
+            .collect::<Vec<_>>();
+        ports.sort_unstable();
+        ports
+    }
+
+    async fn saved_ports(state_file: &Path) -> Vec<u16> {
+        let servers: Vec<Value> = serde_json::from_slice(&fs::read(state_file).await.unwrap()).unwrap();
+        servers
+            .iter()
+            .map(|s| s["server_port"].as_u64().unwrap() as u16)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn state_file_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("state.json");
+        let ports = free_ports(4);
+
+        let mut service = bind_service(&state_file).await;
+        for port in &ports[..2] {
+            let param = json!({ "server_port": port, "password": "test-password", "method": "aes-256-gcm" });
+            service.dispatch_command("add", &param.to_string()).await.unwrap();
+        }
+        service
+            .dispatch_command("remove", &json!({ "server_port": ports[1] }).to_string())
+            .await
+            .unwrap();
+        assert_eq!(saved_ports(&state_file).await, vec![ports[0]]);
+        drop(service);
+
+        // A server that couldn't be restored, like its method is not supported any more
+        let mut servers: Vec<Value> = serde_json::from_slice(&fs::read(&state_file).await.unwrap()).unwrap();
+        servers.push(json!({ "server_port": ports[2], "password": "test-password", "method": "unknown-method" }));
+        fs::write(&state_file, serde_json::to_vec(&servers).unwrap())
+            .await
+            .unwrap();
+
+        let mut service = bind_service(&state_file).await;
+        service.load_state(&state_file).await.unwrap();
+        assert!(service.servers.contains_key(&ports[0]));
+        assert!(!service.servers.contains_key(&ports[2]));
+
+        // It is still saved after the state file is written again
+        let param = json!({ "server_port": ports[3], "password": "test-password", "method": "aes-256-gcm" });
+        service.dispatch_command("add", &param.to_string()).await.unwrap();
+        assert_eq!(saved_ports(&state_file).await, vec![ports[0], ports[2], ports[3]]);
+
+        service
+            .dispatch_command("remove", &json!({ "server_port": ports[2] }).to_string())
+            .await
+            .unwrap();
+        assert_eq!(saved_ports(&state_file).await, vec![ports[0], ports[3]]);
+    }
+
+    #[tokio::test]
+    async fn quota_used_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_file = dir.path().join("state.json");
+        let port = free_ports(1)[0];
+
+        let param = json!({ "server_port": port, "password": "test-password", "method": "aes-256-gcm", "quota": 1000 });
+
+        let mut service = bind_service(&state_file).await;
+        service.dispatch_command("add", &param.to_string()).await.unwrap();
+
+        // Simulates traffic of clients
+        let limiter = service.servers[&port].flow_stat.limiter().unwrap();
+        limiter.add_quota_used(300);
+
+        // Adding the same server again
+        service.dispatch_command("add", &param.to_string()).await.unwrap();
+        assert_eq!(service.servers[&port].quota_used(), Some(300));
+
+        service.save_state().await;
+        let servers: Vec<Value> = serde_json::from_slice(&fs::read(&state_file).await.unwrap()).unwrap();
+        assert_eq!(servers[0]["quota_used"], json!(300));
+        drop(service);
+
+        // Restarted
+        let mut service = bind_service(&state_file).await;
+        service.load_state(&state_file).await.unwrap();
+        assert_eq!(service.servers[&port].quota_used(), Some(300));
+
+        let list = service.dispatch_command("list", "").await.unwrap().unwrap();
+        let list: Value = serde_json::from_slice(&list).unwrap();
+        assert_eq!(list[0]["quota_used"], json!(300));
+        assert_eq!(list[0]["quota_exhausted"], json!(false));
+    }
+
+    #[tokio::test]
+    async fn stat_total_not_reset() {
+        let mut manager_config = manager_config();
+        manager_config.stat_reset = true;
+
+        let mut service = bind_service_with(manager_config).await;
+        let param = json!({ "server_port": 0, "password": "test-password", "method": "aes-256-gcm" });
+        service.dispatch_command("add", &param.to_string()).await.unwrap();
+
+        // Simulates traffic of clients
+        service.servers[&0].flow_stat.tcp().incr_tx(100);
+
+        async fn tcp_tx(service: &mut ManagerService, action: &str) -> Value {
+            let resp = service.dispatch_request(action, "").await.unwrap().unwrap();
+            let stat: Value = serde_json::from_slice(&resp).unwrap();
+            stat["0"]["tcp_tx"].clone()
+        }
+
+        assert_eq!(tcp_tx(&mut service, STAT_TOTAL_ACTION).await, json!(100));
+        assert_eq!(tcp_tx(&mut service, STAT_TOTAL_ACTION).await, json!(100));
+
+        // `stat` command only counts traffic since its last query, which isn't consumed by `STAT_TOTAL_ACTION`
+        assert_eq!(tcp_tx(&mut service, "stat").await, json!(100));
+        assert_eq!(tcp_tx(&mut service, "stat").await, json!(0));
+        assert_eq!(tcp_tx(&mut service, STAT_TOTAL_ACTION).await, json!(100));
+
+        // It is not a command of the manager socket
+        assert!(service.dispatch_command(STAT_TOTAL_ACTION, "").await.is_err());
+    }
+
+    #[test]
+    fn replay_filter_snapshot_per_server() {
+        let mut config = ReplayFilterConfig::default();
+        assert_eq!(server_replay_filter(&config, 8415), config);
+
+        config.entries = Some(1000);
+        config.snapshot_path = Some(PathBuf::from("/var/lib/shadowsocks/replay-filter.bin"));
+
+        let server_config = server_replay_filter(&config, 8415);
+        assert_eq!(server_config.entries, Some(1000));
+        assert_eq!(
+            server_config.snapshot_path,
+            Some(PathBuf::from("/var/lib/shadowsocks/replay-filter.bin.8415"))
+        );
+        assert_ne!(
+            server_replay_filter(&config, 8416).snapshot_path,
+            server_config.snapshot_path
+        );
+    }
+}
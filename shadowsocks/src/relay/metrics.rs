@@ -0,0 +1,435 @@
+//! Prometheus metrics
+//!
+//! Metrics are collected in `ServerState`, which is shared by all servers running in the same process,
+//! and exported in [text format](https://prometheus.io/docs/instrumenting/exposition_formats/) by an HTTP server
+//! listening on `Config::metrics_addr`.
+
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fmt::Write,
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+        Weak,
+    },
+};
+
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body,
+    Method,
+    Request,
+    Response,
+    Server,
+    StatusCode,
+};
+use log::{error, info, trace};
+use spin::Mutex;
+
+use crate::{
+    config::{Config, ServerConfig},
+    relay::flow::{MultiServerFlowStatistic, ServerFlowStatistic, SharedServerFlowStatistic},
+};
+
+/// Metrics of all servers running in the current process
+pub struct Metrics {
+    tcp_connections: AtomicUsize,
+    tcp_connections_total: AtomicUsize,
+    udp_associations: AtomicUsize,
+    handshake_failures: AtomicUsize,
+    replay_hits: AtomicUsize,
+    // Server type -> count
+    balancer_switches: Mutex<BTreeMap<String, usize>>,
+    // (Server type, Server address) -> score
+    balancer_scores: Mutex<BTreeMap<(String, String), u64>>,
+    // Server address -> flow statistic, servers removed by manager will be dropped
+    servers: Mutex<Vec<(String, Weak<ServerFlowStatistic>)>>,
+    // Flow statistic of remote servers, only for local, replaced when servers are reloaded
+    local_servers: Mutex<Vec<(String, SharedServerFlowStatistic)>>,
+}
+
+/// `Metrics` wrapped in `Arc`
+pub type SharedMetrics = Arc<Metrics>;
+
+impl Metrics {
+    /// Create metrics for servers in `config`
+    pub fn new(config: &Config) -> Metrics {
+        let metrics = Metrics {
+            tcp_connections: AtomicUsize::new(0),
+            tcp_connections_total: AtomicUsize::new(0),
+            udp_associations: AtomicUsize::new(0),
+            handshake_failures: AtomicUsize::new(0),
+            replay_hits: AtomicUsize::new(0),
+            balancer_switches: Mutex::new(BTreeMap::new()),
+            balancer_scores: Mutex::new(BTreeMap::new()),
+            servers: Mutex::new(Vec::new()),
+            local_servers: Mutex::new(Vec::new()),
+        };
+
+        if config.config_type.is_local() {
+            metrics.register_local_servers(&config.server);
+        }
+
+        metrics
+    }
+
+    /// Create a new shared reference of `Metrics`
+    pub fn new_shared(config: &Config) -> SharedMetrics {
+        Arc::new(Metrics::new(config))
+    }
+
+    /// Export flow statistics of servers in `config`
+    pub fn register_servers(&self, config: &Config, flow_stat: &MultiServerFlowStatistic) {
+        let mut servers = self.servers.lock();
+        for (idx, svr_cfg) in config.server.iter().enumerate() {
+            if let Some(stat) = flow_stat.get_by_index(idx) {
+                servers.push((svr_cfg.addr().to_string(), Arc::downgrade(stat)));
+            }
+        }
+    }
+
+    /// Stop exporting flow statistic of a server removed by manager, its connections may still be alive
+    pub fn unregister_server(&self, flow_stat: &SharedServerFlowStatistic) {
+        let ptr = Arc::as_ptr(flow_stat);
+        self.servers.lock().retain(|(_, s)| s.as_ptr() != ptr);
+    }
+
+    /// Export flow statistics of remote servers in local, replacing servers registered before
+    ///
+    /// Servers that are still in `servers` keep their counters
+    pub fn register_local_servers(&self, servers: &[ServerConfig]) {
+        let mut local_servers = self.local_servers.lock();
+
+        let mut new_local_servers = Vec::with_capacity(servers.len());
+        for svr_cfg in servers {
+            let addr = svr_cfg.addr().to_string();
+            if new_local_servers.iter().any(|(a, _)| *a == addr) {
+                continue;
+            }
+
+            let flow_stat = match local_servers.iter().find(|(a, _)| *a == addr) {
+                Some((_, s)) => s.clone(),
+                None => {
+                    let flow_stat = ServerFlowStatistic::new_shared();
+                    self.servers.lock().push((addr.clone(), Arc::downgrade(&flow_stat)));
+                    flow_stat
+                }
+            };
+            new_local_servers.push((addr, flow_stat));
+        }
+
+        for (_, flow_stat) in local_servers.iter() {
+            if !new_local_servers.iter().any(|(_, s)| Arc::ptr_eq(s, flow_stat)) {
+                self.unregister_server(flow_stat);
+            }
+        }
+
+        *local_servers = new_local_servers;
+    }
+
+    /// Flow statistic of remote server, only available in local
+    pub fn local_server_flow(&self, svr_cfg: &ServerConfig) -> Option<SharedServerFlowStatistic> {
+        let addr = svr_cfg.addr().to_string();
+        self.local_servers
+            .lock()
+            .iter()
+            .find(|(a, _)| *a == addr)
+            .map(|(_, s)| s.clone())
+    }
+
+    /// Record a failure of decoding target address from client
+    pub fn incr_handshake_failure(&self) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a replayed IV / salt
+    pub fn incr_replay_hit(&self) {
+        self.replay_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Update score of a server in load balancer
+    pub fn set_balancer_score(&self, server_type: &str, server: &str, score: u64) {
+        self.balancer_scores
+            .lock()
+            .insert((server_type.to_owned(), server.to_owned()), score);
+    }
+
+    /// Record a switch of the best server in load balancer
+    pub fn incr_balancer_switch(&self, server_type: &str) {
+        *self.balancer_switches.lock().entry(server_type.to_owned()).or_insert(0) += 1;
+    }
+
+    /// Render all metrics in Prometheus text format
+    pub fn render(&self) -> String {
+        let mut buf = String::new();
+
+        render_metric(
+            &mut buf,
+            "shadowsocks_tcp_connections",
+            "gauge",
+            "Active TCP connections",
+            &[(String::new(), self.tcp_connections.load(Ordering::Relaxed) as u64)],
+        );
+        render_metric(
+            &mut buf,
+            "shadowsocks_tcp_connections_total",
+            "counter",
+            "Accepted TCP connections",
+            &[(String::new(), self.tcp_connections_total.load(Ordering::Relaxed) as u64)],
+        );
+        render_metric(
+            &mut buf,
+            "shadowsocks_udp_associations",
+            "gauge",
+            "Active UDP associations",
+            &[(String::new(), self.udp_associations.load(Ordering::Relaxed) as u64)],
+        );
+        render_metric(
+            &mut buf,
+            "shadowsocks_handshake_failures_total",
+            "counter",
+            "Connections failed to decode target address, may be wrong method or key",
+            &[(String::new(), self.handshake_failures.load(Ordering::Relaxed) as u64)],
+        );
+        render_metric(
+            &mut buf,
+            "shadowsocks_replay_hits_total",
+            "counter",
+            "Repeated IVs or salts detected, may be replay attacks",
+            &[(String::new(), self.replay_hits.load(Ordering::Relaxed) as u64)],
+        );
+
+        // Servers sharing the same address (multi-user) are summed up
+        let mut bytes = BTreeMap::new();
+        {
+            let mut servers = self.servers.lock();
+            servers.retain(|(_, s)| s.strong_count() > 0);
+
+            for (addr, stat) in servers.iter() {
+                let stat = match stat.upgrade() {
+                    Some(s) => s,
+                    None => continue,
+                };
+
+                let values = [
+                    ("tcp", "tx", stat.tcp().tx()),
+                    ("tcp", "rx", stat.tcp().rx()),
+                    ("udp", "tx", stat.udp().tx()),
+                    ("udp", "rx", stat.udp().rx()),
+                ];
+                for &(protocol, direction, n) in values.iter() {
+                    let labels = format!(
+                        "server=\"{}\",protocol=\"{}\",direction=\"{}\"",
+                        addr, protocol, direction
+                    );
+                    *bytes.entry(labels).or_insert(0u64) += n as u64;
+                }
+            }
+        }
+        render_metric(
+            &mut buf,
+            "shadowsocks_transferred_bytes_total",
+            "counter",
+            "Bytes transferred by server",
+            &bytes.into_iter().collect::<Vec<_>>(),
+        );
+
+        let scores = self
+            .balancer_scores
+            .lock()
+            .iter()
+            .map(|((t, s), score)| (format!("type=\"{}\",server=\"{}\"", t, s), *score))
+            .collect::<Vec<_>>();
+        render_metric(
+            &mut buf,
+            "shadowsocks_balancer_server_score",
+            "gauge",
+            "Score of server in load balancer, lower is better",
+            &scores,
+        );
+
+        let switches = self
+            .balancer_switches
+            .lock()
+            .iter()
+            .map(|(t, n)| (format!("type=\"{}\"", t), *n as u64))
+            .collect::<Vec<_>>();
+        render_metric(
+            &mut buf,
+            "shadowsocks_balancer_switches_total",
+            "counter",
+            "Times of load balancer switching to another server",
+            &switches,
+        );
+
+        buf
+    }
+}
+
+fn render_metric(buf: &mut String, name: &str, ty: &str, help: &str, values: &[(String, u64)]) {
+    let _ = writeln!(buf, "# HELP {} {}", name, help);
+    let _ = writeln!(buf, "# TYPE {} {}", name, ty);
+    for (labels, value) in values {
+        if labels.is_empty() {
+            let _ = writeln!(buf, "{} {}", name, value);
+        } else {
+            let _ = writeln!(buf, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+/// Keeps a gauge increased until it is dropped
+pub struct GaugeGuard {
+    metrics: SharedMetrics,
+    gauge: fn(&Metrics) -> &AtomicUsize,
+}
+
+impl GaugeGuard {
+    fn new(metrics: &SharedMetrics, gauge: fn(&Metrics) -> &AtomicUsize) -> GaugeGuard {
+        gauge(metrics).fetch_add(1, Ordering::Relaxed);
+
+        GaugeGuard {
+            metrics: metrics.clone(),
+            gauge,
+        }
+    }
+
+    /// Record an active TCP connection
+    pub fn tcp_connection(metrics: &SharedMetrics) -> GaugeGuard {
+        metrics.tcp_connections_total.fetch_add(1, Ordering::Relaxed);
+        GaugeGuard::new(metrics, |m| &m.tcp_connections)
+    }
+
+    /// Record an active UDP association
+    pub fn udp_association(metrics: &SharedMetrics) -> GaugeGuard {
+        GaugeGuard::new(metrics, |m| &m.udp_associations)
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        (self.gauge)(&self.metrics).fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Starts an HTTP server exporting metrics on `GET /metrics`
+pub async fn run(bind_addr: SocketAddr, metrics: SharedMetrics) -> io::Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let metrics = metrics.clone();
+                async move { Ok::<_, Infallible>(handle_request(req, &metrics)) }
+            }))
+        }
+    });
+
+    let server = match Server::try_bind(&bind_addr) {
+        Ok(builder) => builder.tcp_sleep_on_accept_errors(true).serve(make_service),
+        Err(err) => {
+            let err = io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("failed to bind {}, {}", bind_addr, err),
+            );
+            return Err(err);
+        }
+    };
+
+    info!("shadowsocks metrics listening on {}", server.local_addr());
+
+    if let Err(err) = server.await {
+        error!("metrics server exited with error: {}", err);
+        return Err(io::Error::new(ErrorKind::Other, err));
+    }
+
+    Ok(())
+}
+
+fn handle_request(req: Request<Body>, metrics: &Metrics) -> Response<Body> {
+    trace!("metrics {} {}", req.method(), req.uri());
+
+    if req.method() != Method::GET || req.uri().path() != "/metrics" {
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::NOT_FOUND;
+        return resp;
+    }
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(metrics.render()))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{config::ConfigType, crypto::v1::CipherKind};
+
+    use super::*;
+
+    fn server(addr: &str) -> ServerConfig {
+        ServerConfig::basic(
+            addr.parse().unwrap(),
+            "test-password".to_owned(),
+            CipherKind::AES_256_GCM,
+        )
+    }
+
+    fn rendered_servers(metrics: &Metrics) -> Vec<String> {
+        let mut servers = metrics
+            .render()
+            .lines()
+            .filter(|l| l.starts_with("shadowsocks_transferred_bytes_total{"))
+            .filter_map(|l| l.split('"').nth(1).map(ToOwned::to_owned))
+            .collect::<Vec<_>>();
+        servers.dedup();
+        servers
+    }
+
+    #[test]
+    fn local_servers_reloaded() {
+        let mut config = Config::new(ConfigType::Socks5Local);
+        config.server = vec![server("127.0.0.1:8001"), server("127.0.0.1:8002")];
+
+        let metrics = Metrics::new(&config);
+        assert_eq!(rendered_servers(&metrics), ["127.0.0.1:8001", "127.0.0.1:8002"]);
+
+        let kept = metrics.local_server_flow(&config.server[1]).unwrap();
+        kept.tcp().incr_tx(100);
+
+        metrics.register_local_servers(&[server("127.0.0.1:8002"), server("127.0.0.1:8003")]);
+        assert_eq!(rendered_servers(&metrics), ["127.0.0.1:8002", "127.0.0.1:8003"]);
+
+        assert!(metrics.local_server_flow(&server("127.0.0.1:8001")).is_none());
+        assert!(metrics.local_server_flow(&server("127.0.0.1:8003")).is_some());
+        let reloaded = metrics.local_server_flow(&server("127.0.0.1:8002")).unwrap();
+        assert!(Arc::ptr_eq(&kept, &reloaded));
+        assert_eq!(reloaded.tcp().tx(), 100);
+    }
+
+    #[test]
+    fn servers_unregistered() {
+        let mut config = Config::new(ConfigType::Server);
+        config.server = vec![server("127.0.0.1:8001")];
+
+        let metrics = Metrics::new(&config);
+        assert!(rendered_servers(&metrics).is_empty());
+
+        let flow_stat = MultiServerFlowStatistic::new(&config);
+        metrics.register_servers(&config, &flow_stat);
+        assert_eq!(rendered_servers(&metrics), ["127.0.0.1:8001"]);
+
+        metrics.unregister_server(flow_stat.get_by_index(0).unwrap());
+        assert!(rendered_servers(&metrics).is_empty());
+
+        metrics.register_servers(&config, &flow_stat);
+        assert_eq!(rendered_servers(&metrics), ["127.0.0.1:8001"]);
+
+        drop(flow_stat);
+        assert!(rendered_servers(&metrics).is_empty());
+    }
+}
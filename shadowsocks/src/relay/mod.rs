@@ -1,12 +1,19 @@
 //! Relay server in local and server side implementations.
 
+#[cfg(feature = "aead-cipher-2022")]
+pub mod aead_2022;
+pub mod control;
 pub(crate) mod dns_resolver;
 #[cfg(feature = "local-dns")]
 pub mod dnsrelay;
-pub(crate) mod flow;
+pub mod flow;
+pub(crate) mod limit;
 pub(crate) mod loadbalancing;
 pub mod local;
 pub mod manager;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub(crate) mod online_config;
 #[cfg(feature = "local-redir")]
 pub(crate) mod redir;
 pub mod server;
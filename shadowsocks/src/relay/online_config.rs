@@ -0,0 +1,401 @@
+//! [SIP008](https://github.com/shadowsocks/shadowsocks-org/issues/89) online configuration
+//!
+//! The document is fetched from `OnlineConfig::url` and saved to `OnlineConfig::cache_path`,
+//! the cached document is used if the URL is unreachable when starting.
+
+use std::{
+    io::{self, ErrorKind},
+    path::Path,
+    time::Duration,
+};
+
+use log::{debug, warn};
+use tokio::{fs, io::AsyncReadExt, time};
+use url::Url;
+
+use crate::{
+    config::{Config, OnlineConfig, ServerConfig},
+    context::Context,
+};
+
+/// Timeout of fetching the document
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum size of the document, documents larger than it are rejected
+const MAX_DOCUMENT_SIZE: usize = 4 * 1024 * 1024;
+
+/// Servers loaded from a SIP008 document
+#[derive(Clone)]
+pub(crate) struct OnlineServers {
+    /// The document, for checking if it is changed after fetching again
+    pub document: String,
+    pub servers: Vec<ServerConfig>,
+}
+
+impl OnlineServers {
+    fn parse(document: String) -> io::Result<OnlineServers> {
+        match Config::load_servers_from_sip008(&document) {
+            Ok(servers) => Ok(OnlineServers { document, servers }),
+            Err(err) => Err(io::Error::new(ErrorKind::InvalidData, err.to_string())),
+        }
+    }
+}
+
+/// Fetch servers from `online_config.url`, and save the document to `online_config.cache_path`
+///
+/// Host of the URL is resolved by `context`, outbound sockets are configured by its `config()`
+pub(crate) async fn fetch_servers(context: &Context, online_config: &OnlineConfig) -> io::Result<OnlineServers> {
+    let document = match time::timeout(FETCH_TIMEOUT, fetch_document(context, &online_config.url)).await {
+        Ok(r) => r?,
+        Err(..) => return Err(io::Error::new(ErrorKind::TimedOut, "fetch timed out")),
+    };
+
+    // Don't overwrite the cache with a broken document
+    let servers = OnlineServers::parse(document)?;
+
+    if let Some(ref path) = online_config.cache_path {
+        if let Err(err) = save_cache(path, &servers.document).await {
+            warn!("failed to save online configuration to \"{}\", {}", path.display(), err);
+        }
+    }
+
+    Ok(servers)
+}
+
+/// Fetch servers from `online_config.url`, or load them from `online_config.cache_path` if failed
+pub(crate) async fn load_servers(context: &Context, online_config: &OnlineConfig) -> io::Result<OnlineServers> {
+    let err = match fetch_servers(context, online_config).await {
+        Ok(servers) => return Ok(servers),
+        Err(err) => err,
+    };
+
+    let path = match online_config.cache_path {
+        Some(ref path) => path,
+        None => return Err(err),
+    };
+
+    warn!(
+        "failed to fetch online configuration from {}, {}, loading cached \"{}\"",
+        online_config.url,
+        err,
+        path.display()
+    );
+
+    let document = read_file(path).await?;
+    OnlineServers::parse(document)
+}
+
+fn document_too_large() -> io::Error {
+    let msg = format!("document is larger than {} bytes", MAX_DOCUMENT_SIZE);
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+async fn read_file(path: &Path) -> io::Result<String> {
+    // Reads one more byte for telling if it is too large, the file may not be a regular one
+    let file = fs::File::open(path).await?;
+    let mut document = String::new();
+    file.take(MAX_DOCUMENT_SIZE as u64 + 1)
+        .read_to_string(&mut document)
+        .await?;
+
+    if document.len() > MAX_DOCUMENT_SIZE {
+        return Err(document_too_large());
+    }
+    Ok(document)
+}
+
+async fn save_cache(path: &Path, document: &str) -> io::Result<()> {
+    // Write to a temporary file and rename it, the cache is never left half-written
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    fs::write(&tmp_path, document).await?;
+    fs::rename(&tmp_path, path).await?;
+
+    debug!("saved online configuration to \"{}\"", path.display());
+
+    Ok(())
+}
+
+async fn fetch_document(context: &Context, url: &Url) -> io::Result<String> {
+    match url.scheme() {
+        "file" => match url.to_file_path() {
+            Ok(path) => read_file(&path).await,
+            Err(..) => {
+                let err = io::Error::new(ErrorKind::InvalidInput, format!("invalid file URL {}", url));
+                Err(err)
+            }
+        },
+        "http" | "https" => fetch_http(context, url).await,
+        scheme => Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("unsupported scheme `{}`", scheme),
+        )),
+    }
+}
+
+#[cfg(feature = "local-online-config")]
+async fn fetch_http(context: &Context, url: &Url) -> io::Result<String> {
+    use url::Position;
+
+    use crate::relay::sys::tcp_stream_connect;
+
+    let host = match url.host_str() {
+        Some(h) => h,
+        None => {
+            let err = io::Error::new(ErrorKind::InvalidInput, format!("missing host in {}", url));
+            return Err(err);
+        }
+    };
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let (_, stream) = lookup_then!(context, host, port, |addr| {
+        tcp_stream_connect(&addr, context.config()).await
+    })?;
+
+    let authority = &url[Position::BeforeHost..Position::AfterPort];
+    let path = &url[Position::BeforePath..Position::AfterQuery];
+
+    if url.scheme() == "https" {
+        let stream = tls::connect(stream, host).await?;
+        http_get(stream, authority, path).await
+    } else {
+        http_get(stream, authority, path).await
+    }
+}
+
+#[cfg(not(feature = "local-online-config"))]
+async fn fetch_http(_context: &Context, url: &Url) -> io::Result<String> {
+    let err = io::Error::new(
+        ErrorKind::Other,
+        format!(
+            "fetching {} is not supported, consider enable it by feature \"local-online-config\"",
+            url
+        ),
+    );
+    Err(err)
+}
+
+#[cfg(feature = "local-online-config")]
+async fn http_get<S>(stream: S, authority: &str, path: &str) -> io::Result<String>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    use hyper::{body::HttpBody, client::conn, header, Body, Request, StatusCode};
+
+    fn hyper_error(err: hyper::Error) -> io::Error {
+        io::Error::new(ErrorKind::Other, err)
+    }
+
+    let (mut sender, connection) = conn::handshake(stream).await.map_err(hyper_error)?;
+    tokio::spawn(async move {
+        if let Err(err) = connection.await {
+            debug!("online configuration connection closed with error, {}", err);
+        }
+    });
+
+    let req = match Request::get(path).header(header::HOST, authority).body(Body::empty()) {
+        Ok(r) => r,
+        Err(err) => return Err(io::Error::new(ErrorKind::InvalidInput, err)),
+    };
+
+    let rsp = sender.send_request(req).await.map_err(hyper_error)?;
+    if rsp.status() != StatusCode::OK {
+        let err = io::Error::new(ErrorKind::Other, format!("unexpected status {}", rsp.status()));
+        return Err(err);
+    }
+
+    let content_length = rsp
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if content_length.map_or(false, |n| n > MAX_DOCUMENT_SIZE) {
+        return Err(document_too_large());
+    }
+
+    // Body is read in chunks, stops as soon as it is too large
+    let mut body = rsp.into_body();
+    let mut document = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(hyper_error)?;
+        if document.len() + chunk.len() > MAX_DOCUMENT_SIZE {
+            return Err(document_too_large());
+        }
+        document.extend_from_slice(&chunk);
+    }
+
+    match String::from_utf8(document) {
+        Ok(s) => Ok(s),
+        Err(..) => Err(io::Error::new(ErrorKind::InvalidData, "document is not valid UTF-8")),
+    }
+}
+
+#[cfg(all(feature = "local-online-config", feature = "local-http-native-tls"))]
+mod tls {
+    use std::io::{self, ErrorKind};
+
+    use tokio::net::TcpStream;
+    use tokio_native_tls::TlsStream;
+
+    pub async fn connect(stream: TcpStream, domain: &str) -> io::Result<TlsStream<TcpStream>> {
+        let cx = match native_tls::TlsConnector::builder().build() {
+            Ok(c) => c,
+            Err(err) => {
+                return Err(io::Error::new(ErrorKind::Other, format!("tls build: {}", err)));
+            }
+        };
+        let cx = tokio_native_tls::TlsConnector::from(cx);
+
+        match cx.connect(domain, stream).await {
+            Ok(s) => Ok(s),
+            Err(err) => Err(io::Error::new(ErrorKind::Other, format!("tls connect: {}", err))),
+        }
+    }
+}
+
+// native-tls is preferred if both of TLS features are enabled
+#[cfg(all(
+    feature = "local-online-config",
+    feature = "local-http-rustls",
+    not(feature = "local-http-native-tls")
+))]
+mod tls {
+    use std::{
+        io::{self, ErrorKind},
+        sync::Arc,
+    };
+
+    use lazy_static::lazy_static;
+    use log::warn;
+    use tokio::net::TcpStream;
+    use tokio_rustls::{client::TlsStream, rustls::ClientConfig, webpki::DNSNameRef, TlsConnector};
+
+    lazy_static! {
+        static ref TLS_CONFIG: Arc<ClientConfig> = {
+            let mut config = ClientConfig::new();
+
+            match rustls_native_certs::load_native_certs() {
+                Ok(store) => {
+                    config.root_store = store;
+                }
+                Err((_, err)) => {
+                    warn!("failed to load native certs, {}", err);
+
+                    config
+                        .root_store
+                        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+                }
+            }
+
+            Arc::new(config)
+        };
+    }
+
+    pub async fn connect(stream: TcpStream, domain: &str) -> io::Result<TlsStream<TcpStream>> {
+        let connector = TlsConnector::from(TLS_CONFIG.clone());
+
+        let host = match DNSNameRef::try_from_ascii_str(domain) {
+            Ok(n) => n,
+            Err(_) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid dnsname \"{}\"", domain),
+                ));
+            }
+        };
+
+        connector.connect(host, stream).await
+    }
+}
+
+#[cfg(all(
+    feature = "local-online-config",
+    not(any(feature = "local-http-native-tls", feature = "local-http-rustls"))
+))]
+mod tls {
+    use std::io::{self, ErrorKind};
+
+    use tokio::net::TcpStream;
+
+    pub async fn connect(_stream: TcpStream, _domain: &str) -> io::Result<TcpStream> {
+        let err = io::Error::new(
+            ErrorKind::Other,
+            "https is not supported, consider enable it by feature \"local-http-native-tls\" or \"local-http-rustls\"",
+        );
+        Err(err)
+    }
+}
+
+#[cfg(all(test, feature = "local-online-config"))]
+mod test {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::config::ConfigType;
+
+    use super::*;
+
+    /// Serves `body` to every HTTP request, returns URL of the document
+    async fn serve_document(body: Vec<u8>) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let body = body.clone();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    let _ = stream.read(&mut buf).await;
+
+                    let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len());
+                    let _ = stream.write_all(header.as_bytes()).await;
+                    let _ = stream.write_all(&body).await;
+                });
+            }
+        });
+
+        Url::parse(&format!("http://{}/sip008.json", addr)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn fetch_http_document() {
+        let document = r#"{"version": 1, "servers": [{"server": "127.0.0.1", "server_port": 8388,
+            "password": "test-password", "method": "aes-256-gcm"}]}"#;
+        let online_config = OnlineConfig::new(serve_document(document.as_bytes().to_vec()).await);
+
+        let context = Context::new_shared(Config::new(ConfigType::Socks5Local)).await;
+        let servers = fetch_servers(&context, &online_config).await.unwrap();
+        assert_eq!(servers.document, document);
+        assert_eq!(servers.servers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reject_large_document() {
+        let online_config = OnlineConfig::new(serve_document(vec![b' '; MAX_DOCUMENT_SIZE + 1]).await);
+
+        let context = Context::new_shared(Config::new(ConfigType::Socks5Local)).await;
+        let err = fetch_servers(&context, &online_config).await.err().unwrap();
+        assert!(err.to_string().contains("larger than"), "unexpected error {}", err);
+    }
+
+    #[tokio::test]
+    async fn reject_large_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sip008.json");
+        fs::write(&path, vec![b' '; MAX_DOCUMENT_SIZE + 1]).await.unwrap();
+
+        let mut online_config = OnlineConfig::new(Url::from_file_path(&path).unwrap());
+        let context = Context::new_shared(Config::new(ConfigType::Socks5Local)).await;
+        let err = fetch_servers(&context, &online_config).await.err().unwrap();
+        assert!(err.to_string().contains("larger than"), "unexpected error {}", err);
+
+        // Neither is the cache
+        online_config.url = Url::from_file_path(dir.path().join("not-found.json")).unwrap();
+        online_config.cache_path = Some(path);
+        let err = load_servers(&context, &online_config).await.err().unwrap();
+        assert!(err.to_string().contains("larger than"), "unexpected error {}", err);
+    }
+}
@@ -5,15 +5,16 @@ use std::{
     time::Duration,
 };
 
-use futures::future::{select_all, FutureExt};
-use log::{debug, error, trace, warn};
-use tokio::time;
+use futures::future::{self, FutureExt};
+use log::{debug, error, info, trace, warn};
+use tokio::{sync::mpsc, time};
 
 use crate::{
     config::Config,
     context::{Context, ServerState, SharedContext, SharedServerState},
     plugin::{PluginMode, Plugins},
     relay::{
+        control::{self, ServerControl},
         flow::{MultiServerFlowStatistic, SharedMultiServerFlowStatistic},
         manager::ManagerDatagram,
         tcprelay::server::run as run_tcp,
@@ -25,21 +26,41 @@ use crate::{
 /// Runs Relay server on server side.
 #[inline]
 pub async fn run(config: Config) -> io::Result<()> {
-    // Create a context containing a DNS resolver and server running state flag.
-    let server_state = ServerState::new_shared(&config).await;
+    run_with_control(config, ServerControl::default()).await
+}
 
+/// Runs Relay server on server side, controlled by `control`
+///
+/// ACL is replaced by configurations received from `control.reload_rx`.
+/// Returns `Ok` after shutting down by `control.shutdown_rx`.
+pub async fn run_with_control(config: Config, control: ServerControl) -> io::Result<()> {
     // Create statistics for multiple servers
     //
     // This is for statistic purpose for [Manage Multiple Users](https://github.com/shadowsocks/shadowsocks/wiki/Manage-Multiple-Users) APIs
     let flow_stat = MultiServerFlowStatistic::new_shared(&config);
 
-    run_with(config, flow_stat, server_state).await
+    run_with_flow_stat(config, flow_stat, control).await
+}
+
+/// Runs Relay server on server side, traffic of every server (user) is counted in `flow_stat`
+///
+/// `flow_stat` must be created from `config` by `MultiServerFlowStatistic::new_shared`
+pub async fn run_with_flow_stat(
+    config: Config,
+    flow_stat: SharedMultiServerFlowStatistic,
+    control: ServerControl,
+) -> io::Result<()> {
+    // Create a context containing a DNS resolver and server running state flag.
+    let server_state = ServerState::new_shared(&config).await;
+
+    run_with(config, flow_stat, server_state, control).await
 }
 
 pub(crate) async fn run_with(
     mut config: Config,
     flow_stat: SharedMultiServerFlowStatistic,
     server_stat: SharedServerState,
+    control: ServerControl,
 ) -> io::Result<()> {
     trace!("initializing server with {:?}", config);
 
@@ -71,11 +92,13 @@ pub(crate) async fn run_with(
     let mode = config.mode;
 
     let mut vf = Vec::new();
+    // Kept running while draining connections
+    let mut relay_vf = Vec::new();
 
     let context = if mode.enable_tcp() {
         if config.has_server_plugins() {
             let plugins = Plugins::launch_plugins(&mut config, PluginMode::Server).await?;
-            vf.push(plugins.join_all().boxed());
+            relay_vf.push(plugins.join_all().boxed());
         }
 
         let context = Context::new_with_state_shared(config, server_stat);
@@ -92,7 +115,30 @@ pub(crate) async fn run_with(
         // Run UDP relay before starting plugins
         // Because plugins doesn't support UDP relay
         let udp_fut = run_udp(context.clone(), flow_stat.clone());
-        vf.push(udp_fut.boxed());
+        relay_vf.push(udp_fut.boxed());
+    }
+
+    if let Some(reload_rx) = control.reload_rx {
+        let reload_fut = reload_task(context.clone(), reload_rx);
+        vf.push(reload_fut.boxed());
+    }
+
+    if context.config().replay_filter.snapshot_path.is_some() {
+        let snapshot_fut = control::replay_filter_snapshot_task(context.clone());
+        vf.push(snapshot_fut.boxed());
+    }
+
+    #[cfg(feature = "metrics")]
+    {
+        use crate::relay::metrics::run as run_metrics;
+
+        context.metrics().register_servers(context.config(), &flow_stat);
+
+        // Servers created by manager don't have `metrics_addr`, metrics are exported by manager
+        if let Some(metrics_addr) = context.config().metrics_addr {
+            let metrics_fut = run_metrics(metrics_addr, context.metrics().clone());
+            vf.push(metrics_fut.boxed());
+        }
     }
 
     // If specified manager-address, reports transmission statistic to it
@@ -103,13 +149,39 @@ pub(crate) async fn run_with(
         vf.push(report_fut.boxed());
     }
 
-    let (res, ..) = select_all(vf.into_iter()).await;
-    error!("one of servers exited unexpectly, result: {:?}", res);
+    control::serve(context, vf, relay_vf, control.shutdown_rx).await
+}
 
-    // Tells all detached tasks to exit
-    context.set_server_stopped();
+async fn reload_task(context: SharedContext, mut reload_rx: mpsc::Receiver<Config>) -> io::Result<()> {
+    while let Some(config) = reload_rx.recv().await {
+        if let Err(err) = config.check_integrity() {
+            error!("failed to reload configuration, {}", err);
+            continue;
+        }
+
+        // Listening servers couldn't be changed without closing connections on them
+        if servers_changed(context.config(), &config) {
+            warn!("servers in configuration are changed, they will be applied after restarting");
+        }
 
-    Err(io::Error::new(io::ErrorKind::Other, "server exited unexpectly"))
+        context.set_acl(config.acl).await;
+        info!("reloaded ACL");
+    }
+
+    // Keep serving without reloading
+    future::pending().await
+}
+
+/// Check if servers in `reloaded` are different from the `running` ones
+fn servers_changed(running: &Config, reloaded: &Config) -> bool {
+    running.server.len() != reloaded.server.len()
+        || running.server.iter().zip(reloaded.server.iter()).any(|(r, n)| {
+            r.addr() != n.addr()
+                || r.method() != n.method()
+                || r.is_aead_2022() != n.is_aead_2022()
+                || r.password() != n.password()
+                || r.plugin() != n.plugin()
+        })
 }
 
 async fn manager_report_task(context: SharedContext, flow_stat: SharedMultiServerFlowStatistic) -> io::Result<()> {
@@ -124,30 +196,30 @@ async fn manager_report_task(context: SharedContext, flow_stat: SharedMultiServe
         // Ref: https://github.com/shadowsocks/shadowsocks/wiki/Manage-Multiple-Users
         //
         // If you are using manager in this project, this is not required.
-        for svr_cfg in &context.config().server {
+        for svr_idxs in context.config().server_groups() {
+            let svr_cfg = context.server_config(svr_idxs[0]);
             let port = svr_cfg.addr().port();
 
-            if let Some(ref fstat) = flow_stat.get(port) {
-                let stat = format!("stat: {{\"{}\":{}}}", port, fstat.trans_stat());
-
-                match socket.send_to_manager(stat.as_bytes(), &context, &manager_addr).await {
-                    Ok(..) => {
-                        trace!(
-                            "sent {} for server \"{}\" to manger \"{}\"",
-                            stat,
-                            svr_cfg.addr(),
-                            manager_addr
-                        );
-                    }
-                    Err(err) => {
-                        debug!(
-                            "failed to send {} for server \"{}\" to manager \"{}\", error: {}",
-                            stat,
-                            svr_cfg.addr(),
-                            manager_addr,
-                            err
-                        );
-                    }
+            // Users sharing the same port are reported together
+            let stat = format!("stat: {{\"{}\":{}}}", port, flow_stat.port_trans_stat(port));
+
+            match socket.send_to_manager(stat.as_bytes(), &context, &manager_addr).await {
+                Ok(..) => {
+                    trace!(
+                        "sent {} for server \"{}\" to manger \"{}\"",
+                        stat,
+                        svr_cfg.addr(),
+                        manager_addr
+                    );
+                }
+                Err(err) => {
+                    debug!(
+                        "failed to send {} for server \"{}\" to manager \"{}\", error: {}",
+                        stat,
+                        svr_cfg.addr(),
+                        manager_addr,
+                        err
+                    );
                 }
             }
         }
@@ -158,3 +230,75 @@ async fn manager_report_task(context: SharedContext, flow_stat: SharedMultiServe
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        config::{ConfigType, ServerAddr, ServerConfig},
+        crypto::v1::CipherKind,
+        plugin::PluginConfig,
+    };
+
+    use super::*;
+
+    fn server_config(svr_cfg: ServerConfig) -> Config {
+        let mut config = Config::new(ConfigType::Server);
+        config.server.push(svr_cfg);
+        config
+    }
+
+    #[test]
+    fn servers_changed_by_reloading() {
+        let addr = "127.0.0.1:8388".parse::<ServerAddr>().unwrap();
+        let svr_cfg = ServerConfig::new(addr, "test-password".to_owned(), CipherKind::AES_256_GCM, None, None);
+        let running = server_config(svr_cfg.clone());
+
+        assert!(!servers_changed(&running, &server_config(svr_cfg.clone())));
+
+        // Address of plugin is set after the running server is started
+        let mut started = svr_cfg.clone();
+        started.set_plugin_addr("127.0.0.1:8389".parse().unwrap());
+        assert!(!servers_changed(
+            &server_config(started),
+            &server_config(svr_cfg.clone())
+        ));
+
+        let mut reloaded = svr_cfg.clone();
+        reloaded.set_method(CipherKind::CHACHA20_POLY1305, "test-password".to_owned());
+        assert!(servers_changed(&running, &server_config(reloaded)));
+
+        let addr = "127.0.0.1:8390".parse::<ServerAddr>().unwrap();
+        let reloaded = ServerConfig::new(addr, "test-password".to_owned(), CipherKind::AES_256_GCM, None, None);
+        assert!(servers_changed(&running, &server_config(reloaded)));
+
+        let plugin = PluginConfig {
+            plugin: "obfs-server".to_owned(),
+            plugin_opts: Some("obfs=http".to_owned()),
+            plugin_args: Vec::new(),
+        };
+        let addr = "127.0.0.1:8388".parse::<ServerAddr>().unwrap();
+        let reloaded = ServerConfig::new(
+            addr,
+            "test-password".to_owned(),
+            CipherKind::AES_256_GCM,
+            None,
+            Some(plugin),
+        );
+        assert!(servers_changed(&running, &server_config(reloaded)));
+    }
+
+    #[cfg(feature = "aead-cipher-2022")]
+    #[test]
+    fn servers_changed_by_aead_2022() {
+        let addr = "127.0.0.1:8388".parse::<ServerAddr>().unwrap();
+        let password = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        let svr_cfg =
+            ServerConfig::with_method_name(addr, password.to_owned(), "2022-blake3-aes-256-gcm", None, None).unwrap();
+        let running = server_config(svr_cfg.clone());
+
+        // The original AES-256-GCM method with the same password
+        let mut reloaded = svr_cfg;
+        reloaded.set_method(CipherKind::AES_256_GCM, password.to_owned());
+        assert!(servers_changed(&running, &server_config(reloaded)));
+    }
+}
@@ -1,6 +1,7 @@
 //! Socks5 protocol definition (RFC1928)
 //!
 //! Implements [SOCKS Protocol Version 5](https://www.ietf.org/rfc/rfc1928.txt) proxy protocol
+//! and [Username/Password Authentication for SOCKS V5](https://tools.ietf.org/html/rfc1929)
 
 use std::{
     convert::From,
@@ -21,6 +22,8 @@ pub use self::consts::{
     SOCKS5_AUTH_METHOD_NONE,
     SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE,
     SOCKS5_AUTH_METHOD_PASSWORD,
+    SOCKS5_AUTH_PASSWORD_FAILURE,
+    SOCKS5_AUTH_PASSWORD_SUCCEEDED,
 };
 
 #[rustfmt::skip]
@@ -32,6 +35,10 @@ mod consts {
     pub const SOCKS5_AUTH_METHOD_PASSWORD:             u8 = 0x02;
     pub const SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE:       u8 = 0xff;
 
+    pub const SOCKS5_AUTH_PASSWORD_VERSION:            u8 = 0x01;
+    pub const SOCKS5_AUTH_PASSWORD_SUCCEEDED:          u8 = 0x00;
+    pub const SOCKS5_AUTH_PASSWORD_FAILURE:            u8 = 0x01;
+
     pub const SOCKS5_CMD_TCP_CONNECT:                  u8 = 0x01;
     pub const SOCKS5_CMD_TCP_BIND:                     u8 = 0x02;
     pub const SOCKS5_CMD_UDP_ASSOCIATE:                u8 = 0x03;
@@ -56,7 +63,7 @@ mod consts {
 pub enum Command {
     /// CONNECT command (TCP tunnel)
     TcpConnect,
-    /// BIND command (Not supported in ShadowSocks)
+    /// BIND command, only supported for targets bypassed by ACL
     TcpBind,
     /// UDP ASSOCIATE command
     UdpAssociate,
@@ -735,6 +742,159 @@ impl HandshakeResponse {
     }
 }
 
+/// SOCKS5 username/password authentication request packet
+///
+/// ```plain
+/// +----+------+----------+------+----------+
+/// |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+/// +----+------+----------+------+----------+
+/// | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+/// +----+------+----------+------+----------+
+/// ```
+#[derive(Clone, Debug)]
+pub struct PasswdAuthRequest {
+    pub uname: Vec<u8>,
+    pub passwd: Vec<u8>,
+}
+
+impl PasswdAuthRequest {
+    /// Creates an authentication request
+    ///
+    /// Both `uname` and `passwd` must not be longer than 255 bytes
+    pub fn new<U, P>(uname: U, passwd: P) -> PasswdAuthRequest
+    where
+        U: Into<Vec<u8>>,
+        P: Into<Vec<u8>>,
+    {
+        let uname = uname.into();
+        let passwd = passwd.into();
+        assert!(
+            uname.len() <= u8::MAX as usize && passwd.len() <= u8::MAX as usize,
+            "username and password must not be longer than 255 bytes"
+        );
+
+        PasswdAuthRequest { uname, passwd }
+    }
+
+    /// Read from a reader
+    pub async fn read_from<R>(r: &mut R) -> io::Result<PasswdAuthRequest>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut buf = [0u8; 2];
+        let _ = r.read_exact(&mut buf).await?;
+
+        let ver = buf[0];
+        if ver != consts::SOCKS5_AUTH_PASSWORD_VERSION {
+            use std::io::{Error, ErrorKind};
+            let err = Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported socks password authentication version {:#x}", ver),
+            );
+            return Err(err);
+        }
+
+        let mut uname = vec![0u8; buf[1] as usize];
+        let _ = r.read_exact(&mut uname).await?;
+
+        let mut plen = [0u8; 1];
+        let _ = r.read_exact(&mut plen).await?;
+
+        let mut passwd = vec![0u8; plen[0] as usize];
+        let _ = r.read_exact(&mut passwd).await?;
+
+        Ok(PasswdAuthRequest { uname, passwd })
+    }
+
+    /// Write to a writer
+    pub async fn write_to<W>(&self, w: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = BytesMut::with_capacity(self.serialized_len());
+        self.write_to_buf(&mut buf);
+        w.write_all(&buf).await
+    }
+
+    /// Write to buffer
+    pub fn write_to_buf<B: BufMut>(&self, buf: &mut B) {
+        let PasswdAuthRequest { ref uname, ref passwd } = *self;
+        buf.put_slice(&[consts::SOCKS5_AUTH_PASSWORD_VERSION, uname.len() as u8]);
+        buf.put_slice(uname);
+        buf.put_u8(passwd.len() as u8);
+        buf.put_slice(passwd);
+    }
+
+    /// Get length of bytes
+    pub fn serialized_len(&self) -> usize {
+        3 + self.uname.len() + self.passwd.len()
+    }
+}
+
+/// SOCKS5 username/password authentication response packet
+///
+/// ```plain
+/// +----+--------+
+/// |VER | STATUS |
+/// +----+--------+
+/// | 1  |   1    |
+/// +----+--------+
+/// ```
+#[derive(Clone, Debug, Copy)]
+pub struct PasswdAuthResponse {
+    pub status: u8,
+}
+
+impl PasswdAuthResponse {
+    /// Creates an authentication response
+    pub fn new(status: u8) -> PasswdAuthResponse {
+        PasswdAuthResponse { status }
+    }
+
+    /// Read from a reader
+    pub async fn read_from<R>(r: &mut R) -> io::Result<PasswdAuthResponse>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut buf = [0u8; 2];
+        let _ = r.read_exact(&mut buf).await?;
+
+        let ver = buf[0];
+        let status = buf[1];
+
+        if ver != consts::SOCKS5_AUTH_PASSWORD_VERSION {
+            use std::io::{Error, ErrorKind};
+            let err = Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported socks password authentication version {:#x}", ver),
+            );
+            Err(err)
+        } else {
+            Ok(PasswdAuthResponse { status })
+        }
+    }
+
+    /// Write to a writer
+    pub async fn write_to<W>(self, w: &mut W) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = BytesMut::with_capacity(self.serialized_len());
+        self.write_to_buf(&mut buf);
+        w.write_all(&buf).await
+    }
+
+    /// Write to buffer
+    pub fn write_to_buf<B: BufMut>(self, buf: &mut B) {
+        buf.put_slice(&[consts::SOCKS5_AUTH_PASSWORD_VERSION, self.status]);
+    }
+
+    /// Length in bytes
+    pub fn serialized_len(self) -> usize {
+        2
+    }
+}
+
 /// UDP ASSOCIATE request header
 ///
 /// ```plain
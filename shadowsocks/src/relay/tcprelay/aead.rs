@@ -80,6 +80,25 @@ impl DecryptedReader {
         }
     }
 
+    /// Create a reader with the first encrypted length chunk (`*DataLen*` + `DataLen_TAG`) already read from stream
+    ///
+    /// Servers with multiple users have to read the first chunk for identifying users
+    pub fn with_length_chunk(method: CipherKind, key: &[u8], nonce: &[u8], chunk: &[u8]) -> DecryptedReader {
+        debug_assert_eq!(chunk.len(), 2 + method.tag_len());
+
+        let mut buffer = BytesMut::with_capacity(chunk.len());
+        buffer.put_slice(chunk);
+
+        DecryptedReader {
+            buffer,
+            cipher: Cipher::new(method, key, nonce),
+            pos: 0,
+            buffered: false,
+            tag_size: method.tag_len(),
+            steps: DecryptReadStep::Length,
+        }
+    }
+
     /// Attempt to read decrypted data from reader
     ///
     /// ## Implementation Notes
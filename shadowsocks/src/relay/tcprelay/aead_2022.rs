@@ -0,0 +1,579 @@
+//! AEAD-2022 packet I/O facilities
+//!
+//! AEAD-2022 protocol is defined in https://github.com/shadowsocks/shadowsocks-org/issues/196.
+//!
+//! ```plain
+//! TCP request header (before encryption)
+//! +------+-----------+--------+
+//! | TYPE | TIMESTAMP | LENGTH |                              Fixed-length header, TYPE is 0
+//! +------+-----------+--------+
+//! |  1   |     8     |   2    |
+//! +------+-----------+--------+
+//! +------+----------+----------+------------+----------+-----------------+
+//! | ATYP | DST.ADDR | DST.PORT | PADDINGLEN | PADDING  | INITIAL PAYLOAD |  Variable-length header of LENGTH
+//! +------+----------+----------+------------+----------+-----------------+
+//! |  1   | Variable |    2     |     2      | Variable |    Variable     |
+//! +------+----------+----------+------------+----------+-----------------+
+//!
+//! TCP response header (before encryption)
+//! +------+-----------+--------------+--------+
+//! | TYPE | TIMESTAMP | REQUEST SALT | LENGTH |                 Fixed-length header, TYPE is 1
+//! +------+-----------+--------------+--------+
+//! |  1   |     8     |    Fixed     |   2    |
+//! +------+-----------+--------------+--------+
+//! followed by the first data chunk of LENGTH
+//!
+//! TCP request and response (after encryption, *ciphertext*)
+//! +--------+-------------------------+-------------+---------------------------+-------------+
+//! |  SALT  |  *Fixed-length header*  |  Fixed_TAG  |  *Variable-length chunk*  |  Chunk_TAG  |
+//! +--------+-------------------------+-------------+---------------------------+-------------+
+//! | Fixed  |          Fixed          |     16      |          Variable         |     16      |
+//! +--------+-------------------------+-------------+---------------------------+-------------+
+//! ```
+//!
+//! Chunks after headers are encrypted the same as the original AEAD protocol, but they could be up to 0xFFFF bytes.
+use std::{
+    cmp,
+    io::{self, ErrorKind},
+    marker::Unpin,
+    pin::Pin,
+    slice,
+    task::{Context, Poll},
+};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::ready;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+use crate::{
+    context::SharedContext,
+    crypto::v1::CipherKind,
+    relay::aead_2022::{check_timestamp, padding_len, unix_timestamp, StreamCipher, TAG_LEN},
+};
+
+/// AEAD-2022 packet payload must be smaller than 0xFFFF
+pub const MAX_PACKET_SIZE: usize = 0xFFFF;
+
+const HEADER_TYPE_REQUEST: u8 = 0;
+const HEADER_TYPE_RESPONSE: u8 = 1;
+
+/// Type of a stream's fixed-length header
+#[derive(Debug, Clone)]
+pub enum HeaderKind {
+    /// Request header, sent by clients
+    Request,
+    /// Response header, sent by servers, carrying salt of the request
+    Response(Bytes),
+}
+
+impl HeaderKind {
+    /// Length of the fixed-length header, without tag
+    fn header_len(&self) -> usize {
+        match *self {
+            HeaderKind::Request => 1 + 8 + 2,
+            HeaderKind::Response(ref salt) => 1 + 8 + salt.len() + 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum DecryptReadStep {
+    Header,
+    Init,
+    Length,
+    Data(usize),
+    Eof,
+}
+
+/// Reader wrapper that will decrypt data automatically
+pub struct DecryptedReader {
+    buffer: BytesMut,
+    cipher: StreamCipher,
+    header: HeaderKind,
+    // Servers check if salt of the request is repeated, after its header is authenticated
+    replay_check: Option<(SharedContext, Bytes)>,
+    pos: usize,
+    buffered: bool,
+    steps: DecryptReadStep,
+}
+
+impl DecryptedReader {
+    /// Creates a reader of stream with `salt`, expecting a fixed-length header of `header`
+    pub fn new(method: CipherKind, key: &[u8], salt: &[u8], header: HeaderKind) -> DecryptedReader {
+        DecryptedReader {
+            buffer: BytesMut::with_capacity(header.header_len() + TAG_LEN),
+            cipher: StreamCipher::new(method, key, salt),
+            header,
+            replay_check: None,
+            pos: 0,
+            buffered: false,
+            steps: DecryptReadStep::Header,
+        }
+    }
+
+    /// Creates a reader of request with `salt` for servers, rejecting requests with salts seen in `context`
+    pub fn new_request(context: SharedContext, method: CipherKind, key: &[u8], salt: &[u8]) -> DecryptedReader {
+        DecryptedReader {
+            replay_check: Some((context, Bytes::copy_from_slice(salt))),
+            ..DecryptedReader::new(method, key, salt, HeaderKind::Request)
+        }
+    }
+
+    /// Attempt to read decrypted data from reader
+    ///
+    /// ## Implementation Notes
+    ///
+    /// `DecryptedReader` will try to use `dst` to store immediate data. Any implementations that call `poll_read_decrypted` MUST-NOT
+    /// modify `dst`'s underlying buffer when `Poll::Pending`.
+    pub fn poll_read_decrypted<R>(
+        &mut self,
+        ctx: &mut Context<'_>,
+        r: &mut R,
+        dst: &mut ReadBuf,
+    ) -> Poll<io::Result<()>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        while !self.buffered || self.pos >= self.buffer.len() {
+            // Refill buffer
+            match self.steps {
+                DecryptReadStep::Header => {
+                    match ready!(self.poll_read_decrypted_header_buffered(ctx, r)) {
+                        Ok(plen) => {
+                            // The variable-length header, or the first data chunk
+                            self.buffer.clear();
+                            self.steps = DecryptReadStep::Data(plen);
+                            self.buffer.reserve(plen + TAG_LEN);
+                        }
+                        Err(err) => {
+                            // Peer closed the stream without sending any chunk
+                            if err.kind() == ErrorKind::UnexpectedEof && self.buffer.is_empty() {
+                                self.steps = DecryptReadStep::Eof;
+                            } else {
+                                return Poll::Ready(Err(err));
+                            }
+                        }
+                    }
+                }
+                DecryptReadStep::Init => {
+                    // Cleanup buffer and ready for refill
+                    self.buffer.clear();
+                    self.pos = 0;
+                    self.buffered = false;
+
+                    self.buffer.reserve(2 + TAG_LEN);
+                    self.steps = DecryptReadStep::Length;
+                }
+                DecryptReadStep::Length => {
+                    match ready!(self.poll_read_decrypted_length_buffered(ctx, r)) {
+                        Ok(plen) => {
+                            // Clear buffer before overwriting it
+                            self.buffer.clear();
+
+                            // Next step, read data
+                            self.steps = DecryptReadStep::Data(plen);
+                            self.buffer.reserve(plen + TAG_LEN);
+                        }
+                        Err(err) => {
+                            if err.kind() == ErrorKind::UnexpectedEof && self.buffer.is_empty() {
+                                self.steps = DecryptReadStep::Eof;
+                            } else {
+                                return Poll::Ready(Err(err));
+                            }
+                        }
+                    };
+                }
+                DecryptReadStep::Data(plen) => ready!(self.poll_read_decrypted_data_buffered(ctx, r, plen))?,
+                DecryptReadStep::Eof => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let remaining_len = self.buffer.len() - self.pos;
+        let n = cmp::min(dst.remaining(), remaining_len);
+        dst.put_slice(&self.buffer[self.pos..self.pos + n]);
+        self.pos += n;
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_read_decrypted_header_buffered<R>(&mut self, ctx: &mut Context<'_>, r: &mut R) -> Poll<io::Result<usize>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let hlen = self.header.header_len();
+        let mlen = hlen + TAG_LEN;
+        ready!(self.poll_read_exact_buffered(ctx, r, mlen))?;
+
+        if !self.cipher.decrypt_packet(&mut self.buffer[..mlen]) {
+            return Poll::Ready(Err(io::Error::new(ErrorKind::Other, "invalid tag-in")));
+        }
+
+        let mut header = &self.buffer[..hlen];
+
+        let (expected_type, request_salt) = match self.header {
+            HeaderKind::Request => (HEADER_TYPE_REQUEST, None),
+            HeaderKind::Response(ref salt) => (HEADER_TYPE_RESPONSE, Some(salt)),
+        };
+
+        let header_type = header.get_u8();
+        if header_type != expected_type {
+            let err = io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unexpected header type {}, expecting {}", header_type, expected_type),
+            );
+            return Poll::Ready(Err(err));
+        }
+
+        check_timestamp(header.get_u64())?;
+
+        // Salts are remembered only after the header is authenticated and not expired,
+        // so random salts couldn't evict the others
+        if let Some((ref context, ref salt)) = self.replay_check {
+            if context.check_aead_2022_salt_and_set(salt) {
+                let err = io::Error::new(ErrorKind::Other, "detected repeated salt");
+                return Poll::Ready(Err(err));
+            }
+        }
+
+        if let Some(salt) = request_salt {
+            if &header[..salt.len()] != salt.as_ref() {
+                let err = io::Error::new(ErrorKind::InvalidData, "response doesn't match salt of the request");
+                return Poll::Ready(Err(err));
+            }
+            header.advance(salt.len());
+        }
+
+        Poll::Ready(Ok(header.get_u16() as usize))
+    }
+
+    fn poll_read_decrypted_length_buffered<R>(&mut self, ctx: &mut Context<'_>, r: &mut R) -> Poll<io::Result<usize>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mlen = 2 + TAG_LEN;
+        ready!(self.poll_read_exact_buffered(ctx, r, mlen))?;
+
+        // Done reading, decrypt it
+        let m = &mut self.buffer[..mlen];
+        if !self.cipher.decrypt_packet(m) {
+            return Poll::Ready(Err(io::Error::new(ErrorKind::Other, "invalid tag-in")));
+        }
+
+        Poll::Ready(Ok(u16::from_be_bytes([m[0], m[1]]) as usize))
+    }
+
+    fn poll_read_decrypted_data_buffered<R>(
+        &mut self,
+        ctx: &mut Context<'_>,
+        r: &mut R,
+        plen: usize,
+    ) -> Poll<io::Result<()>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mlen = plen + TAG_LEN;
+        ready!(self.poll_read_exact_buffered(ctx, r, mlen))?;
+
+        // Done reading data, decrypt it
+        let m: &mut [u8] = self.buffer.as_mut();
+        assert_eq!(m.len(), mlen);
+
+        if !self.cipher.decrypt_packet(m) {
+            return Poll::Ready(Err(io::Error::new(ErrorKind::Other, "invalid tag-in")));
+        }
+
+        // self.buffer[..plen] stores decrypted data
+        self.buffer.truncate(plen);
+        self.pos = 0;
+        self.buffered = true;
+
+        // Next step, read length
+        self.steps = DecryptReadStep::Init;
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_read_exact_buffered<R>(&mut self, ctx: &mut Context<'_>, r: &mut R, size: usize) -> Poll<io::Result<()>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut remaining = size - self.buffer.len();
+        while remaining > 0 {
+            let raw_buffer = &mut self.buffer.bytes_mut()[..remaining];
+            assert_eq!(raw_buffer.len(), remaining);
+
+            let mut buffer =
+                unsafe { ReadBuf::uninit(slice::from_raw_parts_mut(raw_buffer.as_mut_ptr() as *mut _, remaining)) };
+
+            // It has enough space, I am sure about that
+            ready!(Pin::new(&mut *r).poll_read(ctx, &mut buffer))?;
+            let n = buffer.filled().len();
+            unsafe {
+                self.buffer.advance_mut(n);
+            }
+
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+            }
+
+            remaining -= n;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+enum EncryptWriteStep {
+    Nothing,
+    Writing,
+}
+
+/// Writer wrapper that will encrypt data automatically
+pub struct EncryptedWriter {
+    cipher: StreamCipher,
+    /// Fixed-length header, taken when the first chunk is written
+    header: Option<HeaderKind>,
+    steps: EncryptWriteStep,
+    buf: BytesMut,
+}
+
+impl EncryptedWriter {
+    /// Creates a new EncryptedWriter, sending a fixed-length header of `header` with the first chunk
+    pub fn new(method: CipherKind, key: &[u8], salt: &[u8], header: HeaderKind) -> EncryptedWriter {
+        // salt should be sent with the first packet
+        let mut buf = BytesMut::with_capacity(salt.len());
+        buf.put(salt);
+
+        EncryptedWriter {
+            cipher: StreamCipher::new(method, key, salt),
+            header: Some(header),
+            steps: EncryptWriteStep::Nothing,
+            buf,
+        }
+    }
+
+    pub fn poll_write_encrypted<W>(
+        &mut self,
+        ctx: &mut Context<'_>,
+        w: &mut W,
+        mut data: &[u8],
+    ) -> Poll<io::Result<usize>>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        if data.len() > MAX_PACKET_SIZE {
+            data = &data[..MAX_PACKET_SIZE];
+        }
+
+        ready!(self.poll_write_all_encrypted(ctx, w, data))?;
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_write_all_encrypted<W>(&mut self, ctx: &mut Context<'_>, w: &mut W, data: &[u8]) -> Poll<io::Result<()>>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        assert!(
+            data.len() <= MAX_PACKET_SIZE,
+            "buffer size too large, AEAD-2022 encryption protocol requires buffer to be smaller than 0xFFFF"
+        );
+
+        loop {
+            match self.steps {
+                EncryptWriteStep::Nothing => {
+                    let plen = data.len();
+
+                    // The fixed-length header for the first chunk, otherwise the length chunk
+                    let mut header = BytesMut::with_capacity(1 + 8 + 32 + 2);
+                    match self.header.take() {
+                        Some(HeaderKind::Request) => {
+                            header.put_u8(HEADER_TYPE_REQUEST);
+                            header.put_u64(unix_timestamp());
+                        }
+                        Some(HeaderKind::Response(ref salt)) => {
+                            header.put_u8(HEADER_TYPE_RESPONSE);
+                            header.put_u64(unix_timestamp());
+                            header.put_slice(salt);
+                        }
+                        None => {}
+                    }
+                    header.put_u16(plen as u16);
+
+                    self.buf.reserve(header.len() + TAG_LEN + plen + TAG_LEN);
+
+                    let hstart = self.buf.len();
+                    self.buf.put_slice(&header);
+                    self.buf.resize(self.buf.len() + TAG_LEN, 0);
+                    self.cipher.encrypt_packet(&mut self.buf[hstart..]);
+
+                    let dstart = self.buf.len();
+                    self.buf.put_slice(data);
+                    self.buf.resize(self.buf.len() + TAG_LEN, 0);
+                    self.cipher.encrypt_packet(&mut self.buf[dstart..]);
+
+                    self.steps = EncryptWriteStep::Writing;
+                }
+                EncryptWriteStep::Writing => {
+                    while self.buf.has_remaining() {
+                        let n = ready!(Pin::new(&mut *w).poll_write(ctx, self.buf.bytes()))?;
+                        self.buf.advance(n);
+                        if n == 0 {
+                            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                        }
+                    }
+
+                    // Reclaim buffer
+                    // NOTE: This operation won't free allocated memory
+                    self.buf.clear();
+                    self.steps = EncryptWriteStep::Nothing;
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+    }
+}
+
+/// Append padding to the variable-length request header, right after the target address
+pub fn write_padding(buf: &mut BytesMut, payload_len: usize) {
+    let padding_len = padding_len(payload_len);
+    buf.put_u16(padding_len as u16);
+    buf.resize(buf.len() + padding_len, 0);
+}
+
+/// Skip padding in the variable-length request header, right after the target address
+pub async fn skip_padding<R>(r: &mut R) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let padding_len = r.read_u16().await? as usize;
+
+    let mut padding = vec![0u8; padding_len];
+    r.read_exact(&mut padding).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use futures::future::poll_fn;
+
+    use crate::{
+        config::{Config, ConfigType},
+        context::Context as SsContext,
+        relay::{aead_2022::TEST_TIMESTAMP, socks5::Address},
+    };
+
+    use super::*;
+
+    const SALT: [u8; 32] = [7u8; 32];
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    async fn encrypt(method: CipherKind, key: &[u8], salt: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut writer = EncryptedWriter::new(method, key, salt, HeaderKind::Request);
+        let mut buf = Vec::new();
+        poll_fn(|cx| writer.poll_write_encrypted(cx, &mut buf, data))
+            .await
+            .unwrap();
+        buf
+    }
+
+    async fn decrypt(mut reader: DecryptedReader, mut r: &[u8]) -> io::Result<Vec<u8>> {
+        let mut data = [0u8; 64];
+        let n = poll_fn(|cx| {
+            let mut buf = ReadBuf::new(&mut data);
+            ready!(reader.poll_read_decrypted(cx, &mut r, &mut buf))?;
+            Poll::Ready(Ok::<_, io::Error>(buf.filled().len()))
+        })
+        .await?;
+        Ok(data[..n].to_vec())
+    }
+
+    async fn encrypt_request(key: &[u8], data: &[u8]) -> Vec<u8> {
+        encrypt(CipherKind::AES_256_GCM, key, &SALT, data).await
+    }
+
+    async fn decrypt_request(context: &SharedContext, key: &[u8], request: &[u8]) -> io::Result<Vec<u8>> {
+        let reader = DecryptedReader::new_request(context.clone(), CipherKind::AES_256_GCM, key, &SALT);
+        decrypt(reader, &request[SALT.len()..]).await
+    }
+
+    #[tokio::test]
+    async fn salts_remembered_after_authenticated() {
+        let context = SsContext::new_shared(Config::new(ConfigType::Server)).await;
+        let key = [1u8; 32];
+
+        // Requests that couldn't be authenticated don't remember their salts
+        let forged = encrypt_request(&[2u8; 32], b"hello").await;
+        assert!(decrypt_request(&context, &key, &forged).await.is_err());
+        assert!(decrypt_request(&context, &key, &forged).await.is_err());
+
+        let request = encrypt_request(&key, b"hello").await;
+        assert_eq!(decrypt_request(&context, &key, &request).await.unwrap(), b"hello");
+
+        // Replayed
+        let err = decrypt_request(&context, &key, &request).await.unwrap_err();
+        assert_eq!(err.to_string(), "detected repeated salt");
+    }
+
+    // Requests generated by `ProxyClientStream` of the shadowsocks crate 1.25.0 (crates.io), which is the
+    // reference implementation of SIP022 in upstream shadowsocks-rust, at `UPSTREAM_TIMESTAMP`
+    //
+    // PSKs are 0x00, 0x01, ... 0x0f (AES-128) or 0x1f (AES-256), salts are random and sent at the beginning.
+    // The payload is sent with the variable-length header in one chunk.
+    const UPSTREAM_VECTORS: &[(CipherKind, usize, &str)] = &[
+        (
+            CipherKind::AES_128_GCM,
+            16,
+            "b6a2b56db96c0a6ed417a0d05bd146a98a1183518ee0d78990784db0aaab22d9\
+             a2f2524775b2e133d4b3100035d3fa44b4686b2007caf31e2a003a5acbaea1f1\
+             08f9ddb76736482799c76e2d0a8a0b0846944154e2d82e9b11963eb128c952e1b7",
+        ),
+        (
+            CipherKind::AES_256_GCM,
+            32,
+            "2db8c64f964a05f9e78acc9b07c581efc33214a8fd49980ce9603fe37390e00a\
+             5f549a78d76b88c8803a126366bb712fc067998c0b34c8f300d4ebce6c3b4f7b\
+             44044f0ce3241df1e82e9b37db14bebecceba903219108b9b81ebc11956293a6\
+             5f8461a68886a31642aa930d7d184358ee",
+        ),
+    ];
+
+    const UPSTREAM_TIMESTAMP: u64 = 1792200737;
+    const UPSTREAM_PAYLOAD: &[u8] = b"shadowsocks 2022 known answer";
+
+    // Variable-length header of the upstream requests, to 127.0.0.1:8080 without padding, followed by the payload
+    fn upstream_header() -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        Address::SocketAddress("127.0.0.1:8080".parse::<SocketAddr>().unwrap()).write_to_buf(&mut buf);
+        write_padding(&mut buf, UPSTREAM_PAYLOAD.len());
+        buf.extend_from_slice(UPSTREAM_PAYLOAD);
+        buf.to_vec()
+    }
+
+    #[tokio::test]
+    async fn upstream_vectors() {
+        TEST_TIMESTAMP.with(|t| t.set(Some(UPSTREAM_TIMESTAMP)));
+
+        let context = SsContext::new_shared(Config::new(ConfigType::Server)).await;
+        let header = upstream_header();
+
+        for &(method, key_len, request) in UPSTREAM_VECTORS {
+            let key = (0..key_len as u8).collect::<Vec<_>>();
+            let request = hex(request);
+            let (salt, chunks) = request.split_at(key_len);
+
+            assert_eq!(encrypt(method, &key, salt, &header).await, request);
+
+            let reader = DecryptedReader::new_request(context.clone(), method, &key, salt);
+            assert_eq!(decrypt(reader, chunks).await.unwrap(), header);
+        }
+    }
+}
@@ -24,6 +24,8 @@ use crate::{
         Command,
         HandshakeRequest,
         HandshakeResponse,
+        PasswdAuthRequest,
+        PasswdAuthResponse,
         Reply,
         TcpRequestHeader,
         TcpResponseHeader,
@@ -43,35 +45,22 @@ impl Socks5Client {
     where
         A: Into<Address>,
     {
-        let mut s = TcpStream::connect(proxy).await?;
-
-        // 1. Handshake
-        let hs = HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_NONE]);
-        trace!("client connected, going to send handshake: {:?}", hs);
-
-        hs.write_to(&mut s).await?;
-
-        let hsp = HandshakeResponse::read_from(&mut s).await?;
-
-        trace!("got handshake response: {:?}", hsp);
-        assert_eq!(hsp.chosen_method, socks5::SOCKS5_AUTH_METHOD_NONE);
-
-        // 2. Send request header
-        let h = TcpRequestHeader::new(Command::TcpConnect, addr.into());
-        trace!("going to connect, req: {:?}", h);
-        h.write_to(&mut s).await?;
-
-        let hp = TcpResponseHeader::read_from(&mut s).await?;
-
-        trace!("got response: {:?}", hp);
-        match hp.reply {
-            Reply::Succeeded => (),
-            r => {
-                let err = io::Error::new(io::ErrorKind::Other, format!("{}", r));
-                return Err(err);
-            }
-        }
+        let (s, _) = Socks5Client::request(Command::TcpConnect, addr.into(), proxy, None).await?;
+        Ok(Socks5Client { stream: s })
+    }
 
+    /// Connects to `addr` via `proxy`, authenticates with username and password (RFC1929)
+    pub async fn connect_with_password<A>(
+        addr: A,
+        proxy: &SocketAddr,
+        username: &str,
+        password: &str,
+    ) -> io::Result<Socks5Client>
+    where
+        A: Into<Address>,
+    {
+        let auth = Some((username, password));
+        let (s, _) = Socks5Client::request(Command::TcpConnect, addr.into(), proxy, auth).await?;
         Ok(Socks5Client { stream: s })
     }
 
@@ -82,10 +71,39 @@ impl Socks5Client {
     where
         A: Into<Address>,
     {
+        let (s, addr) = Socks5Client::request(Command::UdpAssociate, addr.into(), proxy, None).await?;
+        Ok((Socks5Client { stream: s }, addr))
+    }
+
+    /// UDP Associate `addr` via `proxy`, authenticates with username and password (RFC1929)
+    pub async fn udp_associate_with_password<A>(
+        addr: A,
+        proxy: &SocketAddr,
+        username: &str,
+        password: &str,
+    ) -> io::Result<(Socks5Client, Address)>
+    where
+        A: Into<Address>,
+    {
+        let auth = Some((username, password));
+        let (s, addr) = Socks5Client::request(Command::UdpAssociate, addr.into(), proxy, auth).await?;
+        Ok((Socks5Client { stream: s }, addr))
+    }
+
+    async fn request(
+        cmd: Command,
+        addr: Address,
+        proxy: &SocketAddr,
+        auth: Option<(&str, &str)>,
+    ) -> io::Result<(TcpStream, Address)> {
         let mut s = TcpStream::connect(proxy).await?;
 
         // 1. Handshake
-        let hs = HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_NONE]);
+        let method = match auth {
+            Some(..) => socks5::SOCKS5_AUTH_METHOD_PASSWORD,
+            None => socks5::SOCKS5_AUTH_METHOD_NONE,
+        };
+        let hs = HandshakeRequest::new(vec![method]);
         trace!("client connected, going to send handshake: {:?}", hs);
 
         hs.write_to(&mut s).await?;
@@ -93,13 +111,40 @@ impl Socks5Client {
         let hsp = HandshakeResponse::read_from(&mut s).await?;
 
         trace!("got handshake response: {:?}", hsp);
-        assert_eq!(hsp.chosen_method, socks5::SOCKS5_AUTH_METHOD_NONE);
+        if hsp.chosen_method != method {
+            let err = io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "proxy chose an unsupported authentication method {:#x}",
+                    hsp.chosen_method
+                ),
+            );
+            return Err(err);
+        }
 
-        // 2. Send request header
-        let h = TcpRequestHeader::new(Command::UdpAssociate, addr.into());
-        trace!("going to connect, req: {:?}", h);
+        // 2. Authenticate
+        if let Some((username, password)) = auth {
+            let ar = PasswdAuthRequest::new(username, password);
+            trace!("going to authenticate, username: {}", username);
+            ar.write_to(&mut s).await?;
+
+            let arp = PasswdAuthResponse::read_from(&mut s).await?;
 
+            trace!("got authentication response: {:?}", arp);
+            if arp.status != socks5::SOCKS5_AUTH_PASSWORD_SUCCEEDED {
+                let err = io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "username/password authentication failed",
+                );
+                return Err(err);
+            }
+        }
+
+        // 3. Send request header
+        let h = TcpRequestHeader::new(cmd, addr);
+        trace!("going to connect, req: {:?}", h);
         h.write_to(&mut s).await?;
+
         let hp = TcpResponseHeader::read_from(&mut s).await?;
 
         trace!("got response: {:?}", hp);
@@ -111,7 +156,7 @@ impl Socks5Client {
             }
         }
 
-        Ok((Socks5Client { stream: s }, hp.address))
+        Ok((s, hp.address))
     }
 }
 
@@ -17,9 +17,15 @@ use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, ReadHalf, WriteHalf};
 use crate::{
     config::ServerConfig,
     context::SharedContext,
-    crypto::v1::{random_iv_or_salt, CipherCategory, CipherKind},
+    crypto::v1::{random_iv_or_salt, Cipher, CipherCategory, CipherKind},
 };
 
+#[cfg(feature = "aead-cipher-2022")]
+use super::aead_2022::{
+    DecryptedReader as Aead2022DecryptedReader,
+    EncryptedWriter as Aead2022EncryptedWriter,
+    HeaderKind as Aead2022HeaderKind,
+};
 use super::{
     aead::{DecryptedReader as AeadDecryptedReader, EncryptedWriter as AeadEncryptedWriter},
     stream::{DecryptedReader as StreamDecryptedReader, EncryptedWriter as StreamEncryptedWriter},
@@ -28,13 +34,19 @@ use super::{
 enum DecryptedReader {
     None,
     Aead(AeadDecryptedReader),
+    #[cfg(feature = "aead-cipher-2022")]
+    Aead2022(Aead2022DecryptedReader),
     Stream(StreamDecryptedReader),
 }
 
 enum EncryptedWriter {
     None,
     Aead(AeadEncryptedWriter),
+    #[cfg(feature = "aead-cipher-2022")]
+    Aead2022(Aead2022EncryptedWriter),
     Stream(StreamEncryptedWriter),
+    /// Waiting for the user to be identified, or the salt of request (AEAD-2022 servers), holds the generated salt
+    Pending(Vec<u8>),
 }
 
 /// Steps for initializing a DecryptedReader
@@ -44,6 +56,17 @@ enum ReadStatus {
     /// (context, Buffer, already_read_bytes, method, key)
     WaitIv(SharedContext, Limit<BytesMut>, CipherKind, Bytes),
 
+    /// Waiting for salt and the first encrypted length chunk for identifying user (AEAD ciphers only)
+    ///
+    /// (context, Buffer, method, users' (index, key))
+    WaitUser(SharedContext, Limit<BytesMut>, CipherKind, Vec<(usize, Bytes)>),
+
+    /// Waiting for salt of AEAD-2022 ciphers
+    ///
+    /// (context, Buffer, method, key, the expected fixed-length header)
+    #[cfg(feature = "aead-cipher-2022")]
+    WaitSalt(SharedContext, Limit<BytesMut>, CipherKind, Bytes, Aead2022HeaderKind),
+
     /// Connection is established, DecryptedReader is initialized
     Established,
 }
@@ -54,6 +77,7 @@ pub struct CryptoStream<S> {
     dec: Option<DecryptedReader>,
     enc: EncryptedWriter,
     read_status: ReadStatus,
+    user_index: Option<usize>,
 }
 
 impl<S: Unpin> Unpin for CryptoStream<S> {}
@@ -61,6 +85,11 @@ impl<S: Unpin> Unpin for CryptoStream<S> {}
 impl<S> CryptoStream<S> {
     /// Create a new CryptoStream with the underlying stream connection
     pub fn new(context: SharedContext, stream: S, svr_cfg: &ServerConfig) -> CryptoStream<S> {
+        #[cfg(feature = "aead-cipher-2022")]
+        if svr_cfg.is_aead_2022() {
+            return CryptoStream::<S>::new_aead_2022(context, stream, svr_cfg);
+        }
+
         let method = svr_cfg.method();
         let category = method.category();
         let key = svr_cfg.clone_key();
@@ -77,34 +106,12 @@ impl<S> CryptoStream<S> {
 
         let iv = match category {
             CipherCategory::Stream => {
-                let local_iv = loop {
-                    let mut iv = vec![0u8; prev_len];
-                    if prev_len > 0 {
-                        random_iv_or_salt(&mut iv);
-                    }
-
-                    if context.check_nonce_and_set(&iv) {
-                        // IV exist, generate another one
-                        continue;
-                    }
-                    break iv;
-                };
+                let local_iv = generate_nonce(&context, prev_len);
                 trace!("generated Stream cipher IV {:?}", ByteStr::new(&local_iv));
                 local_iv
             }
             CipherCategory::Aead => {
-                let local_salt = loop {
-                    let mut salt = vec![0u8; prev_len];
-                    if prev_len > 0 {
-                        random_iv_or_salt(&mut salt);
-                    }
-
-                    if context.check_nonce_and_set(&salt) {
-                        // Salt exist, generate another one
-                        continue;
-                    }
-                    break salt;
-                };
+                let local_salt = generate_nonce(&context, prev_len);
                 trace!("generated AEAD cipher salt {:?}", ByteStr::new(&local_salt));
                 local_salt
             }
@@ -122,6 +129,82 @@ impl<S> CryptoStream<S> {
             dec: None,
             enc,
             read_status: ReadStatus::WaitIv(context, BytesMut::with_capacity(prev_len).limit(prev_len), method, key),
+            user_index: None,
+        }
+    }
+
+    /// Create a new CryptoStream for servers that have multiple users sharing the same port
+    ///
+    /// `users` are `(index, key)` pairs of all users' `ServerConfig`. User is identified by trying every keys
+    /// on the first encrypted chunk, so only AEAD ciphers are supported.
+    pub fn new_multi_user(
+        context: SharedContext,
+        stream: S,
+        method: CipherKind,
+        users: Vec<(usize, Bytes)>,
+    ) -> CryptoStream<S> {
+        assert_eq!(
+            method.category(),
+            CipherCategory::Aead,
+            "multiple users sharing one port requires AEAD ciphers"
+        );
+
+        let salt_len = method.salt_len();
+        let local_salt = generate_nonce(&context, salt_len);
+        trace!("generated AEAD cipher salt {:?}", ByteStr::new(&local_salt));
+
+        // salt + *DataLen* + DataLen_TAG
+        let prev_len = salt_len + 2 + method.tag_len();
+
+        CryptoStream {
+            stream,
+            dec: None,
+            enc: EncryptedWriter::Pending(local_salt),
+            read_status: ReadStatus::WaitUser(
+                context,
+                BytesMut::with_capacity(prev_len).limit(prev_len),
+                method,
+                users,
+            ),
+            user_index: None,
+        }
+    }
+
+    /// Create a new CryptoStream of AEAD-2022 methods
+    ///
+    /// Local servers send requests and expect responses, and the other servers do the opposite
+    #[cfg(feature = "aead-cipher-2022")]
+    fn new_aead_2022(context: SharedContext, stream: S, svr_cfg: &ServerConfig) -> CryptoStream<S> {
+        let method = svr_cfg.method();
+        let key = svr_cfg.clone_key();
+        let salt_len = method.salt_len();
+
+        // Salts are remembered only for a while by servers, timestamps in headers reject the older ones
+        let mut local_salt = vec![0u8; salt_len];
+        random_iv_or_salt(&mut local_salt);
+        trace!("generated AEAD-2022 salt {:?}", ByteStr::new(&local_salt));
+
+        let (enc, header) = if context.config().config_type.is_local() {
+            let enc = Aead2022EncryptedWriter::new(method, &key, &local_salt, Aead2022HeaderKind::Request);
+            let header = Aead2022HeaderKind::Response(Bytes::from(local_salt));
+            (EncryptedWriter::Aead2022(enc), header)
+        } else {
+            // Response carries salt of the request, so writer is initialized after reading the request's salt
+            (EncryptedWriter::Pending(local_salt), Aead2022HeaderKind::Request)
+        };
+
+        CryptoStream {
+            stream,
+            dec: None,
+            enc,
+            read_status: ReadStatus::WaitSalt(
+                context,
+                BytesMut::with_capacity(salt_len).limit(salt_len),
+                method,
+                key,
+                header,
+            ),
+            user_index: None,
         }
     }
 
@@ -131,9 +214,22 @@ impl<S> CryptoStream<S> {
             dec: Some(DecryptedReader::None),
             enc: EncryptedWriter::None,
             read_status: ReadStatus::Established,
+            user_index: None,
         }
     }
 
+    /// Index of the user identified in handshake
+    ///
+    /// Only available for streams created by `new_multi_user`, after the first chunk have been read
+    pub fn user_index(&self) -> Option<usize> {
+        self.user_index
+    }
+
+    /// Return a mutable reference to the underlying stream
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
     /// Return a reference to the underlying stream
     pub fn get_ref(&self) -> &S {
         &self.stream
@@ -150,51 +246,103 @@ where
     S: AsyncRead + Unpin,
 {
     fn poll_read_handshake(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        if let ReadStatus::WaitIv(ref ctx, ref mut buf, method, ref key) = self.read_status {
-            while buf.has_remaining_mut() {
-                let raw_buffer = buf.bytes_mut();
-                let mut buffer = unsafe {
-                    ReadBuf::uninit(slice::from_raw_parts_mut(
-                        raw_buffer.as_mut_ptr() as *mut _,
-                        raw_buffer.len(),
-                    ))
-                };
-                ready!(Pin::new(&mut self.stream).poll_read(cx, &mut buffer))?;
-                let n = buffer.filled().len();
-                unsafe {
-                    buf.advance_mut(n);
-                }
-                if n == 0 {
-                    return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+        match self.read_status {
+            ReadStatus::WaitIv(ref ctx, ref mut buf, method, ref key) => {
+                ready!(poll_read_limited(&mut self.stream, cx, buf))?;
+
+                let nonce = buf.get_ref();
+
+                // Got iv/salt, check if it is repeated
+                if ctx.check_nonce_and_set(nonce) {
+                    use std::io::{Error, ErrorKind};
+
+                    trace!("detected repeated iv/salt {:?}", ByteStr::new(nonce));
+
+                    let err = Error::new(ErrorKind::Other, "detected repeated iv/salt");
+                    return Poll::Ready(Err(err));
                 }
+
+                let dec = match method.category() {
+                    CipherCategory::Stream => {
+                        trace!("got Stream cipher IV {:?}", ByteStr::new(nonce));
+                        DecryptedReader::Stream(StreamDecryptedReader::new(method, key, nonce))
+                    }
+                    CipherCategory::Aead => {
+                        trace!("got AEAD cipher salt {:?}", ByteStr::new(nonce));
+                        DecryptedReader::Aead(AeadDecryptedReader::new(method, key, nonce))
+                    }
+                    CipherCategory::None => DecryptedReader::None,
+                };
+
+                self.dec = Some(dec);
+                self.read_status = ReadStatus::Established;
             }
+            ReadStatus::WaitUser(ref ctx, ref mut buf, method, ref users) => {
+                ready!(poll_read_limited(&mut self.stream, cx, buf))?;
+
+                let (salt, chunk) = buf.get_ref().split_at(method.salt_len());
 
-            let nonce = buf.get_ref();
+                // Got salt, check if it is repeated
+                if ctx.check_nonce_and_set(salt) {
+                    use std::io::{Error, ErrorKind};
 
-            // Got iv/salt, check if it is repeated
-            if ctx.check_nonce_and_set(nonce) {
-                use std::io::{Error, ErrorKind};
+                    trace!("detected repeated salt {:?}", ByteStr::new(salt));
+
+                    let err = Error::new(ErrorKind::Other, "detected repeated salt");
+                    return Poll::Ready(Err(err));
+                }
 
-                trace!("detected repeated iv/salt {:?}", ByteStr::new(nonce));
+                let (user_index, key) = match find_user(method, users, salt, chunk) {
+                    Some((idx, key)) => (*idx, key),
+                    None => {
+                        use std::io::{Error, ErrorKind};
 
-                let err = Error::new(ErrorKind::Other, "detected repeated iv/salt");
-                return Poll::Ready(Err(err));
+                        let err = Error::new(ErrorKind::Other, "no user matches the first chunk");
+                        return Poll::Ready(Err(err));
+                    }
+                };
+
+                trace!("got AEAD cipher salt {:?}, user #{}", ByteStr::new(salt), user_index);
+
+                let dec = AeadDecryptedReader::with_length_chunk(method, key, salt, chunk);
+                let enc = match self.enc {
+                    EncryptedWriter::Pending(ref local_salt) => AeadEncryptedWriter::new(method, key, local_salt),
+                    _ => unreachable!("multi-user stream have already initialized its writer"),
+                };
+
+                self.dec = Some(DecryptedReader::Aead(dec));
+                self.enc = EncryptedWriter::Aead(enc);
+                self.user_index = Some(user_index);
+                self.read_status = ReadStatus::Established;
             }
+            #[cfg(feature = "aead-cipher-2022")]
+            ReadStatus::WaitSalt(ref ctx, ref mut buf, method, ref key, ref header) => {
+                ready!(poll_read_limited(&mut self.stream, cx, buf))?;
+
+                let salt = buf.get_ref();
+
+                let dec = if let Aead2022HeaderKind::Request = *header {
+                    // Got salt of request, repeated salts are checked after its header is authenticated
+                    let enc = match self.enc {
+                        EncryptedWriter::Pending(ref local_salt) => {
+                            let header = Aead2022HeaderKind::Response(Bytes::copy_from_slice(salt));
+                            Aead2022EncryptedWriter::new(method, key, local_salt, header)
+                        }
+                        _ => unreachable!("AEAD-2022 server stream have already initialized its writer"),
+                    };
+                    self.enc = EncryptedWriter::Aead2022(enc);
+
+                    Aead2022DecryptedReader::new_request(ctx.clone(), method, key, salt)
+                } else {
+                    Aead2022DecryptedReader::new(method, key, salt, header.clone())
+                };
 
-            let dec = match method.category() {
-                CipherCategory::Stream => {
-                    trace!("got Stream cipher IV {:?}", ByteStr::new(nonce));
-                    DecryptedReader::Stream(StreamDecryptedReader::new(method, key, nonce))
-                }
-                CipherCategory::Aead => {
-                    trace!("got AEAD cipher salt {:?}", ByteStr::new(nonce));
-                    DecryptedReader::Aead(AeadDecryptedReader::new(method, key, nonce))
-                }
-                CipherCategory::None => DecryptedReader::None,
-            };
+                trace!("got AEAD-2022 salt {:?}", ByteStr::new(salt));
 
-            self.dec = Some(dec);
-            self.read_status = ReadStatus::Established;
+                self.dec = Some(DecryptedReader::Aead2022(dec));
+                self.read_status = ReadStatus::Established;
+            }
+            ReadStatus::Established => {}
         }
 
         Poll::Ready(Ok(()))
@@ -207,6 +355,8 @@ where
         match *this.dec.as_mut().unwrap() {
             DecryptedReader::None => Pin::new(&mut this.stream).poll_read(ctx, buf),
             DecryptedReader::Aead(ref mut r) => r.poll_read_decrypted(ctx, &mut this.stream, buf),
+            #[cfg(feature = "aead-cipher-2022")]
+            DecryptedReader::Aead2022(ref mut r) => r.poll_read_decrypted(ctx, &mut this.stream, buf),
             DecryptedReader::Stream(ref mut r) => r.poll_read_decrypted(ctx, &mut this.stream, buf),
         }
     }
@@ -221,7 +371,13 @@ where
         match this.enc {
             EncryptedWriter::None => Pin::new(&mut this.stream).poll_write(ctx, buf),
             EncryptedWriter::Aead(ref mut w) => w.poll_write_encrypted(ctx, &mut this.stream, buf),
+            #[cfg(feature = "aead-cipher-2022")]
+            EncryptedWriter::Aead2022(ref mut w) => w.poll_write_encrypted(ctx, &mut this.stream, buf),
             EncryptedWriter::Stream(ref mut w) => w.poll_write_encrypted(ctx, &mut this.stream, buf),
+            EncryptedWriter::Pending(..) => {
+                let err = io::Error::new(io::ErrorKind::Other, "cannot write before the request is read");
+                Poll::Ready(Err(err))
+            }
         }
     }
 
@@ -277,3 +433,64 @@ where
         self.priv_poll_shutdown(ctx)
     }
 }
+
+/// Generate a random IV or salt that haven't been used
+fn generate_nonce(context: &SharedContext, len: usize) -> Vec<u8> {
+    loop {
+        let mut nonce = vec![0u8; len];
+        if len > 0 {
+            random_iv_or_salt(&mut nonce);
+        }
+
+        if context.check_nonce_and_set(&nonce) {
+            // Nonce exist, generate another one
+            continue;
+        }
+        break nonce;
+    }
+}
+
+/// Read from `stream` until `buf` is full
+fn poll_read_limited<S>(stream: &mut S, cx: &mut Context<'_>, buf: &mut Limit<BytesMut>) -> Poll<io::Result<()>>
+where
+    S: AsyncRead + Unpin,
+{
+    while buf.has_remaining_mut() {
+        let raw_buffer = buf.bytes_mut();
+        let mut buffer = unsafe {
+            ReadBuf::uninit(slice::from_raw_parts_mut(
+                raw_buffer.as_mut_ptr() as *mut _,
+                raw_buffer.len(),
+            ))
+        };
+        ready!(Pin::new(&mut *stream).poll_read(cx, &mut buffer))?;
+        let n = buffer.filled().len();
+        unsafe {
+            buf.advance_mut(n);
+        }
+        if n == 0 {
+            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+        }
+    }
+
+    Poll::Ready(Ok(()))
+}
+
+/// Find the user whose key could decrypt the first encrypted length chunk
+fn find_user<'a>(
+    method: CipherKind,
+    users: &'a [(usize, Bytes)],
+    salt: &[u8],
+    chunk: &[u8],
+) -> Option<&'a (usize, Bytes)> {
+    let mut m = chunk.to_vec();
+    for user in users {
+        m.copy_from_slice(chunk);
+
+        let mut cipher = Cipher::new(method, &user.1, salt);
+        if cipher.decrypt_packet(&mut m) {
+            return Some(user);
+        }
+    }
+    None
+}
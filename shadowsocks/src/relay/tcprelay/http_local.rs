@@ -19,7 +19,7 @@ use futures::{
 use http::uri::{Authority, Scheme};
 use hyper::{
     client::connect::{Connected, Connection},
-    header::HeaderValue,
+    header::{self, HeaderValue},
     server::conn::AddrStream,
     service::{make_service_fn, service_fn},
     upgrade::{self, Upgraded},
@@ -39,17 +39,12 @@ use pin_project::pin_project;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 use crate::{
-    config::ConfigType,
+    acl::{Protocol, Route},
+    config::{ConfigType, LocalAuth},
     context::SharedContext,
     crypto::v1::CipherKind,
     relay::{
-        loadbalancing::server::{
-            PingBalancer,
-            ServerData,
-            ServerType,
-            SharedServerStatistic,
-            SharedServerStatisticData,
-        },
+        loadbalancing::server::{PingBalancer, ServerData, ServerType, SharedServerStatisticData},
         socks5::Address,
     },
 };
@@ -574,6 +569,58 @@ fn make_bad_request() -> io::Result<Response<Body>> {
     Ok(resp)
 }
 
+fn make_forbidden() -> io::Result<Response<Body>> {
+    let mut resp = Response::new(Body::empty());
+    *resp.status_mut() = StatusCode::FORBIDDEN;
+    Ok(resp)
+}
+
+fn make_proxy_authentication_required() -> io::Result<Response<Body>> {
+    let mut resp = Response::new(Body::empty());
+    *resp.status_mut() = StatusCode::PROXY_AUTHENTICATION_REQUIRED;
+    resp.headers_mut().insert(
+        header::PROXY_AUTHENTICATE,
+        HeaderValue::from_static("Basic realm=\"shadowsocks\""),
+    );
+    Ok(resp)
+}
+
+/// Check `Proxy-Authorization` with Basic scheme
+///
+/// https://tools.ietf.org/html/rfc7617
+fn check_proxy_authorization(req: &Request<Body>, auth: &LocalAuth) -> bool {
+    let value = match req.headers().get(header::PROXY_AUTHORIZATION) {
+        Some(v) => match v.to_str() {
+            Ok(v) => v.trim(),
+            Err(..) => return false,
+        },
+        None => return false,
+    };
+
+    let mut parts = value.splitn(2, ' ');
+    let (scheme, credentials) = match (parts.next(), parts.next()) {
+        (Some(s), Some(c)) => (s, c.trim()),
+        _ => return false,
+    };
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return false;
+    }
+
+    let credentials = match base64::decode(credentials) {
+        Ok(c) => c,
+        Err(..) => return false,
+    };
+    let credentials = match String::from_utf8(credentials) {
+        Ok(c) => c,
+        Err(..) => return false,
+    };
+
+    match credentials.find(':') {
+        Some(pos) => auth.check(&credentials[..pos], &credentials[pos + 1..]),
+        None => false,
+    }
+}
+
 fn get_addr_from_header(req: &mut Request<Body>) -> Result<Address, ()> {
     // Try to be compatible as a transparent HTTP proxy
     match req.headers().get("Host") {
@@ -647,13 +694,20 @@ fn get_addr_from_header(req: &mut Request<Body>) -> Result<Address, ()> {
 
 async fn server_dispatch(
     mut req: Request<Body>,
-    svr_score: SharedServerStatistic<ServerScore>,
+    context: SharedContext,
+    servers: Arc<PingBalancer<ServerScore>>,
     client_addr: SocketAddr,
     bypass_client: DirectHttpClient,
 ) -> io::Result<Response<Body>> {
     trace!("request {} {:?}", client_addr, req);
 
-    let context = svr_score.context();
+    // Authenticate before anything else, both for plain requests and CONNECT tunnels
+    if let Some(ref auth) = context.config().local_auth {
+        if !check_proxy_authorization(&req, auth) {
+            debug!("HTTP {} {} proxy authentication failed", client_addr, req.method());
+            return make_proxy_authentication_required();
+        }
+    }
 
     // Parse URI
     //
@@ -676,7 +730,8 @@ async fn server_dispatch(
         Some(h) => h,
     };
 
-    let svr_cfg = svr_score.server_config();
+    let svr_score = servers.pick_server_for(&host);
+    trace!("picked proxy server: {:?}", svr_score.server_config());
 
     if Method::CONNECT == req.method() {
         // Establish a TCP tunnel
@@ -687,20 +742,21 @@ async fn server_dispatch(
         // Connect to Shadowsocks' remote
         //
         // FIXME: What STATUS should I return for connection error?
-        let stream = match ProxyStream::connect(svr_score.clone_context(), svr_cfg, &host).await {
+        let (stream, svr_score) = match ProxyStream::connect_with_failover(&*servers, svr_score, &host).await {
             Ok(s) => s,
             Err(err) => {
-                if err.is_proxied() {
-                    // Report failure to global statistic
-                    svr_score.report_failure().await;
+                let err = err.into_inner();
+                if err.kind() == ErrorKind::PermissionDenied {
+                    debug!("HTTP CONNECT {} rejected, {}", host, err);
+                    return make_forbidden();
                 }
-                return Err(err.into_inner());
+                return Err(err);
             }
         };
 
         debug!("CONNECT relay connected {} <-> {}", client_addr, host);
 
-        let method = svr_cfg.method();
+        let method = svr_score.server_config().method();
 
         // Upgrade to a TCP tunnel
         //
@@ -742,7 +798,13 @@ async fn server_dispatch(
         // Set keep-alive for connection with remote
         set_conn_keep_alive(version, req.headers_mut(), conn_keep_alive);
 
-        let mut res = if context.check_target_bypassed(&host).await {
+        let route = context.route_target(&host, Protocol::Tcp).await;
+        if let Route::Reject = route {
+            debug!("HTTP {} {} rejected by ACL", method, host);
+            return make_forbidden();
+        }
+
+        let mut res = if let Route::Bypass = route {
             trace!("bypassed {} -> {} {:?}", client_addr, host, req);
 
             // Keep connections in a global client instance
@@ -763,6 +825,22 @@ async fn server_dispatch(
         } else {
             trace!("proxied {} -> {} {:?}", client_addr, host, req);
 
+            // Targets routed to a group are proxied via servers in that group
+            let svr_score = match route {
+                Route::Proxy(Some(ref group)) => match servers.pick_server_in_group(group, &host) {
+                    Some(s) => s,
+                    None => {
+                        error!("HTTP {} {} routed to group {} without servers", method, host, group);
+
+                        let mut resp = Response::new(Body::from(format!("no servers in group {}", group)));
+                        *resp.status_mut() = StatusCode::BAD_GATEWAY;
+
+                        return Ok(resp);
+                    }
+                },
+                _ => svr_score,
+            };
+
             // Keep connections for clients in ServerScore::client
             //
             // client instance is kept for Keep-Alive connections
@@ -841,13 +919,19 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
         ConfigType::HttpLocal => {
             let make_service = make_service_fn(|socket: &AddrStream| {
                 let client_addr = socket.remote_addr();
+                let context = context.clone();
                 let servers = servers.clone();
                 let bypass_client = bypass_client.clone();
 
                 async move {
                     Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
-                        let svr_score = servers.pick_server();
-                        server_dispatch(req, svr_score, client_addr, bypass_client.clone())
+                        server_dispatch(
+                            req,
+                            context.clone(),
+                            servers.clone(),
+                            client_addr,
+                            bypass_client.clone(),
+                        )
                     }))
                 }
             });
@@ -882,13 +966,19 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
 
             let make_service = make_service_fn(|socket: &TlsStream| {
                 let client_addr = socket.remote_addr();
+                let context = context.clone();
                 let servers = servers.clone();
                 let bypass_client = bypass_client.clone();
 
                 async move {
                     Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
-                        let svr_score = servers.pick_server();
-                        server_dispatch(req, svr_score, client_addr, bypass_client.clone())
+                        server_dispatch(
+                            req,
+                            context.clone(),
+                            servers.clone(),
+                            client_addr,
+                            bypass_client.clone(),
+                        )
                     }))
                 }
             });
@@ -14,6 +14,8 @@ use tokio::{
 };
 
 mod aead;
+#[cfg(feature = "aead-cipher-2022")]
+mod aead_2022;
 mod crypto_io;
 mod stream;
 
@@ -1,16 +1,23 @@
 //! Server traffic monitor
+//!
+//! Traffic limits of server are also enforced here
 
 use std::{
-    io,
+    future::Future,
+    io::{self, ErrorKind},
     marker::Unpin,
     ops::{Deref, DerefMut},
     pin::Pin,
     task::{Context, Poll},
+    time::Duration,
 };
 
 use futures::ready;
 use pin_project::pin_project;
-use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{self, Sleep},
+};
 
 use crate::relay::flow::SharedServerFlowStatistic;
 
@@ -18,12 +25,50 @@ use crate::relay::flow::SharedServerFlowStatistic;
 pub struct TcpMonStream<S> {
     #[pin]
     stream: S,
-    flow_stat: SharedServerFlowStatistic,
+    flow_stat: Option<SharedServerFlowStatistic>,
+    pending_tx: usize,
+    pending_rx: usize,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
 }
 
 impl<S> TcpMonStream<S> {
     pub fn new(flow_stat: SharedServerFlowStatistic, stream: S) -> TcpMonStream<S> {
-        TcpMonStream { stream, flow_stat }
+        TcpMonStream {
+            stream,
+            flow_stat: Some(flow_stat),
+            pending_tx: 0,
+            pending_rx: 0,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+
+    /// Create a monitor without knowing which server (user) the stream belongs to
+    ///
+    /// Transferred bytes are kept until `set_flow_stat` is called
+    pub fn new_pending(stream: S) -> TcpMonStream<S> {
+        TcpMonStream {
+            stream,
+            flow_stat: None,
+            pending_tx: 0,
+            pending_rx: 0,
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+
+    /// Set the statistic that this stream belongs to, flushing bytes transferred before
+    pub fn set_flow_stat(&mut self, flow_stat: SharedServerFlowStatistic) {
+        flow_stat.tcp().incr_tx(self.pending_tx);
+        flow_stat.tcp().incr_rx(self.pending_rx);
+        if let Some(limiter) = flow_stat.limiter() {
+            self.read_delay = make_delay(limiter.consume_upload(self.pending_rx));
+            self.write_delay = make_delay(limiter.consume_download(self.pending_tx));
+        }
+        self.pending_tx = 0;
+        self.pending_rx = 0;
+        self.flow_stat = Some(flow_stat);
     }
 
     pub fn into_inner(self) -> S {
@@ -31,6 +76,31 @@ impl<S> TcpMonStream<S> {
     }
 }
 
+fn check_quota(flow_stat: &Option<SharedServerFlowStatistic>) -> io::Result<()> {
+    match *flow_stat {
+        Some(ref flow_stat) if flow_stat.is_quota_exhausted() => {
+            Err(io::Error::new(ErrorKind::Other, "server traffic quota exhausted"))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn poll_delay(delay: &mut Option<Pin<Box<Sleep>>>, cx: &mut Context<'_>) -> Poll<()> {
+    if let Some(ref mut d) = *delay {
+        ready!(d.as_mut().poll(cx));
+        *delay = None;
+    }
+    Poll::Ready(())
+}
+
+fn make_delay(wait: Duration) -> Option<Pin<Box<Sleep>>> {
+    if wait > Duration::from_secs(0) {
+        Some(Box::pin(time::sleep(wait)))
+    } else {
+        None
+    }
+}
+
 impl<S> AsyncRead for TcpMonStream<S>
 where
     S: AsyncRead + Unpin,
@@ -38,9 +108,21 @@ where
     fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
         let this = self.project();
 
+        check_quota(this.flow_stat)?;
+        ready!(poll_delay(this.read_delay, cx));
+
         let before_remain = buf.remaining();
         ready!(this.stream.poll_read(cx, buf))?;
-        this.flow_stat.tcp().incr_rx(before_remain - buf.remaining());
+        let n = before_remain - buf.remaining();
+        match *this.flow_stat {
+            Some(ref flow_stat) => {
+                flow_stat.tcp().incr_rx(n);
+                if let Some(limiter) = flow_stat.limiter() {
+                    *this.read_delay = make_delay(limiter.consume_upload(n));
+                }
+            }
+            None => *this.pending_rx += n,
+        }
         Poll::Ready(Ok(()))
     }
 }
@@ -52,11 +134,22 @@ where
     fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
         let this = self.project();
 
+        check_quota(this.flow_stat)?;
+        ready!(poll_delay(this.write_delay, cx));
+
         let n = match this.stream.poll_write(cx, buf)? {
             Poll::Ready(n) => n,
             Poll::Pending => return Poll::Pending,
         };
-        this.flow_stat.tcp().incr_tx(n);
+        match *this.flow_stat {
+            Some(ref flow_stat) => {
+                flow_stat.tcp().incr_tx(n);
+                if let Some(limiter) = flow_stat.limiter() {
+                    *this.write_delay = make_delay(limiter.consume_download(n));
+                }
+            }
+            None => *this.pending_tx += n,
+        }
         Poll::Ready(Ok(n))
     }
 
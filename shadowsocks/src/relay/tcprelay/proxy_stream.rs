@@ -2,7 +2,7 @@
 
 use std::{
     fmt::{self, Display, Formatter},
-    io::{self, Error},
+    io::{self, Error, ErrorKind},
     net::SocketAddr,
     pin::Pin,
     task::{self, Poll},
@@ -11,16 +11,27 @@ use std::{
 
 use bytes::{Buf, BufMut, BytesMut};
 use futures::ready;
-use log::{debug, error, trace};
+use log::{debug, error, trace, warn};
 use pin_project::pin_project;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, ReadHalf, WriteHalf};
 
+#[cfg(feature = "metrics")]
+use crate::relay::{flow::SharedServerFlowStatistic, metrics::GaugeGuard};
 use crate::{
+    acl::{Protocol, Route},
     config::{ConfigType, ServerAddr, ServerConfig},
     context::{Context, SharedContext},
-    relay::{socks5::Address, sys::tcp_stream_connect, utils::try_timeout},
+    relay::{
+        control::ConnectionGuard,
+        loadbalancing::server::{PingBalancer, ServerData, SharedServerStatistic},
+        socks5::Address,
+        sys::tcp_stream_connect,
+        utils::try_timeout,
+    },
 };
 
+#[cfg(feature = "aead-cipher-2022")]
+use super::aead_2022::write_padding;
 use super::{connection::Connection, CryptoStream, STcpStream};
 
 enum ProxiedConnectState {
@@ -34,13 +45,16 @@ struct ProxiedConnection {
     #[pin]
     stream: CryptoStream<STcpStream>,
     state: ProxiedConnectState,
+    // AEAD-2022 methods require padding after the address
+    aead_2022: bool,
 }
 
 impl ProxiedConnection {
-    fn connected(stream: CryptoStream<STcpStream>, addr: Address) -> ProxiedConnection {
+    fn connected(stream: CryptoStream<STcpStream>, addr: Address, aead_2022: bool) -> ProxiedConnection {
         ProxiedConnection {
             stream,
             state: ProxiedConnectState::Connected(addr),
+            aead_2022,
         }
     }
 
@@ -81,6 +95,10 @@ impl AsyncWrite for ProxiedConnection {
                     let addr_len = addr.serialized_len();
                     let mut buf = BytesMut::with_capacity(addr_len + data.len());
                     addr.write_to_buf(&mut buf);
+                    if *this.aead_2022 {
+                        #[cfg(feature = "aead-cipher-2022")]
+                        write_padding(&mut buf, data.len());
+                    }
                     buf.put_slice(data);
 
                     trace!(
@@ -243,7 +261,7 @@ impl ProxyStreamError {
 
     /// Check if it is proxied
     pub fn is_proxied(&self) -> bool {
-        self.bypassed
+        !self.bypassed
     }
 
     /// Into internal `std::io::Error`
@@ -264,25 +282,135 @@ impl Display for ProxyStreamError {
     }
 }
 
+/// Error of targets rejected by ACL, with `ErrorKind::PermissionDenied`
+fn rejected_error(addr: &Address) -> Error {
+    Error::new(ErrorKind::PermissionDenied, format!("{} is rejected by ACL", addr))
+}
+
 /// Stream wrapper for both direct connections and proxied connections
 #[pin_project]
 pub struct ProxyStream {
     #[pin]
     connection: ProxyConnection,
     context: SharedContext,
+    // Waited for when shutting down
+    _connection_guard: ConnectionGuard,
+    #[cfg(feature = "metrics")]
+    metrics: ProxyStreamMetrics,
+}
+
+#[cfg(feature = "metrics")]
+struct ProxyStreamMetrics {
+    _conn_guard: GaugeGuard,
+    flow_stat: Option<SharedServerFlowStatistic>,
+}
+
+#[cfg(feature = "metrics")]
+impl ProxyStreamMetrics {
+    fn new(context: &Context, svr_cfg: Option<&ServerConfig>) -> ProxyStreamMetrics {
+        ProxyStreamMetrics {
+            _conn_guard: GaugeGuard::tcp_connection(context.metrics()),
+            flow_stat: svr_cfg.and_then(|s| context.metrics().local_server_flow(s)),
+        }
+    }
 }
 
 impl ProxyStream {
     /// Connect to remote by ACL rules
+    ///
+    /// Routed targets are proxied via `svr_cfg`, because there are no groups of servers without a balancer
     pub async fn connect(
         context: SharedContext,
         svr_cfg: &ServerConfig,
         addr: &Address,
     ) -> Result<ProxyStream, ProxyStreamError> {
-        if context.check_target_bypassed(addr).await {
-            ProxyStream::connect_direct_wrapped(context, addr).await
-        } else {
-            ProxyStream::connect_proxied_wrapped(context, svr_cfg, addr).await
+        match context.route_target(addr, Protocol::Tcp).await {
+            Route::Bypass => ProxyStream::connect_direct_wrapped(context, addr).await,
+            Route::Proxy(..) => ProxyStream::connect_proxied_wrapped(context, svr_cfg, addr).await,
+            Route::Reject => Err(ProxyStreamError::new(rejected_error(addr), false)),
+        }
+    }
+
+    /// Connect to remote by ACL rules, retrying with other servers in `balancer` if failed to connect to `server`
+    ///
+    /// Targets routed to a group of servers are connected via servers picked from that group instead of `server`.
+    /// Retries at most `Config::failover_retries` times. Returns the stream with the server it connected via
+    pub async fn connect_with_failover<S: ServerData>(
+        balancer: &PingBalancer<S>,
+        server: SharedServerStatistic<S>,
+        addr: &Address,
+    ) -> Result<(ProxyStream, SharedServerStatistic<S>), ProxyStreamError> {
+        match server.context().route_target(addr, Protocol::Tcp).await {
+            Route::Bypass => {
+                let stream = ProxyStream::connect_direct_wrapped(server.clone_context(), addr).await?;
+                Ok((stream, server))
+            }
+            Route::Proxy(None) => ProxyStream::connect_proxied_with_failover(balancer, server, addr).await,
+            Route::Proxy(Some(group)) => {
+                let server = match balancer.pick_server_in_group(&group, addr) {
+                    Some(s) => s,
+                    None => {
+                        let err = Error::new(
+                            ErrorKind::Other,
+                            format!("no servers in group {} for routing {}", group, addr),
+                        );
+                        return Err(ProxyStreamError::new(err, false));
+                    }
+                };
+                trace!(
+                    "routed {} to group {} via {}",
+                    addr,
+                    group,
+                    server.server_config().addr()
+                );
+
+                ProxyStream::connect_proxied_with_failover(balancer, server, addr).await
+            }
+            Route::Reject => Err(ProxyStreamError::new(rejected_error(addr), false)),
+        }
+    }
+
+    /// Connect to remote via proxy server, retrying with other servers in `balancer` if failed to connect to `server`
+    ///
+    /// Retries at most `Config::failover_retries` times. Returns the stream with the server it connected via
+    pub async fn connect_proxied_with_failover<S: ServerData>(
+        balancer: &PingBalancer<S>,
+        mut server: SharedServerStatistic<S>,
+        addr: &Address,
+    ) -> Result<(ProxyStream, SharedServerStatistic<S>), ProxyStreamError> {
+        let retries = server.config().failover_retries;
+        let mut tried = Vec::new();
+
+        loop {
+            let res = ProxyStream::connect_proxied_wrapped(server.clone_context(), server.server_config(), addr).await;
+            let err = match res {
+                Ok(stream) => return Ok((stream, server)),
+                Err(err) => err,
+            };
+
+            // Report to global statistic
+            server.report_failure().await;
+
+            if tried.len() >= retries {
+                return Err(err);
+            }
+
+            tried.push(server);
+
+            let next = match balancer.pick_failover_server(&tried) {
+                Some(s) => s,
+                None => return Err(err),
+            };
+
+            warn!(
+                "failed to connect {} via {}, retrying via {}, error: {}",
+                addr,
+                tried[tried.len() - 1].server_config().addr(),
+                next.server_config().addr(),
+                err
+            );
+
+            server = next;
         }
     }
 
@@ -305,6 +433,9 @@ impl ProxyStream {
         };
 
         Ok(ProxyStream {
+            _connection_guard: context.connections().track(),
+            #[cfg(feature = "metrics")]
+            metrics: ProxyStreamMetrics::new(&context, None),
             context,
             connection: ProxyConnection::Direct(Connection::new(stream, None, false)),
         })
@@ -336,8 +467,15 @@ impl ProxyStream {
         let proxy_stream = CryptoStream::new(context.clone(), server_stream, svr_cfg);
 
         Ok(ProxyStream {
+            _connection_guard: context.connections().track(),
+            #[cfg(feature = "metrics")]
+            metrics: ProxyStreamMetrics::new(&context, Some(svr_cfg)),
             context,
-            connection: ProxyConnection::Proxied(ProxiedConnection::connected(proxy_stream, addr.clone())),
+            connection: ProxyConnection::Proxied(ProxiedConnection::connected(
+                proxy_stream,
+                addr.clone(),
+                svr_cfg.is_aead_2022(),
+            )),
         })
     }
 
@@ -393,6 +531,15 @@ impl AsyncRead for ProxyStream {
             }
         }
 
+        #[cfg(feature = "metrics")]
+        {
+            if let Poll::Ready(Ok(..)) = p {
+                if let Some(ref flow_stat) = self.metrics.flow_stat {
+                    flow_stat.tcp().incr_rx(before_remain - buf.remaining());
+                }
+            }
+        }
+
         p
     }
 }
@@ -411,6 +558,15 @@ impl AsyncWrite for ProxyStream {
             }
         }
 
+        #[cfg(feature = "metrics")]
+        {
+            if let Poll::Ready(Ok(n)) = p {
+                if let Some(ref flow_stat) = self.metrics.flow_stat {
+                    flow_stat.tcp().incr_tx(n);
+                }
+            }
+        }
+
         p
     }
 
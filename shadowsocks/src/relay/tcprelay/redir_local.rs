@@ -28,14 +28,15 @@ use super::ProxyStream;
 ///
 /// This method must be called after handshaking with client (for example, socks5 handshaking)
 async fn establish_client_tcp_redir<'a>(
-    server: &SharedPlainServerStatistic,
+    servers: &PlainPingBalancer,
+    server: SharedPlainServerStatistic,
     mut s: TcpStream,
     client_addr: SocketAddr,
     addr: &Address,
 ) -> io::Result<()> {
+    let (svr_s, server) = ProxyStream::connect_with_failover(servers, server, addr).await?;
     let svr_cfg = server.server_config();
 
-    let svr_s = ProxyStream::connect(server.clone_context(), svr_cfg, addr).await?;
     let (mut svr_r, mut svr_w) = svr_s.split();
 
     let (mut r, mut w) = s.split();
@@ -74,7 +75,12 @@ async fn establish_client_tcp_redir<'a>(
     Ok(())
 }
 
-async fn handle_redir_client(server: &SharedPlainServerStatistic, s: TcpStream, daddr: SocketAddr) -> io::Result<()> {
+async fn handle_redir_client(
+    servers: &PlainPingBalancer,
+    server: SharedPlainServerStatistic,
+    s: TcpStream,
+    daddr: SocketAddr,
+) -> io::Result<()> {
     // let svr_cfg = server.server_config();
     //
     // if let Err(err) = s.set_keepalive(svr_cfg.timeout()) {
@@ -91,7 +97,7 @@ async fn handle_redir_client(server: &SharedPlainServerStatistic, s: TcpStream,
 
     // Get forward address from socket
     let target_addr = Address::from(daddr);
-    establish_client_tcp_redir(server, s, client_addr, &target_addr).await
+    establish_client_tcp_redir(servers, server, s, client_addr, &target_addr).await
 }
 
 pub async fn run(context: SharedContext) -> io::Result<()> {
@@ -119,11 +125,9 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
                 continue;
             }
         };
-        let server = servers.pick_server();
-
         trace!("got connection {}", peer_addr);
-        trace!("picked proxy server: {:?}", server.server_config());
 
+        let servers = servers.clone();
         tokio::spawn(async move {
             let dst_addr = match socket.destination_addr(redir_ty) {
                 Ok(d) => d,
@@ -136,7 +140,10 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
                 }
             };
 
-            if let Err(err) = handle_redir_client(&server, socket, dst_addr).await {
+            let server = servers.pick_server_for(&Address::from(dst_addr));
+            trace!("picked proxy server: {:?}", server.server_config());
+
+            if let Err(err) = handle_redir_client(&servers, server, socket, dst_addr).await {
                 debug!("TCP redirect client, error: {:?}", err);
             }
         });
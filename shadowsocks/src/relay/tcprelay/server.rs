@@ -1,6 +1,8 @@
 //! Relay for TCP server that running on the server side
 
-use std::{io, io::ErrorKind, net::SocketAddr, time::Duration};
+use std::{io, io::ErrorKind, net::SocketAddr, sync::Arc, time::Duration};
+
+use bytes::Bytes;
 
 use futures::{
     future::{self, Either},
@@ -9,30 +11,34 @@ use futures::{
 use log::{debug, error, info, trace, warn};
 use tokio::{
     self,
+    io::AsyncRead,
     net::{TcpListener, TcpStream},
     time,
 };
 
 use crate::{
-    config::ServerConfig,
+    acl::Protocol,
     context::SharedContext,
-    relay::{
-        flow::{SharedMultiServerFlowStatistic, SharedServerFlowStatistic},
-        socks5::Address,
-        utils::try_timeout,
-    },
+    relay::{flow::SharedMultiServerFlowStatistic, socks5::Address, utils::try_timeout},
 };
 
+#[cfg(feature = "metrics")]
+use crate::relay::metrics::GaugeGuard;
+
+#[cfg(feature = "aead-cipher-2022")]
+use super::aead_2022::skip_padding;
 use super::{monitor::TcpMonStream, utils::connect_tcp_stream, CryptoStream, STcpStream};
 
 #[allow(clippy::cognitive_complexity)]
 async fn handle_client(
     context: SharedContext,
-    flow_stat: SharedServerFlowStatistic,
-    svr_cfg: &ServerConfig,
+    flow_stat: SharedMultiServerFlowStatistic,
+    users: &[(usize, Bytes)],
     socket: TcpStream,
     peer_addr: SocketAddr,
 ) -> io::Result<()> {
+    // Users sharing the same port are required to have the same method
+    let mut svr_cfg = context.server_config(users[0].0);
     let timeout = svr_cfg.timeout();
 
     // FIXME: set_keepalive have been removed from tokio 0.3
@@ -42,18 +48,39 @@ async fn handle_client(
 
     trace!("got connection addr {} with proxy server {:?}", peer_addr, svr_cfg);
 
+    // Waited for when shutting down
+    let connection_guard = context.connections().track();
+
+    #[cfg(feature = "metrics")]
+    let _conn_guard = GaugeGuard::tcp_connection(context.metrics());
+
     let mut stream = STcpStream::new(socket, timeout, true);
     stream.set_nodelay(context.config().no_delay)?;
 
     // Wrap with a data transfer monitor
-    let stream = TcpMonStream::new(flow_stat, stream);
-
     // Do server-client handshake
     // Perform encryption IV exchange
-    let mut stream = CryptoStream::new(context.clone(), stream, svr_cfg);
+    let mut stream = if users.len() == 1 {
+        let flow_stat = flow_stat
+            .get_by_index(users[0].0)
+            .expect("server not existed in multi-server flow statistic")
+            .clone();
+
+        if flow_stat.is_quota_exhausted() {
+            debug!("refused client {}, traffic quota exhausted", peer_addr);
+            return Ok(());
+        }
+
+        let stream = TcpMonStream::new(flow_stat, stream);
+        CryptoStream::new(context.clone(), stream, svr_cfg)
+    } else {
+        // User is unknown until the first chunk is decrypted
+        let stream = TcpMonStream::new_pending(stream);
+        CryptoStream::new_multi_user(context.clone(), stream, svr_cfg.method(), users.to_vec())
+    };
 
     // Read remote Address
-    let remote_addr = match Address::read_from(&mut stream).await {
+    let remote_addr = match read_request_header(&mut stream, svr_cfg.is_aead_2022()).await {
         Ok(o) => o,
         Err(err) => {
             error!(
@@ -61,19 +88,42 @@ async fn handle_client(
                 peer_addr, err
             );
 
+            #[cfg(feature = "metrics")]
+            context.metrics().incr_handshake_failure();
+
             // Hold the TCP connection until it closes by itself for preventing active probing.
             // Further discussion: https://github.com/shadowsocks/shadowsocks-rust/issues/292
+            //
+            // Shutting down won't wait for it, nothing is relayed through it
+            drop(connection_guard);
             let mut tcp = stream.into_inner().into_inner().into_inner();
             let _ = super::ignore_until_end(&mut tcp).await;
 
-            return Err(From::from(err));
+            return Err(err);
         }
     };
 
+    if let Some(user_idx) = stream.user_index() {
+        let user_flow_stat = flow_stat
+            .get_by_index(user_idx)
+            .expect("server not existed in multi-server flow statistic")
+            .clone();
+
+        svr_cfg = context.server_config(user_idx);
+        trace!("client {} identified as user {:?}", peer_addr, svr_cfg);
+
+        if user_flow_stat.is_quota_exhausted() {
+            debug!("refused client {}, traffic quota exhausted", peer_addr);
+            return Ok(());
+        }
+
+        stream.get_mut().set_flow_stat(user_flow_stat);
+    }
+
     debug!("RELAY {} <-> {} establishing", peer_addr, remote_addr);
 
     // Check if remote_addr matches any ACL rules
-    if context.check_outbound_blocked(&remote_addr).await {
+    if context.check_outbound_blocked(&remote_addr, Protocol::Tcp).await {
         warn!("outbound {} is blocked by ACL rules", remote_addr);
         return Ok(());
     }
@@ -184,11 +234,27 @@ async fn handle_client(
     Ok(())
 }
 
+/// Read target address of the request, and skip padding after it for AEAD-2022 methods
+async fn read_request_header<S>(stream: &mut S, aead_2022: bool) -> io::Result<Address>
+where
+    S: AsyncRead + Unpin,
+{
+    let addr = Address::read_from(stream).await?;
+    if aead_2022 {
+        #[cfg(feature = "aead-cipher-2022")]
+        skip_padding(stream).await?;
+    }
+    Ok(addr)
+}
+
 /// Runs the server
 pub async fn run(context: SharedContext, flow_stat: SharedMultiServerFlowStatistic) -> io::Result<()> {
     let vec_fut = FuturesUnordered::new();
 
-    for (idx, svr_cfg) in context.config().server.iter().enumerate() {
+    // Servers sharing the same address are different users of one listener
+    for svr_idxs in context.config().server_groups() {
+        let svr_cfg = context.server_config(svr_idxs[0]);
+
         let listener = {
             let addr = svr_cfg.external_addr();
             let addr = addr.bind_addr(&context).await?;
@@ -199,17 +265,30 @@ pub async fn run(context: SharedContext, flow_stat: SharedMultiServerFlowStatist
             })?;
 
             let local_addr = listener.local_addr().expect("determine port bound to");
-            info!("shadowsocks TCP listening on {}", local_addr);
+            if svr_idxs.len() > 1 {
+                info!(
+                    "shadowsocks TCP listening on {} with {} users",
+                    local_addr,
+                    svr_idxs.len()
+                );
+            } else {
+                info!("shadowsocks TCP listening on {}", local_addr);
+            }
 
             listener
         };
 
+        // Users' keys for identifying which user the connection belongs to
+        let users: Arc<Vec<(usize, Bytes)>> = Arc::new(
+            svr_idxs
+                .iter()
+                .map(|&idx| (idx, context.server_config(idx).clone_key()))
+                .collect(),
+        );
+
         // Clone and move into the server future
         let context = context.clone();
-        let flow_stat = flow_stat
-            .get(svr_cfg.addr().port())
-            .expect("port not existed in multi-server flow statistic")
-            .clone();
+        let flow_stat = flow_stat.clone();
 
         vec_fut.push(async move {
             loop {
@@ -223,15 +302,11 @@ pub async fn run(context: SharedContext, flow_stat: SharedMultiServerFlowStatist
 
                         let flow_stat = flow_stat.clone();
                         let context = context.clone();
+                        let users = users.clone();
 
                         tokio::spawn(async move {
-                            // Retrieve server config reference from context again
-                            //
-                            // Because the svr_cfg outside doesn't live long enough. WHAT??
-                            let svr_cfg = context.server_config(idx);
-
                             // Error is ignored because it is already logged
-                            let _ = handle_client(context.clone(), flow_stat, svr_cfg, socket, peer_addr).await;
+                            let _ = handle_client(context, flow_stat, &users, socket, peer_addr).await;
                         });
                     }
                     Err(err) => {
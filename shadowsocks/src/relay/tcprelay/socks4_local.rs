@@ -19,38 +19,32 @@ use crate::{
     relay::{
         loadbalancing::server::{PlainPingBalancer, ServerType, SharedPlainServerStatistic},
         socks4::{Address, Command, HandshakeRequest, HandshakeResponse, ResultCode},
+        socks5,
         tcprelay::ProxyStream,
     },
 };
 
 async fn handle_socks4_connect(
-    server: &SharedPlainServerStatistic,
+    servers: &PlainPingBalancer,
+    server: SharedPlainServerStatistic,
     mut stream: BufReader<TcpStream>,
     client_addr: SocketAddr,
     addr: Address,
 ) -> io::Result<()> {
-    let context = server.context();
-    let svr_cfg = server.server_config();
-
     // NOTE: Shadowsocks server uses SOCKS5 Address
     let ss_addr = addr.into();
 
-    let mut svr_s = match ProxyStream::connect(server.clone_context(), svr_cfg, &ss_addr).await {
-        Ok(svr_s) => {
+    let (mut svr_s, server) = match ProxyStream::connect_with_failover(servers, server, &ss_addr).await {
+        Ok((svr_s, server)) => {
             // Tell the client that we are ready
             let handshake_rsp = HandshakeResponse::new(ResultCode::RequestGranted);
             handshake_rsp.write_to(&mut stream).await?;
 
             trace!("sent header: {:?}", handshake_rsp);
 
-            svr_s
+            (svr_s, server)
         }
         Err(perr) => {
-            if perr.is_proxied() {
-                // Report to global statistic
-                server.report_failure().await;
-            }
-
             let err = perr.into_inner();
             let result_code = match err.kind() {
                 ErrorKind::ConnectionRefused => ResultCode::RequestRejectedCannotConnect,
@@ -71,6 +65,9 @@ async fn handle_socks4_connect(
         svr_s.write_all(buffer).await?;
     }
 
+    let context = server.context();
+    let svr_cfg = server.server_config();
+
     // UNWRAP.
     let mut stream = stream.into_inner();
 
@@ -118,7 +115,7 @@ async fn handle_socks4_connect(
     Ok(())
 }
 
-async fn handle_socks4_client(server: &SharedPlainServerStatistic, s: TcpStream) -> io::Result<()> {
+async fn handle_socks4_client(servers: &PlainPingBalancer, s: TcpStream) -> io::Result<()> {
     // let svr_cfg = server.server_config();
     //
     // FIXME: set_keepalive have been removed from tokio 0.3
@@ -144,7 +141,10 @@ async fn handle_socks4_client(server: &SharedPlainServerStatistic, s: TcpStream)
         Command::Connect => {
             debug!("CONNECT {}", handshake_req.dst);
 
-            handle_socks4_connect(server, s, client_addr, handshake_req.dst).await
+            let server = servers.pick_server_for(&socks5::Address::from(handshake_req.dst.clone()));
+            trace!("picked proxy server: {:?}", server.server_config());
+
+            handle_socks4_connect(servers, server, s, client_addr, handshake_req.dst).await
         }
         Command::Bind => {
             warn!("BIND is not supported");
@@ -182,13 +182,11 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
                 continue;
             }
         };
-        let server = servers.pick_server();
-
         trace!("got connection {}", peer_addr);
-        trace!("picked proxy server: {:?}", server.server_config());
 
+        let servers = servers.clone();
         tokio::spawn(async move {
-            if let Err(err) = handle_socks4_client(&server, socket).await {
+            if let Err(err) = handle_socks4_client(&servers, socket).await {
                 debug!("TCP socks4 client exited with error: {}", err);
             }
         });
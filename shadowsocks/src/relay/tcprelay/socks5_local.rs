@@ -2,7 +2,8 @@
 
 use std::{
     io::{self, ErrorKind},
-    net::{Ipv4Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str,
     time::Duration,
 };
 
@@ -10,20 +11,33 @@ use futures::future::{self, Either};
 use log::{debug, error, info, trace, warn};
 use tokio::{
     self,
-    net::{TcpListener, TcpStream},
+    net::{TcpListener, TcpStream, UdpSocket},
     time,
 };
 
 use crate::{
-    context::SharedContext,
+    acl::{Protocol, Route},
+    context::{Context, SharedContext},
     relay::{
         loadbalancing::server::{PlainPingBalancer, ServerType, SharedPlainServerStatistic},
-        socks5::{self, Address, HandshakeRequest, HandshakeResponse, TcpRequestHeader, TcpResponseHeader},
+        socks5::{
+            self,
+            Address,
+            HandshakeRequest,
+            HandshakeResponse,
+            PasswdAuthRequest,
+            PasswdAuthResponse,
+            TcpRequestHeader,
+            TcpResponseHeader,
+        },
     },
 };
 
 use super::{ignore_until_end, ProxyStream};
 
+/// Time for waiting the target to connect in after BIND
+const BIND_ACCEPT_TIMEOUT: Duration = Duration::from_secs(120);
+
 #[derive(Debug, Clone)]
 struct UdpConfig {
     enable_udp: bool,
@@ -31,36 +45,31 @@ struct UdpConfig {
 }
 
 async fn handle_socks5_connect(
-    server: &SharedPlainServerStatistic,
+    servers: &PlainPingBalancer,
+    server: SharedPlainServerStatistic,
     stream: &mut TcpStream,
     client_addr: SocketAddr,
     addr: &Address,
 ) -> io::Result<()> {
-    let context = server.context();
-    let svr_cfg = server.server_config();
-
-    let svr_s = match ProxyStream::connect(server.clone_context(), svr_cfg, addr).await {
-        Ok(svr_s) => {
+    let (svr_s, server) = match ProxyStream::connect_with_failover(servers, server, addr).await {
+        Ok((svr_s, server)) => {
             // Tell the client that we are ready
             let header = TcpResponseHeader::new(socks5::Reply::Succeeded, Address::SocketAddress(svr_s.local_addr()?));
             header.write_to(stream).await?;
 
             trace!("sent header: {:?}", header);
 
-            svr_s
+            (svr_s, server)
         }
         Err(perr) => {
             use crate::relay::socks5::Reply;
 
-            if perr.is_proxied() {
-                // Report to global statistic
-                server.report_failure().await;
-            }
-
             let err = perr.into_inner();
             let reply = match err.kind() {
                 ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
                 ErrorKind::ConnectionAborted => Reply::HostUnreachable,
+                // Rejected by ACL
+                ErrorKind::PermissionDenied => Reply::ConnectionNotAllowed,
                 _ => Reply::NetworkUnreachable,
             };
 
@@ -72,6 +81,9 @@ async fn handle_socks5_connect(
         }
     };
 
+    let context = server.context();
+    let svr_cfg = server.server_config();
+
     let (mut svr_r, mut svr_w) = svr_s.split();
 
     // Reset `TCP_NODELAY` after Socks5 handshake
@@ -117,9 +129,258 @@ async fn handle_socks5_connect(
     Ok(())
 }
 
+/// Get the local IP address that routes to `target`
+async fn outbound_ip(target: &SocketAddr) -> io::Result<IpAddr> {
+    // Connecting an UDP socket sends nothing, but makes the system choose an outbound interface
+    let socket = UdpSocket::bind(SocketAddr::new(unspecified_ip(target), 0)).await?;
+    socket.connect(target).await?;
+    Ok(socket.local_addr()?.ip())
+}
+
+fn unspecified_ip(addr: &SocketAddr) -> IpAddr {
+    match *addr {
+        SocketAddr::V4(..) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(..) => Ipv6Addr::UNSPECIFIED.into(),
+    }
+}
+
+/// Get the IP address for listening BIND's inbound connection from `target`
+///
+/// Loopback addresses are only used for targets on this host. Otherwise, if the target's address is unknown,
+/// or there is no route to it, listens on all interfaces.
+async fn bind_listen_ip(target: &SocketAddr) -> io::Result<IpAddr> {
+    let ip = target.ip();
+    if ip.is_loopback() || ip.is_unspecified() {
+        return Ok(ip);
+    }
+
+    let ip = outbound_ip(target).await?;
+    if ip.is_loopback() || ip.is_unspecified() {
+        Ok(unspecified_ip(target))
+    } else {
+        Ok(ip)
+    }
+}
+
+async fn handle_socks5_bind(
+    context: &Context,
+    stream: &mut TcpStream,
+    client_addr: SocketAddr,
+    addr: &Address,
+) -> io::Result<()> {
+    let dummy_address = Address::SocketAddress(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0));
+
+    // Shadowsocks servers cannot accept inbound connections for us,
+    // so BIND can only be served directly, which is allowed only if the target is bypassed
+    if context.route_target(addr, Protocol::Tcp).await != Route::Bypass {
+        warn!("BIND {} is not allowed, target is not bypassed", addr);
+        let rh = TcpResponseHeader::new(socks5::Reply::ConnectionNotAllowed, dummy_address);
+        rh.write_to(stream).await?;
+        return Ok(());
+    }
+
+    let target_addrs = match *addr {
+        Address::SocketAddress(sa) => Ok(vec![sa]),
+        Address::DomainNameAddress(ref dname, port) => context.dns_resolve(dname, port).await,
+    };
+    let target_addrs = match target_addrs {
+        Ok(addrs) => addrs,
+        Err(err) => {
+            let rh = TcpResponseHeader::new(socks5::Reply::HostUnreachable, dummy_address);
+            rh.write_to(stream).await?;
+            return Err(err);
+        }
+    };
+    let target_addr = match target_addrs.first() {
+        Some(a) => a,
+        None => {
+            let rh = TcpResponseHeader::new(socks5::Reply::HostUnreachable, dummy_address);
+            rh.write_to(stream).await?;
+            return Err(io::Error::new(ErrorKind::Other, "target resolved to no address"));
+        }
+    };
+
+    // Listen on the interface that the target could connect to
+    let listener = match bind_listen_ip(target_addr).await {
+        Ok(ip) => TcpListener::bind(SocketAddr::new(ip, 0)).await,
+        Err(err) => Err(err),
+    };
+    let listener = match listener {
+        Ok(l) => l,
+        Err(err) => {
+            let rh = TcpResponseHeader::new(socks5::Reply::GeneralFailure, dummy_address);
+            rh.write_to(stream).await?;
+            return Err(err);
+        }
+    };
+
+    // The first reply, tells the client where the target should connect to
+    //
+    // Listening on all interfaces, advertises the address that the client connected to
+    let mut bind_addr = listener.local_addr()?;
+    let client_local_addr = stream.local_addr()?;
+    if bind_addr.ip().is_unspecified() && bind_addr.is_ipv4() == client_local_addr.is_ipv4() {
+        bind_addr.set_ip(client_local_addr.ip());
+    }
+    let header = TcpResponseHeader::new(socks5::Reply::Succeeded, Address::SocketAddress(bind_addr));
+    header.write_to(stream).await?;
+
+    debug!("BIND {} listening on {}", addr, bind_addr);
+
+    let (mut inbound, peer_addr) = match time::timeout(BIND_ACCEPT_TIMEOUT, listener.accept()).await {
+        Ok(Ok(s)) => s,
+        Ok(Err(err)) => {
+            let rh = TcpResponseHeader::new(socks5::Reply::GeneralFailure, dummy_address);
+            rh.write_to(stream).await?;
+            return Err(err);
+        }
+        Err(..) => {
+            let rh = TcpResponseHeader::new(socks5::Reply::TtlExpired, dummy_address);
+            rh.write_to(stream).await?;
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "waiting for inbound connection timed out",
+            ));
+        }
+    };
+    drop(listener);
+
+    // Only the target is allowed to connect in, unless the client doesn't know its address
+    let peer_allowed = target_addrs
+        .iter()
+        .any(|a| a.ip().is_unspecified() || a.ip() == peer_addr.ip());
+    if !peer_allowed {
+        let rh = TcpResponseHeader::new(socks5::Reply::ConnectionNotAllowed, dummy_address);
+        rh.write_to(stream).await?;
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!("inbound connection from {} is not the target", peer_addr),
+        ));
+    }
+
+    // The second reply, tells the client who connected in
+    let header = TcpResponseHeader::new(socks5::Reply::Succeeded, Address::SocketAddress(peer_addr));
+    header.write_to(stream).await?;
+
+    trace!("sent header: {:?}", header);
+
+    let _connection_guard = context.connections().track();
+
+    if context.config().no_delay {
+        if let Err(err) = inbound.set_nodelay(true) {
+            error!("failed to set TCP_NODELAY on inbound socket, error: {:?}", err);
+        }
+    } else {
+        // Reset `TCP_NODELAY` after Socks5 handshake
+        if let Err(err) = stream.set_nodelay(false) {
+            error!("failed to reset TCP_NODELAY on socket, error: {:?}", err);
+        }
+    }
+
+    let (mut r, mut w) = stream.split();
+    let (mut ir, mut iw) = inbound.split();
+
+    let rhalf = tokio::io::copy(&mut r, &mut iw);
+    let whalf = tokio::io::copy(&mut ir, &mut w);
+
+    tokio::pin!(rhalf);
+    tokio::pin!(whalf);
+
+    debug!("BIND relay established {} <-> {}", client_addr, peer_addr);
+
+    match future::select(rhalf, whalf).await {
+        Either::Left((Ok(..), _)) => trace!("BIND relay {} -> {} closed", client_addr, peer_addr),
+        Either::Left((Err(err), _)) => debug!("BIND relay {} -> {} closed with error {}", client_addr, peer_addr, err),
+        Either::Right((Ok(..), _)) => trace!("BIND relay {} <- {} closed", client_addr, peer_addr),
+        Either::Right((Err(err), _)) => debug!("BIND relay {} <- {} closed with error {}", client_addr, peer_addr, err),
+    }
+
+    debug!("BIND relay {} <-> {} closed", client_addr, peer_addr);
+
+    Ok(())
+}
+
+/// Choose an authentication method and authenticate the client
+async fn handle_socks5_handshake(
+    context: &Context,
+    s: &mut TcpStream,
+    handshake_req: &HandshakeRequest,
+) -> io::Result<()> {
+    let auth = match context.config().local_auth {
+        Some(ref auth) => auth,
+        None => {
+            if !handshake_req.methods.contains(&socks5::SOCKS5_AUTH_METHOD_NONE) {
+                let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE);
+                resp.write_to(s).await?;
+
+                return Err(io::Error::new(
+                    ErrorKind::Other,
+                    "authentication is not enabled, but client requires one",
+                ));
+            }
+
+            // Reply to client
+            let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NONE);
+            trace!("Reply handshake {:?}", resp);
+            return resp.write_to(s).await;
+        }
+    };
+
+    // Username/Password Authentication (RFC1929)
+    if !handshake_req.methods.contains(&socks5::SOCKS5_AUTH_METHOD_PASSWORD) {
+        let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE);
+        resp.write_to(s).await?;
+
+        return Err(io::Error::new(
+            ErrorKind::Other,
+            "client does not support username/password authentication",
+        ));
+    }
+
+    let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_PASSWORD);
+    trace!("Reply handshake {:?}", resp);
+    resp.write_to(s).await?;
+
+    let auth_req = PasswdAuthRequest::read_from(s).await?;
+
+    let authenticated = match (str::from_utf8(&auth_req.uname), str::from_utf8(&auth_req.passwd)) {
+        (Ok(uname), Ok(passwd)) => auth.check(uname, passwd),
+        _ => false,
+    };
+
+    if !authenticated {
+        let resp = PasswdAuthResponse::new(socks5::SOCKS5_AUTH_PASSWORD_FAILURE);
+        resp.write_to(s).await?;
+
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "username/password authentication failed, username: {}",
+                String::from_utf8_lossy(&auth_req.uname)
+            ),
+        ));
+    }
+
+    let resp = PasswdAuthResponse::new(socks5::SOCKS5_AUTH_PASSWORD_SUCCEEDED);
+    trace!("Reply password authentication {:?}", resp);
+    resp.write_to(s).await
+}
+
+/// Hold the UDP ASSOCIATE connection until it ends by its own
+///
+/// UDP relay accepts packets from the client only while the connection is held
+async fn hold_udp_associate(context: &Context, s: &mut TcpStream, client_addr: SocketAddr) -> io::Result<()> {
+    context.add_udp_associate_client(client_addr.ip());
+    let result = ignore_until_end(s).await;
+    context.remove_udp_associate_client(client_addr.ip());
+
+    result.map(|_| ())
+}
+
 #[allow(clippy::cognitive_complexity)]
 async fn handle_socks5_client(
-    server: &SharedPlainServerStatistic,
+    context: &Context,
+    servers: &PlainPingBalancer,
     mut s: TcpStream,
     udp_conf: UdpConfig,
 ) -> io::Result<()> {
@@ -143,22 +404,7 @@ async fn handle_socks5_client(
     // Socks5 handshakes
     trace!("socks5 {:?}", handshake_req);
 
-    if !handshake_req.methods.contains(&socks5::SOCKS5_AUTH_METHOD_NONE) {
-        use std::io::Error;
-
-        let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE);
-        resp.write_to(&mut s).await?;
-
-        return Err(Error::new(
-            ErrorKind::Other,
-            "currently shadowsocks-rust does not support authentication",
-        ));
-    } else {
-        // Reply to client
-        let resp = HandshakeResponse::new(socks5::SOCKS5_AUTH_METHOD_NONE);
-        trace!("Reply handshake {:?}", resp);
-        resp.write_to(&mut s).await?;
-    }
+    handle_socks5_handshake(context, &mut s, &handshake_req).await?;
 
     // Fetch headers
     let header = match TcpRequestHeader::read_from(&mut s).await {
@@ -176,11 +422,14 @@ async fn handle_socks5_client(
     let addr = header.address;
     match header.command {
         socks5::Command::TcpConnect => {
-            let enable_tcp = server.config().mode.enable_tcp();
+            let enable_tcp = context.config().mode.enable_tcp();
             if enable_tcp {
                 debug!("CONNECT {}", addr);
 
-                match handle_socks5_connect(server, &mut s, client_addr, &addr).await {
+                let server = servers.pick_server_for(&addr);
+                trace!("picked proxy server: {:?}", server.server_config());
+
+                match handle_socks5_connect(servers, server, &mut s, client_addr, &addr).await {
                     Ok(..) => Ok(()),
                     Err(err) => Err(io::Error::new(
                         err.kind(),
@@ -196,32 +445,39 @@ async fn handle_socks5_client(
             }
         }
         socks5::Command::TcpBind => {
-            warn!("BIND is not supported");
-            let rh = TcpResponseHeader::new(socks5::Reply::CommandNotSupported, addr);
-            rh.write_to(&mut s).await?;
+            let enable_tcp = context.config().mode.enable_tcp();
+            if enable_tcp {
+                debug!("BIND {}", addr);
 
-            Ok(())
+                match handle_socks5_bind(context, &mut s, client_addr, &addr).await {
+                    Ok(..) => Ok(()),
+                    Err(err) => Err(io::Error::new(
+                        err.kind(),
+                        format!("BIND {} failed with error \"{}\"", addr, err),
+                    )),
+                }
+            } else {
+                warn!("BIND is not enabled");
+                let rh = TcpResponseHeader::new(socks5::Reply::CommandNotSupported, addr);
+                rh.write_to(&mut s).await?;
+
+                Ok(())
+            }
         }
         socks5::Command::UdpAssociate => {
-            if let Some(ref bind_addr) = server.config().udp_bind_addr {
+            if let Some(ref bind_addr) = context.config().udp_bind_addr {
                 debug!("UDP ASSOCIATE {}", addr);
 
                 let rh = TcpResponseHeader::new(socks5::Reply::Succeeded, bind_addr.into());
                 rh.write_to(&mut s).await?;
 
-                // Hold the connection until it ends by its own
-                ignore_until_end(&mut s).await?;
-
-                Ok(())
+                hold_udp_associate(context, &mut s, client_addr).await
             } else if udp_conf.enable_udp {
                 debug!("UDP ASSOCIATE {}", addr);
                 let rh = TcpResponseHeader::new(socks5::Reply::Succeeded, From::from(udp_conf.client_addr));
                 rh.write_to(&mut s).await?;
 
-                // Hold the connection until it ends by its own
-                ignore_until_end(&mut s).await?;
-
-                Ok(())
+                hold_udp_associate(context, &mut s, client_addr).await
             } else {
                 warn!("UDP ASSOCIATE is not enabled");
                 let rh = TcpResponseHeader::new(socks5::Reply::CommandNotSupported, addr);
@@ -250,7 +506,7 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
         client_addr: actual_local_addr,
     };
 
-    let servers = PlainPingBalancer::new(context, ServerType::Tcp).await;
+    let servers = PlainPingBalancer::new(context.clone(), ServerType::Tcp).await;
 
     info!("shadowsocks SOCKS5 TCP listening on {}", actual_local_addr);
 
@@ -263,14 +519,13 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
                 continue;
             }
         };
-        let server = servers.pick_server();
-
         trace!("got connection {}", peer_addr);
-        trace!("picked proxy server: {:?}", server.server_config());
 
+        let context = context.clone();
+        let servers = servers.clone();
         let udp_conf = udp_conf.clone();
         tokio::spawn(async move {
-            if let Err(err) = handle_socks5_client(&server, socket, udp_conf).await {
+            if let Err(err) = handle_socks5_client(&context, &servers, socket, udp_conf).await {
                 debug!("TCP socks5 client exited with error: {}", err);
             }
         });
@@ -27,15 +27,16 @@ use super::ProxyStream;
 ///
 /// This method must be called after handshaking with client (for example, socks5 handshaking)
 async fn establish_client_tcp_tunnel<'a>(
-    server: &SharedPlainServerStatistic,
+    servers: &PlainPingBalancer,
+    server: SharedPlainServerStatistic,
     mut s: TcpStream,
     client_addr: SocketAddr,
     addr: &Address,
 ) -> io::Result<()> {
+    // NOTE: TUNNEL doesn't need to check ACL, just forward everything to proxy server
+    let (svr_s, server) = ProxyStream::connect_proxied_with_failover(servers, server, addr).await?;
     let svr_cfg = server.server_config();
 
-    // NOTE: TUNNEL doesn't need to check ACL, just forward everything to proxy server
-    let svr_s = ProxyStream::connect_proxied(server.clone_context(), svr_cfg, addr).await?;
     let (mut svr_r, mut svr_w) = svr_s.split();
 
     let (mut r, mut w) = s.split();
@@ -74,7 +75,11 @@ async fn establish_client_tcp_tunnel<'a>(
     Ok(())
 }
 
-async fn handle_tunnel_client(server: &SharedPlainServerStatistic, s: TcpStream) -> io::Result<()> {
+async fn handle_tunnel_client(
+    servers: &PlainPingBalancer,
+    server: SharedPlainServerStatistic,
+    s: TcpStream,
+) -> io::Result<()> {
     // let svr_cfg = server.server_config();
     //
     // FIXME: set_keepalive have been removed from tokio 0.3
@@ -92,9 +97,9 @@ async fn handle_tunnel_client(server: &SharedPlainServerStatistic, s: TcpStream)
     let client_addr = s.peer_addr()?;
 
     // forward must not be None, it is already checked in local.rs
-    let target_addr = server.config().forward.as_ref().unwrap();
+    let target_addr = server.config().forward.clone().unwrap();
 
-    establish_client_tcp_tunnel(server, s, client_addr, target_addr).await
+    establish_client_tcp_tunnel(servers, server, s, client_addr, &target_addr).await
 }
 
 pub async fn run(context: SharedContext) -> io::Result<()> {
@@ -130,13 +135,14 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
                 continue;
             }
         };
-        let server = servers.pick_server();
+        let server = servers.pick_server_for(forward_addr);
 
         trace!("got connection {}", peer_addr);
         trace!("picked proxy server: {:?}", server.server_config());
 
+        let servers = servers.clone();
         tokio::spawn(async move {
-            if let Err(err) = handle_tunnel_client(&server, socket).await {
+            if let Err(err) = handle_tunnel_client(&servers, server, socket).await {
                 debug!("TCP tunnel client exited with error: {:?}", err);
             }
         });
@@ -0,0 +1,829 @@
+//! Crypto protocol of AEAD-2022 methods for ShadowSocks UDP
+//!
+//! Packet of 2022-blake3-aes-128-gcm and 2022-blake3-aes-256-gcm
+//! ```plain
+//! +---------------------+-----------------+-----------+
+//! |  *Separate header*  |  *Main header*  |  Data_TAG |
+//! +---------------------+-----------------+-----------+
+//! |         16          |    Variable     |    16     |
+//! +---------------------+-----------------+-----------+
+//! ```
+//!
+//! Separate header is encrypted by AES with the pre-shared key, main header is encrypted by AES-GCM with the session
+//! subkey derived from session ID, and the last 12 bytes of separate header is the nonce.
+//!
+//! Packet of 2022-blake3-chacha20-poly1305
+//! ```plain
+//! +-------+---------------------+-----------------+-----------+
+//! | NONCE |  *Separate header*  |  *Main header*  |  Data_TAG |
+//! +-------+---------------------+-----------------+-----------+
+//! |  24   |         16          |    Variable     |    16     |
+//! +-------+---------------------+-----------------+-----------+
+//! ```
+//!
+//! Both headers are encrypted by XChaCha20-Poly1305 with the pre-shared key, which is ChaCha20-Poly1305 with a
+//! subkey derived by HChaCha20 from the first 16 bytes of NONCE.
+//!
+//! ```plain
+//! Separate header
+//! +------------+-----------+
+//! | SESSION ID | PACKET ID |
+//! +------------+-----------+
+//! |     8      |     8     |
+//! +------------+-----------+
+//!
+//! Main header of requests, TYPE is 0
+//! +------+-----------+------------+----------+------+----------+----------+----------+
+//! | TYPE | TIMESTAMP | PADDINGLEN | PADDING  | ATYP | DST.ADDR | DST.PORT |   DATA   |
+//! +------+-----------+------------+----------+------+----------+----------+----------+
+//! |  1   |     8     |     2      | Variable |  1   | Variable |    2     | Variable |
+//! +------+-----------+------------+----------+------+----------+----------+----------+
+//!
+//! Main header of responses, TYPE is 1
+//! +------+-----------+-------------------+------------+----------+------+----------+----------+----------+
+//! | TYPE | TIMESTAMP | CLIENT SESSION ID | PADDINGLEN | PADDING  | ATYP | SRC.ADDR | SRC.PORT |   DATA   |
+//! +------+-----------+-------------------+------------+----------+------+----------+----------+----------+
+//! |  1   |     8     |         8         |     2      | Variable |  1   | Variable |    2     | Variable |
+//! +------+-----------+-------------------+------------+----------+------+----------+----------+----------+
+//! ```
+use std::{
+    io::{self, ErrorKind},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use crypto2::{
+    aeadcipher::Chacha20Poly1305,
+    blockcipher::{Aes128, Aes256},
+};
+use lru_time_cache::LruCache;
+use spin::Mutex as SpinMutex;
+
+use crate::{
+    crypto::v1::{random_iv_or_salt, CipherKind},
+    relay::{
+        aead_2022::{
+            check_timestamp,
+            padding_len,
+            session_subkey,
+            unix_timestamp,
+            AeadCipher,
+            SALT_EXPIRY_DURATION,
+            TAG_LEN,
+        },
+        socks5::Address,
+    },
+};
+
+const HEADER_TYPE_REQUEST: u8 = 0;
+const HEADER_TYPE_RESPONSE: u8 = 1;
+
+const SEPARATE_HEADER_LEN: usize = 8 + 8;
+const XCHACHA20_NONCE_LEN: usize = 24;
+const HCHACHA20_NONCE_LEN: usize = 16;
+
+/// Packets with IDs fall behind the latest one at least this size are rejected
+const PACKET_WINDOW_SIZE: u64 = 1024;
+const PACKET_WINDOW_BLOCKS: usize = (PACKET_WINDOW_SIZE / 64) as usize;
+
+/// Period for remembering sessions of the other side since their last packets
+///
+/// Packets sent earlier than that are rejected because of their timestamps
+const PEER_SESSION_EXPIRY_DURATION: Duration = SALT_EXPIRY_DURATION;
+
+/// Sessions of servers remembered by one client session, servers create new sessions only for new associations
+const SERVER_SESSIONS_CAPACITY: usize = 16;
+
+/// Sliding window filter of packet IDs in one session
+struct PacketWindow {
+    latest: u64,
+    bitmap: [u64; PACKET_WINDOW_BLOCKS],
+}
+
+impl PacketWindow {
+    fn new() -> PacketWindow {
+        PacketWindow {
+            latest: 0,
+            bitmap: [0; PACKET_WINDOW_BLOCKS],
+        }
+    }
+
+    // Check if `packet_id` haven't been seen, and mark it as seen
+    //
+    // Return `false` if `packet_id` is replayed, or too old to be checked
+    fn check_and_set(&mut self, packet_id: u64) -> bool {
+        if packet_id > self.latest {
+            if packet_id - self.latest >= PACKET_WINDOW_SIZE {
+                self.bitmap = [0; PACKET_WINDOW_BLOCKS];
+            } else {
+                // IDs slide into the window haven't been seen
+                for id in self.latest + 1..=packet_id {
+                    let (block, bit) = PacketWindow::position(id);
+                    self.bitmap[block] &= !(1 << bit);
+                }
+            }
+            self.latest = packet_id;
+        } else if self.latest - packet_id >= PACKET_WINDOW_SIZE {
+            return false;
+        }
+
+        let (block, bit) = PacketWindow::position(packet_id);
+        if self.bitmap[block] & (1 << bit) != 0 {
+            return false;
+        }
+        self.bitmap[block] |= 1 << bit;
+
+        true
+    }
+
+    fn position(packet_id: u64) -> (usize, u64) {
+        let idx = packet_id % PACKET_WINDOW_SIZE;
+        ((idx / 64) as usize, idx % 64)
+    }
+}
+
+struct PeerSession {
+    // Sessions are numbered in the order they are first seen
+    order: u64,
+    window: PacketWindow,
+}
+
+/// Sessions of the other side, keyed by their session IDs
+///
+/// Servers share one table between all their associations, so packets replayed from other addresses are rejected
+/// as well. Sessions are remembered until they have been idle for `PEER_SESSION_EXPIRY_DURATION`, or evicted by
+/// newer sessions if the table is full.
+pub struct PeerSessions {
+    sessions: LruCache<u64, PeerSession>,
+    next_order: u64,
+}
+
+impl PeerSessions {
+    /// Create a table remembering at most `capacity` sessions
+    pub fn new(capacity: usize) -> PeerSessions {
+        PeerSessions {
+            sessions: LruCache::with_expiry_duration_and_capacity(PEER_SESSION_EXPIRY_DURATION, capacity),
+            next_order: 0,
+        }
+    }
+
+    // Check if a packet is not replayed, and mark it as seen
+    //
+    // Returns the order of its session, packets of sessions older than the `current` one are rejected
+    fn check_and_set(&mut self, current: Option<u64>, session_id: u64, packet_id: u64) -> Option<u64> {
+        if let Some(session) = self.sessions.get_mut(&session_id) {
+            if let Some(current) = current {
+                if session.order < current {
+                    return None;
+                }
+            }
+
+            if !session.window.check_and_set(packet_id) {
+                return None;
+            }
+            return Some(session.order);
+        }
+
+        let order = self.next_order;
+        self.next_order += 1;
+
+        let mut window = PacketWindow::new();
+        window.check_and_set(packet_id);
+        self.sessions.insert(session_id, PeerSession { order, window });
+
+        Some(order)
+    }
+}
+
+/// Session of one side of a UDP association
+///
+/// Clients and servers have their own sessions, packets are numbered in their senders' sessions.
+pub struct UdpSession {
+    session_id: u64,
+    packet_id: AtomicU64,
+    // Current session of the other side, (session ID, order in `peers`)
+    peer: SpinMutex<Option<(u64, u64)>>,
+    // Sessions of the other side have been seen
+    peers: Arc<SpinMutex<PeerSessions>>,
+}
+
+impl UdpSession {
+    /// Create a session of client with a random session ID
+    pub fn new() -> UdpSession {
+        let peers = Arc::new(SpinMutex::new(PeerSessions::new(SERVER_SESSIONS_CAPACITY)));
+        UdpSession::new_with_peers(peers)
+    }
+
+    /// Create a session with a random session ID, sessions of the other side are checked in `peers`
+    ///
+    /// Servers should share `peers` between all their associations
+    pub fn new_with_peers(peers: Arc<SpinMutex<PeerSessions>>) -> UdpSession {
+        let mut session_id = [0u8; 8];
+        random_iv_or_salt(&mut session_id);
+
+        UdpSession {
+            session_id: u64::from_be_bytes(session_id),
+            packet_id: AtomicU64::new(0),
+            peer: SpinMutex::new(None),
+            peers,
+        }
+    }
+
+    fn next_packet_id(&self) -> u64 {
+        self.packet_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn peer_session_id(&self) -> Option<u64> {
+        self.peer.lock().as_ref().map(|&(id, _)| id)
+    }
+
+    // Check if a packet from the other side is not replayed
+    //
+    // A new session of the other side replaces the current one, which happens if it have been restarted.
+    // Sessions have been replaced are never accepted again.
+    fn check_peer_packet(&self, session_id: u64, packet_id: u64) -> bool {
+        let mut peer = self.peer.lock();
+        let current = peer.as_ref().map(|&(_, order)| order);
+
+        match self.peers.lock().check_and_set(current, session_id, packet_id) {
+            Some(order) => {
+                *peer = Some((session_id, order));
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Encrypt a request packet from client, with `data` to `addr`
+pub fn encrypt_request(
+    method: CipherKind,
+    key: &[u8],
+    session: &UdpSession,
+    addr: &Address,
+    data: &[u8],
+    dst: &mut BytesMut,
+) {
+    let mut header = BytesMut::with_capacity(1 + 8 + 2 + addr.serialized_len());
+    header.put_u8(HEADER_TYPE_REQUEST);
+    header.put_u64(unix_timestamp());
+    write_padding(&mut header, data.len());
+    addr.write_to_buf(&mut header);
+
+    encrypt_packet(method, key, session, &header, data, dst);
+}
+
+/// Encrypt a response packet from server, with `data` from `addr`
+///
+/// Session of client is learnt from its requests, so it fails if there is no valid request decrypted by `session`
+pub fn encrypt_response(
+    method: CipherKind,
+    key: &[u8],
+    session: &UdpSession,
+    addr: &Address,
+    data: &[u8],
+    dst: &mut BytesMut,
+) -> io::Result<()> {
+    let client_session_id = match session.peer_session_id() {
+        Some(id) => id,
+        None => return Err(io::Error::new(ErrorKind::Other, "session of client is unknown")),
+    };
+
+    let mut header = BytesMut::with_capacity(1 + 8 + 8 + 2 + addr.serialized_len());
+    header.put_u8(HEADER_TYPE_RESPONSE);
+    header.put_u64(unix_timestamp());
+    header.put_u64(client_session_id);
+    write_padding(&mut header, data.len());
+    addr.write_to_buf(&mut header);
+
+    encrypt_packet(method, key, session, &header, data, dst);
+    Ok(())
+}
+
+/// Decrypt a request packet from client, returns ADDRESS + DATA in it
+///
+/// Session of client is recorded in `session`, for checking replayed packets and encrypting responses
+pub fn decrypt_request(method: CipherKind, key: &[u8], session: &UdpSession, pkt: &[u8]) -> io::Result<Vec<u8>> {
+    let (session_id, packet_id, body) = decrypt_packet(method, key, pkt)?;
+
+    let mut header = &body[..];
+    if header.remaining() < 1 + 8 + 2 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "packet too short"));
+    }
+
+    let header_type = header.get_u8();
+    if header_type != HEADER_TYPE_REQUEST {
+        let err = io::Error::new(
+            ErrorKind::InvalidData,
+            format!("unexpected header type {}", header_type),
+        );
+        return Err(err);
+    }
+    check_timestamp(header.get_u64())?;
+
+    if !session.check_peer_packet(session_id, packet_id) {
+        let err = io::Error::new(ErrorKind::Other, format!("detected repeated packet {}", packet_id));
+        return Err(err);
+    }
+
+    let offset = body.len() - header.remaining();
+    skip_padding(body, offset)
+}
+
+/// Decrypt a response packet from server, returns ADDRESS + DATA in it
+pub fn decrypt_response(method: CipherKind, key: &[u8], session: &UdpSession, pkt: &[u8]) -> io::Result<Vec<u8>> {
+    let (session_id, packet_id, body) = decrypt_packet(method, key, pkt)?;
+
+    let mut header = &body[..];
+    if header.remaining() < 1 + 8 + 8 + 2 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "packet too short"));
+    }
+
+    let header_type = header.get_u8();
+    if header_type != HEADER_TYPE_RESPONSE {
+        let err = io::Error::new(
+            ErrorKind::InvalidData,
+            format!("unexpected header type {}", header_type),
+        );
+        return Err(err);
+    }
+    check_timestamp(header.get_u64())?;
+
+    if header.get_u64() != session.session_id {
+        return Err(io::Error::new(ErrorKind::InvalidData, "response of another session"));
+    }
+
+    if !session.check_peer_packet(session_id, packet_id) {
+        let err = io::Error::new(ErrorKind::Other, format!("detected repeated packet {}", packet_id));
+        return Err(err);
+    }
+
+    let offset = body.len() - header.remaining();
+    skip_padding(body, offset)
+}
+
+fn write_padding(header: &mut BytesMut, data_len: usize) {
+    let padding_len = padding_len(data_len);
+    header.put_u16(padding_len as u16);
+    header.resize(header.len() + padding_len, 0);
+}
+
+// Skip padding in the main header from `offset`, returns ADDRESS + DATA after it
+fn skip_padding(mut body: Vec<u8>, offset: usize) -> io::Result<Vec<u8>> {
+    let mut header = &body[offset..];
+    let padding_len = header.get_u16() as usize;
+    if header.remaining() < padding_len {
+        return Err(io::Error::new(ErrorKind::InvalidData, "packet too short"));
+    }
+
+    Ok(body.split_off(offset + 2 + padding_len))
+}
+
+fn encrypt_packet(
+    method: CipherKind,
+    key: &[u8],
+    session: &UdpSession,
+    header: &[u8],
+    data: &[u8],
+    dst: &mut BytesMut,
+) {
+    let mut separate_header = [0u8; SEPARATE_HEADER_LEN];
+    separate_header[..8].copy_from_slice(&session.session_id.to_be_bytes());
+    separate_header[8..].copy_from_slice(&session.next_packet_id().to_be_bytes());
+
+    match method {
+        CipherKind::CHACHA20_POLY1305 => {
+            let mut nonce = [0u8; XCHACHA20_NONCE_LEN];
+            random_iv_or_salt(&mut nonce);
+
+            dst.reserve(XCHACHA20_NONCE_LEN + SEPARATE_HEADER_LEN + header.len() + data.len() + TAG_LEN);
+
+            dst.put_slice(&nonce);
+            let start = dst.len();
+            dst.put_slice(&separate_header);
+            dst.put_slice(header);
+            dst.put_slice(data);
+            dst.resize(dst.len() + TAG_LEN, 0);
+
+            let (cipher, nonce) = xchacha20_poly1305(key, &nonce);
+            cipher.encrypt_slice(&nonce, &[], &mut dst[start..]);
+        }
+        _ => {
+            dst.reserve(SEPARATE_HEADER_LEN + header.len() + data.len() + TAG_LEN);
+
+            let start = dst.len();
+            dst.put_slice(&separate_header);
+            dst.put_slice(header);
+            dst.put_slice(data);
+            dst.resize(dst.len() + TAG_LEN, 0);
+
+            let subkey = session_subkey(key, &separate_header[..8]);
+            let cipher = AeadCipher::new(method, &subkey);
+            cipher.encrypt(&separate_header[4..], &mut dst[start + SEPARATE_HEADER_LEN..]);
+
+            encrypt_separate_header(method, key, &mut dst[start..start + SEPARATE_HEADER_LEN]);
+        }
+    }
+}
+
+// Decrypt a packet, returns (session ID, packet ID, main header)
+fn decrypt_packet(method: CipherKind, key: &[u8], pkt: &[u8]) -> io::Result<(u64, u64, Vec<u8>)> {
+    let (separate_header, body) = match method {
+        CipherKind::CHACHA20_POLY1305 => {
+            if pkt.len() < XCHACHA20_NONCE_LEN + SEPARATE_HEADER_LEN + TAG_LEN {
+                return Err(io::Error::new(ErrorKind::InvalidData, "packet too short"));
+            }
+
+            let (nonce, m) = pkt.split_at(XCHACHA20_NONCE_LEN);
+            let mut m = m.to_vec();
+
+            let (cipher, nonce) = xchacha20_poly1305(key, nonce);
+            if !cipher.decrypt_slice(&nonce, &[], &mut m) {
+                return Err(io::Error::new(ErrorKind::Other, "invalid tag-in"));
+            }
+
+            m.truncate(m.len() - TAG_LEN);
+            let body = m.split_off(SEPARATE_HEADER_LEN);
+            (m, body)
+        }
+        _ => {
+            if pkt.len() < SEPARATE_HEADER_LEN + TAG_LEN {
+                return Err(io::Error::new(ErrorKind::InvalidData, "packet too short"));
+            }
+
+            let mut separate_header = pkt[..SEPARATE_HEADER_LEN].to_vec();
+            decrypt_separate_header(method, key, &mut separate_header);
+
+            let subkey = session_subkey(key, &separate_header[..8]);
+            let cipher = AeadCipher::new(method, &subkey);
+
+            let mut body = pkt[SEPARATE_HEADER_LEN..].to_vec();
+            if !cipher.decrypt(&separate_header[4..], &mut body) {
+                return Err(io::Error::new(ErrorKind::Other, "invalid tag-in"));
+            }
+            body.truncate(body.len() - TAG_LEN);
+
+            (separate_header, body)
+        }
+    };
+
+    let mut separate_header = &separate_header[..];
+    let session_id = separate_header.get_u64();
+    let packet_id = separate_header.get_u64();
+
+    Ok((session_id, packet_id, body))
+}
+
+fn encrypt_separate_header(method: CipherKind, key: &[u8], header: &mut [u8]) {
+    match method {
+        CipherKind::AES_128_GCM => Aes128::new(key).encrypt(header),
+        CipherKind::AES_256_GCM => Aes256::new(key).encrypt(header),
+        _ => unreachable!("{} doesn't encrypt separate header by block cipher", method),
+    }
+}
+
+fn decrypt_separate_header(method: CipherKind, key: &[u8], header: &mut [u8]) {
+    match method {
+        CipherKind::AES_128_GCM => Aes128::new(key).decrypt(header),
+        CipherKind::AES_256_GCM => Aes256::new(key).decrypt(header),
+        _ => unreachable!("{} doesn't encrypt separate header by block cipher", method),
+    }
+}
+
+// XChaCha20-Poly1305 with the 24 bytes `nonce`, returns the ChaCha20-Poly1305 cipher and its 12 bytes nonce
+fn xchacha20_poly1305(key: &[u8], nonce: &[u8]) -> (Chacha20Poly1305, [u8; 12]) {
+    let subkey = hchacha20(key, &nonce[..HCHACHA20_NONCE_LEN]);
+
+    let mut chacha20_nonce = [0u8; 12];
+    chacha20_nonce[4..].copy_from_slice(&nonce[HCHACHA20_NONCE_LEN..]);
+
+    (Chacha20Poly1305::new(&subkey), chacha20_nonce)
+}
+
+// HChaCha20, derives a subkey from a 32 bytes key and a 16 bytes nonce
+fn hchacha20(key: &[u8], nonce: &[u8]) -> [u8; 32] {
+    fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+        s[a] = s[a].wrapping_add(s[b]);
+        s[d] = (s[d] ^ s[a]).rotate_left(16);
+        s[c] = s[c].wrapping_add(s[d]);
+        s[b] = (s[b] ^ s[c]).rotate_left(12);
+        s[a] = s[a].wrapping_add(s[b]);
+        s[d] = (s[d] ^ s[a]).rotate_left(8);
+        s[c] = s[c].wrapping_add(s[d]);
+        s[b] = (s[b] ^ s[c]).rotate_left(7);
+    }
+
+    let mut input = [0u8; 48];
+    input[..32].copy_from_slice(key);
+    input[32..].copy_from_slice(nonce);
+
+    // "expand 32-byte k"
+    let mut s = [0u32; 16];
+    s[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    let mut input = &input[..];
+    for word in s[4..].iter_mut() {
+        *word = input.get_u32_le();
+    }
+
+    for _ in 0..10 {
+        quarter_round(&mut s, 0, 4, 8, 12);
+        quarter_round(&mut s, 1, 5, 9, 13);
+        quarter_round(&mut s, 2, 6, 10, 14);
+        quarter_round(&mut s, 3, 7, 11, 15);
+        quarter_round(&mut s, 0, 5, 10, 15);
+        quarter_round(&mut s, 1, 6, 11, 12);
+        quarter_round(&mut s, 2, 7, 8, 13);
+        quarter_round(&mut s, 3, 4, 9, 14);
+    }
+
+    let mut subkey = [0u8; 32];
+    let mut out = &mut subkey[..];
+    for &word in s[..4].iter().chain(s[12..].iter()) {
+        out.put_u32_le(word);
+    }
+    subkey
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use crate::relay::aead_2022::TEST_TIMESTAMP;
+
+    use super::*;
+
+    const METHOD: CipherKind = CipherKind::AES_128_GCM;
+    const KEY: [u8; 16] = [7u8; 16];
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn target() -> Address {
+        Address::SocketAddress("127.0.0.1:8080".parse::<SocketAddr>().unwrap())
+    }
+
+    fn target_and_data(data: &[u8]) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        target().write_to_buf(&mut buf);
+        buf.extend_from_slice(data);
+        buf.to_vec()
+    }
+
+    fn request(session: &UdpSession, data: &[u8]) -> BytesMut {
+        let mut pkt = BytesMut::new();
+        encrypt_request(METHOD, &KEY, session, &target(), data, &mut pkt);
+        pkt
+    }
+
+    fn server_session() -> UdpSession {
+        UdpSession::new_with_peers(Arc::new(SpinMutex::new(PeerSessions::new(16))))
+    }
+
+    #[test]
+    fn hchacha20_vector() {
+        // draft-irtf-cfrg-xchacha-03, section 2.2.1
+        let key = (0..32).collect::<Vec<u8>>();
+        let nonce = hex("000000090000004a0000000031415927");
+        assert_eq!(
+            hchacha20(&key, &nonce).to_vec(),
+            hex("82413b4227b27bfed30e42508a877d73a0f9e4d58a74a853c12ec41326d3ecdc")
+        );
+    }
+
+    #[test]
+    fn packet_window() {
+        let mut window = PacketWindow::new();
+        for id in 0..10 {
+            assert!(window.check_and_set(id));
+        }
+        for id in 0..10 {
+            assert!(!window.check_and_set(id));
+        }
+
+        // Packets out of order
+        assert!(window.check_and_set(20));
+        assert!(window.check_and_set(15));
+        assert!(!window.check_and_set(15));
+        assert!(!window.check_and_set(20));
+    }
+
+    #[test]
+    fn packet_window_slides() {
+        let mut window = PacketWindow::new();
+        assert!(window.check_and_set(1000));
+
+        let latest = 1000 + PACKET_WINDOW_SIZE - 1;
+        assert!(window.check_and_set(latest));
+        assert!(!window.check_and_set(1000));
+        assert!(!window.check_and_set(999));
+        assert!(window.check_and_set(1500));
+
+        // Slot of 1000 is reused by the next ID
+        assert!(window.check_and_set(latest + 1));
+        assert!(!window.check_and_set(latest + 1));
+        assert!(!window.check_and_set(1000));
+
+        // Jumping over the whole window
+        assert!(window.check_and_set(1 << 40));
+        assert!(!window.check_and_set(1 << 40));
+        assert!(window.check_and_set((1 << 40) - 1));
+        assert!(!window.check_and_set(latest));
+    }
+
+    #[test]
+    fn replayed_request() {
+        let peers = Arc::new(SpinMutex::new(PeerSessions::new(16)));
+        let client = UdpSession::new();
+        let server = UdpSession::new_with_peers(peers.clone());
+
+        let pkt = request(&client, b"hello");
+        assert_eq!(
+            decrypt_request(METHOD, &KEY, &server, &pkt).unwrap(),
+            target_and_data(b"hello")
+        );
+        assert!(decrypt_request(METHOD, &KEY, &server, &pkt).is_err());
+
+        // Replayed from another address, which is handled by another association
+        let another = UdpSession::new_with_peers(peers);
+        assert!(decrypt_request(METHOD, &KEY, &another, &pkt).is_err());
+
+        // Client's address has been changed
+        let pkt = request(&client, b"world");
+        assert_eq!(
+            decrypt_request(METHOD, &KEY, &another, &pkt).unwrap(),
+            target_and_data(b"world")
+        );
+    }
+
+    #[test]
+    fn replaced_session() {
+        let server = server_session();
+        let old = UdpSession::new();
+        let new = UdpSession::new();
+
+        let first = request(&old, b"hello");
+        let second = request(&old, b"hello");
+        assert!(decrypt_request(METHOD, &KEY, &server, &first).is_ok());
+
+        // Client has been restarted
+        assert!(decrypt_request(METHOD, &KEY, &server, &request(&new, b"hello")).is_ok());
+        assert_eq!(server.peer_session_id(), Some(new.session_id));
+
+        // Packets of the replaced session are rejected, even if they haven't been seen
+        assert!(decrypt_request(METHOD, &KEY, &server, &second).is_err());
+        assert!(decrypt_request(METHOD, &KEY, &server, &first).is_err());
+        assert_eq!(server.peer_session_id(), Some(new.session_id));
+
+        assert!(decrypt_request(METHOD, &KEY, &server, &request(&new, b"world")).is_ok());
+    }
+
+    #[test]
+    fn response() {
+        let client = UdpSession::new();
+        let server = server_session();
+
+        let mut pkt = BytesMut::new();
+        assert!(encrypt_response(METHOD, &KEY, &server, &target(), b"hello", &mut pkt).is_err());
+
+        decrypt_request(METHOD, &KEY, &server, &request(&client, b"hello")).unwrap();
+        encrypt_response(METHOD, &KEY, &server, &target(), b"hello", &mut pkt).unwrap();
+
+        assert!(decrypt_response(METHOD, &KEY, &UdpSession::new(), &pkt).is_err());
+        assert_eq!(
+            decrypt_response(METHOD, &KEY, &client, &pkt).unwrap(),
+            target_and_data(b"hello")
+        );
+        assert!(decrypt_response(METHOD, &KEY, &client, &pkt).is_err());
+    }
+
+    // Packets with session ID 0x0102030405060708, packet ID 42 and main header "shadowsocks 2022 udp body"
+    //
+    // PSKs are 0x00, 0x01, ... 0x0f (AES-128) or 0x1f (AES-256, ChaCha20), the XChaCha20 nonce is 0x40, ... 0x57
+    const VECTORS: &[(CipherKind, usize, &str)] = &[
+        (
+            CipherKind::AES_128_GCM,
+            16,
+            "390693e83195b05ac85ba159331516b5a6a8312eb9bdd5a9dbc073ef80bccfc4\
+             8d3de21276b12fc389b7c0ba7a59cce949b26c4e6c46abf6f9",
+        ),
+        (
+            CipherKind::AES_256_GCM,
+            32,
+            "b7dee5b35eb5fdd6b2fe2b7715e6ccce13eeeee15399a411e456a118b7792fc2\
+             44e562589919002a09203aaa82dbe0f7e7253e32666f93c062",
+        ),
+        (
+            CipherKind::CHACHA20_POLY1305,
+            32,
+            "404142434445464748494a4b4c4d4e4f5051525354555657d53b0674d5e67e1e\
+             8ff487beaf9c65b8e1d2cca07c2e20f5095a8e653b3411a2368c661e75f0eb74\
+             86b73f17a49d939b5348154f2356c121ba",
+        ),
+    ];
+
+    const VECTOR_SESSION_ID: u64 = 0x0102030405060708;
+    const VECTOR_PACKET_ID: u64 = 42;
+    const VECTOR_BODY: &[u8] = b"shadowsocks 2022 udp body";
+
+    #[test]
+    fn decrypt_vectors() {
+        for &(method, key_len, pkt) in VECTORS {
+            let key = (0..key_len as u8).collect::<Vec<_>>();
+
+            let (session_id, packet_id, body) = decrypt_packet(method, &key, &hex(pkt)).unwrap();
+            assert_eq!(session_id, VECTOR_SESSION_ID);
+            assert_eq!(packet_id, VECTOR_PACKET_ID);
+            assert_eq!(body, VECTOR_BODY);
+        }
+    }
+
+    #[test]
+    fn encrypt_vectors() {
+        // Nonces of XChaCha20-Poly1305 are random
+        for &(method, key_len, pkt) in &VECTORS[..2] {
+            let key = (0..key_len as u8).collect::<Vec<_>>();
+            let mut session = UdpSession::new();
+            session.session_id = VECTOR_SESSION_ID;
+            session.packet_id = AtomicU64::new(VECTOR_PACKET_ID);
+
+            let mut dst = BytesMut::new();
+            encrypt_packet(method, &key, &session, VECTOR_BODY, &[], &mut dst);
+            assert_eq!(&dst[..], &hex(pkt)[..]);
+        }
+    }
+
+    // Packets generated by `encrypt_client_payload` and `encrypt_server_payload` of the shadowsocks crate 1.25.0
+    // (crates.io), which is the reference implementation of SIP022 in upstream shadowsocks-rust, at
+    // `UPSTREAM_TIMESTAMP`
+    //
+    // PSKs are 0x00, 0x01, ... 0x0f (AES-128) or 0x1f (AES-256), packet IDs are 42. Requests carry
+    // `UPSTREAM_PAYLOAD` to `target()`, responses carry it from `target()`.
+    const UPSTREAM_VECTORS: &[(CipherKind, usize, &str, &str)] = &[
+        (
+            CipherKind::AES_128_GCM,
+            16,
+            "390693e83195b05ac85ba159331516b5d5c0504ad6a0741699ab00cecd8cfdf7\
+             b2d8f50a37b72fd0835943ce20f51d2d65bc55c8a291ad78d1061060e2a824b0\
+             55a5a453605427bddf4589009f8a84",
+            "988e5698a6ff68c7f4e1ca86c881fdab710e8bcbc14ed4aa2451e6e722b112bb\
+             545c59e38934dde2a0431c9436720de51560d446ced9ab90f522ea160111dd63\
+             7dfb78cfd480a0104bc660b6372a13ae17da9f26df4ab4",
+        ),
+        (
+            CipherKind::AES_256_GCM,
+            32,
+            "b7dee5b35eb5fdd6b2fe2b7715e6ccce60868f853c8405aea63dd239fa491df1\
+             7b007540d81f00390391dcb4663ef4a43883991fa98dcbf179fb141b098c2565\
+             a68c3e230076a69319ed8b579442b7",
+            "424deb20cae629681a08ea56355d09d998067e2805d219c6a92c52796a386f3f\
+             7201cec9158e5e5e1d2ded045903f7d6df1879117e7950dcfa4df93f7842762a\
+             a996b292845eaabe53a2474d497349f0ea9ead77ff0dc6",
+        ),
+    ];
+
+    const UPSTREAM_TIMESTAMP: u64 = 1792200737;
+    const UPSTREAM_CLIENT_SESSION_ID: u64 = 0x0102030405060708;
+    const UPSTREAM_SERVER_SESSION_ID: u64 = 0x1112131415161718;
+    const UPSTREAM_PACKET_ID: u64 = 42;
+    const UPSTREAM_PAYLOAD: &[u8] = b"shadowsocks 2022 known answer";
+
+    #[test]
+    fn upstream_vectors() {
+        TEST_TIMESTAMP.with(|t| t.set(Some(UPSTREAM_TIMESTAMP)));
+
+        for &(method, key_len, request, response) in UPSTREAM_VECTORS {
+            let key = (0..key_len as u8).collect::<Vec<_>>();
+
+            let mut client = UdpSession::new();
+            client.session_id = UPSTREAM_CLIENT_SESSION_ID;
+            client.packet_id = AtomicU64::new(UPSTREAM_PACKET_ID);
+            let mut server = server_session();
+            server.session_id = UPSTREAM_SERVER_SESSION_ID;
+            server.packet_id = AtomicU64::new(UPSTREAM_PACKET_ID);
+
+            let mut pkt = BytesMut::new();
+            encrypt_request(method, &key, &client, &target(), UPSTREAM_PAYLOAD, &mut pkt);
+            assert_eq!(&pkt[..], &hex(request)[..]);
+            assert_eq!(
+                decrypt_request(method, &key, &server, &hex(request)).unwrap(),
+                target_and_data(UPSTREAM_PAYLOAD)
+            );
+
+            let mut pkt = BytesMut::new();
+            encrypt_response(method, &key, &server, &target(), UPSTREAM_PAYLOAD, &mut pkt).unwrap();
+            assert_eq!(&pkt[..], &hex(response)[..]);
+            assert_eq!(
+                decrypt_response(method, &key, &client, &hex(response)).unwrap(),
+                target_and_data(UPSTREAM_PAYLOAD)
+            );
+        }
+    }
+}
@@ -5,8 +5,9 @@
 #![allow(dead_code)]
 
 use std::{
+    collections::HashMap,
     future::Future,
-    io::{self, Cursor, Read},
+    io::{self, Cursor, ErrorKind, Read},
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     sync::Arc,
     time::Duration,
@@ -15,7 +16,7 @@ use std::{
 use async_trait::async_trait;
 use bytes::BytesMut;
 use futures::future::{self, AbortHandle};
-use log::{debug, error, trace, warn};
+use log::{debug, error, info, trace, warn};
 use lru_time_cache::{Entry, LruCache};
 use spin::Mutex as SyncMutex;
 use tokio::{
@@ -25,13 +26,17 @@ use tokio::{
     time,
 };
 
+#[cfg(feature = "metrics")]
+use crate::relay::metrics::GaugeGuard;
 use crate::{
+    acl::{Protocol, Route},
     config::{Config, ServerAddr, ServerConfig},
     context::{Context, SharedContext},
     crypto::v1::CipherCategory,
     relay::{
+        control::ConnectionGuard,
         flow::SharedServerFlowStatistic,
-        loadbalancing::server::{ServerData, SharedServerStatistic},
+        loadbalancing::server::{PingBalancer, ServerData, SharedServerStatistic},
         socks5::Address,
         sys::create_outbound_udp_socket,
         utils::try_timeout,
@@ -39,7 +44,7 @@ use crate::{
 };
 
 use super::{
-    crypto_io::{decrypt_payload, encrypt_payload},
+    crypto_io::{decrypt_payload, encrypt_payload, Aead2022Session},
     DEFAULT_TIMEOUT,
     MAXIMUM_UDP_PAYLOAD_SIZE,
 };
@@ -49,21 +54,57 @@ pub trait ProxySend {
     async fn send_packet(&mut self, addr: Address, data: Vec<u8>) -> io::Result<()>;
 }
 
+/// Socket connected to the server of a proxied association
+struct ProxiedSocket {
+    socket: UdpSocket,
+    // Session of AEAD-2022 methods, empty for the other methods
+    session: Aead2022Session,
+}
+
+impl ProxiedSocket {
+    fn new(socket: UdpSocket, svr_cfg: &ServerConfig) -> ProxiedSocket {
+        ProxiedSocket {
+            socket,
+            session: Aead2022Session::client(svr_cfg),
+        }
+    }
+}
+
+/// Refuses new associations while draining connections for shutting down
+fn check_draining(context: &Context) -> io::Result<()> {
+    if context.connections().is_draining() {
+        let err = io::Error::new(ErrorKind::Other, "shutting down, new UDP association refused");
+        return Err(err);
+    }
+    Ok(())
+}
+
 struct ProxyTaskWatchers {
-    proxied_watcher: SyncMutex<Option<AbortHandle>>,
+    // Keyed by the group of servers, associations with ACL have one proxied task for each group
+    proxied_watchers: SyncMutex<HashMap<Option<String>, AbortHandle>>,
     bypassed_watcher: SyncMutex<Option<AbortHandle>>,
+    // Waited for when shutting down
+    _connection_guard: ConnectionGuard,
+    #[cfg(feature = "metrics")]
+    _assoc_guard: GaugeGuard,
 }
 
 impl ProxyTaskWatchers {
-    fn new(pw: Option<AbortHandle>, bw: Option<AbortHandle>) -> ProxyTaskWatchers {
+    fn new(context: &Context, bw: Option<AbortHandle>) -> ProxyTaskWatchers {
         ProxyTaskWatchers {
-            proxied_watcher: SyncMutex::new(pw),
+            proxied_watchers: SyncMutex::new(HashMap::new()),
             bypassed_watcher: SyncMutex::new(bw),
+            _connection_guard: context.connections().track(),
+            #[cfg(feature = "metrics")]
+            _assoc_guard: GaugeGuard::udp_association(context.metrics()),
         }
     }
 
-    fn set_proxied_watcher(&self, h: AbortHandle) {
-        *self.proxied_watcher.lock() = Some(h);
+    fn set_proxied_watcher(&self, group: Option<&str>, h: AbortHandle) {
+        // Replaced when rebinding to another server in the same group
+        if let Some(old) = self.proxied_watchers.lock().insert(group.map(str::to_owned), h) {
+            old.abort();
+        }
     }
 
     fn set_bypassed_watcher(&self, h: AbortHandle) {
@@ -79,7 +120,7 @@ pub struct ProxyAssociation {
 
 impl Drop for ProxyAssociation {
     fn drop(&mut self) {
-        if let Some(ref h) = *self.watchers.proxied_watcher.lock() {
+        for h in self.watchers.proxied_watchers.lock().values() {
             h.abort();
         }
 
@@ -90,33 +131,44 @@ impl Drop for ProxyAssociation {
 }
 
 impl ProxyAssociation {
-    fn create(
-        pw: Option<AbortHandle>,
-        bw: Option<AbortHandle>,
-    ) -> (ProxyAssociation, mpsc::Receiver<(Address, Vec<u8>)>) {
+    fn create(context: &Context, bw: Option<AbortHandle>) -> (ProxyAssociation, mpsc::Receiver<(Address, Vec<u8>)>) {
         // Create a channel for sending packets to remote
         // FIXME: Channel size 1024?
         let (tx, rx) = mpsc::channel::<(Address, Vec<u8>)>(1024);
-        let watchers = Arc::new(ProxyTaskWatchers::new(pw, bw));
+        let watchers = Arc::new(ProxyTaskWatchers::new(context, bw));
 
         (ProxyAssociation { tx, watchers }, rx)
     }
 
     pub async fn associate_proxied<S, H>(
         src_addr: SocketAddr,
+        balancer: PingBalancer<S>,
         server: SharedServerStatistic<S>,
         sender: H,
     ) -> io::Result<ProxyAssociation>
     where
         S: ServerData + Send + 'static,
-        H: ProxySend + Send + 'static,
+        H: ProxySend + Clone + Send + 'static,
     {
-        let (remote_sender, remote_watcher) = Self::create_associate_proxied(src_addr, server.clone(), sender).await?;
-        let (assoc, rx) = ProxyAssociation::create(Some(remote_watcher), None);
+        check_draining(server.context())?;
+
+        let (server, remote_sender, remote_watcher) =
+            Self::create_associate_proxied_with_failover(src_addr, &balancer, server, Vec::new(), sender.clone())
+                .await?;
+        let (assoc, rx) = ProxyAssociation::create(server.context(), None);
+        assoc.watchers.set_proxied_watcher(server.group(), remote_watcher);
 
         // LOCAL -> REMOTE task
         // All packets will be sent directly to proxy
-        tokio::spawn(Self::l2r_packet_proxied(src_addr, server.clone(), rx, remote_sender));
+        tokio::spawn(Self::l2r_packet_proxied(
+            src_addr,
+            balancer,
+            server,
+            rx,
+            remote_sender,
+            sender,
+            assoc.watchers.clone(),
+        ));
 
         Ok(assoc)
     }
@@ -125,7 +177,7 @@ impl ProxyAssociation {
         src_addr: SocketAddr,
         server: SharedServerStatistic<S>,
         sender: H,
-    ) -> io::Result<(Arc<UdpSocket>, AbortHandle)>
+    ) -> io::Result<(Arc<ProxiedSocket>, AbortHandle)>
     where
         S: ServerData + Send + 'static,
         H: ProxySend + Send + 'static,
@@ -155,15 +207,160 @@ impl ProxyAssociation {
         );
 
         // Splits socket into sender and receiver
-        let remote_receiver = Arc::new(remote_udp);
+        let remote_receiver = Arc::new(ProxiedSocket::new(remote_udp, server.server_config()));
         let remote_sender = remote_receiver.clone();
 
         // LOCAL <- REMOTE task
-        let remote_watcher = Self::r2l_packet_abortable(src_addr, server, sender, remote_receiver, false);
+        let remote_watcher = Self::r2l_packet_proxied_abortable(src_addr, server, sender, remote_receiver);
 
         Ok((remote_sender, remote_watcher))
     }
 
+    /// Create a proxied association, retrying with other servers in `balancer` if failed with `server`
+    ///
+    /// Servers in `tried` are excluded, they are counted as retries
+    async fn create_associate_proxied_with_failover<S, H>(
+        src_addr: SocketAddr,
+        balancer: &PingBalancer<S>,
+        mut server: SharedServerStatistic<S>,
+        mut tried: Vec<SharedServerStatistic<S>>,
+        sender: H,
+    ) -> io::Result<(SharedServerStatistic<S>, Arc<ProxiedSocket>, AbortHandle)>
+    where
+        S: ServerData + Send + 'static,
+        H: ProxySend + Clone + Send + 'static,
+    {
+        let retries = server.config().failover_retries;
+
+        loop {
+            let err = match Self::create_associate_proxied(src_addr, server.clone(), sender.clone()).await {
+                Ok((remote_sender, remote_watcher)) => return Ok((server, remote_sender, remote_watcher)),
+                Err(err) => err,
+            };
+
+            // Report to global statistic
+            server.report_failure().await;
+
+            if tried.len() >= retries {
+                return Err(err);
+            }
+
+            tried.push(server);
+
+            let next = match balancer.pick_failover_server(&tried) {
+                Some(s) => s,
+                None => return Err(err),
+            };
+
+            warn!(
+                "failed to create UDP association {} via {}, retrying via {}, error: {}",
+                src_addr,
+                tried[tried.len() - 1].server_config().addr(),
+                next.server_config().addr(),
+                err
+            );
+
+            server = next;
+        }
+    }
+
+    /// Rebind a proxied association to another server in `balancer` after failed to send packets via `server`
+    async fn rebind_associate_proxied<S, H>(
+        src_addr: SocketAddr,
+        balancer: &PingBalancer<S>,
+        server: &mut SharedServerStatistic<S>,
+        remote_sender: &mut Arc<ProxiedSocket>,
+        sender: H,
+        watchers: &ProxyTaskWatchers,
+    ) -> io::Result<()>
+    where
+        S: ServerData + Send + 'static,
+        H: ProxySend + Clone + Send + 'static,
+    {
+        // Report to global statistic
+        server.report_failure().await;
+
+        if server.config().failover_retries == 0 {
+            return Err(io::Error::new(ErrorKind::Other, "failover is disabled"));
+        }
+
+        let tried = vec![server.clone()];
+        let next = match balancer.pick_failover_server(&tried) {
+            Some(s) => s,
+            None => return Err(io::Error::new(ErrorKind::Other, "no other server to failover")),
+        };
+
+        warn!(
+            "UDP association {} rebinding from {} to {}",
+            src_addr,
+            server.server_config().addr(),
+            next.server_config().addr()
+        );
+
+        Self::switch_associate_proxied(src_addr, balancer, server, next, remote_sender, sender, watchers).await
+    }
+
+    /// Rebind a proxied association to the server that `balancer` prefers for `target`
+    ///
+    /// Keeps `server` if it is still preferred, or failover is disabled
+    async fn rebind_associate_proxied_if_replaced<S, H>(
+        src_addr: SocketAddr,
+        balancer: &PingBalancer<S>,
+        server: &mut SharedServerStatistic<S>,
+        remote_sender: &mut Arc<ProxiedSocket>,
+        sender: H,
+        watchers: &ProxyTaskWatchers,
+        target: &Address,
+    ) -> io::Result<()>
+    where
+        S: ServerData + Send + 'static,
+        H: ProxySend + Clone + Send + 'static,
+    {
+        if server.config().failover_retries == 0 {
+            return Ok(());
+        }
+
+        let next = match balancer.pick_replacement(server, target) {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+
+        info!(
+            "UDP association {} rebinding from {} to {}, which is preferred by load balancer",
+            src_addr,
+            server.server_config().addr(),
+            next.server_config().addr()
+        );
+
+        Self::switch_associate_proxied(src_addr, balancer, server, next, remote_sender, sender, watchers).await
+    }
+
+    /// Replace `server` of a proxied association with `next`, or the other servers if failed
+    async fn switch_associate_proxied<S, H>(
+        src_addr: SocketAddr,
+        balancer: &PingBalancer<S>,
+        server: &mut SharedServerStatistic<S>,
+        next: SharedServerStatistic<S>,
+        remote_sender: &mut Arc<ProxiedSocket>,
+        sender: H,
+        watchers: &ProxyTaskWatchers,
+    ) -> io::Result<()>
+    where
+        S: ServerData + Send + 'static,
+        H: ProxySend + Clone + Send + 'static,
+    {
+        let tried = vec![server.clone()];
+        let (next, next_sender, next_watcher) =
+            Self::create_associate_proxied_with_failover(src_addr, balancer, next, tried, sender).await?;
+
+        // Old LOCAL <- REMOTE task is aborted
+        watchers.set_proxied_watcher(next.group(), next_watcher);
+        *server = next;
+        *remote_sender = next_sender;
+
+        Ok(())
+    }
+
     pub async fn associate_bypassed<S, H>(
         src_addr: SocketAddr,
         server: SharedServerStatistic<S>,
@@ -173,8 +370,10 @@ impl ProxyAssociation {
         S: ServerData + Send + 'static,
         H: ProxySend + Send + 'static,
     {
+        check_draining(server.context())?;
+
         let (remote_sender, remote_watcher) = Self::create_associate_bypassed(src_addr, server.clone(), sender).await?;
-        let (assoc, rx) = ProxyAssociation::create(None, Some(remote_watcher));
+        let (assoc, rx) = ProxyAssociation::create(server.context(), Some(remote_watcher));
 
         // LOCAL -> REMOTE task
         // All packets will be sent directly to proxy
@@ -208,13 +407,14 @@ impl ProxyAssociation {
         let remote_sender = remote_receiver.clone();
 
         // REMOTE <- LOCAL task
-        let remote_watcher = Self::r2l_packet_abortable(src_addr, server, sender, remote_receiver, true);
+        let remote_watcher = Self::r2l_packet_bypassed_abortable(src_addr, server, sender, remote_receiver);
 
         Ok((remote_sender, remote_watcher))
     }
 
     pub async fn associate_with_acl<S, H>(
         src_addr: SocketAddr,
+        balancer: PingBalancer<S>,
         server: SharedServerStatistic<S>,
         sender: H,
     ) -> io::Result<ProxyAssociation>
@@ -224,17 +424,19 @@ impl ProxyAssociation {
     {
         // Proxies everything if there is no ACL configured.
         if server.context().acl().is_none() {
-            return ProxyAssociation::associate_proxied(src_addr, server, sender).await;
+            return ProxyAssociation::associate_proxied(src_addr, balancer, server, sender).await;
         }
 
-        let (assoc, rx) = ProxyAssociation::create(None, None);
+        check_draining(server.context())?;
+
+        let (assoc, rx) = ProxyAssociation::create(server.context(), None);
 
         // LOCAL -> REMOTE task
         // Packets may be sent via proxy decided by acl rules
 
         {
             let assoc = assoc.clone();
-            tokio::spawn(async move { assoc.l2r_packet_acl(src_addr, server, rx, sender).await });
+            tokio::spawn(async move { assoc.l2r_packet_acl(src_addr, balancer, server, rx, sender).await });
         }
 
         Ok(assoc)
@@ -279,71 +481,115 @@ impl ProxyAssociation {
     async fn l2r_packet_acl<S, H>(
         &self,
         src_addr: SocketAddr,
+        balancer: PingBalancer<S>,
         server: SharedServerStatistic<S>,
         mut rx: mpsc::Receiver<(Address, Vec<u8>)>,
-        sender: H,
+        mut sender: H,
     ) where
         S: ServerData + Send + 'static,
         H: ProxySend + Clone + Send + 'static,
     {
-        let context = server.context();
-        let svr_cfg = server.server_config();
+        let context = server.clone_context();
 
         let mut bypass_sender_opt = None;
-        let mut remote_sender_opt = None;
+        // Proxied associations, one for each group of servers that packets are routed to
+        let mut remote_senders = HashMap::new();
 
         while let Some((addr, payload)) = rx.recv().await {
             // Check if addr should be bypassed
             //
-            // Bypassed and Proxied are 2 separated associations, will be created dynamically.
-            let is_bypassed = context.check_target_bypassed(&addr).await;
-
-            let res = if is_bypassed {
-                if bypass_sender_opt.is_none() {
-                    let server = server.clone();
-                    let sender = sender.clone();
-
-                    let bypass_sender = match Self::create_associate_bypassed(src_addr, server, sender).await {
-                        Ok((bypass_sender, bypass_watcher)) => {
-                            self.watchers.set_bypassed_watcher(bypass_watcher);
-                            bypass_sender
-                        }
-                        Err(err) => {
-                            error!(
-                                "creating UDP association from {} (bypassed) failed, err: {}",
-                                src_addr, err
-                            );
-                            continue;
-                        }
-                    };
-                    bypass_sender_opt = Some(bypass_sender);
+            // Bypassed and Proxied are separated associations, will be created dynamically.
+            let group = match context.route_target(&addr, Protocol::Udp).await {
+                Route::Bypass => None,
+                Route::Proxy(group) => Some(group),
+                Route::Reject => {
+                    trace!("UDP association {} -> {} rejected by ACL", src_addr, addr);
+                    continue;
                 }
+            };
+            let is_bypassed = group.is_none();
 
-                let bypass_sender = bypass_sender_opt.as_mut().unwrap();
-                Self::send_packet_bypassed(src_addr, context, &addr, &payload, bypass_sender).await
-            } else {
-                if remote_sender_opt.is_none() {
-                    let server = server.clone();
-                    let sender = sender.clone();
-
-                    let remote_sender = match Self::create_associate_proxied(src_addr, server, sender).await {
-                        Ok((remote_sender, remote_watcher)) => {
-                            self.watchers.set_proxied_watcher(remote_watcher);
-                            remote_sender
-                        }
-                        Err(err) => {
-                            debug!(
-                                "creating UDP association from {} (proxied) failed, err: {}",
-                                src_addr, err
-                            );
-                            continue;
-                        }
-                    };
-                    remote_sender_opt = Some(remote_sender);
+            let res = match group {
+                None => {
+                    if bypass_sender_opt.is_none() {
+                        let server = server.clone();
+                        let sender = sender.clone();
+
+                        let bypass_sender = match Self::create_associate_bypassed(src_addr, server, sender).await {
+                            Ok((bypass_sender, bypass_watcher)) => {
+                                self.watchers.set_bypassed_watcher(bypass_watcher);
+                                bypass_sender
+                            }
+                            Err(err) => {
+                                error!(
+                                    "creating UDP association from {} (bypassed) failed, err: {}",
+                                    src_addr, err
+                                );
+                                continue;
+                            }
+                        };
+                        bypass_sender_opt = Some(bypass_sender);
+                    }
+
+                    let bypass_sender = bypass_sender_opt.as_mut().unwrap();
+                    Self::send_packet_bypassed(src_addr, &context, &addr, &payload, bypass_sender).await
                 }
+                Some(group) => {
+                    if !remote_senders.contains_key(&group) {
+                        let sender = sender.clone();
+
+                        // Each proxied association is bound to one server in its group, failover servers are
+                        // chosen from the same group
+                        let first_server = match group {
+                            Some(ref group) => match balancer.pick_server_in_group(group, &addr) {
+                                Some(s) => s,
+                                None => {
+                                    error!(
+                                        "UDP association {} -> {} routed to group {} without servers",
+                                        src_addr, addr, group
+                                    );
+                                    continue;
+                                }
+                            },
+                            None => server.clone(),
+                        };
+
+                        let res = Self::create_associate_proxied_with_failover(
+                            src_addr,
+                            &balancer,
+                            first_server,
+                            Vec::new(),
+                            sender,
+                        )
+                        .await;
+                        match res {
+                            Ok((chosen_server, remote_sender, remote_watcher)) => {
+                                self.watchers.set_proxied_watcher(chosen_server.group(), remote_watcher);
+                                remote_senders.insert(group.clone(), (chosen_server, remote_sender));
+                            }
+                            Err(err) => {
+                                debug!(
+                                    "creating UDP association from {} (proxied) failed, err: {}",
+                                    src_addr, err
+                                );
+                                continue;
+                            }
+                        }
+                    }
 
-                let remote_sender = remote_sender_opt.as_mut().unwrap();
-                Self::send_packet_proxied(src_addr, context, svr_cfg, &addr, &payload, remote_sender).await
+                    let (server, remote_sender) = remote_senders.get_mut(&group).unwrap();
+                    Self::send_packet_proxied_with_failover(
+                        src_addr,
+                        &balancer,
+                        server,
+                        remote_sender,
+                        &mut sender,
+                        &self.watchers,
+                        &addr,
+                        &payload,
+                    )
+                    .await
+                }
             };
 
             if let Err(err) = res {
@@ -357,19 +603,30 @@ impl ProxyAssociation {
         debug!("UDP association {} -> .. task is closing", src_addr);
     }
 
-    async fn l2r_packet_proxied<S>(
+    async fn l2r_packet_proxied<S, H>(
         src_addr: SocketAddr,
-        server: SharedServerStatistic<S>,
+        balancer: PingBalancer<S>,
+        mut server: SharedServerStatistic<S>,
         mut rx: mpsc::Receiver<(Address, Vec<u8>)>,
-        remote_sender: Arc<UdpSocket>,
+        mut remote_sender: Arc<ProxiedSocket>,
+        mut sender: H,
+        watchers: Arc<ProxyTaskWatchers>,
     ) where
         S: ServerData + Send + 'static,
+        H: ProxySend + Clone + Send + 'static,
     {
-        let context = server.context();
-        let svr_cfg = server.server_config();
-
         while let Some((addr, payload)) = rx.recv().await {
-            let res = Self::send_packet_proxied(src_addr, context, svr_cfg, &addr, &payload, &remote_sender).await;
+            let res = Self::send_packet_proxied_with_failover(
+                src_addr,
+                &balancer,
+                &mut server,
+                &mut remote_sender,
+                &mut sender,
+                &watchers,
+                &addr,
+                &payload,
+            )
+            .await;
 
             if let Err(err) = res {
                 error!(
@@ -406,28 +663,114 @@ impl ProxyAssociation {
         debug!("UDP association (bypassed) {} -> .. task is closing", src_addr);
     }
 
+    /// Send a packet via `server`, rebinds to another server and resends if failed
+    ///
+    /// Rebinds before sending if `server` is down, or another server is preferred by `balancer`
+    #[allow(clippy::too_many_arguments)]
+    async fn send_packet_proxied_with_failover<S, H>(
+        src_addr: SocketAddr,
+        balancer: &PingBalancer<S>,
+        server: &mut SharedServerStatistic<S>,
+        remote_sender: &mut Arc<ProxiedSocket>,
+        sender: &mut H,
+        watchers: &ProxyTaskWatchers,
+        target: &Address,
+        payload: &[u8],
+    ) -> io::Result<()>
+    where
+        S: ServerData + Send + 'static,
+        H: ProxySend + Clone + Send + 'static,
+    {
+        if let Err(err) = Self::rebind_associate_proxied_if_replaced(
+            src_addr,
+            balancer,
+            server,
+            remote_sender,
+            sender.clone(),
+            watchers,
+            target,
+        )
+        .await
+        {
+            // Keeps using the current server
+            debug!(
+                "UDP association {} failed to rebind from {}, error: {}",
+                src_addr,
+                server.server_config().addr(),
+                err
+            );
+        }
+
+        let err = match Self::send_packet_proxied(
+            src_addr,
+            server.context(),
+            server.server_config(),
+            target,
+            payload,
+            remote_sender,
+        )
+        .await
+        {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+
+        debug!(
+            "UDP association {} -> {} (proxied) failed via {}, error: {}",
+            src_addr,
+            target,
+            server.server_config().addr(),
+            err
+        );
+
+        if Self::rebind_associate_proxied(src_addr, balancer, server, remote_sender, sender.clone(), watchers)
+            .await
+            .is_err()
+        {
+            return Err(err);
+        }
+
+        Self::send_packet_proxied(
+            src_addr,
+            server.context(),
+            server.server_config(),
+            target,
+            payload,
+            remote_sender,
+        )
+        .await
+    }
+
     async fn send_packet_proxied(
         src_addr: SocketAddr,
         context: &Context,
         svr_cfg: &ServerConfig,
         target: &Address,
         payload: &[u8],
-        socket: &UdpSocket,
+        socket: &ProxiedSocket,
     ) -> io::Result<()> {
-        // CLIENT -> SERVER protocol: ADDRESS + PAYLOAD
-        let mut send_buf = Vec::with_capacity(target.serialized_len() + payload.len());
-        target.write_to_buf(&mut send_buf);
-        send_buf.extend_from_slice(payload);
-
-        let (send_len, expected_len) = if let CipherCategory::None = svr_cfg.method().category() {
-            let send_len = socket.send(&send_buf).await?;
-            (send_len, send_buf.len())
+        let encrypted = socket
+            .session
+            .encrypt_request(svr_cfg.method(), svr_cfg.key(), target, payload);
+        let (send_len, expected_len) = if let Some(encrypt_buf) = encrypted {
+            let send_len = socket.socket.send(&encrypt_buf).await?;
+            (send_len, encrypt_buf.len())
         } else {
-            let mut encrypt_buf = BytesMut::new();
-            encrypt_payload(context, svr_cfg.method(), svr_cfg.key(), &send_buf, &mut encrypt_buf);
+            // CLIENT -> SERVER protocol: ADDRESS + PAYLOAD
+            let mut send_buf = Vec::with_capacity(target.serialized_len() + payload.len());
+            target.write_to_buf(&mut send_buf);
+            send_buf.extend_from_slice(payload);
+
+            if let CipherCategory::None = svr_cfg.method().category() {
+                let send_len = socket.socket.send(&send_buf).await?;
+                (send_len, send_buf.len())
+            } else {
+                let mut encrypt_buf = BytesMut::new();
+                encrypt_payload(context, svr_cfg.method(), svr_cfg.key(), &send_buf, &mut encrypt_buf);
 
-            let send_len = socket.send(&encrypt_buf).await?;
-            (send_len, encrypt_buf.len())
+                let send_len = socket.socket.send(&encrypt_buf).await?;
+                (send_len, encrypt_buf.len())
+            }
         };
 
         if expected_len != send_len {
@@ -453,6 +796,11 @@ impl ProxyAssociation {
             context.local_flow_statistic().udp().incr_tx(send_len);
         }
 
+        #[cfg(feature = "metrics")]
+        if let Some(flow_stat) = context.metrics().local_server_flow(svr_cfg) {
+            flow_stat.udp().incr_tx(send_len);
+        }
+
         Ok(())
     }
 
@@ -492,45 +840,53 @@ impl ProxyAssociation {
         Ok(())
     }
 
-    fn r2l_packet_abortable<S, H>(
+    fn r2l_packet_proxied_abortable<S, H>(
         src_addr: SocketAddr,
         server: SharedServerStatistic<S>,
         sender: H,
-        socket: Arc<UdpSocket>,
-        is_bypassed: bool,
+        socket: Arc<ProxiedSocket>,
     ) -> AbortHandle
     where
         S: ServerData + Send + 'static,
         H: ProxySend + Send + 'static,
     {
-        if is_bypassed {
-            let relay_fut = Self::r2l_packet_bypassed(src_addr, server, sender, socket);
-            let (relay_task, relay_watcher) = future::abortable(relay_fut);
+        let relay_fut = Self::r2l_packet_proxied(src_addr, server, sender, socket);
+        let (relay_task, relay_watcher) = future::abortable(relay_fut);
 
-            tokio::spawn(async move {
-                let _ = relay_task.await;
-                debug!("UDP association (bypassed) {} <- .. task is closing", src_addr);
-            });
+        tokio::spawn(async move {
+            let _ = relay_task.await;
+            debug!("UDP association (proxied) {} <- .. task is closing", src_addr);
+        });
 
-            relay_watcher
-        } else {
-            let relay_fut = Self::r2l_packet_proxied(src_addr, server, sender, socket);
-            let (relay_task, relay_watcher) = future::abortable(relay_fut);
+        relay_watcher
+    }
 
-            tokio::spawn(async move {
-                let _ = relay_task.await;
-                debug!("UDP association (proxied) {} <- .. task is closing", src_addr);
-            });
+    fn r2l_packet_bypassed_abortable<S, H>(
+        src_addr: SocketAddr,
+        server: SharedServerStatistic<S>,
+        sender: H,
+        socket: Arc<UdpSocket>,
+    ) -> AbortHandle
+    where
+        S: ServerData + Send + 'static,
+        H: ProxySend + Send + 'static,
+    {
+        let relay_fut = Self::r2l_packet_bypassed(src_addr, server, sender, socket);
+        let (relay_task, relay_watcher) = future::abortable(relay_fut);
 
-            relay_watcher
-        }
+        tokio::spawn(async move {
+            let _ = relay_task.await;
+            debug!("UDP association (bypassed) {} <- .. task is closing", src_addr);
+        });
+
+        relay_watcher
     }
 
     async fn r2l_packet_proxied<S, H>(
         src_addr: SocketAddr,
         server: SharedServerStatistic<S>,
         mut sender: H,
-        socket: Arc<UdpSocket>,
+        socket: Arc<ProxiedSocket>,
     ) where
         S: ServerData + Send + 'static,
         H: ProxySend + Send + 'static,
@@ -561,15 +917,20 @@ impl ProxyAssociation {
     async fn recv_packet_proxied(
         context: &Context,
         svr_cfg: &ServerConfig,
-        socket: &UdpSocket,
+        socket: &ProxiedSocket,
     ) -> io::Result<(Address, Vec<u8>)> {
         // Waiting for response from server SERVER -> CLIENT
         // Packet length is limited by MAXIMUM_UDP_PAYLOAD_SIZE, excess bytes will be discarded.
         let mut recv_buf = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
 
-        let recv_n = socket.recv(&mut recv_buf).await?;
+        let recv_n = socket.socket.recv(&mut recv_buf).await?;
 
-        let mut cur = if let CipherCategory::None = svr_cfg.method().category() {
+        let decrypted = socket
+            .session
+            .decrypt_response(svr_cfg.method(), svr_cfg.key(), &recv_buf[..recv_n]);
+        let mut cur = if let Some(decrypt_buf) = decrypted {
+            Cursor::new(decrypt_buf?)
+        } else if let CipherCategory::None = svr_cfg.method().category() {
             recv_buf.truncate(recv_n);
             Cursor::new(recv_buf)
         } else {
@@ -596,6 +957,11 @@ impl ProxyAssociation {
             context.local_flow_statistic().udp().incr_rx(recv_n);
         }
 
+        #[cfg(feature = "metrics")]
+        if let Some(flow_stat) = context.metrics().local_server_flow(svr_cfg) {
+            flow_stat.udp().incr_rx(recv_n);
+        }
+
         Ok((addr, payload))
     }
 
@@ -755,7 +1121,9 @@ where
 pub type ProxyAssociationManager<K> = AssociationManager<K, ProxyAssociation>;
 
 /// Server Association's Key type
-pub type ServerAssociationKey = [u8; 18];
+///
+/// Client's address and the index of the user, users sharing one port may be behind the same NAT address
+pub type ServerAssociationKey = ([u8; 18], usize);
 
 /// Handler for handing remote response
 pub struct ServerProxyHandler {
@@ -770,13 +1138,14 @@ impl ServerProxyHandler {
     /// Create a new ServerProxyHandler
     pub fn new(
         src_addr: SocketAddr,
+        svr_idx: usize,
         assoc_manager: ServerAssociationManager<ServerAssociationKey>,
         flow_stat: SharedServerFlowStatistic,
         tx: Arc<UdpSocket>,
     ) -> ServerProxyHandler {
         ServerProxyHandler {
             src_addr,
-            cache_key: ServerProxyHandler::association_key(&src_addr),
+            cache_key: ServerProxyHandler::association_key(&src_addr, svr_idx),
             assoc_manager,
             flow_stat,
             tx,
@@ -784,14 +1153,14 @@ impl ServerProxyHandler {
     }
 
     /// Make server association key
-    pub fn association_key(saddr: &SocketAddr) -> ServerAssociationKey {
+    pub fn association_key(saddr: &SocketAddr, svr_idx: usize) -> ServerAssociationKey {
         let mut result = [0; 18];
         result[..16].copy_from_slice(&match saddr.ip() {
             IpAddr::V4(ref ip) => ip.to_ipv6_mapped().octets(),
             IpAddr::V6(ref ip) => ip.octets(),
         });
         result[16..].copy_from_slice(&saddr.port().to_ne_bytes());
-        result
+        (result, svr_idx)
     }
 
     /// Send packet back to source client
@@ -805,6 +1174,17 @@ impl ServerProxyHandler {
             return Ok(());
         }
 
+        if let Some(limiter) = self.flow_stat.limiter() {
+            if !limiter.check_download(pkt.len()) {
+                trace!(
+                    "UDP association {} <- ... dropped packet {} bytes by traffic limits",
+                    self.src_addr,
+                    pkt.len()
+                );
+                return Ok(());
+            }
+        }
+
         let n = match self.tx.send_to(&pkt, &self.src_addr).await {
             Ok(n) => {
                 if n < pkt.len() {
@@ -834,6 +1214,12 @@ pub struct ServerAssociation {
 
     // local <- remote task life watcher
     watcher: AbortHandle,
+
+    // Waited for when shutting down
+    _connection_guard: ConnectionGuard,
+
+    #[cfg(feature = "metrics")]
+    _assoc_guard: GaugeGuard,
 }
 
 impl Drop for ServerAssociation {
@@ -852,6 +1238,8 @@ impl ServerAssociation {
         src_addr: SocketAddr,
         response_tx: ServerProxyHandler,
     ) -> io::Result<ServerAssociation> {
+        check_draining(&context)?;
+
         // Create a socket for receiving packets
         // Let system allocate an address for us (INADDR_ANY)
         let bind_addr = match src_addr.ip() {
@@ -864,6 +1252,11 @@ impl ServerAssociation {
         let local_addr = remote_udp.local_addr().expect("could not determine port bound to");
         debug!("created UDP Association for {} from {}", src_addr, local_addr);
 
+        let connection_guard = context.connections().track();
+
+        #[cfg(feature = "metrics")]
+        let assoc_guard = GaugeGuard::udp_association(context.metrics());
+
         // Create a channel for sending packets to remote
         // FIXME: Channel size 1024?
         let (tx, mut rx) = mpsc::channel::<Vec<u8>>(1024);
@@ -872,6 +1265,9 @@ impl ServerAssociation {
         let receiver = Arc::new(remote_udp);
         let sender = receiver.clone();
 
+        // Session of AEAD-2022 methods, shared by both directions
+        let session = Aead2022Session::server(&context, context.server_config(svr_idx));
+
         let timeout = context.config().udp_timeout.unwrap_or(DEFAULT_TIMEOUT);
 
         // ResolvedIP:Port -> Domain:Port
@@ -886,6 +1282,7 @@ impl ServerAssociation {
         {
             let context = context.clone();
             let resolved_address_cache = resolved_address_cache.clone();
+            let session = session.clone();
             tokio::spawn(async move {
                 let svr_cfg = context.server_config(svr_idx);
 
@@ -898,6 +1295,7 @@ impl ServerAssociation {
                         pkt,
                         timeout,
                         svr_cfg,
+                        &session,
                         &resolved_address_cache,
                     )
                     .await
@@ -923,6 +1321,7 @@ impl ServerAssociation {
                     &receiver,
                     &response_tx,
                     svr_cfg,
+                    &session,
                     &resolved_address_cache,
                 )
                 .await
@@ -948,10 +1347,14 @@ impl ServerAssociation {
         Ok(ServerAssociation {
             tx,
             watcher: close_flag,
+            _connection_guard: connection_guard,
+            #[cfg(feature = "metrics")]
+            _assoc_guard: assoc_guard,
         })
     }
 
     /// Relay packets from local to remote
+    #[allow(clippy::too_many_arguments)]
     async fn relay_l2r(
         context: &Context,
         src: SocketAddr,
@@ -959,10 +1362,19 @@ impl ServerAssociation {
         pkt: Vec<u8>,
         timeout: Duration,
         svr_cfg: &ServerConfig,
+        session: &Aead2022Session,
         resolved_address_cache: &SharedResolvedAddressCache,
     ) -> io::Result<()> {
         // First of all, decrypt payload CLIENT -> SERVER
-        let mut cur = if let CipherCategory::None = svr_cfg.method().category() {
+        let mut cur = if let Some(result) = session.decrypt_request(svr_cfg.method(), svr_cfg.key(), &pkt) {
+            match result {
+                Ok(decrypted_pkt) => Cursor::new(decrypted_pkt),
+                Err(err) => {
+                    error!("failed to decrypt pkt in UDP relay: {}", err);
+                    return Err(err);
+                }
+            }
+        } else if let CipherCategory::None = svr_cfg.method().category() {
             Cursor::new(pkt)
         } else {
             let decrypted_pkt = match decrypt_payload(context, svr_cfg.method(), svr_cfg.key(), &pkt) {
@@ -985,7 +1397,7 @@ impl ServerAssociation {
         // CLIENT -> SERVER protocol: ADDRESS + PAYLOAD
         let addr = Address::read_from(&mut cur).await?;
 
-        if context.check_outbound_blocked(&addr).await {
+        if context.check_outbound_blocked(&addr, Protocol::Udp).await {
             warn!("{} -> outbound {} is blocked by ACL rules", src, addr);
             return Ok(());
         }
@@ -1048,6 +1460,7 @@ impl ServerAssociation {
         remote_udp: &UdpSocket,
         response_tx: &ServerProxyHandler,
         svr_cfg: &ServerConfig,
+        session: &Aead2022Session,
         resolved_address_cache: &SharedResolvedAddressCache,
     ) -> io::Result<()> {
         // Waiting for response from server SERVER -> CLIENT
@@ -1066,28 +1479,30 @@ impl ServerAssociation {
             src_addr, addr, remote_addr, remote_recv_len
         );
 
-        // CLIENT <- SERVER protocol: ADDRESS + PAYLOAD
-        let mut send_buf = BytesMut::with_capacity(addr.serialized_len() + remote_recv_len);
-        addr.write_to_buf(&mut send_buf);
-        send_buf.extend_from_slice(&remote_buf[..remote_recv_len]);
-
-        if let CipherCategory::None = svr_cfg.method().category() {
-            // Send back to src_addr
-            if let Err(err) = response_tx.send_packet(&send_buf).await {
-                error!("failed to send packet into response channel, error: {}", err);
+        let encrypted =
+            session.encrypt_response(svr_cfg.method(), svr_cfg.key(), &addr, &remote_buf[..remote_recv_len]);
+        let send_buf = if let Some(encrypt_buf) = encrypted {
+            encrypt_buf?
+        } else {
+            // CLIENT <- SERVER protocol: ADDRESS + PAYLOAD
+            let mut send_buf = BytesMut::with_capacity(addr.serialized_len() + remote_recv_len);
+            addr.write_to_buf(&mut send_buf);
+            send_buf.extend_from_slice(&remote_buf[..remote_recv_len]);
 
-                // FIXME: What to do? Ignore?
+            if let CipherCategory::None = svr_cfg.method().category() {
+                send_buf
+            } else {
+                let mut encrypt_buf = BytesMut::new();
+                encrypt_payload(context, svr_cfg.method(), svr_cfg.key(), &send_buf, &mut encrypt_buf);
+                encrypt_buf
             }
-        } else {
-            let mut encrypt_buf = BytesMut::new();
-            encrypt_payload(context, svr_cfg.method(), svr_cfg.key(), &send_buf, &mut encrypt_buf);
+        };
 
-            // Send back to src_addr
-            if let Err(err) = response_tx.send_packet(&encrypt_buf).await {
-                error!("failed to send packet into response channel, error: {}", err);
+        // Send back to src_addr
+        if let Err(err) = response_tx.send_packet(&send_buf).await {
+            error!("failed to send packet into response channel, error: {}", err);
 
-                // FIXME: What to do? Ignore?
-            }
+            // FIXME: What to do? Ignore?
         }
 
         Ok(())
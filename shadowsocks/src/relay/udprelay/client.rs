@@ -23,7 +23,7 @@ use crate::{
 };
 
 use super::{
-    crypto_io::{decrypt_payload, encrypt_payload},
+    crypto_io::{decrypt_payload, encrypt_payload, Aead2022Session},
     DEFAULT_TIMEOUT,
     MAXIMUM_UDP_PAYLOAD_SIZE,
 };
@@ -32,7 +32,7 @@ use super::{
 pub struct Socks5Client {
     socket: UdpSocket,
     // Socks5 protocol requires to keep this TCP connection alive
-    // Theoretically if this connection is broken, the association is broken too, but the UDP Socks5 server in this crate doesn't behave like that
+    // If authentication is enabled, the UDP Socks5 server in this crate drops packets after this connection is broken
     #[allow(dead_code)]
     assoc_client: Socks5TcpClient,
 }
@@ -40,13 +40,31 @@ pub struct Socks5Client {
 impl Socks5Client {
     /// Create a new UDP associate to `proxy`
     pub async fn associate(proxy: &SocketAddr) -> io::Result<Socks5Client> {
+        Socks5Client::associate_with_auth(proxy, None).await
+    }
+
+    /// Create a new UDP associate to `proxy`, authenticates with username and password (RFC1929)
+    pub async fn associate_with_password(
+        proxy: &SocketAddr,
+        username: &str,
+        password: &str,
+    ) -> io::Result<Socks5Client> {
+        Socks5Client::associate_with_auth(proxy, Some((username, password))).await
+    }
+
+    async fn associate_with_auth(proxy: &SocketAddr, auth: Option<(&str, &str)>) -> io::Result<Socks5Client> {
         let local_addr = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0);
         let socket = create_udp_socket(&local_addr).await?;
 
         // The actual bind address, tell the proxy that I am going to send packets from this address
         let local_addr = socket.local_addr()?;
 
-        let (assoc_client, proxy_addr) = Socks5TcpClient::udp_associate(local_addr, proxy).await?;
+        let (assoc_client, proxy_addr) = match auth {
+            Some((username, password)) => {
+                Socks5TcpClient::udp_associate_with_password(local_addr, proxy, username, password).await?
+            }
+            None => Socks5TcpClient::udp_associate(local_addr, proxy).await?,
+        };
         match proxy_addr {
             Address::SocketAddress(sa) => socket.connect(sa).await?,
             // FIXME: `connect` will use tokio's builtin DNS resolver.
@@ -96,6 +114,8 @@ pub struct ServerClient {
     socket: UdpSocket,
     method: CipherKind,
     key: Bytes,
+    // Session of AEAD-2022 methods, empty for the other methods
+    session: Aead2022Session,
 }
 
 impl ServerClient {
@@ -142,17 +162,28 @@ impl ServerClient {
 
         let key = svr_cfg.clone_key();
         let method = svr_cfg.method();
-
-        Ok(ServerClient { socket, method, key })
+        let session = Aead2022Session::client(svr_cfg);
+
+        Ok(ServerClient {
+            socket,
+            method,
+            key,
+            session,
+        })
     }
 
     async fn pack_req(
         method: CipherKind,
         key: &Bytes,
+        session: &Aead2022Session,
         context: &Context,
         addr: &Address,
         payload: &[u8],
     ) -> io::Result<Bytes> {
+        if let Some(encrypt_buf) = session.encrypt_request(method, key, addr, payload) {
+            return Ok(encrypt_buf.freeze());
+        }
+
         // CLIENT -> SERVER protocol: ADDRESS + PAYLOAD
         let mut send_buf = BytesMut::with_capacity(addr.serialized_len() + payload.len());
         addr.write_to_buf(&mut send_buf);
@@ -176,7 +207,7 @@ impl ServerClient {
 
         let timeout = context.config().udp_timeout.unwrap_or(DEFAULT_TIMEOUT);
 
-        let send_buf = Self::pack_req(self.method, &self.key, context, addr, payload).await?;
+        let send_buf = Self::pack_req(self.method, &self.key, &self.session, context, addr, payload).await?;
 
         let send_len = try_timeout(self.socket.send(&send_buf), Some(timeout)).await?;
         if send_buf.len() != send_len {
@@ -194,9 +225,12 @@ impl ServerClient {
         context: &Context,
         method: CipherKind,
         key: &Bytes,
+        session: &Aead2022Session,
         recv_buf: &[u8],
     ) -> io::Result<(Address, Vec<u8>)> {
-        let mut cur = if let CipherCategory::None = method.category() {
+        let mut cur = if let Some(decrypt_buf) = session.decrypt_response(method, key, recv_buf) {
+            Cursor::new(decrypt_buf?)
+        } else if let CipherCategory::None = method.category() {
             Cursor::new(recv_buf.to_vec())
         } else {
             let decrypt_buf = match decrypt_payload(context, method, key, recv_buf)? {
@@ -234,7 +268,8 @@ impl ServerClient {
         // Packet length is limited by MAXIMUM_UDP_PAYLOAD_SIZE, excess bytes will be discarded.
         let mut recv_buf = vec![0u8; MAXIMUM_UDP_PAYLOAD_SIZE];
         let recv_n = try_timeout(self.socket.recv(&mut recv_buf), Some(timeout)).await?;
-        let (addr, payload) = Self::parse_resp(context, self.method, &self.key, &recv_buf[..recv_n]).await?;
+        let (addr, payload) =
+            Self::parse_resp(context, self.method, &self.key, &self.session, &recv_buf[..recv_n]).await?;
         Ok((addr, payload))
     }
 }
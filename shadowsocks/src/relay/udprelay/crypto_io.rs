@@ -19,17 +19,121 @@
 //! | Fixed  | Variable  |   Fixed   |
 //! +--------+-----------+-----------+
 //! ```
+//!
+//! Packets of AEAD-2022 methods are encrypted with `Aead2022Session`
 use std::io;
+#[cfg(feature = "aead-cipher-2022")]
+use std::sync::Arc;
 
 use byte_string::ByteStr;
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use log::{debug, trace};
 
 use crate::{
+    config::ServerConfig,
     context::Context,
     crypto::v1::{random_iv_or_salt, Cipher, CipherCategory, CipherKind},
+    relay::socks5::Address,
 };
 
+#[cfg(feature = "aead-cipher-2022")]
+use super::aead_2022::{decrypt_request, decrypt_response, encrypt_request, encrypt_response, UdpSession};
+
+/// Session of AEAD-2022 methods, shared by both directions of an association
+///
+/// It is empty for the other methods, or if feature "aead-cipher-2022" is disabled. Methods of an empty session
+/// return `None`, packets should be encrypted by `encrypt_payload` and decrypted by `decrypt_payload` instead.
+#[derive(Clone, Default)]
+pub struct Aead2022Session {
+    #[cfg(feature = "aead-cipher-2022")]
+    session: Option<Arc<UdpSession>>,
+}
+
+impl Aead2022Session {
+    /// Create a session of client if `svr_cfg` is using an AEAD-2022 method
+    #[allow(unused_variables)]
+    pub fn client(svr_cfg: &ServerConfig) -> Aead2022Session {
+        #[cfg(feature = "aead-cipher-2022")]
+        if svr_cfg.is_aead_2022() {
+            return Aead2022Session {
+                session: Some(Arc::new(UdpSession::new())),
+            };
+        }
+
+        Aead2022Session::default()
+    }
+
+    /// Create a session of server if `svr_cfg` is using an AEAD-2022 method
+    ///
+    /// Sessions of clients are shared by all associations in `context`, a client's session may be moved to another
+    /// association if the client's address has been changed
+    #[allow(unused_variables)]
+    pub fn server(context: &Context, svr_cfg: &ServerConfig) -> Aead2022Session {
+        #[cfg(feature = "aead-cipher-2022")]
+        if svr_cfg.is_aead_2022() {
+            let session = UdpSession::new_with_peers(context.aead_2022_client_sessions());
+            return Aead2022Session {
+                session: Some(Arc::new(session)),
+            };
+        }
+
+        Aead2022Session::default()
+    }
+
+    /// Encrypt a request packet of client, with `payload` to `addr`
+    #[allow(unused_variables)]
+    pub fn encrypt_request(&self, method: CipherKind, key: &[u8], addr: &Address, payload: &[u8]) -> Option<BytesMut> {
+        #[cfg(feature = "aead-cipher-2022")]
+        if let Some(ref session) = self.session {
+            let mut dst = BytesMut::new();
+            encrypt_request(method, key, session, addr, payload, &mut dst);
+            return Some(dst);
+        }
+
+        None
+    }
+
+    /// Encrypt a response packet of server, with `payload` from `addr`
+    #[allow(unused_variables)]
+    pub fn encrypt_response(
+        &self,
+        method: CipherKind,
+        key: &[u8],
+        addr: &Address,
+        payload: &[u8],
+    ) -> Option<io::Result<BytesMut>> {
+        #[cfg(feature = "aead-cipher-2022")]
+        if let Some(ref session) = self.session {
+            let mut dst = BytesMut::new();
+            return Some(encrypt_response(method, key, session, addr, payload, &mut dst).map(|_| dst));
+        }
+
+        None
+    }
+
+    /// Decrypt a request packet from client, returns ADDRESS + DATA in it
+    #[allow(unused_variables)]
+    pub fn decrypt_request(&self, method: CipherKind, key: &[u8], pkt: &[u8]) -> Option<io::Result<Vec<u8>>> {
+        #[cfg(feature = "aead-cipher-2022")]
+        if let Some(ref session) = self.session {
+            return Some(decrypt_request(method, key, session, pkt));
+        }
+
+        None
+    }
+
+    /// Decrypt a response packet from server, returns ADDRESS + DATA in it
+    #[allow(unused_variables)]
+    pub fn decrypt_response(&self, method: CipherKind, key: &[u8], pkt: &[u8]) -> Option<io::Result<Vec<u8>>> {
+        #[cfg(feature = "aead-cipher-2022")]
+        if let Some(ref session) = self.session {
+            return Some(decrypt_response(method, key, session, pkt));
+        }
+
+        None
+    }
+}
+
 /// Encrypt payload into ShadowSocks UDP encrypted packet
 pub fn encrypt_payload(context: &Context, method: CipherKind, key: &[u8], payload: &[u8], dst: &mut BytesMut) {
     match method.category() {
@@ -198,3 +302,68 @@ fn decrypt_payload_aead(
 
     Ok(Some(buf))
 }
+
+/// Find the user whose key could decrypt the AEAD encrypted packet
+///
+/// For servers that have multiple users sharing the same port. `users` are `(index, key)` pairs, returns the
+/// identified user's index. Salt is not checked and won't be recorded, leaving it to `decrypt_payload`.
+pub fn find_user_aead(method: CipherKind, users: &[(usize, Bytes)], payload: &[u8]) -> Option<usize> {
+    let salt_len = method.salt_len();
+    if payload.len() < salt_len + method.tag_len() {
+        return None;
+    }
+
+    let (salt, data) = payload.split_at(salt_len);
+    let mut buf = vec![0u8; data.len()];
+
+    for &(idx, ref key) in users {
+        buf.copy_from_slice(data);
+
+        let mut cipher = Cipher::new(method, key, salt);
+        if cipher.decrypt_packet(&mut buf) {
+            trace!(
+                "UDP packet with AEAD salt {:?} identified as user #{}",
+                ByteStr::new(salt),
+                idx
+            );
+            return Some(idx);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encrypt_aead(method: CipherKind, key: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut pkt = vec![0u8; method.salt_len()];
+        random_iv_or_salt(&mut pkt);
+
+        let mut cipher = Cipher::new(method, key, &pkt);
+
+        let salt_len = pkt.len();
+        pkt.extend_from_slice(payload);
+        pkt.resize(pkt.len() + method.tag_len(), 0);
+        cipher.encrypt_packet(&mut pkt[salt_len..]);
+        pkt
+    }
+
+    #[test]
+    fn find_user_by_key() {
+        let method = CipherKind::AES_256_GCM;
+        let users = (0..3)
+            .map(|i| (i * 2, Bytes::from(vec![i as u8; method.key_len()])))
+            .collect::<Vec<_>>();
+
+        for &(idx, ref key) in &users {
+            let pkt = encrypt_aead(method, key, b"hello");
+            assert_eq!(find_user_aead(method, &users, &pkt), Some(idx));
+        }
+
+        let pkt = encrypt_aead(method, &vec![0xff; method.key_len()], b"hello");
+        assert_eq!(find_user_aead(method, &users, &pkt), None);
+        assert_eq!(find_user_aead(method, &users, &pkt[..method.salt_len()]), None);
+    }
+}
@@ -46,9 +46,13 @@
 //! | Fixed |   Variable   |
 //! +-------+--------------+
 //! ```
+//!
+//! AEAD-2022 methods have their own packet format, which is described in `aead_2022`
 
 use std::time::Duration;
 
+#[cfg(feature = "aead-cipher-2022")]
+pub(crate) mod aead_2022;
 mod association;
 pub mod client;
 mod crypto_io;
@@ -1,12 +1,17 @@
 //! UDP relay local server
 
-use std::{io, net::SocketAddr, time::Duration};
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use log::{debug, error, info, trace, warn};
 use tokio::time;
 
 use crate::{
+    acl::{Protocol, Route},
     config::RedirType,
     context::SharedContext,
     relay::{
@@ -22,6 +27,7 @@ use super::{
     MAXIMUM_UDP_PAYLOAD_SIZE,
 };
 
+#[derive(Clone)]
 struct ProxyHandler {
     ty: RedirType,
     src_addr: SocketAddr,
@@ -133,15 +139,30 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
 
         // Check destination should be proxied or not
         let target = Address::SocketAddress(dst);
-        let is_bypassed = context.check_target_bypassed(&target).await;
+        let route = context.route_target(&target, Protocol::Udp).await;
+        if let Route::Reject = route {
+            trace!("UDP redir {} -> {} rejected by ACL", src, dst);
+            continue;
+        }
 
         // Check or (re)create an association
         let cache_key = format!("{}-{}", src, dst);
         let cache_key_cloned = cache_key.clone();
         let res = assoc_manager
             .send_packet(cache_key, target, pkt.to_vec(), async {
-                // Pick a server
-                let server = balancer.pick_server();
+                // Pick a server, in the group that the target is routed to
+                let server = match route {
+                    Route::Proxy(Some(ref group)) => {
+                        match balancer.pick_server_in_group(group, &Address::SocketAddress(dst)) {
+                            Some(s) => s,
+                            None => {
+                                let err = io::Error::new(ErrorKind::Other, format!("no servers in group {}", group));
+                                return Err(err);
+                            }
+                        }
+                    }
+                    _ => balancer.pick_server_for(&Address::SocketAddress(dst)),
+                };
 
                 let sender = match ProxyHandler::new(ty, src, cache_key_cloned, assoc_manager.clone()) {
                     Ok(s) => s,
@@ -151,10 +172,10 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
                     }
                 };
 
-                if is_bypassed {
+                if let Route::Bypass = route {
                     ProxyAssociation::associate_bypassed(src, server, sender).await
                 } else {
-                    ProxyAssociation::associate_proxied(src, server, sender).await
+                    ProxyAssociation::associate_proxied(src, balancer.clone(), server, sender).await
                 }
             })
             .await;
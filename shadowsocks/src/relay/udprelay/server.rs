@@ -2,30 +2,49 @@
 
 use std::{io, sync::Arc, time::Duration};
 
+use bytes::Bytes;
 use futures::{stream::FuturesUnordered, StreamExt};
 use log::{debug, error, info, trace, warn};
 use tokio::{self, time};
 
 use crate::{
     context::SharedContext,
-    relay::{
-        flow::{SharedMultiServerFlowStatistic, SharedServerFlowStatistic},
-        sys::create_udp_socket,
-    },
+    relay::{flow::SharedMultiServerFlowStatistic, sys::create_udp_socket},
 };
 
 use super::{
     association::{ServerAssociation, ServerAssociationManager, ServerProxyHandler},
+    crypto_io::find_user_aead,
     MAXIMUM_UDP_PAYLOAD_SIZE,
 };
 
-async fn listen(context: SharedContext, flow_stat: SharedServerFlowStatistic, svr_idx: usize) -> io::Result<()> {
-    let svr_cfg = context.server_config(svr_idx);
+async fn listen(
+    context: SharedContext,
+    flow_stat: SharedMultiServerFlowStatistic,
+    svr_idxs: Vec<usize>,
+) -> io::Result<()> {
+    let svr_cfg = context.server_config(svr_idxs[0]);
     let listen_addr = svr_cfg.addr().bind_addr(&context).await?;
 
     let listener = create_udp_socket(&listen_addr).await?;
     let local_addr = listener.local_addr().expect("determine port bound to");
-    info!("shadowsocks UDP listening on {}", local_addr);
+    if svr_idxs.len() > 1 {
+        info!(
+            "shadowsocks UDP listening on {} with {} users",
+            local_addr,
+            svr_idxs.len()
+        );
+    } else {
+        info!("shadowsocks UDP listening on {}", local_addr);
+    }
+
+    // Users' keys for identifying which user the packet belongs to
+    // Users sharing the same port are required to have the same method
+    let method = svr_cfg.method();
+    let users: Vec<(usize, Bytes)> = svr_idxs
+        .iter()
+        .map(|&idx| (idx, context.server_config(idx).clone_key()))
+        .collect();
 
     let r = Arc::new(listener);
     let w = r.clone();
@@ -48,7 +67,6 @@ async fn listen(context: SharedContext, flow_stat: SharedServerFlowStatistic, sv
         let pkt = &pkt_buf[..recv_len];
 
         trace!("received UDP packet from {}, length {} bytes", src, recv_len);
-        flow_stat.udp().incr_rx(pkt.len());
 
         if recv_len == 0 {
             // For windows, it will generate a ICMP Port Unreachable Message
@@ -67,12 +85,49 @@ async fn listen(context: SharedContext, flow_stat: SharedServerFlowStatistic, sv
             continue;
         }
 
+        let svr_idx = if users.len() == 1 {
+            users[0].0
+        } else {
+            match find_user_aead(method, &users, pkt) {
+                Some(idx) => idx,
+                None => {
+                    debug!(
+                        "UDP packet from {} doesn't match any users, length {} bytes",
+                        src, recv_len
+                    );
+                    continue;
+                }
+            }
+        };
+
+        let user_flow_stat = flow_stat
+            .get_by_index(svr_idx)
+            .expect("server not existed in multi-server flow statistic");
+
+        if let Some(limiter) = user_flow_stat.limiter() {
+            if !limiter.check_upload(pkt.len()) {
+                trace!(
+                    "UDP packet from {} dropped by traffic limits, length {} bytes",
+                    src,
+                    recv_len
+                );
+                continue;
+            }
+        }
+
+        user_flow_stat.udp().incr_rx(pkt.len());
+
         // Check or (re)create an association
         let res = assoc_manager
-            .send_packet(ServerProxyHandler::association_key(&src), pkt.to_vec(), async {
-                let handler = ServerProxyHandler::new(src, assoc_manager.clone(), flow_stat.clone(), w.clone());
-                ServerAssociation::associate(context.clone(), svr_idx, src, handler).await
-            })
+            .send_packet(
+                ServerProxyHandler::association_key(&src, svr_idx),
+                pkt.to_vec(),
+                async {
+                    let handler =
+                        ServerProxyHandler::new(src, svr_idx, assoc_manager.clone(), user_flow_stat.clone(), w.clone());
+                    ServerAssociation::associate(context.clone(), svr_idx, src, handler).await
+                },
+            )
             .await;
 
         if let Err(err) = res {
@@ -85,14 +140,12 @@ async fn listen(context: SharedContext, flow_stat: SharedServerFlowStatistic, sv
 pub async fn run(context: SharedContext, flow_stat: SharedMultiServerFlowStatistic) -> io::Result<()> {
     let vec_fut = FuturesUnordered::new();
 
-    for (svr_idx, svr_cfg) in context.config().server.iter().enumerate() {
+    // Servers sharing the same address are different users of one listener
+    for svr_idxs in context.config().server_groups() {
         let context = context.clone();
-        let flow_stat = flow_stat
-            .get(svr_cfg.addr().port())
-            .expect("port not existed in multi-server flow statistic")
-            .clone();
+        let flow_stat = flow_stat.clone();
 
-        let svr_fut = listen(context, flow_stat, svr_idx);
+        let svr_fut = listen(context, flow_stat, svr_idxs);
         vec_fut.push(svr_fut);
     }
 
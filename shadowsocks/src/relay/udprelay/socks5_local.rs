@@ -161,6 +161,15 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
             continue;
         }
 
+        // Clients must authenticate by UDP ASSOCIATE first
+        if !context.check_udp_associate_client(&src.ip()) {
+            debug!(
+                "dropped UDP packet from {}, length {} bytes, without an authenticated UDP ASSOCIATE",
+                src, recv_len
+            );
+            continue;
+        }
+
         // Parse it for validating
         let (target, payload) = match parse_packet(pkt).await {
             Ok(t) => t,
@@ -174,14 +183,17 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
         };
 
         // Check or (re)create an association
+        //
+        // Association is bound to one server, chosen by the first packet's target
+        let assoc_target = target.clone();
         let res = assoc_manager
             .send_packet(src.to_string(), target, payload, async {
                 // Pick a server
-                let server = balancer.pick_server();
+                let server = balancer.pick_server_for(&assoc_target);
 
                 let sender = ProxyHandler::new(src, assoc_manager.clone(), w.clone());
 
-                ProxyAssociation::associate_with_acl(src, server, sender).await
+                ProxyAssociation::associate_with_acl(src, balancer.clone(), server, sender).await
             })
             .await;
 
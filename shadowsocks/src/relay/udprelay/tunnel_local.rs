@@ -123,11 +123,11 @@ pub async fn run(context: SharedContext) -> io::Result<()> {
         let res = assoc_manager
             .send_packet(src.to_string(), forward_target.clone(), pkt.to_vec(), async {
                 // Pick a server
-                let server = balancer.pick_server();
+                let server = balancer.pick_server_for(&forward_target);
 
                 let sender = ProxyHandler::new(src, assoc_manager.clone(), w.clone());
 
-                ProxyAssociation::associate_with_acl(src, server, sender).await
+                ProxyAssociation::associate_with_acl(src, balancer.clone(), server, sender).await
             })
             .await;
 
@@ -18,6 +18,11 @@ where
     .map_err(From::from)
 }
 
+/// Compares secrets without returning early, response time shouldn't tell how many bytes matched
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 #[cfg(all(unix, not(target_os = "android")))]
 pub fn set_nofile(nofile: u64) -> io::Result<()> {
     unsafe {
@@ -42,3 +47,18 @@ pub fn set_nofile(_nofile: u64) -> io::Result<()> {
     // Windows' limit of opening files is the size of HANDLE (32-bits), so it is unlimited
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn constant_time_eq_matches() {
+        assert!(constant_time_eq(b"password", b"password"));
+        assert!(constant_time_eq(b"", b""));
+
+        assert!(!constant_time_eq(b"password", b"passwore"));
+        assert!(!constant_time_eq(b"password", b"password1"));
+        assert!(!constant_time_eq(b"password", b""));
+    }
+}
@@ -0,0 +1,313 @@
+use std::{
+    fs,
+    net::{IpAddr, SocketAddr},
+};
+
+use shadowsocks::{
+    acl::{AccessControl, AclInspector, AclRule, Protocol, Route, Section},
+    config::{Config, ConfigType},
+    context::Context,
+    relay::socks5::Address,
+};
+
+#[test]
+fn acl_domain_rules() {
+    let dir = tempfile::tempdir().unwrap();
+    let acl_path = dir.path().join("rules.acl");
+    fs::write(
+        &acl_path,
+        r"[bypass_all]
+
+[proxy_list]
+domain:google.com
+full:www.example.com
+keyword:youtube
+(^|\.)github\.com$
+^mail\.example\.org$
+regexp:^cdn[0-9]+\.example\.net$
+
+[bypass_list]
+domain:cn
+",
+    )
+    .unwrap();
+
+    let acl = AccessControl::load_from_file(&acl_path).unwrap();
+
+    // Suffix rules
+    assert_eq!(acl.check_host_in_proxy_list("google.com"), Some(true));
+    assert_eq!(acl.check_host_in_proxy_list("mail.google.com"), Some(true));
+    assert_eq!(acl.check_host_in_proxy_list("WWW.Google.COM."), Some(true));
+    assert_eq!(acl.check_host_in_proxy_list("notgoogle.com"), None);
+    assert_eq!(acl.check_host_in_proxy_list("google.com.evil.net"), None);
+
+    // Exact rules
+    assert_eq!(acl.check_host_in_proxy_list("www.example.com"), Some(true));
+    assert_eq!(acl.check_host_in_proxy_list("example.com"), None);
+    assert_eq!(acl.check_host_in_proxy_list("a.www.example.com"), None);
+
+    // Keyword rules
+    assert_eq!(acl.check_host_in_proxy_list("i.ytimg.youtube-nocookie.com"), Some(true));
+
+    // Regular expressions that are loaded as suffix and exact rules
+    assert_eq!(acl.check_host_in_proxy_list("github.com"), Some(true));
+    assert_eq!(acl.check_host_in_proxy_list("api.github.com"), Some(true));
+    assert_eq!(acl.check_host_in_proxy_list("mail.example.org"), Some(true));
+    assert_eq!(acl.check_host_in_proxy_list("www.mail.example.org"), None);
+
+    // Regular expressions
+    assert_eq!(acl.check_host_in_proxy_list("cdn42.example.net"), Some(true));
+    assert_eq!(acl.check_host_in_proxy_list("cdn.example.net"), None);
+
+    assert_eq!(acl.check_host_in_proxy_list("baidu.cn"), Some(false));
+}
+
+#[test]
+fn acl_geoip_rules() {
+    let dir = tempfile::tempdir().unwrap();
+    let acl_path = dir.path().join("rules.acl");
+
+    // Private networks don't require GeoIP databases
+    fs::write(&acl_path, "[proxy_all]\n\n[bypass_list]\ngeoip:private\n").unwrap();
+    let acl = AccessControl::load_from_file(&acl_path).unwrap();
+
+    assert!(!acl.is_ip_empty());
+    assert!(!acl.check_ip_in_proxy_list(&"192.168.1.1".parse::<IpAddr>().unwrap()));
+    assert!(!acl.check_ip_in_proxy_list(&"::1".parse::<IpAddr>().unwrap()));
+    assert!(acl.check_ip_in_proxy_list(&"8.8.8.8".parse::<IpAddr>().unwrap()));
+    assert!(acl.check_client_blocked(&"10.1.2.3:1080".parse::<SocketAddr>().unwrap()));
+
+    // Countries must be looked up in a GeoIP database
+    fs::write(&acl_path, "[bypass_list]\ngeoip:CN\n").unwrap();
+    assert!(AccessControl::load_from_file(&acl_path).is_err());
+
+    fs::write(&acl_path, "[bypass_list]\ngeoip:china\n").unwrap();
+    assert!(AccessControl::load_from_file(&acl_path).is_err());
+}
+
+#[tokio::test]
+async fn acl_qualified_rules() {
+    let dir = tempfile::tempdir().unwrap();
+    let acl_path = dir.path().join("rules.acl");
+    fs::write(
+        &acl_path,
+        "[proxy_all]
+
+[bypass_list]
+* ; udp port:123
+10.0.0.0/8 ; port:8000-8999
+domain:example.com ; tcp
+
+[outbound_block_list]
+* ; port:25,465,587 tcp
+* ; port:6881-6889
+",
+    )
+    .unwrap();
+
+    let acl = AccessControl::load_from_file(&acl_path).unwrap();
+    let context = Context::new_shared(Config::new(ConfigType::Local)).await;
+
+    let addr = |s: &str| Address::SocketAddress(s.parse::<SocketAddr>().unwrap());
+    let domain = |s: &str, port: u16| Address::DomainNameAddress(s.to_owned(), port);
+
+    let targets = [
+        (addr("1.2.3.4:123"), Protocol::Udp, true),
+        (addr("1.2.3.4:123"), Protocol::Tcp, false),
+        (addr("10.1.1.1:8080"), Protocol::Tcp, true),
+        (addr("10.1.1.1:9000"), Protocol::Tcp, false),
+        (domain("www.example.com", 443), Protocol::Tcp, true),
+        (domain("www.example.com", 443), Protocol::Udp, false),
+    ];
+    for (target, protocol, bypassed) in targets.iter() {
+        let result = acl.check_target_bypassed(&context, target, *protocol).await;
+        assert_eq!(result, *bypassed, "{} {:?}", target, protocol);
+    }
+
+    let outbounds = [
+        (addr("1.2.3.4:25"), Protocol::Tcp, true),
+        (addr("1.2.3.4:25"), Protocol::Udp, false),
+        (addr("1.2.3.4:6881"), Protocol::Udp, true),
+        (addr("1.2.3.4:443"), Protocol::Tcp, false),
+    ];
+    for (outbound, protocol, blocked) in outbounds.iter() {
+        let result = acl.check_outbound_blocked(&context, outbound, *protocol).await;
+        assert_eq!(result, *blocked, "{} {:?}", outbound, protocol);
+    }
+
+    // Invalid qualifiers
+    for rule in &["* ; port:80-70", "* ; port:http", "* ; tcp udp", "* ; sctp"] {
+        fs::write(&acl_path, format!("[outbound_block_list]\n{}\n", rule)).unwrap();
+        assert!(AccessControl::load_from_file(&acl_path).is_err(), "{}", rule);
+    }
+}
+
+#[tokio::test]
+async fn acl_route_rules() {
+    let dir = tempfile::tempdir().unwrap();
+    let acl_path = dir.path().join("rules.acl");
+    fs::write(
+        &acl_path,
+        "[proxy_all]
+
+[bypass_list]
+10.0.0.0/8
+
+[route:us]
+domain:netflix.com
+
+[route_all:hk]
+
+[reject_list]
+domain:ads.example.com
+* ; udp port:443
+
+[route:us]
+full:www.hulu.com
+",
+    )
+    .unwrap();
+
+    let mut config = Config::load_from_str(
+        r#"{
+            "local_address": "127.0.0.1",
+            "local_port": 1080,
+            "servers": [
+                {"address": "1.1.1.1", "port": 8388, "password": "p", "method": "aes-256-gcm", "id": "us-1"},
+                {"address": "2.2.2.2", "port": 8388, "password": "p", "method": "aes-256-gcm", "remarks": "hk-1"},
+                {"address": "3.3.3.3", "port": 8388, "password": "p", "method": "aes-256-gcm", "id": "us-2"}
+            ],
+            "groups": {
+                "us": ["us-1", "us-2"],
+                "hk": ["hk-1"],
+                "jp": ["jp-1"]
+            }
+        }"#,
+        ConfigType::Local,
+    )
+    .unwrap();
+
+    assert_eq!(config.servers_in_group("us"), Some(vec![0, 2]));
+    assert_eq!(config.servers_in_group("hk"), Some(vec![1]));
+    assert_eq!(config.servers_in_group("jp"), Some(vec![]));
+    assert_eq!(config.servers_in_group("eu"), None);
+
+    config.acl = Some(AccessControl::load_from_file(&acl_path).unwrap());
+    let context = Context::new_shared(config).await;
+
+    let addr = |s: &str| Address::SocketAddress(s.parse::<SocketAddr>().unwrap());
+    let domain = |s: &str, port: u16| Address::DomainNameAddress(s.to_owned(), port);
+    let group = |s: &str| Route::Proxy(Some(s.to_owned()));
+
+    let targets = [
+        (domain("www.netflix.com", 443), Protocol::Tcp, group("us")),
+        (domain("www.hulu.com", 443), Protocol::Tcp, group("us")),
+        (domain("ads.example.com", 443), Protocol::Tcp, Route::Reject),
+        (domain("www.netflix.com", 443), Protocol::Udp, Route::Reject),
+        (addr("10.1.1.1:80"), Protocol::Tcp, Route::Bypass),
+        (addr("1.2.3.4:80"), Protocol::Tcp, group("hk")),
+    ];
+    for (target, protocol, route) in targets.iter() {
+        let result = context.route_target(target, *protocol).await;
+        assert_eq!(result, *route, "{} {:?}", target, protocol);
+    }
+}
+
+#[test]
+fn acl_inspector() {
+    let dir = tempfile::tempdir().unwrap();
+    let acl_path = dir.path().join("rules.acl");
+    fs::write(
+        &acl_path,
+        r"[proxy_all]
+
+[bypass_list]
+10.0.0.0/8
+10.1.0.0/16
+domain:example.com
+full:www.example.com
+(^|\.)example\.com$
+keyword:google
+domain:mail.google.com
+
+[proxy_list]
+domain:www.example.com
+1.2.3.4 ; udp
+
+[reject_list]
+* ; port:25
+
+[route:us]
+domain:netflix.com
+* ; port:25 tcp
+[route_all:hk]
+",
+    )
+    .unwrap();
+
+    let inspector = AclInspector::load_from_file_with_geoip(&acl_path, None).unwrap();
+    assert_eq!(inspector.rules().len(), 12);
+
+    let counts = inspector
+        .rule_counts()
+        .into_iter()
+        .map(|(s, n)| (s.clone(), n))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        counts,
+        vec![
+            (Section::Bypass, 7),
+            (Section::Proxy, 2),
+            (Section::Reject, 1),
+            (Section::Route("us".to_owned()), 2),
+        ]
+    );
+
+    assert_eq!(inspector.default_route(), Some(("hk", 22)));
+
+    let line = |rule: Option<&AclRule>| rule.map(|r| r.line_number());
+    let addr = |s: &str| Address::SocketAddress(s.parse::<SocketAddr>().unwrap());
+    let domain = |s: &str, port: u16| Address::DomainNameAddress(s.to_owned(), port);
+
+    // Only the rule deciding the route, in the order that `AccessControl` checks sections
+    let targets = [
+        (domain("www.example.com", 443), Protocol::Tcp, Some(13)),
+        (domain("mail.google.com", 443), Protocol::Tcp, Some(9)),
+        (domain("www.netflix.com", 25), Protocol::Tcp, Some(17)),
+        (domain("www.netflix.com", 443), Protocol::Tcp, Some(20)),
+        (addr("1.2.3.4:25"), Protocol::Tcp, Some(17)),
+        (addr("1.2.3.4:80"), Protocol::Udp, Some(14)),
+        (addr("1.2.3.4:80"), Protocol::Tcp, None),
+        (addr("10.1.1.1:80"), Protocol::Tcp, Some(4)),
+    ];
+    for (target, protocol, rule) in targets.iter() {
+        let result = line(inspector.deciding_rule(target, &[], *protocol));
+        assert_eq!(result, *rule, "{} {:?}", target, protocol);
+    }
+
+    // Domain names are matched by IP rules with resolved addresses
+    let resolved = ["10.1.1.1".parse::<IpAddr>().unwrap()];
+    let host = domain("internal.test", 80);
+    assert_eq!(line(inspector.deciding_rule(&host, &resolved, Protocol::Tcp)), Some(4));
+
+    // Servers
+    assert_eq!(line(inspector.client_rule(&"10.1.1.1".parse().unwrap())), Some(4));
+    assert_eq!(line(inspector.client_rule(&"1.2.3.4".parse().unwrap())), None);
+    assert_eq!(
+        line(inspector.outbound_block_rule(&host, &resolved, Protocol::Tcp)),
+        None
+    );
+
+    // (rule, earlier or broader rule)
+    let pairs = |rules: Vec<(&AclRule, &AclRule)>| {
+        rules
+            .iter()
+            .map(|(r, by)| (r.line_number(), by.line_number()))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(pairs(inspector.duplicated_rules()), vec![(8, 6)]);
+    assert_eq!(
+        pairs(inspector.shadowed_rules()),
+        vec![(5, 4), (7, 6), (10, 9), (21, 17)]
+    );
+}
@@ -1,6 +1,6 @@
 #![cfg(feature = "local-http")]
 
-use std::time::Duration;
+use std::{net::SocketAddr, str, time::Duration};
 
 use tokio::{net::TcpStream, prelude::*, time};
 
@@ -74,3 +74,139 @@ async fn http_proxy() {
         assert!(buf.starts_with(b"HTTP/1.0 200 OK\r\n"));
     }
 }
+
+/// Address on localhost with a free port, for servers that are started by configurations
+fn free_addr() -> SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+#[tokio::test]
+async fn http_proxy_authentication() {
+    let _ = env_logger::try_init();
+
+    let local_addr = free_addr();
+    let server_port = free_addr().port();
+
+    let local_config = Config::load_from_str(
+        &format!(
+            r#"{{
+                "local_port": {},
+                "local_address": "127.0.0.1",
+                "server": "127.0.0.1",
+                "server_port": {},
+                "password": "password",
+                "method": "aes-256-gcm",
+                "local_users": [
+                    {{
+                        "username": "user",
+                        "password": "pass"
+                    }}
+                ]
+            }}"#,
+            local_addr.port(),
+            server_port
+        ),
+        ConfigType::HttpLocal,
+    )
+    .unwrap();
+
+    let server_config = Config::load_from_str(
+        &format!(
+            r#"{{
+                "server": "127.0.0.1",
+                "server_port": {},
+                "password": "password",
+                "method": "aes-256-gcm"
+            }}"#,
+            server_port
+        ),
+        ConfigType::Server,
+    )
+    .unwrap();
+
+    tokio::spawn(run_local(local_config));
+    tokio::spawn(run_server(server_config));
+
+    time::sleep(Duration::from_secs(1)).await;
+
+    // dXNlcjp3cm9uZw== is "user:wrong"
+    let requests: [&[u8]; 3] = [
+        b"GET http://www.example.com/ HTTP/1.0\r\nHost: www.example.com\r\n\r\n",
+        b"GET http://www.example.com/ HTTP/1.0\r\nHost: www.example.com\r\nProxy-Authorization: Basic dXNlcjp3cm9uZw==\r\n\r\n",
+        b"CONNECT http://www.example.com/ HTTP/1.0\r\n\r\n",
+    ];
+
+    for req in requests.iter() {
+        let mut c = TcpStream::connect(local_addr).await.unwrap();
+        c.write_all(req).await.unwrap();
+        c.flush().await.unwrap();
+
+        let mut buf = Vec::new();
+        c.read_to_end(&mut buf).await.unwrap();
+
+        assert!(buf.starts_with(b"HTTP/1.0 407 Proxy Authentication Required\r\n"));
+        assert!(str::from_utf8(&buf).unwrap().contains("proxy-authenticate: Basic"));
+    }
+
+    {
+        // dXNlcjpwYXNz is "user:pass"
+        let mut c = TcpStream::connect(local_addr).await.unwrap();
+        c.write_all(b"GET http://www.example.com/ HTTP/1.0\r\nHost: www.example.com\r\nProxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n")
+            .await
+            .unwrap();
+        c.flush().await.unwrap();
+
+        let mut buf = Vec::new();
+        c.read_to_end(&mut buf).await.unwrap();
+
+        assert!(buf.starts_with(b"HTTP/1.0 200 OK\r\n"));
+    }
+}
+
+#[test]
+fn http_proxy_users_config() {
+    let config = Config::load_from_str(
+        r#"{
+            "local_port": 5112,
+            "local_address": "127.0.0.1",
+            "server": "127.0.0.1",
+            "server_port": 5122,
+            "password": "password",
+            "method": "aes-256-gcm",
+            "local_users": []
+        }"#,
+        ConfigType::HttpLocal,
+    )
+    .unwrap();
+
+    // Nobody could pass the authentication
+    assert!(config.check_integrity().is_err());
+
+    let config = Config::load_from_str(
+        r#"{
+            "local_port": 5112,
+            "local_address": "127.0.0.1",
+            "server": "127.0.0.1",
+            "server_port": 5122,
+            "password": "password",
+            "method": "aes-256-gcm",
+            "local_users": [
+                {
+                    "username": "user",
+                    "password": "user-secret"
+                }
+            ]
+        }"#,
+        ConfigType::HttpLocal,
+    )
+    .unwrap();
+
+    assert!(config.check_integrity().is_ok());
+    assert!(config.local_auth.as_ref().unwrap().check("user", "user-secret"));
+
+    let display = config.to_string();
+    assert!(display.contains("user"));
+    assert!(!display.contains("user-secret"));
+    assert!(!format!("{:?}", config).contains("user-secret"));
+}
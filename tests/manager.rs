@@ -0,0 +1,140 @@
+#![cfg(feature = "manager-http")]
+
+use std::{net::SocketAddr, str};
+
+use tokio::{
+    net::TcpStream,
+    prelude::*,
+    time::{self, Duration},
+};
+
+use shadowsocks::{run_manager, Config, ConfigType, ManagerAddr, ManagerConfig};
+
+const HTTP_TOKEN: &str = "test-http-token";
+
+/// Address on localhost with a free port for both TCP and UDP, for servers that are started by configurations
+fn free_addr() -> SocketAddr {
+    loop {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        if std::net::UdpSocket::bind(addr).is_ok() {
+            return addr;
+        }
+    }
+}
+
+fn manager_config(manager_addr: SocketAddr, http_addr: SocketAddr, http_token: Option<&str>) -> Config {
+    let mut manager_config = ManagerConfig::new(ManagerAddr::SocketAddr(manager_addr));
+    manager_config.http_addr = Some(http_addr);
+    manager_config.http_token = http_token.map(ToOwned::to_owned);
+
+    let mut config = Config::new(ConfigType::Manager);
+    config.manager = Some(manager_config);
+    config
+}
+
+/// Sends a HTTP/1.1 request and returns status code and body of the response
+async fn http_request(addr: SocketAddr, method: &str, path: &str, token: Option<&str>, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+
+    let mut req = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        addr,
+        body.len()
+    );
+    if let Some(token) = token {
+        req += &format!("Authorization: Bearer {}\r\n", token);
+    }
+    req += "\r\n";
+    req += body;
+    stream.write_all(req.as_bytes()).await.unwrap();
+
+    let mut resp = Vec::new();
+    time::timeout(Duration::from_secs(5), stream.read_to_end(&mut resp))
+        .await
+        .unwrap()
+        .unwrap();
+    let resp = str::from_utf8(&resp).unwrap();
+
+    let status = resp.split_whitespace().nth(1).unwrap().parse::<u16>().unwrap();
+    let body = resp.splitn(2, "\r\n\r\n").nth(1).unwrap_or("").to_owned();
+    (status, body)
+}
+
+#[tokio::test]
+async fn manager_http_api() {
+    let _ = env_logger::try_init();
+
+    let http_addr = free_addr();
+    tokio::spawn(run_manager(manager_config(free_addr(), http_addr, Some(HTTP_TOKEN))));
+    time::sleep(Duration::from_secs(1)).await;
+
+    // Port of the server added by API
+    let port = free_addr().port();
+    let server_path = format!("/servers/{}", port);
+    let server_port = format!(r#""server_port":{}"#, port);
+
+    // Requests without the token are rejected
+    let (status, _) = http_request(http_addr, "GET", "/servers", None, "").await;
+    assert_eq!(status, 401);
+    let (status, _) = http_request(http_addr, "GET", "/servers", Some("wrong-token"), "").await;
+    assert_eq!(status, 401);
+    let (status, _) = http_request(http_addr, "POST", &server_path, None, "{}").await;
+    assert_eq!(status, 401);
+
+    let token = Some(HTTP_TOKEN);
+
+    let (status, body) = http_request(http_addr, "GET", "/servers", token, "").await;
+    assert_eq!(status, 200);
+    assert_eq!(body, "[]");
+
+    let server = r#"{"password":"test-password","method":"aes-256-gcm"}"#;
+    let (status, body) = http_request(http_addr, "POST", &server_path, token, server).await;
+    assert_eq!(status, 201);
+    assert!(body.contains(&server_port), "unexpected body {}", body);
+
+    let (status, body) = http_request(http_addr, "GET", &server_path, token, "").await;
+    assert_eq!(status, 200);
+    assert!(body.contains(&server_port), "unexpected body {}", body);
+
+    let (status, body) = http_request(http_addr, "GET", "/stats", token, "").await;
+    assert_eq!(status, 200);
+    assert!(body.contains(&port.to_string()), "unexpected body {}", body);
+
+    let (status, _) = http_request(http_addr, "POST", &server_path, token, "[]").await;
+    assert_eq!(status, 400);
+    let (status, _) = http_request(http_addr, "GET", "/servers/not-a-port", token, "").await;
+    assert_eq!(status, 400);
+    let (status, _) = http_request(http_addr, "PUT", "/servers", token, "").await;
+    assert_eq!(status, 405);
+
+    let (status, _) = http_request(http_addr, "DELETE", &server_path, token, "").await;
+    assert_eq!(status, 204);
+    let (status, _) = http_request(http_addr, "GET", &server_path, token, "").await;
+    assert_eq!(status, 404);
+}
+
+#[tokio::test]
+async fn manager_http_api_requires_token_on_public_address() {
+    let _ = env_logger::try_init();
+
+    // Configurations are only checked, nothing is bound to these addresses
+    let manager_addr = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
+    let public_addr = "0.0.0.0:0".parse::<SocketAddr>().unwrap();
+    let loopback_addr = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
+
+    let config = manager_config(manager_addr, public_addr, None);
+    assert!(config.check_integrity().is_err());
+    assert!(run_manager(config).await.is_err());
+
+    let config = manager_config(manager_addr, public_addr, Some(""));
+    assert!(config.check_integrity().is_err());
+
+    let config = manager_config(manager_addr, public_addr, Some(HTTP_TOKEN));
+    assert!(config.check_integrity().is_ok());
+
+    let config = manager_config(manager_addr, loopback_addr, None);
+    assert!(config.check_integrity().is_ok());
+}
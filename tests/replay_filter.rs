@@ -0,0 +1,30 @@
+use shadowsocks::{
+    config::{Config, ConfigType},
+    context::Context,
+};
+
+#[tokio::test]
+async fn replay_filter_snapshot_reload() {
+    let _ = env_logger::try_init();
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("replay-filter.bin");
+
+    let mut config = Config::new(ConfigType::Server);
+    config.replay_filter.entries = Some(1000);
+    config.replay_filter.snapshot_path = Some(path.clone());
+
+    let context = Context::new_shared(config.clone()).await;
+    assert!(!context.check_nonce_and_set(b"replayed-salt"));
+    context.save_replay_filter().await.unwrap();
+
+    // Salts seen before restarting are still rejected
+    let context = Context::new_shared(config.clone()).await;
+    assert!(context.check_nonce_and_set(b"replayed-salt"));
+    assert!(!context.check_nonce_and_set(b"another-salt"));
+
+    // Snapshots of filters with different sizes are discarded
+    config.replay_filter.entries = Some(2000);
+    let context = Context::new_shared(config).await;
+    assert!(!context.check_nonce_and_set(b"replayed-salt"));
+}
@@ -1,20 +1,42 @@
 use std::{
+    fs,
     net::{SocketAddr, ToSocketAddrs},
     str,
 };
 
+use futures::future;
 use tokio::{
+    net::{TcpListener, TcpStream},
     prelude::*,
+    sync::mpsc,
     time::{self, Duration},
 };
 
 use shadowsocks::{
-    config::{Config, ConfigType, Mode, ServerAddr, ServerConfig},
+    acl::AccessControl,
+    config::{Config, ConfigType, HealthCheckProbe, LocalAuth, Mode, OnlineConfig, ServerAddr, ServerConfig},
     crypto::v1::CipherKind,
-    relay::{socks5::Address, tcprelay::client::Socks5Client},
+    relay::{
+        flow::MultiServerFlowStatistic,
+        server::run_with_flow_stat as run_server_with_flow_stat,
+        socks5::{
+            self,
+            Address,
+            Command,
+            HandshakeRequest,
+            HandshakeResponse,
+            Reply,
+            TcpRequestHeader,
+            TcpResponseHeader,
+        },
+        tcprelay::client::Socks5Client,
+    },
     run_local,
+    run_local_with_control,
     run_server,
+    ServerControl,
 };
+use url::Url;
 
 pub struct Socks5TestServer {
     local_addr: SocketAddr,
@@ -64,6 +86,61 @@ impl Socks5TestServer {
     }
 }
 
+/// Address on localhost with a free port for both TCP and UDP, for servers that are started by configurations
+fn free_addr() -> SocketAddr {
+    loop {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        if std::net::UdpSocket::bind(addr).is_ok() {
+            return addr;
+        }
+    }
+}
+
+/// Start a TCP server echoing everything back, as a target that doesn't depend on the Internet
+///
+/// Returns the address it is listening on
+async fn start_tcp_echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut r, mut w) = stream.split();
+                let _ = io::copy(&mut r, &mut w).await;
+            });
+        }
+    });
+
+    addr
+}
+
+/// Check that `data` sent via `c` is echoed back
+async fn assert_echo<S>(c: &mut S, data: &[u8])
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    c.write_all(data).await.unwrap();
+    c.flush().await.unwrap();
+
+    let mut buf = vec![0u8; data.len()];
+    time::timeout(Duration::from_secs(5), c.read_exact(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(buf, data);
+}
+
+/// SIP008 online configuration document with one server on localhost
+fn sip008_document(port: u16, password: &str, method: CipherKind) -> String {
+    format!(
+        r#"{{"version": 1, "servers": [{{"server": "127.0.0.1", "server_port": {}, "password": "{}", "method": "{}"}}]}}"#,
+        port, password, method
+    )
+}
+
 #[tokio::test]
 async fn socks5_relay_stream() {
     let _ = env_logger::try_init();
@@ -129,3 +206,369 @@ async fn socks5_relay_aead() {
     let http_status = b"HTTP/1.0 200 OK\r\n";
     buf.starts_with(http_status);
 }
+
+#[cfg(feature = "aead-cipher-2022")]
+#[tokio::test]
+async fn socks5_relay_aead_2022() {
+    let _ = env_logger::try_init();
+
+    // Base64 encoded 32 bytes key
+    const PASSWORD: &str = "N+EOGT+UQ+Jj7w6aKR+G6FuYkz6IfxXe6mEzK23r5EI=";
+    const METHOD: &str = "2022-blake3-aes-256-gcm";
+
+    let svr_addr = free_addr();
+    let mut svr = Socks5TestServer::new(svr_addr, free_addr(), PASSWORD, CipherKind::AES_256_GCM, false);
+
+    let svr_addr = ServerAddr::from(svr_addr);
+    let svr_cfg = ServerConfig::with_method_name(svr_addr, PASSWORD.to_owned(), METHOD, None, None).unwrap();
+    assert!(svr_cfg.is_aead_2022());
+    svr.svr_config.server = vec![svr_cfg.clone()];
+    svr.cli_config.server = vec![svr_cfg];
+    svr.run().await;
+
+    let echo_addr = start_tcp_echo_server().await;
+
+    let target = Address::SocketAddress(echo_addr);
+    let mut c = Socks5Client::connect(target, svr.client_addr()).await.unwrap();
+
+    assert_echo(&mut c, b"hello AEAD-2022").await;
+    assert_echo(&mut c, &[0x5a; 100_000]).await;
+}
+
+#[tokio::test]
+async fn socks5_relay_multi_user() {
+    let _ = env_logger::try_init();
+
+    // The last one is not a user of the server
+    const PASSWORDS: [&str; 3] = ["user-1-password", "user-2-password", "unknown-password"];
+    const METHOD: CipherKind = CipherKind::AES_256_GCM;
+
+    // Sizes of data sent by users, far apart so that each user's traffic could be told
+    const DATA_SIZES: [usize; 2] = [100, 10_000];
+    // Salt, address header and AEAD tags of chunks
+    const MAX_OVERHEAD: usize = 1024;
+
+    let echo_addr = start_tcp_echo_server().await;
+
+    let svr_addr = free_addr();
+    let local_addrs = PASSWORDS.iter().map(|_| free_addr()).collect::<Vec<_>>();
+
+    let mut svr_config = Config::new(ConfigType::Server);
+    svr_config.server = PASSWORDS[..2]
+        .iter()
+        .map(|pwd| ServerConfig::basic(svr_addr, (*pwd).to_owned(), METHOD))
+        .collect();
+    let flow_stat = MultiServerFlowStatistic::new_shared(&svr_config);
+    tokio::spawn(run_server_with_flow_stat(
+        svr_config,
+        flow_stat.clone(),
+        ServerControl::default(),
+    ));
+
+    for (local_addr, pwd) in local_addrs.iter().zip(PASSWORDS.iter()) {
+        let mut cli_config = Config::new(ConfigType::Socks5Local);
+        cli_config.local_addr = Some(ServerAddr::from(*local_addr));
+        cli_config.server = vec![ServerConfig::basic(svr_addr, (*pwd).to_owned(), METHOD)];
+        tokio::spawn(run_local(cli_config));
+    }
+
+    time::sleep(Duration::from_secs(1)).await;
+
+    let target = Address::SocketAddress(echo_addr);
+
+    for (local_addr, size) in local_addrs[..2].iter().zip(DATA_SIZES.iter()) {
+        let mut c = Socks5Client::connect(target.clone(), local_addr).await.unwrap();
+        assert_echo(&mut c, &vec![0x5a; *size]).await;
+    }
+
+    // Requests of unknown users are never relayed, the server holds the connection until it is closed by client
+    let mut c = Socks5Client::connect(target, &local_addrs[2]).await.unwrap();
+    c.write_all(b"hello").await.unwrap();
+    c.flush().await.unwrap();
+
+    let mut buf = [0u8; 5];
+    assert!(time::timeout(Duration::from_secs(1), c.read(&mut buf)).await.is_err());
+
+    c.shutdown().await.unwrap();
+    let mut buf = Vec::new();
+    time::timeout(Duration::from_secs(5), c.read_to_end(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(buf.is_empty());
+
+    // Traffic is counted for each user, none of them is charged for the unknown user
+    for (idx, size) in DATA_SIZES.iter().enumerate() {
+        let tcp = flow_stat.get_by_index(idx).unwrap().tcp();
+        let (rx, tx) = (tcp.rx(), tcp.tx());
+        assert!(rx > *size && rx < size + MAX_OVERHEAD, "user {} rx {}", idx, rx);
+        assert!(tx > *size && tx < size + MAX_OVERHEAD, "user {} tx {}", idx, tx);
+    }
+}
+
+#[tokio::test]
+async fn socks5_relay_password() {
+    let _ = env_logger::try_init();
+
+    const PASSWORD: &str = "test-password";
+    const METHOD: CipherKind = CipherKind::AES_256_GCM;
+
+    let mut svr = Socks5TestServer::new(free_addr(), free_addr(), PASSWORD, METHOD, false);
+    let mut auth = LocalAuth::new();
+    auth.add_user("user", "pass");
+    svr.cli_config.local_auth = Some(auth);
+    svr.run().await;
+
+    let addr = Address::DomainNameAddress("www.example.com".to_owned(), 80);
+
+    assert!(Socks5Client::connect(addr.clone(), svr.client_addr()).await.is_err());
+    assert!(
+        Socks5Client::connect_with_password(addr.clone(), svr.client_addr(), "user", "wrong")
+            .await
+            .is_err()
+    );
+
+    let mut c = Socks5Client::connect_with_password(addr, svr.client_addr(), "user", "pass")
+        .await
+        .unwrap();
+
+    let req = b"GET / HTTP/1.0\r\nHost: www.example.com\r\nAccept: */*\r\n\r\n";
+    c.write_all(req).await.unwrap();
+    c.flush().await.unwrap();
+
+    let mut buf = Vec::new();
+    c.read_to_end(&mut buf).await.unwrap();
+
+    println!("Got reply from server: {}", str::from_utf8(&buf).unwrap());
+
+    let http_status = b"HTTP/1.0 200 OK\r\n";
+    buf.starts_with(http_status);
+}
+
+#[tokio::test]
+async fn socks5_relay_failover() {
+    let _ = env_logger::try_init();
+
+    const PASSWORD: &str = "test-password";
+    const METHOD: CipherKind = CipherKind::AES_256_GCM;
+
+    let echo_addr = start_tcp_echo_server().await;
+
+    let mut svr = Socks5TestServer::new(free_addr(), free_addr(), PASSWORD, METHOD, false);
+
+    // The killed server is chosen first, because there is no probe to find out
+    let killed_addr = free_addr();
+    let killed_svr_cfg = ServerConfig::basic(killed_addr, PASSWORD.to_owned(), METHOD);
+    svr.cli_config.server.insert(0, killed_svr_cfg.clone());
+    svr.cli_config.health_check.tcp_probe = HealthCheckProbe::Disabled;
+
+    let mut killed_config = Config::new(ConfigType::Server);
+    killed_config.server = vec![killed_svr_cfg];
+    let (killed_server, killed_handle) = future::abortable(run_server(killed_config));
+    tokio::spawn(killed_server);
+
+    svr.run().await;
+
+    let target = Address::SocketAddress(echo_addr);
+
+    let mut c = Socks5Client::connect(target.clone(), svr.client_addr()).await.unwrap();
+    assert_echo(&mut c, b"relayed by the first server").await;
+
+    killed_handle.abort();
+    time::sleep(Duration::from_millis(100)).await;
+
+    // Retried with the other server before replying
+    let mut c = Socks5Client::connect(target, svr.client_addr()).await.unwrap();
+    assert_echo(&mut c, b"relayed by the other server").await;
+}
+
+#[tokio::test]
+async fn socks5_relay_online_config() {
+    let _ = env_logger::try_init();
+
+    const PASSWORD: &str = "test-password";
+    const METHOD: CipherKind = CipherKind::AES_256_GCM;
+
+    let echo_addr = start_tcp_echo_server().await;
+
+    let svr_addr = free_addr();
+    // Nothing is listening on it
+    let down_port = free_addr().port();
+
+    let dir = tempfile::tempdir().unwrap();
+    let config_path = dir.path().join("online-config.json");
+    fs::write(&config_path, sip008_document(down_port, PASSWORD, METHOD)).unwrap();
+
+    let mut online_config = OnlineConfig::new(Url::from_file_path(&config_path).unwrap());
+    online_config.update_interval = Duration::from_secs(1);
+
+    let mut svr = Socks5TestServer::new(svr_addr, free_addr(), PASSWORD, METHOD, false);
+    svr.cli_config.server.clear();
+    svr.cli_config.online_config = Some(online_config);
+    svr.cli_config.health_check.tcp_probe = HealthCheckProbe::Disabled;
+    svr.run().await;
+
+    let target = Address::SocketAddress(echo_addr);
+    assert!(Socks5Client::connect(target.clone(), svr.client_addr()).await.is_err());
+
+    // Servers are replaced after the document is fetched again
+    fs::write(&config_path, sip008_document(svr_addr.port(), PASSWORD, METHOD)).unwrap();
+    time::sleep(Duration::from_secs(2)).await;
+
+    let mut c = Socks5Client::connect(target, svr.client_addr()).await.unwrap();
+    assert_echo(&mut c, b"relayed by the server in online configuration").await;
+}
+
+#[tokio::test]
+async fn socks5_relay_online_config_reloaded() {
+    let _ = env_logger::try_init();
+
+    const PASSWORD: &str = "test-password";
+    const METHOD: CipherKind = CipherKind::AES_256_GCM;
+
+    let echo_addr = start_tcp_echo_server().await;
+
+    let svr_addr = free_addr();
+    // Nothing is listening on it
+    let down_addr = free_addr();
+
+    let dir = tempfile::tempdir().unwrap();
+    let config_path = dir.path().join("online-config.json");
+    fs::write(&config_path, sip008_document(svr_addr.port(), PASSWORD, METHOD)).unwrap();
+
+    let mut svr = Socks5TestServer::new(svr_addr, free_addr(), PASSWORD, METHOD, false);
+    svr.cli_config.server = vec![ServerConfig::basic(down_addr, PASSWORD.to_owned(), METHOD)];
+    svr.cli_config.health_check.tcp_probe = HealthCheckProbe::Disabled;
+    svr.cli_config.failover_retries = 0;
+
+    tokio::spawn(run_server(svr.svr_config.clone()));
+
+    let (reload_tx, reload_rx) = mpsc::channel(1);
+    let control = ServerControl {
+        reload_rx: Some(reload_rx),
+        shutdown_rx: None,
+    };
+    tokio::spawn(run_local_with_control(svr.cli_config.clone(), control));
+    time::sleep(Duration::from_secs(1)).await;
+
+    let target = Address::SocketAddress(echo_addr);
+    assert!(Socks5Client::connect(target.clone(), svr.client_addr()).await.is_err());
+
+    // Online configuration added by reloading is fetched at once
+    let mut config = svr.cli_config.clone();
+    config.server.clear();
+    config.online_config = Some(OnlineConfig::new(Url::from_file_path(&config_path).unwrap()));
+    reload_tx.send(config).await.unwrap();
+    time::sleep(Duration::from_secs(1)).await;
+
+    let mut c = Socks5Client::connect(target, svr.client_addr()).await.unwrap();
+    assert_echo(&mut c, b"relayed by the server in reloaded online configuration").await;
+}
+
+#[tokio::test]
+async fn socks5_bind_bypassed() {
+    let _ = env_logger::try_init();
+
+    const PASSWORD: &str = "test-password";
+    const METHOD: CipherKind = CipherKind::AES_256_GCM;
+
+    let dir = tempfile::tempdir().unwrap();
+    let acl_path = dir.path().join("bypass.acl");
+    fs::write(&acl_path, "[bypass_all]\n").unwrap();
+
+    let mut svr = Socks5TestServer::new(free_addr(), free_addr(), PASSWORD, METHOD, false);
+    svr.cli_config.acl = Some(AccessControl::load_from_file(&acl_path).unwrap());
+    svr.run().await;
+
+    let mut c = TcpStream::connect(svr.client_addr()).await.unwrap();
+
+    HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_NONE])
+        .write_to(&mut c)
+        .await
+        .unwrap();
+    let hsp = HandshakeResponse::read_from(&mut c).await.unwrap();
+    assert_eq!(hsp.chosen_method, socks5::SOCKS5_AUTH_METHOD_NONE);
+
+    // Target is the host that is going to connect in
+    let target = "127.0.0.1:21".parse::<SocketAddr>().unwrap();
+    TcpRequestHeader::new(Command::TcpBind, Address::SocketAddress(target))
+        .write_to(&mut c)
+        .await
+        .unwrap();
+
+    // The first reply carries the address that sslocal listens on
+    let hp = TcpResponseHeader::read_from(&mut c).await.unwrap();
+    assert!(matches!(hp.reply, Reply::Succeeded));
+    let bind_addr = match hp.address {
+        Address::SocketAddress(sa) => sa,
+        addr => panic!("unexpected BIND address {}", addr),
+    };
+
+    let mut inbound = TcpStream::connect(bind_addr).await.unwrap();
+
+    // The second reply carries the address of the inbound connection
+    let hp = TcpResponseHeader::read_from(&mut c).await.unwrap();
+    assert!(matches!(hp.reply, Reply::Succeeded));
+    match hp.address {
+        Address::SocketAddress(sa) => assert_eq!(sa, inbound.local_addr().unwrap()),
+        addr => panic!("unexpected peer address {}", addr),
+    }
+
+    inbound.write_all(b"hello").await.unwrap();
+    let mut buf = [0u8; 5];
+    c.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+
+    c.write_all(b"world").await.unwrap();
+    inbound.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"world");
+}
+
+#[tokio::test]
+async fn socks5_bind_unknown_target() {
+    let _ = env_logger::try_init();
+
+    const PASSWORD: &str = "test-password";
+    const METHOD: CipherKind = CipherKind::AES_256_GCM;
+
+    let dir = tempfile::tempdir().unwrap();
+    let acl_path = dir.path().join("bypass.acl");
+    fs::write(&acl_path, "[bypass_all]\n").unwrap();
+
+    let mut svr = Socks5TestServer::new(free_addr(), free_addr(), PASSWORD, METHOD, false);
+    svr.cli_config.acl = Some(AccessControl::load_from_file(&acl_path).unwrap());
+    svr.run().await;
+
+    async fn bind(proxy: &SocketAddr, target: Address) -> (TcpStream, TcpResponseHeader) {
+        let mut c = TcpStream::connect(proxy).await.unwrap();
+
+        HandshakeRequest::new(vec![socks5::SOCKS5_AUTH_METHOD_NONE])
+            .write_to(&mut c)
+            .await
+            .unwrap();
+        HandshakeResponse::read_from(&mut c).await.unwrap();
+
+        TcpRequestHeader::new(Command::TcpBind, target)
+            .write_to(&mut c)
+            .await
+            .unwrap();
+        let hp = TcpResponseHeader::read_from(&mut c).await.unwrap();
+        (c, hp)
+    }
+
+    // The client doesn't know the target's address, BND.ADDR must be reachable instead of 0.0.0.0
+    let target = Address::SocketAddress("0.0.0.0:0".parse().unwrap());
+    let (_c, hp) = bind(svr.client_addr(), target).await;
+    assert!(matches!(hp.reply, Reply::Succeeded));
+    let bind_addr = match hp.address {
+        Address::SocketAddress(sa) => sa,
+        addr => panic!("unexpected BIND address {}", addr),
+    };
+    assert_eq!(bind_addr.ip(), svr.client_addr().ip());
+    TcpStream::connect(bind_addr).await.unwrap();
+
+    // Unresolvable target is replied, instead of closing the connection
+    let target = Address::DomainNameAddress("shadowsocks-test.invalid".to_owned(), 21);
+    let (_c, hp) = bind(svr.client_addr(), target).await;
+    assert!(matches!(hp.reply, Reply::HostUnreachable));
+}
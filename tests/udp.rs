@@ -2,15 +2,23 @@
 
 use std::net::SocketAddr;
 
+use tokio::net::UdpSocket;
+
 use log::debug;
 use tokio::time::{self, Duration};
 
 use shadowsocks::{
-    config::{Config, ConfigType, Mode, ServerConfig},
+    config::{Config, ConfigType, LocalAuth, Mode, ServerConfig},
     crypto::v1::CipherKind,
-    relay::{socks5::Address, udprelay::client::Socks5Client},
+    relay::{
+        flow::MultiServerFlowStatistic,
+        server::run_with_flow_stat as run_server_with_flow_stat,
+        socks5::{Address, UdpAssociateHeader},
+        udprelay::client::Socks5Client,
+    },
     run_local,
     run_server,
+    ServerControl,
 };
 
 const SERVER_ADDR: &str = "127.0.0.1:8093";
@@ -44,6 +52,17 @@ fn get_cli_config() -> Config {
     cfg
 }
 
+/// Address on localhost with a free port for both TCP and UDP, for servers that are started by configurations
+fn free_addr() -> SocketAddr {
+    loop {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        if std::net::UdpSocket::bind(addr).is_ok() {
+            return addr;
+        }
+    }
+}
+
 fn get_client_addr() -> SocketAddr {
     LOCAL_ADDR.parse().unwrap()
 }
@@ -56,13 +75,11 @@ fn start_local() {
     tokio::spawn(run_local(get_cli_config()));
 }
 
-fn start_udp_echo_server() {
-    use tokio::net::UdpSocket;
-
-    tokio::spawn(async {
-        let l = UdpSocket::bind(UDP_ECHO_SERVER_ADDR).await.unwrap();
+fn start_udp_echo_server(addr: &'static str) {
+    tokio::spawn(async move {
+        let l = UdpSocket::bind(addr).await.unwrap();
 
-        debug!("UDP echo server started {}", UDP_ECHO_SERVER_ADDR);
+        debug!("UDP echo server started {}", addr);
 
         let mut buf = vec![0u8; 65536];
         let (amt, src) = l.recv_from(&mut buf).await.unwrap();
@@ -84,7 +101,7 @@ async fn udp_relay() {
     start_server();
     start_local();
 
-    start_udp_echo_server();
+    start_udp_echo_server(UDP_ECHO_SERVER_ADDR);
 
     // Wait until all server starts
     time::sleep(Duration::from_secs(1)).await;
@@ -104,3 +121,143 @@ async fn udp_relay() {
     assert_eq!(recv_addr, remote_addr);
     assert_eq!(&buf[..amt], payload);
 }
+
+#[tokio::test]
+async fn udp_relay_authenticated() {
+    let _ = env_logger::try_init();
+
+    // Echoes the packet sent by the authenticated client
+    let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let remote_addr = Address::SocketAddress(echo.local_addr().unwrap());
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 65536];
+        let (amt, src) = echo.recv_from(&mut buf).await.unwrap();
+        echo.send_to(&buf[..amt], &src).await.unwrap();
+    });
+
+    let svr_addr = free_addr();
+    let local_addr = free_addr();
+
+    let mut svr_cfg = get_svr_config();
+    svr_cfg.server[0] = ServerConfig::basic(svr_addr, PASSWORD.to_owned(), METHOD);
+    tokio::spawn(run_server(svr_cfg));
+
+    let mut cli_cfg = get_cli_config();
+    cli_cfg.local_addr = Some(local_addr.into());
+    cli_cfg.server[0] = ServerConfig::basic(svr_addr, PASSWORD.to_owned(), METHOD);
+    cli_cfg.mode = Mode::TcpAndUdp;
+    let mut auth = LocalAuth::new();
+    auth.add_user("user", "pass");
+    cli_cfg.local_auth = Some(auth);
+    tokio::spawn(run_local(cli_cfg));
+
+    // Wait until all server starts
+    time::sleep(Duration::from_secs(1)).await;
+
+    let payload = b"HEllo WORld";
+    let mut buf = vec![0u8; 65536];
+
+    // Packets without an authenticated UDP ASSOCIATE are dropped
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut pkt = Vec::new();
+    UdpAssociateHeader::new(0, remote_addr.clone()).write_to_buf(&mut pkt);
+    pkt.extend_from_slice(payload);
+    socket.send_to(&pkt, &local_addr).await.unwrap();
+    let res = time::timeout(Duration::from_secs(1), socket.recv_from(&mut buf)).await;
+    assert!(res.is_err());
+
+    assert!(Socks5Client::associate(&local_addr).await.is_err());
+
+    let l = Socks5Client::associate_with_password(&local_addr, "user", "pass")
+        .await
+        .unwrap();
+    l.send_to(payload, &remote_addr).await.unwrap();
+
+    let (amt, recv_addr) = time::timeout(Duration::from_secs(5), l.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(recv_addr, remote_addr);
+    assert_eq!(&buf[..amt], payload);
+}
+
+#[tokio::test]
+async fn udp_relay_multi_user() {
+    let _ = env_logger::try_init();
+
+    // The last one is not a user of the server
+    const PASSWORDS: [&str; 3] = ["user-1-password", "user-2-password", "unknown-password"];
+
+    // Sizes of payloads sent by users, far apart so that each user's traffic could be told
+    const PAYLOAD_SIZES: [usize; 2] = [100, 1000];
+    // Salt, address header and AEAD tag of a packet
+    const MAX_OVERHEAD: usize = 128;
+
+    // Echoes every packet, for all users
+    let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let remote_addr = Address::SocketAddress(echo.local_addr().unwrap());
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let (amt, src) = echo.recv_from(&mut buf).await.unwrap();
+            let _ = echo.send_to(&buf[..amt], &src).await;
+        }
+    });
+
+    let svr_addr = free_addr();
+    let local_addrs = PASSWORDS.iter().map(|_| free_addr()).collect::<Vec<_>>();
+
+    let mut svr_cfg = Config::new(ConfigType::Server);
+    svr_cfg.server = PASSWORDS[..2]
+        .iter()
+        .map(|pwd| ServerConfig::basic(svr_addr, (*pwd).to_owned(), METHOD))
+        .collect();
+    svr_cfg.mode = Mode::UdpOnly;
+    let flow_stat = MultiServerFlowStatistic::new_shared(&svr_cfg);
+    tokio::spawn(run_server_with_flow_stat(
+        svr_cfg,
+        flow_stat.clone(),
+        ServerControl::default(),
+    ));
+
+    for (local_addr, pwd) in local_addrs.iter().zip(PASSWORDS.iter()) {
+        let mut cli_cfg = get_cli_config();
+        cli_cfg.local_addr = Some((*local_addr).into());
+        cli_cfg.server = vec![ServerConfig::basic(svr_addr, (*pwd).to_owned(), METHOD)];
+        tokio::spawn(run_local(cli_cfg));
+    }
+
+    // Wait until all server starts
+    time::sleep(Duration::from_secs(1)).await;
+
+    let mut buf = vec![0u8; 65536];
+
+    for (local_addr, size) in local_addrs[..2].iter().zip(PAYLOAD_SIZES.iter()) {
+        let l = Socks5Client::associate(local_addr).await.unwrap();
+
+        let payload = vec![0x5a; *size];
+        l.send_to(&payload, &remote_addr).await.unwrap();
+
+        let (amt, recv_addr) = time::timeout(Duration::from_secs(5), l.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(recv_addr, remote_addr);
+        assert_eq!(&buf[..amt], &payload[..]);
+    }
+
+    // Packets of unknown users are dropped
+    let l = Socks5Client::associate(&local_addrs[2]).await.unwrap();
+    l.send_to(b"HEllo WORld", &remote_addr).await.unwrap();
+    let res = time::timeout(Duration::from_secs(1), l.recv_from(&mut buf)).await;
+    assert!(res.is_err());
+
+    // Traffic is counted for each user, none of them is charged for the unknown user
+    for (idx, size) in PAYLOAD_SIZES.iter().enumerate() {
+        let udp = flow_stat.get_by_index(idx).unwrap().udp();
+        let (rx, tx) = (udp.rx(), udp.tx());
+        assert!(rx > *size && rx < size + MAX_OVERHEAD, "user {} rx {}", idx, rx);
+        assert!(tx > *size && tx < size + MAX_OVERHEAD, "user {} tx {}", idx, tx);
+    }
+}
@@ -0,0 +1,101 @@
+#[cfg(feature = "aead-cipher-2022")]
+use std::net::SocketAddr;
+
+#[cfg(feature = "aead-cipher-2022")]
+use shadowsocks::config::ServerAddr;
+use shadowsocks::{
+    config::{ServerConfig, UrlParseError},
+    crypto::v1::CipherKind,
+    plugin::PluginConfig,
+};
+
+#[test]
+fn url_sip002_round_trip() {
+    let mut svr = ServerConfig::basic(
+        "127.0.0.1:8388".parse().unwrap(),
+        "p@ss:word".to_owned(),
+        CipherKind::AES_256_GCM,
+    );
+    svr.set_plugin(PluginConfig {
+        plugin: "obfs-local".to_owned(),
+        plugin_opts: Some("obfs=tls".to_owned()),
+        plugin_args: Vec::new(),
+    });
+    svr.set_remarks("My Server #1 100%".to_owned());
+
+    for url in &[svr.to_url(), svr.to_qrcode_url()] {
+        let parsed = ServerConfig::from_url(url).unwrap();
+        assert_eq!(parsed.addr().to_string(), "127.0.0.1:8388");
+        assert_eq!(parsed.method(), CipherKind::AES_256_GCM);
+        assert_eq!(parsed.password(), "p@ss:word");
+        assert_eq!(parsed.remarks(), Some("My Server #1 100%"));
+    }
+
+    let parsed = ServerConfig::from_url(&svr.to_url()).unwrap();
+    let plugin = parsed.plugin().unwrap();
+    assert_eq!(plugin.plugin, "obfs-local");
+    assert_eq!(plugin.plugin_opts.as_deref(), Some("obfs=tls"));
+}
+
+#[cfg(feature = "aead-cipher-2022")]
+#[test]
+fn url_aead_2022_round_trip() {
+    let svr = ServerConfig::with_method_name(
+        ServerAddr::from("127.0.0.1:8388".parse::<SocketAddr>().unwrap()),
+        "AAECAwQFBgcICQoLDA0ODw==".to_owned(),
+        "2022-blake3-aes-128-gcm",
+        None,
+        None,
+    )
+    .unwrap();
+
+    let parsed = ServerConfig::from_url(&svr.to_url()).unwrap();
+    assert!(parsed.is_aead_2022());
+    assert_eq!(parsed.method(), CipherKind::AES_128_GCM);
+    assert_eq!(parsed.method_name(), "2022-blake3-aes-128-gcm");
+    assert_eq!(parsed.key(), &(0u8..16).collect::<Vec<u8>>()[..]);
+
+    // Keys must be base64 encoded, with the cipher's key length
+    assert!(ServerConfig::from_url("ss://2022-blake3-aes-128-gcm:password@127.0.0.1:8388").is_err());
+}
+
+#[test]
+fn url_plain_user_info() {
+    let parsed = ServerConfig::from_url("ss://aes-128-gcm:test%2Fpass@[::1]:8388#example").unwrap();
+    assert_eq!(parsed.addr().to_string(), "[::1]:8388");
+    assert_eq!(parsed.method(), CipherKind::AES_128_GCM);
+    assert_eq!(parsed.password(), "test/pass");
+    assert_eq!(parsed.remarks(), Some("example"));
+}
+
+#[test]
+fn url_legacy_padded() {
+    // Standard base64 with padding, of "aes-256-gcm:password@example.com:8388"
+    let parsed = ServerConfig::from_url("ss://YWVzLTI1Ni1nY206cGFzc3dvcmRAZXhhbXBsZS5jb206ODM4OA==#legacy").unwrap();
+    assert_eq!(parsed.addr().to_string(), "example.com:8388");
+    assert_eq!(parsed.method(), CipherKind::AES_256_GCM);
+    assert_eq!(parsed.password(), "password");
+    assert_eq!(parsed.remarks(), Some("legacy"));
+}
+
+#[test]
+fn url_errors() {
+    let cases = [
+        "http://YWVzLTI1Ni1nY206cGFzc3dvcmQ@127.0.0.1:8388",
+        // Base64 of "aes-256-gcm"
+        "ss://YWVzLTI1Ni1nY20@127.0.0.1:8388",
+        "ss://not-a-cipher:password@127.0.0.1:8388",
+        "ss://!!!@127.0.0.1:8388",
+        // Base64 of "aes-256-gcm:password"
+        "ss://YWVzLTI1Ni1nY206cGFzc3dvcmQ",
+    ];
+
+    for url in &cases {
+        assert!(ServerConfig::from_url(url).is_err(), "{} should be rejected", url);
+    }
+
+    assert!(matches!(
+        ServerConfig::from_url("ss://not-a-cipher:password@127.0.0.1:8388"),
+        Err(UrlParseError::UnsupportedMethod)
+    ));
+}